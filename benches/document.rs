@@ -0,0 +1,52 @@
+#![feature(test)]
+
+extern crate test;
+extern crate pyramid;
+
+use test::Bencher;
+use pyramid::bench::*;
+use pyramid::document::*;
+use pyramid::system::*;
+use pyramid::pon::*;
+
+fn load(b: &mut Bencher, shape: SceneShape) {
+    let xml = generate_scene(&shape);
+    b.iter(|| Document::from_string(&xml).unwrap());
+}
+
+fn set_property(b: &mut Bencher, shape: SceneShape) {
+    let mut doc = Document::from_string(&generate_scene(&shape)).unwrap();
+    let ent = doc.get_entity_by_name("e0").unwrap();
+    b.iter(|| doc.set_property(&ent, "x", Pon::Float(2.0)).unwrap());
+}
+
+fn cascade(b: &mut Bencher, shape: SceneShape) {
+    let mut system = System::new();
+    system.set_document(Document::from_string(&generate_scene(&shape)).unwrap());
+    let ent = system.document().get_entity_by_name("e0").unwrap();
+    b.iter(|| {
+        system.document_mut().set_property(&ent, "x", Pon::Float(2.0)).unwrap();
+        system.update();
+    });
+}
+
+#[bench]
+fn bench_load_deep(b: &mut Bencher) { load(b, SceneShape::Deep(200)); }
+#[bench]
+fn bench_load_wide(b: &mut Bencher) { load(b, SceneShape::Wide(2000)); }
+#[bench]
+fn bench_load_dense(b: &mut Bencher) { load(b, SceneShape::DenseReferences { entities: 1000, references: 8 }); }
+
+#[bench]
+fn bench_set_property_deep(b: &mut Bencher) { set_property(b, SceneShape::Deep(200)); }
+#[bench]
+fn bench_set_property_wide(b: &mut Bencher) { set_property(b, SceneShape::Wide(2000)); }
+#[bench]
+fn bench_set_property_dense(b: &mut Bencher) { set_property(b, SceneShape::DenseReferences { entities: 1000, references: 8 }); }
+
+#[bench]
+fn bench_cascade_deep(b: &mut Bencher) { cascade(b, SceneShape::Deep(200)); }
+#[bench]
+fn bench_cascade_wide(b: &mut Bencher) { cascade(b, SceneShape::Wide(2000)); }
+#[bench]
+fn bench_cascade_dense(b: &mut Bencher) { cascade(b, SceneShape::DenseReferences { entities: 1000, references: 8 }); }
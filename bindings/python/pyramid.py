@@ -0,0 +1,93 @@
+"""Python bindings for pyramid documents, built on the C ABI in include/pyramid.h.
+
+Build the crate (it produces a dylib next to the rlib) and point PYRAMID_LIB at it, or leave the library
+where ctypes.util.find_library can see it.
+
+    doc = Document.from_file("level.xml")
+    ship = doc.entity("ship")
+    doc.set(ship, "x", "5.0")
+    open("level.xml", "w").write(doc.to_string())
+"""
+
+import ctypes
+import ctypes.util
+import os
+
+_lib = ctypes.CDLL(os.environ.get("PYRAMID_LIB") or ctypes.util.find_library("pyramid"))
+
+_lib.pyramid_document_new.restype = ctypes.c_void_p
+_lib.pyramid_document_from_string.restype = ctypes.c_void_p
+_lib.pyramid_document_from_string.argtypes = [ctypes.c_char_p]
+_lib.pyramid_document_from_file.restype = ctypes.c_void_p
+_lib.pyramid_document_from_file.argtypes = [ctypes.c_char_p]
+_lib.pyramid_document_free.argtypes = [ctypes.c_void_p]
+_lib.pyramid_document_to_string.restype = ctypes.c_void_p
+_lib.pyramid_document_to_string.argtypes = [ctypes.c_void_p]
+_lib.pyramid_string_free.argtypes = [ctypes.c_void_p]
+_lib.pyramid_get_entity_by_name.restype = ctypes.c_uint64
+_lib.pyramid_get_entity_by_name.argtypes = [ctypes.c_void_p, ctypes.c_char_p]
+_lib.pyramid_entity_count.restype = ctypes.c_size_t
+_lib.pyramid_entity_count.argtypes = [ctypes.c_void_p]
+_lib.pyramid_entities.restype = ctypes.c_size_t
+_lib.pyramid_entities.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_uint64), ctypes.c_size_t]
+_lib.pyramid_get_property.restype = ctypes.c_void_p
+_lib.pyramid_get_property.argtypes = [ctypes.c_void_p, ctypes.c_uint64, ctypes.c_char_p]
+_lib.pyramid_set_property.restype = ctypes.c_int
+_lib.pyramid_set_property.argtypes = [ctypes.c_void_p, ctypes.c_uint64, ctypes.c_char_p, ctypes.c_char_p]
+
+
+class PyramidError(Exception):
+    pass
+
+
+def _take_string(ptr):
+    if not ptr:
+        return None
+    try:
+        return ctypes.cast(ptr, ctypes.c_char_p).value.decode("utf-8")
+    finally:
+        _lib.pyramid_string_free(ptr)
+
+
+class Document(object):
+    def __init__(self, handle=None):
+        self._handle = handle if handle is not None else _lib.pyramid_document_new()
+        if not self._handle:
+            raise PyramidError("failed to load document")
+
+    @classmethod
+    def from_string(cls, xml):
+        return cls(_lib.pyramid_document_from_string(xml.encode("utf-8")) or 0)
+
+    @classmethod
+    def from_file(cls, path):
+        # Also fails when the library was built without the "fs" feature
+        return cls(_lib.pyramid_document_from_file(path.encode("utf-8")) or 0)
+
+    def __del__(self):
+        if self._handle:
+            _lib.pyramid_document_free(self._handle)
+            self._handle = None
+
+    def to_string(self):
+        return _take_string(_lib.pyramid_document_to_string(self._handle))
+
+    def entity(self, name):
+        """Entity id for name, or None."""
+        entity_id = _lib.pyramid_get_entity_by_name(self._handle, name.encode("utf-8"))
+        return entity_id or None
+
+    def entities(self):
+        count = _lib.pyramid_entity_count(self._handle)
+        ids = (ctypes.c_uint64 * count)()
+        written = _lib.pyramid_entities(self._handle, ids, count)
+        return list(ids[:written])
+
+    def get(self, entity_id, key):
+        """Resolved value of the property as PON source, or None."""
+        return _take_string(_lib.pyramid_get_property(self._handle, entity_id, key.encode("utf-8")))
+
+    def set(self, entity_id, key, expression):
+        """Sets the property from PON source, e.g. "5.0" or "@parent.x"."""
+        if _lib.pyramid_set_property(self._handle, entity_id, key.encode("utf-8"), expression.encode("utf-8")) != 0:
+            raise PyramidError("failed to set %s on entity %d" % (key, entity_id))
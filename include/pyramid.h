@@ -0,0 +1,44 @@
+#ifndef PYRAMID_H
+#define PYRAMID_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct PyramidDocument PyramidDocument;
+
+/* Entity ids start at 1; 0 means "no entity". */
+typedef uint64_t PyramidEntityId;
+
+/* Strings returned by these functions are owned by the caller; release them with pyramid_string_free. */
+
+PyramidDocument *pyramid_document_new(void);
+PyramidDocument *pyramid_document_from_string(const char *xml);
+/* Returns NULL on failure, and always when the library was built without the "fs" feature. */
+PyramidDocument *pyramid_document_from_file(const char *path);
+void pyramid_document_free(PyramidDocument *doc);
+char *pyramid_document_to_string(const PyramidDocument *doc);
+void pyramid_string_free(char *s);
+
+PyramidEntityId pyramid_get_entity_by_name(const PyramidDocument *doc, const char *name);
+size_t pyramid_entity_count(const PyramidDocument *doc);
+size_t pyramid_entities(const PyramidDocument *doc, PyramidEntityId *out, size_t len);
+
+/* Values are exchanged as PON source. Setters return 0 on success and -1 on failure. */
+char *pyramid_get_property(const PyramidDocument *doc, PyramidEntityId entity, const char *key);
+int pyramid_set_property(PyramidDocument *doc, PyramidEntityId entity, const char *key, const char *expression);
+int pyramid_get_property_float(const PyramidDocument *doc, PyramidEntityId entity, const char *key, float *out);
+int pyramid_set_property_float(PyramidDocument *doc, PyramidEntityId entity, const char *key, float value);
+
+/* Subscriptions add up; every callback is called on each set or unset. */
+typedef void (*PyramidPropertyCallback)(PyramidEntityId entity, const char *key, void *user_data);
+void pyramid_subscribe_property_set(PyramidDocument *doc, PyramidPropertyCallback callback, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,183 @@
+
+use std::collections::HashMap;
+use std::cell::Ref;
+
+use document::*;
+use pon::*;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum CapabilityScope {
+    // Any property of the entity or its descendants, and appending entities below it
+    Subtree(EntityId),
+    // Exactly these properties
+    Properties(Vec<PropRef>)
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub struct CapabilityToken(u64);
+
+// Hands out capability tokens. Hosts keep the AccessControl and give scripts or network clients a
+// RestrictedDocument, which only lets through the mutations its token is scoped to.
+pub struct AccessControl {
+    token_counter: u64,
+    scopes: HashMap<CapabilityToken, CapabilityScope>
+}
+
+impl AccessControl {
+    pub fn new() -> AccessControl {
+        AccessControl {
+            token_counter: 0,
+            scopes: HashMap::new()
+        }
+    }
+    pub fn grant(&mut self, scope: CapabilityScope) -> CapabilityToken {
+        self.token_counter += 1;
+        let token = CapabilityToken(self.token_counter);
+        self.scopes.insert(token, scope);
+        token
+    }
+    pub fn revoke(&mut self, token: &CapabilityToken) {
+        self.scopes.remove(token);
+    }
+    pub fn allows(&self, document: &Document, token: &CapabilityToken, prop_ref: &PropRef) -> Result<bool, DocError> {
+        match self.scopes.get(token) {
+            Some(&CapabilityScope::Subtree(ref root)) => is_in_subtree(document, root, &prop_ref.entity_id),
+            Some(&CapabilityScope::Properties(ref prop_refs)) => Ok(prop_refs.contains(prop_ref)),
+            None => Ok(false)
+        }
+    }
+    pub fn restrict<'a>(&'a self, document: &'a mut Document, token: CapabilityToken) -> RestrictedDocument<'a> {
+        RestrictedDocument {
+            document: document,
+            access: self,
+            token: token
+        }
+    }
+}
+
+fn is_in_subtree(document: &Document, root: &EntityId, entity_id: &EntityId) -> Result<bool, DocError> {
+    let mut current = Some(*entity_id);
+    while let Some(id) = current {
+        if id == *root {
+            return Ok(true);
+        }
+        current = try!(document.get_parent(&id));
+    }
+    Ok(false)
+}
+
+pub struct RestrictedDocument<'a> {
+    document: &'a mut Document,
+    access: &'a AccessControl,
+    token: CapabilityToken
+}
+
+impl<'a> RestrictedDocument<'a> {
+    // Read only; every change goes through the checked methods below
+    pub fn document(&self) -> &Document {
+        &*self.document
+    }
+    pub fn get_property(&self, entity_id: &EntityId, property_key: &str) -> Result<Ref<Pon>, DocError> {
+        self.document.get_property(entity_id, property_key)
+    }
+    fn check_property(&self, entity_id: &EntityId, property_key: &str) -> Result<(), DocError> {
+        let prop_ref = PropRef::new(entity_id, property_key);
+        if !try!(self.access.allows(&*self.document, &self.token, &prop_ref)) {
+            return Err(DocError::AccessDenied(prop_ref));
+        }
+        Ok(())
+    }
+    // Only Subtree capabilities allow structural changes
+    fn check_structure(&self, entity_id: &EntityId) -> Result<(), DocError> {
+        let allowed = match self.access.scopes.get(&self.token) {
+            Some(&CapabilityScope::Subtree(ref root)) => try!(is_in_subtree(&*self.document, root, entity_id)),
+            _ => false
+        };
+        if !allowed {
+            return Err(DocError::AccessDenied(PropRef::new(entity_id, "")));
+        }
+        Ok(())
+    }
+    pub fn set_property(&mut self, entity_id: &EntityId, property_key: &str, expression: Pon) -> Result<(), DocError> {
+        try!(self.check_property(entity_id, property_key));
+        self.document.set_property(entity_id, property_key, expression)
+    }
+    pub fn set_property_floats(&mut self, entity_id: &EntityId, property_key: &str, values: Vec<f32>) -> Result<(), DocError> {
+        try!(self.check_property(entity_id, property_key));
+        self.document.set_property_floats(entity_id, property_key, values)
+    }
+    pub fn set_property_integers(&mut self, entity_id: &EntityId, property_key: &str, values: Vec<i64>) -> Result<(), DocError> {
+        try!(self.check_property(entity_id, property_key));
+        self.document.set_property_integers(entity_id, property_key, values)
+    }
+    pub fn modify_property_floats<F: FnOnce(&mut [f32])>(&mut self, entity_id: &EntityId, property_key: &str, func: F) -> Result<(), DocError> {
+        try!(self.check_property(entity_id, property_key));
+        self.document.modify_property_floats(entity_id, property_key, func)
+    }
+    pub fn modify_property_integers<F: FnOnce(&mut [i64])>(&mut self, entity_id: &EntityId, property_key: &str, func: F) -> Result<(), DocError> {
+        try!(self.check_property(entity_id, property_key));
+        self.document.modify_property_integers(entity_id, property_key, func)
+    }
+    pub fn unset_property(&mut self, entity_id: &EntityId, property_key: &str) -> Result<Pon, DocError> {
+        try!(self.check_property(entity_id, property_key));
+        self.document.unset_property(entity_id, property_key)
+    }
+    pub fn remove_property(&mut self, entity_id: &EntityId, property_key: &str) -> Result<Vec<PropRef>, DocError> {
+        try!(self.check_property(entity_id, property_key));
+        self.document.remove_property(entity_id, property_key)
+    }
+    pub fn append_entity(&mut self, parent_id: &EntityId, type_name: &str, name: Option<String>) -> Result<EntityId, DocError> {
+        try!(self.check_structure(parent_id));
+        self.document.append_entity(Some(*parent_id), type_name, name)
+    }
+    pub fn move_child(&mut self, parent_id: &EntityId, from: usize, to: usize) -> Result<(), DocError> {
+        try!(self.check_structure(parent_id));
+        self.document.move_child(parent_id, from, to)
+    }
+    pub fn rename_entity(&mut self, entity_id: &EntityId, new_name: &str) -> Result<Vec<PropRef>, DocError> {
+        try!(self.check_structure(entity_id));
+        self.document.rename_entity(entity_id, new_name)
+    }
+    pub fn set_entity_type_name(&mut self, entity_id: &EntityId, type_name: &str) -> Result<(), DocError> {
+        try!(self.check_structure(entity_id));
+        self.document.set_entity_type_name(entity_id, type_name)
+    }
+    pub fn remove_entity(&mut self, entity_id: &EntityId) -> Result<Vec<PropRef>, DocError> {
+        try!(self.check_structure(entity_id));
+        self.document.remove_entity(entity_id)
+    }
+    // Both where the subtree is and where it goes have to be in the capability's subtree
+    pub fn reparent_entity(&mut self, entity_id: &EntityId, new_parent_id: &EntityId, index: usize) -> Result<Vec<PropRef>, DocError> {
+        try!(self.check_structure(entity_id));
+        try!(self.check_structure(new_parent_id));
+        self.document.reparent_entity(entity_id, new_parent_id, index)
+    }
+}
+
+#[test]
+fn test_restricted_set_property() {
+    let mut doc = Document::from_string(r#"<Entity name="engine"><Entity name="player" /></Entity>"#).unwrap();
+    let engine = doc.get_entity_by_name("engine").unwrap();
+    let player = doc.get_entity_by_name("player").unwrap();
+    let mut access = AccessControl::new();
+    let token = access.grant(CapabilityScope::Subtree(player));
+    let mut restricted = access.restrict(&mut doc, token);
+    assert_eq!(restricted.set_property(&player, "x", Pon::Integer(1)), Ok(()));
+    assert_eq!(restricted.set_property(&engine, "x", Pon::Integer(1)), Err(DocError::AccessDenied(PropRef::new(&engine, "x"))));
+}
+
+#[test]
+fn test_restricted_structural_changes() {
+    let mut doc = Document::from_string(r#"<Entity name="engine" x="1"><Entity name="player"><Entity name="gun" /></Entity></Entity>"#).unwrap();
+    let engine = doc.get_entity_by_name("engine").unwrap();
+    let player = doc.get_entity_by_name("player").unwrap();
+    let gun = doc.get_entity_by_name("gun").unwrap();
+    let mut access = AccessControl::new();
+    let token = access.grant(CapabilityScope::Subtree(player));
+    let mut restricted = access.restrict(&mut doc, token);
+    assert_eq!(restricted.unset_property(&engine, "x").err(), Some(DocError::AccessDenied(PropRef::new(&engine, "x"))));
+    assert_eq!(restricted.remove_entity(&engine).err(), Some(DocError::AccessDenied(PropRef::new(&engine, ""))));
+    assert_eq!(restricted.reparent_entity(&gun, &engine, 0).err(), Some(DocError::AccessDenied(PropRef::new(&engine, ""))));
+    assert!(restricted.rename_entity(&gun, "rifle").is_ok());
+    assert!(restricted.remove_entity(&gun).is_ok());
+}
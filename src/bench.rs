@@ -0,0 +1,66 @@
+
+use std::iter;
+use std::slice::SliceConcatExt;
+
+// Shapes of synthetic documents used by the benches to stress different parts of the dependency engine
+#[derive(PartialEq, Debug, Clone)]
+pub enum SceneShape {
+    // A single chain of nested entities, each referencing its parent
+    Deep(usize),
+    // One root with a flat list of children, each referencing the root
+    Wide(usize),
+    // A flat list of entities where each references the previous `references` entities
+    DenseReferences { entities: usize, references: usize }
+}
+
+// Entities are named e0, e1, ... in document order so benches can look them up with get_entity_by_name
+pub fn generate_scene(shape: &SceneShape) -> String {
+    match shape {
+        &SceneShape::Deep(depth) => {
+            let mut s = String::new();
+            for i in 0..depth {
+                if i == 0 {
+                    s.push_str(r#"<Entity name="e0" x="1.0">"#);
+                } else {
+                    s.push_str(&format!(r#"<Entity name="e{}" x="@parent.x">"#, i));
+                }
+            }
+            let closing: Vec<&str> = iter::repeat("</Entity>").take(depth).collect();
+            s.push_str(&closing.concat());
+            s
+        },
+        &SceneShape::Wide(width) => {
+            let mut s = r#"<Entity name="e0" x="1.0">"#.to_string();
+            for i in 1..(width + 1) {
+                s.push_str(&format!(r#"<Entity name="e{}" x="@parent.x" />"#, i));
+            }
+            s.push_str("</Entity>");
+            s
+        },
+        &SceneShape::DenseReferences { entities, references } => {
+            let mut s = r#"<Entity name="root">"#.to_string();
+            for i in 0..entities {
+                if i == 0 {
+                    s.push_str(r#"<Entity name="e0" x="1.0" />"#);
+                    continue;
+                }
+                let refs: Vec<String> = (0..references)
+                    .filter(|j| *j < i)
+                    .map(|j| format!("@e{}.x", i - j - 1))
+                    .collect();
+                s.push_str(&format!(r#"<Entity name="e{}" x="[{}]" />"#, i, refs.join(", ")));
+            }
+            s.push_str("</Entity>");
+            s
+        }
+    }
+}
+
+
+#[test]
+fn test_generate_deep_scene() {
+    use document::*;
+    let doc = Document::from_string(&generate_scene(&SceneShape::Deep(3))).unwrap();
+    let ent = doc.get_entity_by_name("e2").unwrap();
+    assert_eq!(doc.get_property(&ent, "x").unwrap().concretize().unwrap(), ::pon::Pon::Float(1.0));
+}
@@ -0,0 +1,36 @@
+// pyramid-server <document.xml> [address]
+//
+// Serves the document over HTTP, see server.rs for the API. Build with `--features server`.
+
+extern crate pyramid;
+
+#[cfg(feature = "server")]
+fn main() {
+    use std::env;
+    use std::path::Path;
+    use pyramid::document::Document;
+    use pyramid::server::DocumentServer;
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        println!("Usage: {} <document.xml> [address]", args[0]);
+        return;
+    }
+    let address = if args.len() > 2 { args[2].clone() } else { "127.0.0.1:8080".to_string() };
+    let document = match Document::from_file(Path::new(&args[1])) {
+        Ok(document) => document,
+        Err(err) => {
+            println!("Failed to load {}: {:?}", args[1], err);
+            return;
+        }
+    };
+    println!("Serving {} on http://{}", args[1], address);
+    if let Err(err) = DocumentServer::new(document).serve(&address) {
+        println!("Server stopped: {}", err);
+    }
+}
+
+#[cfg(not(feature = "server"))]
+fn main() {
+    println!("pyramid-server was built without the server feature; rebuild with --features server");
+}
@@ -0,0 +1,379 @@
+use std::cell::{Ref, RefCell};
+use std::collections::HashMap;
+use std::io::Write;
+#[cfg(all(feature = "fs", unix, target_pointer_width = "64"))]
+use std::slice;
+use std::str;
+#[cfg(feature = "fs")]
+use std::fs::File;
+#[cfg(feature = "fs")]
+use std::io::Read;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use document::*;
+use pon::*;
+
+// Binary documents for shipping, read back without parsing any values up front. Little endian:
+// MAGIC, the version and the entity count as u32s, then the entities depth first, root first. Each entity is
+// its parent's index plus one (0 for the root), its type, a name flag byte and the name, the property count
+// and then key and PON source pairs. Strings are u32 byte lengths followed by utf-8.
+pub const MAGIC: &'static [u8] = b"PYRB";
+pub const VERSION: u32 = 1;
+
+fn write_u32(out: &mut Write, value: u32) -> Result<(), DocError> {
+    let bytes = [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+    out.write_all(&bytes).map_err(|err| DocError::IoError(err.to_string()))
+}
+
+fn write_str(out: &mut Write, value: &str) -> Result<(), DocError> {
+    try!(write_u32(out, value.len() as u32));
+    out.write_all(value.as_bytes()).map_err(|err| DocError::IoError(err.to_string()))
+}
+
+fn collect_depth_first(document: &Document, entity_id: &EntityId, parent: usize, out: &mut Vec<(EntityId, usize)>) -> Result<(), DocError> {
+    out.push((*entity_id, parent));
+    let index = out.len();
+    for child in try!(document.get_children(entity_id)) {
+        try!(collect_depth_first(document, child, index, out));
+    }
+    Ok(())
+}
+
+pub fn write_binary(document: &Document, out: &mut Write) -> Result<(), DocError> {
+    let mut entities = vec![];
+    if let Some(root) = document.get_root() {
+        try!(collect_depth_first(document, &root, 0, &mut entities));
+    }
+    try!(out.write_all(MAGIC).map_err(|err| DocError::IoError(err.to_string())));
+    try!(write_u32(out, VERSION));
+    try!(write_u32(out, entities.len() as u32));
+    for &(ref entity_id, parent) in &entities {
+        try!(write_u32(out, parent as u32));
+        try!(write_str(out, try!(document.get_entity_type_name(entity_id))));
+        match try!(document.get_entity_name(entity_id)) {
+            Some(name) => {
+                try!(out.write_all(&[1]).map_err(|err| DocError::IoError(err.to_string())));
+                try!(write_str(out, name));
+            },
+            None => try!(out.write_all(&[0]).map_err(|err| DocError::IoError(err.to_string())))
+        }
+        let mut keys: Vec<String> = try!(document.get_properties(entity_id)).into_iter().map(|p| p.property_key).collect();
+        keys.sort();
+        try!(write_u32(out, keys.len() as u32));
+        for key in &keys {
+            try!(write_str(out, key));
+            try!(write_str(out, &try!(document.get_property(entity_id, key)).to_string()));
+        }
+    }
+    Ok(())
+}
+
+// Start and end of a string in the bytes
+type Span = (usize, usize);
+
+struct MappedProperty {
+    key: Span,
+    source: Span,
+    // Parsed on first access
+    value: RefCell<Option<Pon>>
+}
+
+struct MappedEntity {
+    parent: Option<usize>,
+    type_name: Span,
+    name: Option<Span>,
+    children: Vec<usize>,
+    properties: Vec<MappedProperty>
+}
+
+enum Bytes {
+    Owned(Vec<u8>),
+    #[cfg(all(feature = "fs", unix, target_pointer_width = "64"))]
+    Mapped(*const u8, usize)
+}
+
+impl Bytes {
+    fn as_slice(&self) -> &[u8] {
+        match *self {
+            Bytes::Owned(ref bytes) => &bytes[..],
+            #[cfg(all(feature = "fs", unix, target_pointer_width = "64"))]
+            Bytes::Mapped(ptr, len) => unsafe { slice::from_raw_parts(ptr, len) }
+        }
+    }
+}
+
+#[cfg(all(feature = "fs", unix, target_pointer_width = "64"))]
+mod mmap {
+    use std::fs::File;
+    use std::os::raw::{c_int, c_void};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    const PROT_READ: c_int = 1;
+    const MAP_PRIVATE: c_int = 2;
+
+    extern {
+        fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+
+    // None if the file can't be mapped, e.g. because it's empty
+    pub fn map(file: &File, len: usize) -> Option<*const u8> {
+        if len == 0 {
+            return None;
+        }
+        let ptr = unsafe { mmap(ptr::null_mut(), len, PROT_READ, MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if ptr as isize == -1 {
+            return None;
+        }
+        Some(ptr as *const u8)
+    }
+
+    pub fn unmap(ptr: *const u8, len: usize) {
+        unsafe { munmap(ptr as *mut c_void, len); }
+    }
+}
+
+impl Drop for Bytes {
+    fn drop(&mut self) {
+        #[cfg(all(feature = "fs", unix, target_pointer_width = "64"))]
+        fn unmap(bytes: &Bytes) {
+            if let &Bytes::Mapped(ptr, len) = bytes {
+                mmap::unmap(ptr, len);
+            }
+        }
+        #[cfg(not(all(feature = "fs", unix, target_pointer_width = "64")))]
+        fn unmap(_bytes: &Bytes) {}
+        unmap(self);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn truncated(&self) -> DocError {
+        DocError::FormatError(format!("Binary document ends early, at byte {}", self.position))
+    }
+    fn byte(&mut self) -> Result<u8, DocError> {
+        if self.position >= self.bytes.len() {
+            return Err(self.truncated());
+        }
+        self.position += 1;
+        Ok(self.bytes[self.position - 1])
+    }
+    fn u32(&mut self) -> Result<u32, DocError> {
+        let mut value = 0u32;
+        for i in 0..4 {
+            value |= (try!(self.byte()) as u32) << (i * 8);
+        }
+        Ok(value)
+    }
+    fn span(&mut self) -> Result<Span, DocError> {
+        let len = try!(self.u32()) as usize;
+        let start = self.position;
+        if start + len > self.bytes.len() {
+            return Err(self.truncated());
+        }
+        self.position += len;
+        Ok((start, start + len))
+    }
+    // Type names, names and keys are small and always needed, so they're checked right away
+    fn str_span(&mut self) -> Result<Span, DocError> {
+        let span = try!(self.span());
+        match str::from_utf8(&self.bytes[span.0..span.1]) {
+            Ok(_) => Ok(span),
+            Err(_) => Err(DocError::FormatError(format!("Invalid utf-8 at byte {}", span.0)))
+        }
+    }
+}
+
+// A read only document in the binary format. Opening only reads the entity table; property values are
+// parsed the first time they're asked for, so a huge cooked scene costs about what is actually looked at.
+// Entities are numbered depth first from 0, the root. Values are the expressions as written: references
+// aren't resolved, use to_document for that.
+pub struct MappedDocument {
+    bytes: Bytes,
+    entities: Vec<MappedEntity>,
+    entities_by_name: HashMap<String, usize>
+}
+
+impl MappedDocument {
+    // Memory maps the file where that's possible, and reads it otherwise
+    #[cfg(feature = "fs")]
+    pub fn open(path: &Path) -> Result<MappedDocument, DocError> {
+        let mut file = try!(File::open(path).map_err(|err| DocError::IoError(err.to_string())));
+        let len = try!(file.metadata().map_err(|err| DocError::IoError(err.to_string()))).len() as usize;
+        if let Some(bytes) = MappedDocument::map(&file, len) {
+            return MappedDocument::from_storage(bytes);
+        }
+        let mut bytes = vec![];
+        try!(file.read_to_end(&mut bytes).map_err(|err| DocError::IoError(err.to_string())));
+        MappedDocument::from_bytes(bytes)
+    }
+    #[cfg(all(feature = "fs", unix, target_pointer_width = "64"))]
+    fn map(file: &File, len: usize) -> Option<Bytes> {
+        mmap::map(file, len).map(|ptr| Bytes::Mapped(ptr, len))
+    }
+    #[cfg(all(feature = "fs", not(all(unix, target_pointer_width = "64"))))]
+    fn map(_file: &File, _len: usize) -> Option<Bytes> {
+        None
+    }
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<MappedDocument, DocError> {
+        MappedDocument::from_storage(Bytes::Owned(bytes))
+    }
+    fn from_storage(bytes: Bytes) -> Result<MappedDocument, DocError> {
+        let mut entities: Vec<MappedEntity> = vec![];
+        let mut entities_by_name = HashMap::new();
+        {
+            let mut reader = Reader { bytes: bytes.as_slice(), position: 0 };
+            if reader.bytes.len() < MAGIC.len() || &reader.bytes[..MAGIC.len()] != MAGIC {
+                return Err(DocError::FormatError("Not a binary pyramid document".to_string()));
+            }
+            reader.position = MAGIC.len();
+            let version = try!(reader.u32());
+            if version != VERSION {
+                return Err(DocError::FormatError(format!("Unsupported binary document version {}", version)));
+            }
+            let count = try!(reader.u32()) as usize;
+            for index in 0..count {
+                let parent = match try!(reader.u32()) as usize {
+                    0 => None,
+                    parent if parent <= index => Some(parent - 1),
+                    parent => return Err(DocError::FormatError(format!("Entity {} has parent {} which comes after it", index, parent - 1)))
+                };
+                if parent.is_none() && index > 0 {
+                    return Err(DocError::FormatError(format!("Entity {} is a second root", index)));
+                }
+                let type_name = try!(reader.str_span());
+                let name = match try!(reader.byte()) {
+                    0 => None,
+                    _ => Some(try!(reader.str_span()))
+                };
+                let property_count = try!(reader.u32()) as usize;
+                let mut properties = vec![];
+                for _ in 0..property_count {
+                    let key = try!(reader.str_span());
+                    let source = try!(reader.span());
+                    properties.push(MappedProperty { key: key, source: source, value: RefCell::new(None) });
+                }
+                if let Some(name) = name {
+                    let name = unsafe { str::from_utf8_unchecked(&reader.bytes[name.0..name.1]) };
+                    entities_by_name.insert(name.to_string(), index);
+                }
+                if let Some(parent) = parent {
+                    entities[parent].children.push(index);
+                }
+                entities.push(MappedEntity { parent: parent, type_name: type_name, name: name, children: vec![], properties: properties });
+            }
+        }
+        Ok(MappedDocument { bytes: bytes, entities: entities, entities_by_name: entities_by_name })
+    }
+    // Only for spans checked when opening
+    fn str_at(&self, span: Span) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.bytes.as_slice()[span.0..span.1]) }
+    }
+    fn entity(&self, entity: usize) -> Result<&MappedEntity, DocError> {
+        match self.entities.get(entity) {
+            Some(entity) => Ok(entity),
+            None => Err(DocError::NoSuchEntity(entity as EntityId))
+        }
+    }
+    pub fn root(&self) -> Option<usize> {
+        if self.entities.len() > 0 { Some(0) } else { None }
+    }
+    pub fn entity_count(&self) -> usize {
+        self.entities.len()
+    }
+    pub fn get_entity_by_name(&self, name: &str) -> Option<usize> {
+        self.entities_by_name.get(name).map(|x| *x)
+    }
+    pub fn get_entity_type_name(&self, entity: usize) -> Result<&str, DocError> {
+        let entity = try!(self.entity(entity));
+        Ok(self.str_at(entity.type_name))
+    }
+    pub fn get_entity_name(&self, entity: usize) -> Result<Option<&str>, DocError> {
+        let entity = try!(self.entity(entity));
+        Ok(entity.name.map(|name| self.str_at(name)))
+    }
+    pub fn get_parent(&self, entity: usize) -> Result<Option<usize>, DocError> {
+        Ok(try!(self.entity(entity)).parent)
+    }
+    pub fn get_children(&self, entity: usize) -> Result<&Vec<usize>, DocError> {
+        Ok(&try!(self.entity(entity)).children)
+    }
+    pub fn get_property_keys(&self, entity: usize) -> Result<Vec<&str>, DocError> {
+        let entity = try!(self.entity(entity));
+        Ok(entity.properties.iter().map(|prop| self.str_at(prop.key)).collect())
+    }
+    pub fn get_property(&self, entity: usize, property_key: &str) -> Result<Ref<Pon>, DocError> {
+        let prop = match try!(self.entity(entity)).properties.iter().find(|prop| self.str_at(prop.key) == property_key) {
+            Some(prop) => prop,
+            None => return Err(DocError::NoSuchProperty(property_key.to_string()))
+        };
+        if prop.value.borrow().is_none() {
+            let bytes = &self.bytes.as_slice()[prop.source.0..prop.source.1];
+            let source = try!(str::from_utf8(bytes).map_err(|_| DocError::FormatError(format!("Invalid utf-8 at byte {}", prop.source.0))));
+            let value = try!(Pon::from_string(source).map_err(|err| DocError::FormatError(format!("{:?}", err))));
+            *prop.value.borrow_mut() = Some(value);
+        }
+        Ok(Ref::map(prop.value.borrow(), |value| value.as_ref().unwrap()))
+    }
+    // An editable copy with references resolved. Parses every value.
+    pub fn to_document(&self) -> Result<Document, DocError> {
+        let mut document = match self.entities.first() {
+            Some(root) => Document::with_root(self.str_at(root.type_name)),
+            None => Document::new()
+        };
+        let mut ids: Vec<EntityId> = vec![];
+        for entity in &self.entities {
+            let name = entity.name.map(|name| self.str_at(name).to_string());
+            match entity.parent {
+                Some(parent) => ids.push(try!(document.append_entity(Some(ids[parent]), self.str_at(entity.type_name), name))),
+                // The root is the one with_root made
+                None => {
+                    let root = document.get_root().unwrap();
+                    if let Some(name) = name {
+                        try!(document.rename_entity(&root, &name));
+                    }
+                    ids.push(root);
+                }
+            }
+        }
+        for (index, entity) in self.entities.iter().enumerate() {
+            for prop in &entity.properties {
+                let key = self.str_at(prop.key);
+                let value = (*try!(self.get_property(index, key))).clone();
+                try!(document.set_property(&ids[index], key, value));
+            }
+        }
+        Ok(document)
+    }
+}
+
+
+#[test]
+fn test_binary_document() {
+    let doc = Document::from_string(r#"<Entity name="root" x="1"><Mesh name="ship" y="@root.x" /><Entity z="{ a: 2.0 }" /></Entity>"#).unwrap();
+    let mut bytes = vec![];
+    doc.write_binary(&mut bytes).unwrap();
+    let mapped = MappedDocument::from_bytes(bytes).unwrap();
+    let ship = mapped.get_entity_by_name("ship").unwrap();
+    let root = mapped.get_entity_by_name("root").unwrap();
+    // The document's own root comes first
+    assert_eq!(mapped.entity_count(), 4);
+    assert_eq!(mapped.get_entity_type_name(0).unwrap(), ROOT_TYPE_NAME);
+    assert_eq!(mapped.get_entity_type_name(ship).unwrap(), "Mesh");
+    assert_eq!(mapped.get_parent(ship).unwrap(), Some(root));
+    assert_eq!(mapped.get_children(root).unwrap().len(), 2);
+    assert!(mapped.entities[3].properties[0].value.borrow().is_none());
+    assert_eq!(*mapped.get_property(root, "x").unwrap(), Pon::Integer(1));
+    let copy = mapped.to_document().unwrap();
+    assert_eq!(copy.to_string(), doc.to_string());
+    let copy_ship = copy.get_entity_by_name("ship").unwrap();
+    assert_eq!(copy.get_property(&copy_ship, "y").unwrap().concretize().unwrap(), Pon::Integer(1));
+    assert_eq!(MappedDocument::from_bytes(b"XML!".to_vec()).err(), Some(DocError::FormatError("Not a binary pyramid document".to_string())));
+}
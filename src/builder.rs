@@ -0,0 +1,203 @@
+
+use document::*;
+use pon::*;
+
+// Builds entity trees in code, mostly for tests and procedural generators:
+//
+//   doc.build_entity("Mesh").name("ship").prop("x", Pon::Float(1.0))
+//       .child(EntityBuilder::new("Wheel").prop("radius", Pon::Float(0.5)))
+//       .append()
+//
+// Properties are set after all the entities are in place, so they may refer to entities built later.
+#[derive(PartialEq, Debug, Clone)]
+pub struct EntityBuilder {
+    type_name: String,
+    name: Option<String>,
+    properties: Vec<(String, Pon)>,
+    children: Vec<EntityBuilder>
+}
+
+impl EntityBuilder {
+    pub fn new(type_name: &str) -> EntityBuilder {
+        EntityBuilder { type_name: type_name.to_string(), name: None, properties: vec![], children: vec![] }
+    }
+    pub fn name(mut self, name: &str) -> EntityBuilder {
+        self.name = Some(name.to_string());
+        self
+    }
+    pub fn prop(mut self, property_key: &str, expression: Pon) -> EntityBuilder {
+        self.properties.push((property_key.to_string(), expression));
+        self
+    }
+    pub fn child(mut self, child: EntityBuilder) -> EntityBuilder {
+        self.children.push(child);
+        self
+    }
+    // An xml style attribute: name, or a property whose expression is value read as PON, so strings can hold
+    // references ("@parent.x"). Panics if it isn't valid PON; used by the pyramid! macro.
+    pub fn attribute<T: AttributeValue>(self, key: &str, value: T) -> EntityBuilder {
+        let value = value.attribute_string();
+        if key == "name" {
+            return self.name(&value);
+        }
+        match Pon::from_string(&value) {
+            Ok(expression) => self.prop(key, expression),
+            Err(err) => panic!("Invalid value for {}: {} ({:?})", key, value, err)
+        }
+    }
+    // Appends the tree below parent_id, or as the root if None; returns the id of the top entity
+    pub fn append_to(&self, document: &mut Document, parent_id: Option<EntityId>) -> Result<EntityId, DocError> {
+        let mut properties = vec![];
+        let entity_id = try!(self.append_entities(document, parent_id, &mut properties));
+        for (entity_id, key, expression) in properties {
+            try!(document.set_property(&entity_id, &key, expression));
+        }
+        Ok(entity_id)
+    }
+    fn append_entities(&self, document: &mut Document, parent_id: Option<EntityId>, properties: &mut Vec<(EntityId, String, Pon)>) -> Result<EntityId, DocError> {
+        let entity_id = try!(document.append_entity(parent_id, &self.type_name, self.name.clone()));
+        for &(ref key, ref expression) in &self.properties {
+            properties.push((entity_id, key.clone(), expression.clone()));
+        }
+        for child in &self.children {
+            try!(child.append_entities(document, Some(entity_id), properties));
+        }
+        Ok(entity_id)
+    }
+}
+
+// An EntityBuilder that knows which document it goes into, see Document::build_entity
+pub struct DocumentBuilder<'a> {
+    document: &'a mut Document,
+    parent_id: Option<EntityId>,
+    entity: EntityBuilder
+}
+
+impl<'a> DocumentBuilder<'a> {
+    // Below the root by default, or as the root of an empty document
+    pub fn new(document: &'a mut Document, type_name: &str) -> DocumentBuilder<'a> {
+        let parent_id = document.get_root();
+        DocumentBuilder { document: document, parent_id: parent_id, entity: EntityBuilder::new(type_name) }
+    }
+    pub fn parent(mut self, parent_id: EntityId) -> DocumentBuilder<'a> {
+        self.parent_id = Some(parent_id);
+        self
+    }
+    pub fn name(mut self, name: &str) -> DocumentBuilder<'a> {
+        self.entity = self.entity.name(name);
+        self
+    }
+    pub fn prop(mut self, property_key: &str, expression: Pon) -> DocumentBuilder<'a> {
+        self.entity = self.entity.prop(property_key, expression);
+        self
+    }
+    pub fn child(mut self, child: EntityBuilder) -> DocumentBuilder<'a> {
+        self.entity = self.entity.child(child);
+        self
+    }
+    pub fn append(self) -> Result<EntityId, DocError> {
+        self.entity.append_to(self.document, self.parent_id)
+    }
+}
+
+// Values the pyramid! macro accepts for attributes, as they'd be written in xml
+pub trait AttributeValue {
+    fn attribute_string(&self) -> String;
+}
+
+impl<'a> AttributeValue for &'a str {
+    fn attribute_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl AttributeValue for i32 {
+    fn attribute_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl AttributeValue for i64 {
+    fn attribute_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl AttributeValue for bool {
+    fn attribute_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl AttributeValue for f32 {
+    fn attribute_string(&self) -> String {
+        float_string(self.to_string())
+    }
+}
+
+impl AttributeValue for f64 {
+    fn attribute_string(&self) -> String {
+        float_string(self.to_string())
+    }
+}
+
+// 5.0 prints as 5, which would read back as an integer
+fn float_string(value: String) -> String {
+    if value.chars().all(|c| c.is_digit(10) || c == '-') { format!("{}.0", value) } else { value }
+}
+
+// A document literal, checked for structure when it's compiled:
+//
+//   pyramid! { Entity(name="tmp", x=5.0) { Entity(y="@parent.x") Entity() } }
+//
+// Attributes work as in xml: name names the entity, and string values are PON expressions.
+#[macro_export]
+macro_rules! pyramid {
+    (@entity $type_name:ident ( $($key:ident = $value:expr),* ) { $($children:tt)* }) => {
+        pyramid!(@children $crate::builder::EntityBuilder::new(stringify!($type_name))$(.attribute(stringify!($key), $value))*; $($children)*)
+    };
+    (@entity $type_name:ident ( $($key:ident = $value:expr),* )) => {
+        $crate::builder::EntityBuilder::new(stringify!($type_name))$(.attribute(stringify!($key), $value))*
+    };
+    (@children $builder:expr; ) => {
+        $builder
+    };
+    (@children $builder:expr; $type_name:ident ( $($attributes:tt)* ) { $($children:tt)* } $($rest:tt)*) => {
+        pyramid!(@children $builder.child(pyramid!(@entity $type_name ( $($attributes)* ) { $($children)* })); $($rest)*)
+    };
+    (@children $builder:expr; $type_name:ident ( $($attributes:tt)* ) $($rest:tt)*) => {
+        pyramid!(@children $builder.child(pyramid!(@entity $type_name ( $($attributes)* ))); $($rest)*)
+    };
+    ($($entity:tt)+) => {
+        {
+            let mut document = $crate::document::Document::new();
+            pyramid!(@entity $($entity)+).append_to(&mut document, None).unwrap();
+            document
+        }
+    };
+}
+
+
+#[test]
+fn test_build_entity() {
+    let mut doc = Document::new();
+    let root = doc.get_root().unwrap();
+    let ship = doc.build_entity("Mesh").name("ship").prop("x", Pon::Float(1.0))
+        .child(EntityBuilder::new("Wheel").name("wheel").prop("radius", Pon::from_string("@ship.x").unwrap()))
+        .append().unwrap();
+    assert_eq!(doc.get_parent(&ship).unwrap(), Some(root));
+    let wheel = doc.get_entity_by_name("wheel").unwrap();
+    assert_eq!(doc.get_parent(&wheel).unwrap(), Some(ship));
+    assert_eq!(doc.get_property(&wheel, "radius").unwrap().to_string(), "@ship.x");
+    assert_eq!(*doc.get_property(&ship, "x").unwrap(), Pon::Float(1.0));
+}
+
+#[test]
+fn test_pyramid_macro() {
+    let doc = pyramid! { Entity(name="tmp", x=5.0) { Entity(name="child", y="@parent.x") Entity() } };
+    let root = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(*doc.get_property(&root, "x").unwrap(), Pon::Float(5.0));
+    assert_eq!(doc.get_children(&root).unwrap().len(), 2);
+    let child = doc.get_entity_by_name("child").unwrap();
+    assert_eq!(doc.get_property(&child, "y").unwrap().to_string(), "@parent.x");
+}
@@ -0,0 +1,206 @@
+
+// C ABI over the document API, see include/pyramid.h. Entity ids start at 1, so 0 is used for "no entity".
+// Strings returned to C are owned by the caller and must be released with pyramid_string_free.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::ptr;
+
+use document::*;
+use pon::*;
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+fn to_c_string(s: String) -> *mut c_char {
+    match CString::new(s) {
+        Ok(s) => s.into_raw(),
+        Err(_) => ptr::null_mut()
+    }
+}
+
+fn into_handle(doc: Result<Document, DocError>) -> *mut Document {
+    match doc {
+        Ok(doc) => Box::into_raw(Box::new(doc)),
+        Err(_) => ptr::null_mut()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pyramid_document_new() -> *mut Document {
+    into_handle(Ok(Document::new()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_document_from_string(xml: *const c_char) -> *mut Document {
+    match to_str(xml) {
+        Some(xml) => into_handle(Document::from_string(xml)),
+        None => ptr::null_mut()
+    }
+}
+
+#[cfg(feature = "fs")]
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_document_from_file(path: *const c_char) -> *mut Document {
+    match to_str(path) {
+        Some(path) => into_handle(Document::from_file(Path::new(path))),
+        None => ptr::null_mut()
+    }
+}
+
+// Without file system support there is nothing to load from, but the symbol is still there for the header
+#[cfg(not(feature = "fs"))]
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_document_from_file(_path: *const c_char) -> *mut Document {
+    ptr::null_mut()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_document_free(doc: *mut Document) {
+    if !doc.is_null() {
+        drop(Box::from_raw(doc));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_document_to_string(doc: *const Document) -> *mut c_char {
+    to_c_string((*doc).to_string())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_get_entity_by_name(doc: *const Document, name: *const c_char) -> u64 {
+    match to_str(name).and_then(|name| (*doc).get_entity_by_name(name)) {
+        Some(id) => id,
+        None => 0
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_entity_count(doc: *const Document) -> usize {
+    (*doc).entities_iter().count()
+}
+
+// Writes up to len entity ids to out, returns how many were written
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_entities(doc: *const Document, out: *mut u64, len: usize) -> usize {
+    let mut written = 0;
+    for id in (*doc).entities_iter().take(len) {
+        *out.offset(written as isize) = *id;
+        written += 1;
+    }
+    written
+}
+
+// Returns the resolved value as PON source, or null if the entity or property doesn't exist
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_get_property(doc: *const Document, entity_id: u64, key: *const c_char) -> *mut c_char {
+    let key = match to_str(key) {
+        Some(key) => key,
+        None => return ptr::null_mut()
+    };
+    match (*doc).get_property(&entity_id, key) {
+        Ok(value) => match value.concretize() {
+            Ok(value) => to_c_string(value.to_string()),
+            Err(_) => ptr::null_mut()
+        },
+        Err(_) => ptr::null_mut()
+    }
+}
+
+// Parses expression as PON. Returns 0 on success, -1 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_set_property(doc: *mut Document, entity_id: u64, key: *const c_char, expression: *const c_char) -> c_int {
+    let (key, expression) = match (to_str(key), to_str(expression)) {
+        (Some(key), Some(expression)) => (key, expression),
+        _ => return -1
+    };
+    match Pon::from_string(expression) {
+        Ok(expression) => match (*doc).set_property(&entity_id, key, expression) {
+            Ok(_) => 0,
+            Err(_) => -1
+        },
+        Err(_) => -1
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_get_property_float(doc: *const Document, entity_id: u64, key: *const c_char, out: *mut f32) -> c_int {
+    let key = match to_str(key) {
+        Some(key) => key,
+        None => return -1
+    };
+    match (*doc).get_property(&entity_id, key) {
+        Ok(value) => match value.translate::<f32>(&mut TranslateContext::empty()) {
+            Ok(v) => {
+                *out = v;
+                0
+            },
+            Err(_) => -1
+        },
+        Err(_) => -1
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_set_property_float(doc: *mut Document, entity_id: u64, key: *const c_char, value: f32) -> c_int {
+    match to_str(key) {
+        Some(key) => match (*doc).set_property(&entity_id, key, Pon::Float(value)) {
+            Ok(_) => 0,
+            Err(_) => -1
+        },
+        None => -1
+    }
+}
+
+pub type PyramidPropertyCallback = extern "C" fn(entity_id: u64, key: *const c_char, user_data: *mut c_void);
+
+// Called on every set or unset, along with earlier subscriptions and the document's on_property_set (which
+// System uses). The key pointer is only valid for the duration of the callback.
+#[no_mangle]
+pub unsafe extern "C" fn pyramid_subscribe_property_set(doc: *mut Document, callback: PyramidPropertyCallback, user_data: *mut c_void) {
+    (*doc).on_change(Box::new(move |event| {
+        if let &DocEvent::PropertyChanged { ref prop_ref, .. } = event {
+            if let Ok(key) = CString::new(prop_ref.property_key.as_str()) {
+                callback(prop_ref.entity_id, key.as_ptr(), user_data);
+            }
+        }
+    }));
+}
+
+
+#[test]
+fn test_capi_roundtrip() {
+    unsafe {
+        let xml = CString::new(r#"<Entity name="tmp" x="5.0" />"#).unwrap();
+        let doc = pyramid_document_from_string(xml.as_ptr());
+        let name = CString::new("tmp").unwrap();
+        let key = CString::new("x").unwrap();
+        let ent = pyramid_get_entity_by_name(doc, name.as_ptr());
+        assert_eq!(pyramid_set_property_float(doc, ent, key.as_ptr(), 2.0), 0);
+        let mut out = 0.0;
+        assert_eq!(pyramid_get_property_float(doc, ent, key.as_ptr(), &mut out), 0);
+        assert_eq!(out, 2.0);
+        extern "C" fn count(_entity: u64, _key: *const c_char, user_data: *mut c_void) {
+            unsafe { *(user_data as *mut i32) += 1; }
+        }
+        let mut calls = 0i32;
+        pyramid_subscribe_property_set(doc, count, &mut calls as *mut i32 as *mut c_void);
+        pyramid_subscribe_property_set(doc, count, &mut calls as *mut i32 as *mut c_void);
+        assert_eq!(pyramid_set_property_float(doc, ent, key.as_ptr(), 3.0), 0);
+        assert_eq!(calls, 2);
+        pyramid_document_free(doc);
+    }
+}
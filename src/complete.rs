@@ -0,0 +1,139 @@
+
+// Editor support for property expressions: completion candidates and hover info at a byte offset into the
+// expression text, evaluated against the entity the property belongs to.
+
+use document::*;
+use pon::*;
+
+// Type names understood by the translators shipped with the crate
+pub const BUILTIN_TYPES: &'static [&'static str] = &["vec3", "vec4", "matrix", "translate", "rotate_x", "rotate_y",
+    "rotate_z", "rotate_quaternion", "scale", "lookat", "projection", "mul"];
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum CompletionKind {
+    EntityName,
+    PropertyKey,
+    Function
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind
+}
+
+fn is_path_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == ':' || c == '@'
+}
+
+// The reference-ish token ending at cursor, e.g. "@this.po" for "[@this.po|"
+fn token_before(expression: &str, cursor: usize) -> &str {
+    let before = &expression[0..cursor];
+    let start = before.char_indices().rev()
+        .take_while(|&(_, c)| is_path_char(c))
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(cursor);
+    &before[start..]
+}
+
+fn token_at(expression: &str, cursor: usize) -> &str {
+    let start = cursor - token_before(expression, cursor).len();
+    let end = expression[cursor..].char_indices()
+        .find(|&(_, c)| !is_path_char(c))
+        .map(|(i, _)| cursor + i)
+        .unwrap_or(expression.len());
+    &expression[start..end]
+}
+
+fn resolve_path(document: &Document, entity_id: &EntityId, path: &str) -> Option<EntityId> {
+    match Pon::from_string(&format!("@{}.x", path)) {
+        Ok(Pon::DependencyReference(named_prop_ref, _)) => document.resolve_entity_path(entity_id, &named_prop_ref.entity_path).ok(),
+        _ => None
+    }
+}
+
+fn entity_names(document: &Document) -> Vec<String> {
+    let mut names: Vec<String> = document.entities_iter()
+        .filter_map(|id| document.get_entity_name(id).ok().and_then(|x| x.cloned()))
+        .collect();
+    names.sort();
+    names
+}
+
+pub fn complete(document: &Document, entity_id: &EntityId, expression: &str, cursor: usize, functions: &[&str]) -> Vec<Completion> {
+    let token = token_before(expression, cursor);
+    let is_dependency = token.starts_with("@");
+    let token = token.trim_left_matches('@');
+    let mut completions = vec![];
+    if let Some(dot) = token.rfind('.') {
+        let prefix = &token[dot + 1..];
+        if let Some(target) = resolve_path(document, entity_id, &token[0..dot]) {
+            let mut keys: Vec<String> = document.get_properties(&target).unwrap_or(vec![]).into_iter()
+                .map(|prop_ref| prop_ref.property_key)
+                .filter(|key| key.starts_with(prefix))
+                .collect();
+            keys.sort();
+            completions.extend(keys.into_iter().map(|key| Completion { label: key, kind: CompletionKind::PropertyKey }));
+        }
+        return completions;
+    }
+    let prefix = match token.rfind(':') {
+        Some(colon) => &token[colon + 1..],
+        None => token
+    };
+    if !is_dependency && !token.contains(':') {
+        for function in functions {
+            if function.starts_with(prefix) {
+                completions.push(Completion { label: function.to_string(), kind: CompletionKind::Function });
+            }
+        }
+    }
+    let mut names = vec!["this".to_string(), "parent".to_string()];
+    names.extend(entity_names(document));
+    for name in names {
+        if name.starts_with(prefix) {
+            completions.push(Completion { label: name, kind: CompletionKind::EntityName });
+        }
+    }
+    completions
+}
+
+// A short description of whatever reference or entity name is under the cursor
+pub fn hover(document: &Document, entity_id: &EntityId, expression: &str, cursor: usize) -> Option<String> {
+    let token = token_at(expression, cursor).trim_left_matches('@');
+    if token.len() == 0 {
+        return None;
+    }
+    match token.rfind('.') {
+        Some(dot) => {
+            let target = match resolve_path(document, entity_id, &token[0..dot]) {
+                Some(target) => target,
+                None => return None
+            };
+            match document.get_property(&target, &token[dot + 1..]) {
+                Ok(value) => Some(match value.concretize() {
+                    Ok(value) => value.to_string(),
+                    Err(err) => err.to_string()
+                }),
+                Err(_) => None
+            }
+        },
+        None => match resolve_path(document, entity_id, token) {
+            Some(target) => document.get_entity_type_name(&target).ok().map(|x| x.clone()),
+            None => None
+        }
+    }
+}
+
+
+#[test]
+fn test_complete_property_keys() {
+    let doc = Document::from_string(r#"<Entity name="cam" position="1.0" pitch="0.0"><Entity name="tmp" /></Entity>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let completions = complete(&doc, &ent, "@parent.po", 10, BUILTIN_TYPES);
+    assert_eq!(completions, vec![Completion { label: "position".to_string(), kind: CompletionKind::PropertyKey }]);
+    let completions = complete(&doc, &ent, "@ca", 3, BUILTIN_TYPES);
+    assert_eq!(completions, vec![Completion { label: "cam".to_string(), kind: CompletionKind::EntityName }]);
+    assert_eq!(hover(&doc, &ent, "@cam.position", 6), Some(Pon::Float(1.0).to_string()));
+}
@@ -0,0 +1,253 @@
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::slice::SliceConcatExt;
+
+use xml::reader::EventReader;
+use xml::reader::events::XmlEvent;
+
+use document::*;
+use pon::*;
+use format::escape_attribute;
+
+// Shrinks a document for shipping. Entity names nothing refers to are dropped, editor-only properties are
+// dropped and property keys are replaced by short ones. The SymbolMap records what was done, so tools can
+// show the original names and runtime code can look up the short key of a property it knows by name.
+#[derive(PartialEq, Debug, Clone)]
+pub struct CookOptions {
+    // Properties with a key starting with one of these are removed
+    pub editor_only_prefixes: Vec<String>,
+    // Names runtime code looks entities up by; kept even if nothing references them
+    pub keep_names: Vec<String>,
+    // Keys that aren't shortened
+    pub keep_keys: Vec<String>,
+    pub shorten_keys: bool
+}
+
+impl CookOptions {
+    pub fn default() -> CookOptions {
+        CookOptions {
+            editor_only_prefixes: vec!["editor_".to_string()],
+            keep_names: vec![],
+            keep_keys: vec![],
+            shorten_keys: true
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct SymbolMap {
+    // Short key to original key
+    pub keys: BTreeMap<String, String>,
+    // Index of the entity in the cooked document (depth first, root is 0) to its stripped name
+    pub names: BTreeMap<usize, String>,
+    // Index of the entity to the Document::subtree_hash of its source, for incremental cooking
+    pub hashes: BTreeMap<usize, u64>
+}
+
+impl SymbolMap {
+    pub fn short_key(&self, original: &str) -> Option<&String> {
+        self.keys.iter().find(|&(_, o)| o == original).map(|(short, _)| short)
+    }
+    pub fn original_key<'a>(&'a self, key: &'a str) -> &'a str {
+        match self.keys.get(key) {
+            Some(original) => original,
+            None => key
+        }
+    }
+    // One `key short original`, `name index original` or `hash index hex` line per symbol
+    pub fn to_string(&self) -> String {
+        let mut lines: Vec<String> = self.keys.iter().map(|(short, original)| format!("key {} {}", short, original)).collect();
+        lines.extend(self.names.iter().map(|(index, name)| format!("name {} {}", index, name)));
+        lines.extend(self.hashes.iter().map(|(index, hash)| format!("hash {} {:016x}", index, hash)));
+        lines.join("\n")
+    }
+    pub fn from_string(source: &str) -> Result<SymbolMap, DocError> {
+        let mut map = SymbolMap { keys: BTreeMap::new(), names: BTreeMap::new(), hashes: BTreeMap::new() };
+        for line in source.lines() {
+            let words: Vec<&str> = line.splitn(3, ' ').collect();
+            match (words[0], words.len()) {
+                ("key", 3) => { map.keys.insert(words[1].to_string(), words[2].to_string()); },
+                ("name", 3) => match words[1].parse() {
+                    Ok(index) => { map.names.insert(index, words[2].to_string()); },
+                    Err(_) => return Err(DocError::FormatError(format!("Bad symbol map line: {}", line)))
+                },
+                ("hash", 3) => match (words[1].parse(), u64::from_str_radix(words[2], 16)) {
+                    (Ok(index), Ok(hash)) => { map.hashes.insert(index, hash); },
+                    _ => return Err(DocError::FormatError(format!("Bad symbol map line: {}", line)))
+                },
+                ("", 1) => {},
+                _ => return Err(DocError::FormatError(format!("Bad symbol map line: {}", line)))
+            }
+        }
+        Ok(map)
+    }
+}
+
+// a, b, ..., z, aa, ab, ...
+fn short_key_for(mut index: usize) -> String {
+    let mut chars = vec![];
+    loop {
+        chars.push((b'a' + (index % 26) as u8) as char);
+        if index < 26 { break; }
+        index = index / 26 - 1;
+    }
+    chars.iter().rev().cloned().collect()
+}
+
+fn path_names(path: &EntityPath, names: &mut HashSet<String>) {
+    match path {
+        &EntityPath::Named(ref name) => { names.insert(name.clone()); },
+        &EntityPath::Search(ref path, ref name) => {
+            names.insert(name.clone());
+            path_names(path, names);
+        },
+        _ => {}
+    }
+}
+
+fn visit_references<F: FnMut(&mut NamedPropRef)>(pon: &mut Pon, func: &mut F) {
+    pon.visit_mut(&mut |node| match node {
+        &mut Pon::DependencyReference(ref mut reference, _) => func(reference),
+        &mut Pon::Reference(ref mut reference) => func(reference),
+        _ => {}
+    });
+}
+
+struct Element {
+    type_name: String,
+    attributes: Vec<(String, String)>
+}
+
+pub fn cook(document: &Document, options: &CookOptions) -> Result<(String, SymbolMap), DocError> {
+    let source = document.to_string();
+    let mut elements = vec![];
+    let mut depths = vec![];
+    let mut depth = 0;
+    let mut parser = EventReader::from_str(&source);
+    for e in parser.events() {
+        match e {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                elements.push(Element {
+                    type_name: name.local_name.to_string(),
+                    attributes: attributes.iter().map(|a| (a.name.local_name.to_string(), a.value.to_string())).collect()
+                });
+                depths.push(depth);
+                depth += 1;
+            },
+            XmlEvent::EndElement { .. } => depth -= 1,
+            XmlEvent::Error(err) => return Err(DocError::FormatError(format!("{}", err))),
+            _ => {}
+        }
+    }
+
+    let is_editor_only = |key: &str| options.editor_only_prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()));
+    let mut referenced_names: HashSet<String> = options.keep_names.iter().cloned().collect();
+    let mut key_map: HashMap<String, String> = HashMap::new();
+    let mut symbols = SymbolMap {
+        keys: BTreeMap::new(),
+        names: BTreeMap::new(),
+        // The document's root isn't written, so it has no element
+        hashes: document.subtree_hashes().into_iter().skip(1).enumerate().map(|(index, (_, hash))| (index, hash)).collect()
+    };
+    let mut next_key = 0;
+    let mut expressions: Vec<Vec<Option<Pon>>> = vec![];
+    for element in &elements {
+        let mut element_expressions = vec![];
+        for &(ref key, ref value) in &element.attributes {
+            let base_key = key.split(QUALIFIER_SEPARATOR).next().unwrap().to_string();
+            if key == "name" || is_editor_only(&base_key) {
+                element_expressions.push(None);
+                continue;
+            }
+            let mut expression = try!(Pon::from_string(value).map_err(|err| DocError::FormatError(format!("{:?}", err))));
+            let mut keys = vec![base_key];
+            visit_references(&mut expression, &mut |reference: &mut NamedPropRef| {
+                path_names(&reference.entity_path, &mut referenced_names);
+                keys.push(reference.property_key.clone());
+            });
+            if options.shorten_keys {
+                for key in keys {
+                    if key_map.contains_key(&key) || options.keep_keys.contains(&key) {
+                        continue;
+                    }
+                    let mut short = short_key_for(next_key);
+                    next_key += 1;
+                    while short == "name" || options.keep_keys.contains(&short) {
+                        short = short_key_for(next_key);
+                        next_key += 1;
+                    }
+                    symbols.keys.insert(short.clone(), key.clone());
+                    key_map.insert(key, short);
+                }
+            }
+            element_expressions.push(Some(expression));
+        }
+        expressions.push(element_expressions);
+    }
+    let shorten = |key: &str| -> String {
+        let (base, qualifier) = match key.find(QUALIFIER_SEPARATOR) {
+            Some(at) => (&key[..at], &key[at..]),
+            None => (key, "")
+        };
+        match key_map.get(base) {
+            Some(short) => format!("{}{}", short, qualifier),
+            None => key.to_string()
+        }
+    };
+
+    let mut out = vec![];
+    let mut open: Vec<(usize, String)> = vec![];
+    for (index, (element, element_expressions)) in elements.iter().zip(expressions.into_iter()).enumerate() {
+        let depth = depths[index];
+        while open.len() > 0 && open[open.len() - 1].0 >= depth {
+            out.push(format!("</{}>", open.pop().unwrap().1));
+        }
+        let mut attrs = vec![];
+        for (&(ref key, ref value), expression) in element.attributes.iter().zip(element_expressions.into_iter()) {
+            if key == "name" {
+                if referenced_names.contains(value) {
+                    attrs.push(format!("name=\"{}\"", escape_attribute(value)));
+                } else {
+                    symbols.names.insert(index, value.to_string());
+                }
+                continue;
+            }
+            if let Some(mut expression) = expression {
+                visit_references(&mut expression, &mut |reference: &mut NamedPropRef| {
+                    reference.property_key = shorten(&reference.property_key);
+                });
+                attrs.push(format!("{}=\"{}\"", shorten(key), escape_attribute(&expression.to_string())));
+            }
+        }
+        let has_children = index + 1 < elements.len() && depths[index + 1] > depth;
+        let attrs = if attrs.len() > 0 { format!(" {}", attrs.join(" ")) } else { "".to_string() };
+        if has_children {
+            out.push(format!("<{}{}>", element.type_name, attrs));
+            open.push((depth, element.type_name.clone()));
+        } else {
+            out.push(format!("<{}{}/>", element.type_name, attrs));
+        }
+    }
+    while let Some((_, type_name)) = open.pop() {
+        out.push(format!("</{}>", type_name));
+    }
+    Ok((out.concat(), symbols))
+}
+
+
+#[test]
+fn test_cook() {
+    let doc = Document::from_string(r#"<Entity name="root"><Entity name="player" health="10" editor_color="'red'" /><Entity name="hud" value="@player.health" /></Entity>"#).unwrap();
+    let (xml, symbols) = cook(&doc, &CookOptions::default()).unwrap();
+    assert_eq!(xml, r#"<Entity><Entity a="10" name="player"/><Entity b="@player.a"/></Entity>"#);
+    assert_eq!(symbols.original_key("b"), "value");
+    assert_eq!(symbols.names.get(&0), Some(&"root".to_string()));
+    assert_eq!(symbols.hashes.get(&1), Some(&doc.subtree_hash(&doc.get_entity_by_name("player").unwrap()).unwrap()));
+    assert_eq!(SymbolMap::from_string(&symbols.to_string()).unwrap(), symbols);
+    let cooked = Document::from_string(&xml).unwrap();
+    let top = cooked.get_children(&cooked.get_root().unwrap()).unwrap()[0];
+    let hud = cooked.get_children(&top).unwrap()[1];
+    assert_eq!(cooked.get_property(&hud, "b").unwrap().concretize().unwrap(), Pon::Integer(10));
+}
@@ -0,0 +1,101 @@
+use cgmath::*;
+
+use pon::*;
+
+// The `bounds` property of an entity is a world space box enclosing it and all its descendants:
+//
+//   bounds="aabb { min: vec3 { x: -1.0, y: 0.0, z: -1.0 }, max: vec3 { x: 1.0, y: 2.0, z: 1.0 } }"
+//
+// Document::query_visible skips whole subtrees whose bounds are outside the query volume.
+pub const BOUNDS_PROPERTY: &'static str = "bounds";
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Aabb {
+        Aabb { min: min, max: max }
+    }
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x && other.min.x <= self.max.x &&
+        self.min.y <= other.max.y && other.min.y <= self.max.y &&
+        self.min.z <= other.max.z && other.min.z <= self.max.z
+    }
+}
+
+// Planes as (normal, distance) in x, y, z and w, with the normals pointing inwards, so a point p is inside
+// when dot(normal, p) + w >= 0 for every plane
+#[derive(PartialEq, Debug, Clone)]
+pub struct Frustum {
+    pub planes: Vec<Vector4<f32>>
+}
+
+impl Frustum {
+    pub fn new(planes: Vec<Vector4<f32>>) -> Frustum {
+        Frustum { planes: planes }
+    }
+    // Conservative: may accept boxes just outside a corner of the frustum
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane normal
+            let x = if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x };
+            let y = if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y };
+            let z = if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z };
+            plane.x * x + plane.y * y + plane.z * z + plane.w >= 0.0
+        })
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum CullVolume {
+    Aabb(Aabb),
+    Frustum(Frustum)
+}
+
+impl CullVolume {
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        match self {
+            &CullVolume::Aabb(ref volume) => volume.intersects(aabb),
+            &CullVolume::Frustum(ref frustum) => frustum.intersects(aabb)
+        }
+    }
+}
+
+impl Translatable<Aabb> for Pon {
+    fn inner_translate(&self, context: &mut TranslateContext) -> Result<Aabb, PonTranslateErr> {
+        let data = match self {
+            &Pon::TypedPon(box TypedPon { ref type_name, ref data }) if type_name == "aabb" => data,
+            &Pon::TypedPon(box TypedPon { ref type_name, .. }) => return Err(PonTranslateErr::UnrecognizedType(type_name.to_string())),
+            &Pon::Object(..) => self,
+            _ => return Err(PonTranslateErr::MismatchType { expected: "TypedPon or Object".to_string(), found: format!("{:?}", self) })
+        };
+        let aabb = Aabb {
+            min: try!(data.field_as("min", context)),
+            max: try!(data.field_as("max", context))
+        };
+        if aabb.min.x > aabb.max.x || aabb.min.y > aabb.max.y || aabb.min.z > aabb.max.z {
+            return Err(PonTranslateErr::InvalidValue { value: format!("aabb with min {:?} not below max {:?}", aabb.min, aabb.max) });
+        }
+        Ok(aabb)
+    }
+}
+
+impl ToPon for Aabb {
+    fn to_pon(&self) -> Pon {
+        Pon::new_typed_pon("aabb", Pon::Object(hashmap!(
+            "min" => self.min.to_pon(),
+            "max" => self.max.to_pon()
+        )))
+    }
+}
+
+#[test]
+fn test_frustum_intersects() {
+    // Everything with x >= 0
+    let frustum = Frustum::new(vec![Vector4::new(1.0, 0.0, 0.0, 0.0)]);
+    assert!(frustum.intersects(&Aabb::new(Vector3::new(-1.0, 0.0, 0.0), Vector3::new(0.5, 1.0, 1.0))));
+    assert!(!frustum.intersects(&Aabb::new(Vector3::new(-2.0, 0.0, 0.0), Vector3::new(-1.0, 1.0, 1.0))));
+}
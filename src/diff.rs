@@ -0,0 +1,279 @@
+
+use std::slice::SliceConcatExt;
+
+use document::*;
+use pon::*;
+use format::{escape_attribute, escape_text};
+
+// The changes that turn one document into another, see `Document::diff`. Entity ids are those of the old
+// document; children are matched the same way as in diff_to_xml, and reordering of surviving children
+// isn't recorded. Property values are compared under the old document's numeric options.
+#[derive(PartialEq, Debug, Clone)]
+pub struct DocumentPatch {
+    pub removed: Vec<EntityId>,
+    pub added: Vec<AddedEntity>,
+    pub retyped: Vec<(EntityId, String)>,
+    // None when the property was removed
+    pub properties: Vec<(PropRef, Option<Pon>)>
+}
+
+impl DocumentPatch {
+    pub fn is_empty(&self) -> bool {
+        self.removed.len() == 0 && self.added.len() == 0 && self.retyped.len() == 0 && self.properties.len() == 0
+    }
+}
+
+// A subtree to add under parent_id (None for a new root), at index among the parent's children once the
+// removals are done and the preceding additions are in place
+#[derive(PartialEq, Debug, Clone)]
+pub struct AddedEntity {
+    pub parent_id: Option<EntityId>,
+    pub index: usize,
+    pub entity: PatchEntity
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PatchEntity {
+    pub type_name: String,
+    pub name: Option<String>,
+    pub properties: Vec<(String, Pon)>,
+    pub children: Vec<PatchEntity>
+}
+
+pub fn diff_documents(old: &Document, new: &Document) -> DocumentPatch {
+    let mut patch = DocumentPatch { removed: vec![], added: vec![], retyped: vec![], properties: vec![] };
+    match (old.get_root(), new.get_root()) {
+        (Some(old_root), Some(new_root)) => diff_entity(old, &old_root, new, &new_root, &mut patch),
+        (None, Some(new_root)) => patch.added.push(AddedEntity { parent_id: None, index: 0, entity: patch_entity(new, &new_root) }),
+        (Some(old_root), None) => patch.removed.push(old_root),
+        (None, None) => {}
+    }
+    patch
+}
+
+// Set properties as (key, expression) pairs, sorted by key
+fn expressions(doc: &Document, entity_id: &EntityId) -> Vec<(String, Pon)> {
+    let mut props: Vec<(String, Pon)> = doc.get_properties(entity_id).unwrap_or(vec![]).into_iter()
+        .filter_map(|p| match doc.get_property(entity_id, &p.property_key) {
+            Ok(value) => Some((p.property_key.to_string(), (*value).clone())),
+            Err(_) => None
+        }).collect();
+    props.sort_by(|a, b| a.0.cmp(&b.0));
+    props
+}
+
+fn patch_entity(doc: &Document, entity_id: &EntityId) -> PatchEntity {
+    PatchEntity {
+        type_name: doc.get_entity_type_name(entity_id).unwrap().to_string(),
+        name: doc.get_entity_name(entity_id).unwrap_or(None).map(|x| x.to_string()),
+        properties: expressions(doc, entity_id),
+        children: doc.get_children(entity_id).map(|c| c.clone()).unwrap_or(vec![]).iter().map(|child| patch_entity(doc, child)).collect()
+    }
+}
+
+fn diff_entity(old: &Document, old_id: &EntityId, new: &Document, new_id: &EntityId, patch: &mut DocumentPatch) {
+    let new_type = new.get_entity_type_name(new_id).unwrap();
+    if old.get_entity_type_name(old_id).unwrap() != new_type {
+        patch.retyped.push((*old_id, new_type.to_string()));
+    }
+    let old_props = expressions(old, old_id);
+    let new_props = expressions(new, new_id);
+    for &(ref key, _) in &old_props {
+        if !new_props.iter().any(|p| &p.0 == key) {
+            patch.properties.push((PropRef::new(old_id, key), None));
+        }
+    }
+    for &(ref key, ref new_value) in &new_props {
+        if old_props.iter().find(|p| &p.0 == key).map(|p| !p.1.numeric_eq(new_value, old.get_numeric_options())).unwrap_or(true) {
+            patch.properties.push((PropRef::new(old_id, key), Some(new_value.clone())));
+        }
+    }
+    let old_children = old.get_children(old_id).map(|c| c.clone()).unwrap_or(vec![]);
+    let new_children = new.get_children(new_id).map(|c| c.clone()).unwrap_or(vec![]);
+    let matches = match_children(old, &old_children, new, &new_children);
+    for old_child in &old_children {
+        if !matches.contains(&Some(*old_child)) {
+            patch.removed.push(*old_child);
+        }
+    }
+    for (index, (new_child, old_child)) in new_children.iter().zip(matches.iter()).enumerate() {
+        match old_child {
+            &Some(old_child) => diff_entity(old, &old_child, new, new_child, patch),
+            &None => patch.added.push(AddedEntity { parent_id: Some(*old_id), index: index, entity: patch_entity(new, new_child) })
+        }
+    }
+}
+
+// Renders the differences between two documents as the new document's xml, annotated for human review:
+// entities get a `diff:status` attribute of added, removed or changed (removed entities are kept, after their
+// surviving siblings) and every changed property is preceded by a comment with its old value.
+pub fn diff_to_xml(old: &Document, new: &Document) -> String {
+    let mut out = vec!["<?xml version=\"1.0\" encoding=\"UTF-8\"?>".to_string()];
+    let root_attrs = vec![("xmlns:diff".to_string(), "urn:pyramid:diff".to_string())];
+    // The roots aren't saved, their children are the top level elements
+    let old_top = old.get_root().map(|root| old.get_children(&root).unwrap().clone()).unwrap_or(vec![]);
+    let new_top = new.get_root().map(|root| new.get_children(&root).unwrap().clone()).unwrap_or(vec![]);
+    write_children(old, &old_top, new, &new_top, &root_attrs, 0, &mut out);
+    let mut xml = out.join("\n");
+    xml.push('\n');
+    xml
+}
+
+fn indent(depth: usize) -> String {
+    (0..depth).map(|_| "  ").collect::<Vec<&str>>().concat()
+}
+
+// Set properties as (key, expression) pairs, sorted by key
+fn properties(doc: &Document, entity_id: &EntityId) -> Vec<(String, String)> {
+    let mut props: Vec<(String, String)> = doc.get_properties(entity_id).unwrap_or(vec![]).into_iter()
+        .filter_map(|p| match doc.get_property(entity_id, &p.property_key) {
+            Ok(value) => Some((p.property_key.to_string(), value.to_string())),
+            Err(_) => None
+        }).collect();
+    props.sort_by(|a, b| a.0.cmp(&b.0));
+    props
+}
+
+fn start_tag(doc: &Document, entity_id: &EntityId, mut attrs: Vec<(String, String)>, depth: usize, self_closing: bool) -> String {
+    if let Ok(Some(name)) = doc.get_entity_name(entity_id) {
+        attrs.insert(0, ("name".to_string(), name.to_string()));
+    }
+    let type_name = doc.get_entity_type_name(entity_id).unwrap();
+    let parts: Vec<String> = attrs.iter().map(|&(ref k, ref v)| format!(" {}=\"{}\"", k, escape_attribute(v))).collect();
+    format!("{}<{}{}{}", indent(depth), type_name, parts.concat(), if self_closing { " />" } else { ">" })
+}
+
+fn write_element(doc: &Document, entity_id: &EntityId, attrs: Vec<(String, String)>, depth: usize, out: &mut Vec<String>, children: &mut FnMut(&mut Vec<String>)) {
+    let mut inner = vec![];
+    children(&mut inner);
+    if inner.len() == 0 {
+        out.push(start_tag(doc, entity_id, attrs, depth, true));
+    } else {
+        out.push(start_tag(doc, entity_id, attrs, depth, false));
+        out.push_all(&inner);
+        out.push(format!("{}</{}>", indent(depth), doc.get_entity_type_name(entity_id).unwrap()));
+    }
+}
+
+// A whole subtree that only exists on one side
+fn write_entity(doc: &Document, entity_id: &EntityId, status: &str, mut attrs: Vec<(String, String)>, depth: usize, out: &mut Vec<String>) {
+    attrs.push(("diff:status".to_string(), status.to_string()));
+    attrs.push_all(&properties(doc, entity_id));
+    let children = doc.get_children(entity_id).map(|c| c.clone()).unwrap_or(vec![]);
+    write_element(doc, entity_id, attrs, depth, out, &mut |out| {
+        for child in &children {
+            write_entity(doc, child, status, vec![], depth + 1, out);
+        }
+    });
+}
+
+fn comment(depth: usize, text: String) -> String {
+    format!("{}<!-- {} -->", indent(depth), escape_text(&text).replace("--", "- -"))
+}
+
+fn write_diff(old: &Document, old_id: &EntityId, new: &Document, new_id: &EntityId, mut attrs: Vec<(String, String)>, depth: usize, out: &mut Vec<String>) {
+    let old_props = properties(old, old_id);
+    let new_props = properties(new, new_id);
+    let mut comments = vec![];
+    let old_type = old.get_entity_type_name(old_id).unwrap();
+    let new_type = new.get_entity_type_name(new_id).unwrap();
+    if old_type != new_type {
+        comments.push(comment(depth, format!("type: {} -> {}", old_type, new_type)));
+    }
+    for &(ref key, ref old_value) in &old_props {
+        match new_props.iter().find(|p| &p.0 == key) {
+            Some(&(_, ref new_value)) => if old_value != new_value {
+                comments.push(comment(depth, format!("{}: {} -> {}", key, old_value, new_value)));
+            },
+            None => comments.push(comment(depth, format!("removed {}: {}", key, old_value)))
+        }
+    }
+    for &(ref key, ref new_value) in &new_props {
+        if !old_props.iter().any(|p| &p.0 == key) {
+            comments.push(comment(depth, format!("added {}: {}", key, new_value)));
+        }
+    }
+    if comments.len() > 0 {
+        attrs.push(("diff:status".to_string(), "changed".to_string()));
+    }
+    attrs.push_all(&new_props);
+    out.push_all(&comments);
+    let old_children = old.get_children(old_id).map(|c| c.clone()).unwrap_or(vec![]);
+    let new_children = new.get_children(new_id).map(|c| c.clone()).unwrap_or(vec![]);
+    write_element(new, new_id, attrs, depth, out, &mut |out| {
+        write_children(old, &old_children, new, &new_children, &vec![], depth + 1, out);
+    });
+}
+
+// Each of the children gets attrs
+fn write_children(old: &Document, old_children: &Vec<EntityId>, new: &Document, new_children: &Vec<EntityId>, attrs: &Vec<(String, String)>, depth: usize, out: &mut Vec<String>) {
+    let matches = match_children(old, old_children, new, new_children);
+    for (new_child, old_child) in new_children.iter().zip(matches.iter()) {
+        match old_child {
+            &Some(old_child) => write_diff(old, &old_child, new, new_child, attrs.clone(), depth, out),
+            &None => write_entity(new, new_child, "added", attrs.clone(), depth, out)
+        }
+    }
+    for old_child in old_children {
+        if !matches.contains(&Some(*old_child)) {
+            write_entity(old, old_child, "removed", attrs.clone(), depth, out);
+        }
+    }
+}
+
+// For each new child the old child it corresponds to: the one with the same name, or for unnamed children the
+// next unmatched unnamed one of the same type
+pub fn match_children(old: &Document, old_children: &Vec<EntityId>, new: &Document, new_children: &Vec<EntityId>) -> Vec<Option<EntityId>> {
+    let mut used: Vec<EntityId> = vec![];
+    new_children.iter().map(|new_child| {
+        let new_name = new.get_entity_name(new_child).unwrap_or(None);
+        let found = old_children.iter().find(|old_child| {
+            if used.contains(old_child) {
+                return false;
+            }
+            match (new_name, old.get_entity_name(old_child).unwrap_or(None)) {
+                (Some(a), Some(b)) => a == b,
+                (None, None) => new.get_entity_type_name(new_child).ok() == old.get_entity_type_name(old_child).ok(),
+                _ => false
+            }
+        }).map(|x| *x);
+        if let Some(id) = found {
+            used.push(id);
+        }
+        found
+    }).collect()
+}
+
+
+#[test]
+fn test_diff_to_xml() {
+    let old = Document::from_string(r#"<Entity name="root"><Entity name="a" x="1" /><Entity name="b" /></Entity>"#).unwrap();
+    let new = Document::from_string(r#"<Entity name="root"><Entity name="a" x="2" /><Entity name="c" /></Entity>"#).unwrap();
+    assert_eq!(diff_to_xml(&old, &new), r#"<?xml version="1.0" encoding="UTF-8"?>
+<Entity name="root" xmlns:diff="urn:pyramid:diff">
+  <!-- x: 1 -> 2 -->
+  <Entity name="a" diff:status="changed" x="2" />
+  <Entity name="c" diff:status="added" />
+  <Entity name="b" diff:status="removed" />
+</Entity>
+"#);
+}
+
+#[test]
+fn test_diff_documents() {
+    let old = Document::from_string(r#"<Entity name="root"><Entity name="a" x="1" y="1" /><Entity name="b" /></Entity>"#).unwrap();
+    let new = Document::from_string(r#"<Entity name="root"><Light name="c" /><Entity name="a" x="2" /></Entity>"#).unwrap();
+    let patch = old.diff(&new);
+    let a = old.get_entity_by_name("a").unwrap();
+    assert_eq!(patch.removed, vec![old.get_entity_by_name("b").unwrap()]);
+    assert_eq!(patch.added, vec![AddedEntity {
+        parent_id: old.get_entity_by_name("root"),
+        index: 0,
+        entity: PatchEntity { type_name: "Light".to_string(), name: Some("c".to_string()), properties: vec![], children: vec![] }
+    }]);
+    assert_eq!(patch.properties, vec![
+        (PropRef::new(&a, "y"), None),
+        (PropRef::new(&a, "x"), Some(Pon::Integer(2)))
+    ]);
+    assert!(old.diff(&old).is_empty());
+}
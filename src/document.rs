@@ -2,21 +2,55 @@
 extern crate xml;
 
 use pon::*;
+use wal::WriteAheadLog;
+use import::*;
+use format::*;
+use schema::{Schema, ValidationError, XmlSchemaError};
+use interest::*;
+use repair::*;
+use metrics::*;
+use culling::{Aabb, CullVolume, BOUNDS_PROPERTY};
+use diff::{DocumentPatch, PatchEntity, diff_documents};
+use merge::{MergeConflict, merge3};
+use selector::Selector;
+use dsl::document_from_dsl;
+use ids::{IdGenerator, SequentialIds};
+use builder::DocumentBuilder;
+use overrides::{export_overrides, parse_overrides};
+use prefab::{TemplateConflict, propagate_template};
+use golden::canonical_xml;
+#[cfg(feature = "fs")]
+use golden::compare_snapshot;
+use binary::write_binary;
 
+#[cfg(feature = "fs")]
 use std::fs::File;
+#[cfg(feature = "fs")]
 use std::io::BufReader;
+#[cfg(feature = "fs")]
+use std::io::BufWriter;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::collections::hash_map::Keys;
 use std::collections::hash_map::Entry;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::io::Read;
+#[cfg(feature = "fs")]
+use std::fs;
 use std::io::Write;
 use std::cell::RefCell;
 use std::cell::Ref;
+use std::cell::Cell;
 use std::any::Any;
+use std::ops::Deref;
+use std::hash::{Hasher, SipHasher};
 use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
 
-use xml::reader::EventReader;
+use xml::reader::{EventReader, ParserConfig};
 use xml::reader::events::*;
+use xml::common::HasPosition;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum DocError {
@@ -24,7 +58,66 @@ pub enum DocError {
     NoSuchProperty(String),
     NoSuchEntity(EntityId),
     CantFindEntityByName(String),
-    InvalidParent
+    InvalidParent,
+    IoError(String),
+    UnnamedAliasTarget(EntityId),
+    AccessDenied(PropRef),
+    ImportError(String),
+    FormatError(String),
+    MigrationError(String),
+    ValidationFailed(Vec<ValidationError>),
+    EntityFrozen(EntityId),
+    XmlValidationFailed(Vec<XmlSchemaError>),
+    LoadError(LoadError),
+    // A child index past the end of the parent's children
+    NoSuchChild(EntityId, usize),
+    UnnamedLinkTarget(EntityId),
+    NameTaken(String),
+    TransactionInProgress,
+    NoTransaction,
+    // set_property_if found something else than expected; the current expression, None if there is none
+    Conflict(PropRef, Option<Pon>),
+    InvalidSelector(String),
+    // A `parent` path from the root
+    NoParent(EntityId),
+    // An expression with references set through a ShardedDocument, which can't add dependency edges
+    ShardedReference(PropRef),
+    // save_dirty with changes to entities an importer made, which can't be written back to their files
+    IncludesNotSaved(Vec<PathBuf>)
+}
+
+// Malformed xml (unclosed or crossed tags, duplicate attributes, ...) in the document being loaded
+#[derive(PartialEq, Debug, Clone)]
+pub struct LoadError {
+    // None when loading from a string
+    pub file: Option<String>,
+    // 1-based row and column, when the parser knows them
+    pub position: Option<(u64, u64)>,
+    pub message: String
+}
+
+// Comments and processing instructions from the loaded xml, written back out on save
+#[derive(PartialEq, Debug, Clone)]
+pub enum XmlTrivia {
+    Comment(String),
+    ProcessingInstruction { name: String, data: Option<String> }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct EntityTrivia {
+    // Right before the entity's start tag
+    pub leading: Vec<XmlTrivia>,
+    // After the entity's last child, before its end tag
+    pub trailing: Vec<XmlTrivia>
+}
+
+// Attributes the error to path, unless it already came from an included file
+fn in_file(err: DocError, path: &Path) -> DocError {
+    match err {
+        DocError::LoadError(LoadError { file: None, position, message }) =>
+            DocError::LoadError(LoadError { file: Some(path.display().to_string()), position: position, message: message }),
+        err => err
+    }
 }
 
 impl From<PonTranslateErr> for DocError {
@@ -35,14 +128,536 @@ impl From<PonTranslateErr> for DocError {
 
 pub type EntityId = u64;
 
+// Members of named entity sets list the sets in this property, which is how membership is saved
+pub const SETS_PROPERTY: &'static str = "sets";
+// Tags of an entity, e.g. `['enemies', 'static']`. Kept apart from sets, so a tag and a set can share a name.
+pub const TAGS_PROPERTY: &'static str = "tags";
+// Typed links to other entities are saved in this property of the source, e.g. `{ target: ['enemy'] }`
+pub const LINKS_PROPERTY: &'static str = "links";
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PropertyHistoryEntry {
+    pub value: Pon,
+    // From Document::clock, 0 if there isn't one
+    pub timestamp: u64,
+    // Whatever Document::set_mutation_source was set to at the time
+    pub source: Option<String>
+}
+
+// Passed to debug_break_on callbacks; set a debugger breakpoint in the callback to see the writer's stack
+#[derive(PartialEq, Debug, Clone)]
+pub struct PropertyChange {
+    pub prop_ref: PropRef,
+    pub old_value: Option<Pon>,
+    pub new_value: Option<Pon>,
+    pub source: Option<String>
+}
+
+// What Document::merge does with a merged entity whose name is already taken
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum NameCollision {
+    // Add a numeric suffix, renaming the references in the merged document along
+    Rename,
+    // Leave the merged entity unnamed, so references to the name go to the existing entity
+    KeepExisting,
+    // Merge nothing and return NameTaken
+    Fail
+}
+
+// A subtree removed with trash_entity, and where to put it back
+struct TrashedSubtree {
+    parent_id: EntityId,
+    index: usize,
+    entities: Vec<Entity>
+}
+
+// What an Include element brought in, so save_dirty can write the element again and the entities to their file
+struct IncludedSubtree {
+    path: PathBuf,
+    parent_id: Option<EntityId>,
+    attributes: Vec<xml::attribute::OwnedAttribute>,
+    // The top level entities of the file, in order
+    entities: Vec<EntityId>,
+    // Anything else went through an importer
+    is_xml: bool
+}
+
+// Depth first ordinals, see Document::entity_ordinal. Structural changes forget the ordinals from the first
+// one that moved; the rest are kept and extended from there when asked for.
+struct Ordinals {
+    order: Vec<EntityId>,
+    by_id: HashMap<EntityId, usize>,
+    complete: bool
+}
+
+// Entities by the concrete value (as a string) of one property key, see Document::index_property
+struct ValueIndex {
+    entities_by_value: HashMap<String, Vec<EntityId>>,
+    values: HashMap<EntityId, String>
+}
+
+// What rollback needs to undo the open transaction
+struct Transaction {
+    // Every property changed in the transaction, with the expression it had before the first change
+    previous: Vec<(PropRef, Option<Pon>)>,
+    appended: Vec<EntityId>,
+    back_buffer_len: usize
+}
+
+// Parsed xml includes, shared between documents so files many of them include are only parsed once. Entries
+// are keyed by path and checked against a hash of the file's contents, so edited files are parsed again.
+pub struct IncludeCache {
+    entries: RefCell<HashMap<PathBuf, (u64, Rc<Vec<PositionedEvent>>)>>,
+    hits: Cell<usize>
+}
+
+impl IncludeCache {
+    pub fn new() -> IncludeCache {
+        IncludeCache { entries: RefCell::new(HashMap::new()), hits: Cell::new(0) }
+    }
+    // How many includes were served without parsing
+    pub fn hits(&self) -> usize {
+        self.hits.get()
+    }
+    fn events(&self, path: &Path, bytes: &[u8]) -> Rc<Vec<PositionedEvent>> {
+        let mut hasher = SipHasher::new();
+        hasher.write(bytes);
+        let hash = hasher.finish();
+        if let Some(&(cached_hash, ref events)) = self.entries.borrow().get(path) {
+            if cached_hash == hash {
+                self.hits.set(self.hits.get() + 1);
+                return events.clone();
+            }
+        }
+        let mut parser = EventReader::new_with_config(bytes, parser_config());
+        let events = Rc::new(positioned(&mut parser).collect::<Vec<PositionedEvent>>());
+        self.entries.borrow_mut().insert(path.to_path_buf(), (hash, events.clone()));
+        events
+    }
+}
+
+// An xml event and the 1-based row and column the parser was at after reading it
+pub type PositionedEvent = (XmlEvent, Option<(u64, u64)>);
+
+pub struct PositionedEvents<'a, R: Read + 'a> {
+    parser: &'a mut EventReader<R>,
+    finished: bool
+}
+
+impl<'a, R: Read> Iterator for PositionedEvents<'a, R> {
+    type Item = PositionedEvent;
+    fn next(&mut self) -> Option<PositionedEvent> {
+        if self.finished {
+            return None;
+        }
+        let e = self.parser.next();
+        match e {
+            XmlEvent::EndDocument | XmlEvent::Error(_) => self.finished = true,
+            _ => {}
+        }
+        Some((e, Some((self.parser.row() + 1, self.parser.col() + 1))))
+    }
+}
+
+// Like EventReader::events, with positions
+pub fn positioned<R: Read>(parser: &mut EventReader<R>) -> PositionedEvents<R> {
+    PositionedEvents { parser: parser, finished: false }
+}
+
+// Where an entity was loaded from, see Document::get_entity_source
+#[derive(PartialEq, Debug, Clone)]
+pub struct EntitySource {
+    // None when loaded from a string
+    pub file: Option<PathBuf>,
+    // 1-based row and column of the end of the element's start tag
+    pub position: Option<(u64, u64)>,
+    // The files whose Include elements led to file, outermost first
+    pub include_stack: Vec<PathBuf>
+}
+
+// Numbers returned by get_property_floats and get_property_integers: borrowed straight out of a packed
+// array, or converted from the other shapes
+pub enum NumericSlice<'a, T: 'a> {
+    Borrowed(Ref<'a, [T]>),
+    Converted(Vec<T>)
+}
+
+impl<'a, T> Deref for NumericSlice<'a, T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        match self {
+            &NumericSlice::Borrowed(ref values) => &**values,
+            &NumericSlice::Converted(ref values) => &values[..]
+        }
+    }
+}
+
+// Preorder walk of a subtree, children in order
+pub struct DfsIter<'a> {
+    document: &'a Document,
+    stack: Vec<EntityId>
+}
+
+impl<'a> Iterator for DfsIter<'a> {
+    type Item = EntityId;
+    fn next(&mut self) -> Option<EntityId> {
+        let id = match self.stack.pop() {
+            Some(id) => id,
+            None => return None
+        };
+        if let Some(entity) = self.document.entities.get(&id) {
+            self.stack.extend(entity.children_ids.iter().rev().cloned());
+        }
+        Some(id)
+    }
+}
+
+// Level by level walk of a subtree
+pub struct BfsIter<'a> {
+    document: &'a Document,
+    queue: VecDeque<EntityId>
+}
+
+impl<'a> Iterator for BfsIter<'a> {
+    type Item = EntityId;
+    fn next(&mut self) -> Option<EntityId> {
+        let id = match self.queue.pop_front() {
+            Some(id) => id,
+            None => return None
+        };
+        if let Some(entity) = self.document.entities.get(&id) {
+            self.queue.extend(entity.children_ids.iter().cloned());
+        }
+        Some(id)
+    }
+}
+
+// Owns the entities appended with Document::append_scoped_entity. Dropping it can't reach the document, so
+// its entities are queued there and removed when the document is next changed, or by Document::collect_scopes.
+pub struct ScopeToken {
+    entity_ids: Vec<EntityId>,
+    released: Rc<RefCell<Vec<EntityId>>>
+}
+
+impl Drop for ScopeToken {
+    fn drop(&mut self) {
+        self.released.borrow_mut().extend(self.entity_ids.iter().cloned());
+    }
+}
+
+// Parent, grandparent and so on up to the root
+pub struct AncestorIter<'a> {
+    document: &'a Document,
+    next: Option<EntityId>
+}
+
+impl<'a> Iterator for AncestorIter<'a> {
+    type Item = EntityId;
+    fn next(&mut self) -> Option<EntityId> {
+        let id = match self.next {
+            Some(id) => id,
+            None => return None
+        };
+        self.next = self.document.entities.get(&id).and_then(|entity| entity.parent_id);
+        Some(id)
+    }
+}
+
+// Read access to one entity, for find_entities predicates
+pub struct EntityView<'a> {
+    document: &'a Document,
+    entity: &'a Entity
+}
+
+impl<'a> EntityView<'a> {
+    pub fn id(&self) -> EntityId {
+        self.entity.id
+    }
+    pub fn type_name(&self) -> &'a str {
+        &self.entity.type_name
+    }
+    pub fn name(&self) -> Option<&'a str> {
+        self.entity.name.as_ref().map(|x| &x[..])
+    }
+    pub fn has_property(&self, property_key: &str) -> bool {
+        self.document.has_property(&self.entity.id, property_key).unwrap_or(false)
+    }
+    pub fn get_property(&self, property_key: &str) -> Option<Ref<'a, Pon>> {
+        self.document.get_entity_property(self.entity, property_key).ok()
+    }
+}
+
+// Handle on an entity that's known to exist, so its accessors don't need a Result
+#[derive(Clone, Copy)]
+pub struct EntityRef<'a> {
+    document: &'a Document,
+    entity_id: EntityId
+}
+
+impl<'a> EntityRef<'a> {
+    fn entity(&self) -> &'a Entity {
+        &self.document.entities[&self.entity_id]
+    }
+    pub fn id(&self) -> EntityId {
+        self.entity_id
+    }
+    pub fn document(&self) -> &'a Document {
+        self.document
+    }
+    pub fn name(&self) -> Option<&'a str> {
+        self.entity().name.as_ref().map(|x| &x[..])
+    }
+    pub fn type_name(&self) -> &'a str {
+        &self.entity().type_name
+    }
+    pub fn prop(&self, property_key: &str) -> Option<Ref<'a, Pon>> {
+        self.document.get_entity_property(self.entity(), property_key).ok()
+    }
+    pub fn children(&self) -> Vec<EntityRef<'a>> {
+        self.entity().children_ids.iter().map(|id| EntityRef { document: self.document, entity_id: *id }).collect()
+    }
+    pub fn parent(&self) -> Option<EntityRef<'a>> {
+        self.entity().parent_id.map(|id| EntityRef { document: self.document, entity_id: id })
+    }
+    pub fn child_by_name(&self, name: &str) -> Option<EntityRef<'a>> {
+        self.children().into_iter().find(|child| child.name() == Some(name))
+    }
+}
+
+pub struct EntityRefMut<'a> {
+    document: &'a mut Document,
+    entity_id: EntityId
+}
+
+impl<'a> EntityRefMut<'a> {
+    pub fn id(&self) -> EntityId {
+        self.entity_id
+    }
+    pub fn as_ref(&self) -> EntityRef {
+        EntityRef { document: &*self.document, entity_id: self.entity_id }
+    }
+    pub fn set_prop(&mut self, property_key: &str, expression: Pon) -> Result<(), DocError> {
+        self.document.set_property(&self.entity_id, property_key, expression)
+    }
+    pub fn unset_prop(&mut self, property_key: &str) -> Result<Pon, DocError> {
+        self.document.unset_property(&self.entity_id, property_key)
+    }
+    pub fn rename(&mut self, new_name: &str) -> Result<Vec<PropRef>, DocError> {
+        self.document.rename_entity(&self.entity_id, new_name)
+    }
+    pub fn append_child(&mut self, type_name: &str, name: Option<String>) -> Result<EntityRefMut, DocError> {
+        let id = try!(self.document.append_entity(Some(self.entity_id), type_name, name));
+        Ok(EntityRefMut { document: &mut *self.document, entity_id: id })
+    }
+}
+
+// Columnar snapshot from Document::export_property_table: columns[k][i] is the concrete value of keys[k] on
+// entity_ids[i], None where it isn't set or doesn't concretize
+#[derive(PartialEq, Debug, Clone)]
+pub struct PropertyTable {
+    pub entity_ids: Vec<EntityId>,
+    pub keys: Vec<String>,
+    pub columns: Vec<Vec<Option<Pon>>>
+}
+
+impl PropertyTable {
+    pub fn column(&self, property_key: &str) -> Option<&Vec<Option<Pon>>> {
+        self.keys.iter().position(|key| key == property_key).map(|i| &self.columns[i])
+    }
+    pub fn row(&self, entity_id: &EntityId) -> Option<usize> {
+        self.entity_ids.iter().position(|id| id == entity_id)
+    }
+}
+
+// What Document::on_change listeners are told, after the change is made
+#[derive(PartialEq, Debug, Clone)]
+pub enum DocEvent {
+    EntityAdded(EntityId),
+    // For every entity of a removed subtree, top first
+    EntityRemoved(EntityId),
+    // Reparented, see Document::reparent_entity
+    EntityMoved { entity_id: EntityId, old_parent_id: EntityId, new_parent_id: EntityId },
+    // The property was set or unset; cascade is it and everything depending on it
+    PropertyChanged { prop_ref: PropRef, cascade: Vec<PropRef> }
+}
+
+// What appending an entity with a name that's already taken does, see Document::set_duplicate_names
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DuplicateNames {
+    Error,
+    // The name keeps referring to the earlier entity
+    KeepFirst,
+    // The name refers to the new entity; the default
+    KeepLast,
+    // The new entity is named name_1, name_2... instead
+    Suffix
+}
+
+// Bounds on how far build_cascade follows dependants, see Document::set_cascade_limits
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct CascadeLimits {
+    // Steps from a changed property along dependants edges
+    pub max_depth: Option<usize>,
+    // Properties in one cascade, including the changed ones
+    pub max_size: Option<usize>
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CascadeLimit {
+    Depth(usize),
+    Size(usize)
+}
+
+// Where a cascade was cut short: chain runs from a changed property, one dependant at a time, to the first
+// property that was left out
+#[derive(PartialEq, Debug, Clone)]
+pub struct CascadeDiagnostic {
+    pub limit: CascadeLimit,
+    pub chain: Vec<PropRef>
+}
+
+// Part of a cascade split at barriers, see Document::build_cascade_stages. barrier is None for the first
+// stage, which holds the changed properties themselves.
+#[derive(PartialEq, Debug, Clone)]
+pub struct CascadeStage {
+    pub barrier: Option<String>,
+    pub properties: Vec<PropRef>
+}
+
+// Separates a property key from the qualifier of one of its variants, as in shadow_res--low. It has to be
+// legal in xml attribute names.
+pub const QUALIFIER_SEPARATOR: &'static str = "--";
+
+// Saved as an attribute of the entity's element, see Provenance
+pub const PROVENANCE_ATTRIBUTE: &'static str = "_provenance";
+
+// Who created and last changed an entity and when, as told by the host while Document::record_provenance is
+// on. Fields are None when the entity predates recording or the host had no author.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Provenance {
+    pub created: Option<u64>,
+    pub created_by: Option<String>,
+    pub modified: Option<u64>,
+    pub modified_by: Option<String>
+}
+
+impl Provenance {
+    fn to_attribute(&self) -> String {
+        let mut fields = vec![];
+        if let Some(created) = self.created {
+            fields.push(format!("created: {}", created));
+        }
+        if let Some(ref created_by) = self.created_by {
+            fields.push(format!("created_by: {}", Pon::String(created_by.clone()).to_string()));
+        }
+        if let Some(modified) = self.modified {
+            fields.push(format!("modified: {}", modified));
+        }
+        if let Some(ref modified_by) = self.modified_by {
+            fields.push(format!("modified_by: {}", Pon::String(modified_by.clone()).to_string()));
+        }
+        format!("{{ {} }}", fields.join(", "))
+    }
+    fn from_attribute(value: &str) -> Option<Provenance> {
+        let fields = match Pon::from_string(value) {
+            Ok(Pon::Object(fields)) => fields,
+            _ => return None
+        };
+        let timestamp = |key: &str| match fields.get(key) {
+            Some(&Pon::Integer(t)) if t >= 0 => Some(t as u64),
+            _ => None
+        };
+        let author = |key: &str| match fields.get(key) {
+            Some(&Pon::String(ref author)) => Some(author.clone()),
+            _ => None
+        };
+        Some(Provenance {
+            created: timestamp("created"),
+            created_by: author("created_by"),
+            modified: timestamp("modified"),
+            modified_by: author("modified_by")
+        })
+    }
+}
+
+struct ProvenanceSource {
+    clock: Box<Fn() -> u64>,
+    author: Option<String>
+}
+
+// Modification counters: every change gets the next number of one document wide sequence, and entities and
+// properties remember the number of their last change. Also where changes get their provenance.
+struct Versions {
+    last: u64,
+    entities: HashMap<EntityId, u64>,
+    properties: HashMap<PropRef, u64>,
+    provenance: HashMap<EntityId, Provenance>,
+    provenance_source: Option<ProvenanceSource>
+}
+
+impl Versions {
+    fn new() -> Versions {
+        Versions { last: 0, entities: HashMap::new(), properties: HashMap::new(), provenance: HashMap::new(), provenance_source: None }
+    }
+    fn touch_new_entity(&mut self, entity_id: EntityId) {
+        if let Some(ref source) = self.provenance_source {
+            let now = (source.clock)();
+            self.provenance.insert(entity_id, Provenance {
+                created: Some(now),
+                created_by: source.author.clone(),
+                modified: None,
+                modified_by: None
+            });
+        }
+        self.touch_entity(entity_id);
+    }
+    fn touch_entity(&mut self, entity_id: EntityId) {
+        self.last += 1;
+        self.entities.insert(entity_id, self.last);
+        if let Some(ref source) = self.provenance_source {
+            let now = (source.clock)();
+            let provenance = self.provenance.entry(entity_id).or_insert(Provenance { created: None, created_by: None, modified: None, modified_by: None });
+            provenance.modified = Some(now);
+            provenance.modified_by = source.author.clone();
+        }
+    }
+    fn touch_property(&mut self, entity_id: &EntityId, property_key: &str) {
+        self.touch_entity(*entity_id);
+        self.properties.insert(PropRef::new(entity_id, property_key), self.last);
+    }
+}
+
+// Notifications held back while coalescing, see Document::set_notification_window
+struct PendingNotifications {
+    prop_refs: Vec<PropRef>,
+    seen: HashSet<PropRef>,
+    // When the first of them came in
+    since: u64
+}
+
+struct PropertyHistory {
+    capacity: usize,
+    entries: VecDeque<PropertyHistoryEntry>
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PruneReport {
+    // Properties nothing depends on and that have never been read through get_property
+    pub unused_properties: Vec<PropRef>,
+    // Properties with a dependency reference that resolves to () or to a property that doesn't exist
+    pub nil_references: Vec<PropRef>
+}
+
 pub type EntityIter<'a> = Keys<'a, EntityId, Entity>;
+pub type EntityTypeIter<'a> = ::std::iter::Cloned<::std::slice::Iter<'a, EntityId>>;
 pub type PropertyIter<'a> = Keys<'a, String, Property>;
 
 
 #[derive(Debug)]
 struct Property {
     expression: Rc<RefCell<Option<Pon>>>,
-    dependants: Vec<PropRef>
+    dependants: Vec<PropRef>,
+    reads: Cell<u64>
 }
 
 #[derive(Debug)]
@@ -52,7 +667,9 @@ struct Entity {
     properties: HashMap<String, Property>,
     name: Option<String>,
     children_ids: Vec<EntityId>,
-    parent_id: Option<EntityId>
+    parent_id: Option<EntityId>,
+    // For keys that have qualified variants (key--qualifier), the value to use when no variant's qualifier is active
+    qualified_defaults: HashMap<String, Option<Pon>>
 }
 
 impl Entity {
@@ -64,7 +681,8 @@ impl Entity {
             Entry::Vacant(v) => {
                 v.insert(Property {
                     expression: Rc::new(RefCell::new(None)),
-                    dependants: vec![]
+                    dependants: vec![],
+                    reads: Cell::new(0)
                 })
             }
         }
@@ -72,32 +690,174 @@ impl Entity {
 }
 
 pub struct Document {
-    id_counter: EntityId,
+    id_generator: Box<IdGenerator>,
     root: Option<EntityId>,
     entities: HashMap<EntityId, Entity>,
     entity_ids_by_name: HashMap<String, EntityId>,
+    dirty_entities: HashSet<EntityId>,
+    trash: HashMap<EntityId, TrashedSubtree>,
+    ordinals: RefCell<Ordinals>,
+    // The expressions frozen entities had before their values were pinned
+    frozen: HashMap<EntityId, HashMap<String, Pon>>,
+    write_ahead_log: Option<WriteAheadLog>,
+    qualifiers: Vec<String>,
+    locale: Option<String>,
+    property_history: HashMap<PropRef, PropertyHistory>,
+    mutation_source: Option<String>,
+    breakpoints: HashMap<PropRef, Box<Fn(&PropertyChange) -> ()>>,
+    interest_sets: Vec<(String, InterestSet)>,
+    double_buffered: bool,
+    // Live entities by type name, in the order they got the type
+    entities_by_type: HashMap<String, Vec<EntityId>>,
+    // Derived from the SETS_PROPERTY of every entity
+    entity_sets: HashMap<String, Vec<EntityId>>,
+    // Derived from the TAGS_PROPERTY of every entity
+    entity_tags: HashMap<String, Vec<EntityId>>,
+    // Derived from the LINKS_PROPERTY of every entity: (kind, target name) pairs by source
+    links: HashMap<EntityId, Vec<(String, String)>>,
+    // Properties that couldn't be loaded because they reference an unknown entity, kept for repair
+    unresolved: Vec<(EntityId, String, Pon)>,
+    metrics: MetricsCounters,
+    access_patterns: Option<RefCell<AccessPatterns>>,
+    // Writes waiting for flip, in order; None unsets
+    back_buffer: Vec<(EntityId, String, Option<Pon>)>,
+    // Structural rules checked whenever an entity gets a parent
+    schema: Option<Schema>,
+    value_indexes: HashMap<String, ValueIndex>,
+    numeric_options: NumericOptions,
+    transaction: Option<Transaction>,
+    xml_trivia: HashMap<EntityId, EntityTrivia>,
+    notification_window: Option<u64>,
+    versions: Versions,
+    pending_notifications: RefCell<PendingNotifications>,
+    cascade_limits: Option<CascadeLimits>,
+    cascade_barriers: HashMap<PropRef, String>,
+    duplicate_names: DuplicateNames,
+    keep_unknown_expressions: bool,
+    entity_sources: HashMap<EntityId, EntitySource>,
+    // The files being loaded, innermost last
+    loading_files: Vec<PathBuf>,
+    // Every file an Include element pulled in, in load order
+    included_files: Vec<PathBuf>,
+    // Inner includes come before the ones containing them
+    included_subtrees: Vec<IncludedSubtree>,
+    cascade_diagnostic: RefCell<Option<CascadeDiagnostic>>,
+    // Entities of dropped scope tokens, waiting for collect_scopes
+    released_scopes: Rc<RefCell<Vec<EntityId>>>,
+    // After the root's end tag
+    trailing_trivia: Vec<XmlTrivia>,
+    // Host provided time source, used to timestamp debugging information
+    pub clock: Option<Box<Fn() -> u64>>,
+    // Used by `<Include file="..." />` for anything that isn't an xml document
+    pub importers: ImporterRegistry,
+    // Reads the files of Include elements; without one they fail to load
+    pub include_resolver: Option<Box<IncludeResolver>>,
+    pub include_cache: Option<Rc<IncludeCache>>,
     pub resources: HashMap<String, Box<Any>>,
+    change_listeners: Vec<Box<Fn(&DocEvent) -> ()>>,
+    subscribers: RefCell<Vec<Sender<DocEvent>>>,
+    property_observers: HashMap<PropRef, Vec<Box<Fn(&PropRef) -> ()>>>,
     pub on_entity_added: Option<Box<Fn(&EntityId) -> ()>>,
     pub on_property_set: Option<Box<Fn(&EntityId, &str) -> ()>>
 }
 
+// The type of the root Document::new creates
+pub const ROOT_TYPE_NAME: &'static str = "Document";
+
 impl Document {
+    // A document with just a root, of type ROOT_TYPE_NAME
     pub fn new() -> Document {
+        Document::with_root(ROOT_TYPE_NAME)
+    }
+    // Like new, with a root of type_name. Top level xml elements are loaded as children of the root, and saved
+    // as siblings at the top level; the root itself isn't saved.
+    pub fn with_root(type_name: &str) -> Document {
+        let mut doc = Document::without_root();
+        doc.append_entity(None, type_name, None).unwrap();
+        doc.dirty_entities.clear();
+        doc.metrics = MetricsCounters::new();
+        doc
+    }
+    fn without_root() -> Document {
         Document {
-            id_counter: 0,
+            id_generator: Box::new(SequentialIds::new()),
             root: None,
             entities: HashMap::new(),
             entity_ids_by_name: HashMap::new(),
+            dirty_entities: HashSet::new(),
+            trash: HashMap::new(),
+            ordinals: RefCell::new(Ordinals { order: vec![], by_id: HashMap::new(), complete: false }),
+            frozen: HashMap::new(),
+            write_ahead_log: None,
+            qualifiers: vec![],
+            locale: None,
+            property_history: HashMap::new(),
+            mutation_source: None,
+            breakpoints: HashMap::new(),
+            interest_sets: vec![],
+            double_buffered: false,
+            entities_by_type: HashMap::new(),
+            entity_sets: HashMap::new(),
+            entity_tags: HashMap::new(),
+            links: HashMap::new(),
+            unresolved: vec![],
+            metrics: MetricsCounters::new(),
+            access_patterns: None,
+            back_buffer: vec![],
+            schema: None,
+            value_indexes: HashMap::new(),
+            numeric_options: NumericOptions::default(),
+            transaction: None,
+            xml_trivia: HashMap::new(),
+            notification_window: None,
+            versions: Versions::new(),
+            pending_notifications: RefCell::new(PendingNotifications { prop_refs: vec![], seen: HashSet::new(), since: 0 }),
+            cascade_limits: None,
+            cascade_barriers: HashMap::new(),
+            duplicate_names: DuplicateNames::KeepLast,
+            keep_unknown_expressions: false,
+            entity_sources: HashMap::new(),
+            loading_files: vec![],
+            included_files: vec![],
+            included_subtrees: vec![],
+            cascade_diagnostic: RefCell::new(None),
+            released_scopes: Rc::new(RefCell::new(vec![])),
+            trailing_trivia: vec![],
+            clock: None,
+            importers: ImporterRegistry::new(),
+            include_resolver: default_include_resolver(),
+            include_cache: None,
             resources: HashMap::new(),
+            change_listeners: vec![],
+            subscribers: RefCell::new(vec![]),
+            property_observers: HashMap::new(),
             on_entity_added: None,
             on_property_set: None
         }
     }
+    // Only affects entities created from now on
+    pub fn set_id_generator(&mut self, id_generator: Box<IdGenerator>) {
+        self.id_generator = id_generator;
+    }
     fn new_id(&mut self) -> EntityId {
-        self.id_counter += 1;
-        return self.id_counter;
+        loop {
+            let id = self.id_generator.next_id();
+            // Trashed entities get their ids back when restored
+            let trashed = self.trash.values().any(|trashed| trashed.entities.iter().any(|entity| entity.id == id));
+            if !self.entities.contains_key(&id) && !trashed {
+                return id;
+            }
+        }
     }
+    // Without a parent the entity goes below the root, or becomes the root if there is none (after
+    // remove_entity on the root)
     pub fn append_entity(&mut self, parent_id: Option<EntityId>, type_name: &str, name: Option<String>) -> Result<EntityId, DocError> {
+        try!(self.remove_released_scopes());
+        let parent_id = parent_id.or(self.root);
+        if let Some(parent_id) = parent_id {
+            try!(self.check_child_allowed(&parent_id, type_name));
+        }
+        let name = try!(self.name_for_new_entity(name));
         let id = self.new_id();
         let entity = Entity {
             id: id.clone(),
@@ -105,7 +865,8 @@ impl Document {
             properties: HashMap::new(),
             name: name,
             parent_id: parent_id,
-            children_ids: vec![]
+            children_ids: vec![],
+            qualified_defaults: HashMap::new()
         };
         if let Some(parent_id) = parent_id {
             let parent = match self.entities.get_mut(&parent_id) {
@@ -113,21 +874,148 @@ impl Document {
                 None => return Err(DocError::InvalidParent)
             };
             parent.children_ids.push(id);
+            self.dirty_entities.insert(parent_id);
+            self.versions.touch_entity(parent_id);
+            self.forget_ordinals_after(&parent_id);
         } else {
-            if self.root.is_some() {
-                panic!("Cannot set root twice.");
-            }
             self.root = Some(id);
+            self.forget_ordinals_from(0);
         }
         if let &Some(ref name) = &entity.name {
-            self.entity_ids_by_name.insert(name.clone(), entity.id);
+            if self.duplicate_names != DuplicateNames::KeepFirst || !self.entity_ids_by_name.contains_key(name) {
+                self.entity_ids_by_name.insert(name.clone(), entity.id);
+            }
+        }
+        if let Some(ref mut log) = self.write_ahead_log {
+            try!(log.log_append_entity(&id, parent_id, type_name, &entity.name));
         }
+        self.entities_by_type.entry(type_name.to_string()).or_insert(vec![]).push(id);
         self.entities.insert(entity.id, entity);
+        self.dirty_entities.insert(id);
+        self.versions.touch_new_entity(id);
+        self.metrics.entity_added();
+        if let Some(ref mut transaction) = self.transaction {
+            transaction.appended.push(id);
+        }
         if let &Some(ref cb) = &self.on_entity_added {
             cb(&id);
         }
+        self.emit(DocEvent::EntityAdded(id));
         return Ok(id);
     }
+    // Enforces the child types schema allows from now on, and in debug builds its property constraints;
+    // existing entities aren't checked, see Schema::validate
+    pub fn set_schema(&mut self, schema: Option<Schema>) {
+        self.schema = schema;
+    }
+    pub fn get_schema(&self) -> Option<&Schema> {
+        self.schema.as_ref()
+    }
+    // Used when loading, saving and diffing from now on
+    pub fn set_numeric_options(&mut self, options: NumericOptions) {
+        self.numeric_options = options;
+    }
+    pub fn get_numeric_options(&self) -> &NumericOptions {
+        &self.numeric_options
+    }
+    pub fn get_xml_trivia(&self, entity_id: &EntityId) -> Option<&EntityTrivia> {
+        self.xml_trivia.get(entity_id)
+    }
+    pub fn set_xml_trivia(&mut self, entity_id: &EntityId, trivia: EntityTrivia) -> Result<(), DocError> {
+        if !self.entities.contains_key(entity_id) {
+            return Err(DocError::NoSuchEntity(*entity_id));
+        }
+        self.xml_trivia.insert(*entity_id, trivia);
+        self.dirty_entities.insert(*entity_id);
+        self.versions.touch_entity(*entity_id);
+        Ok(())
+    }
+    fn check_child_allowed(&self, parent_id: &EntityId, type_name: &str) -> Result<(), DocError> {
+        if let Some(ref schema) = self.schema {
+            let parent_type = match self.entities.get(parent_id) {
+                Some(parent) => &parent.type_name,
+                None => return Err(DocError::InvalidParent)
+            };
+            if !schema.allows_child(parent_type, type_name) {
+                return Err(DocError::ValidationFailed(vec![ValidationError::DisallowedChild(*parent_id, type_name.to_string())]));
+            }
+        }
+        Ok(())
+    }
+    // Debug builds refuse set_property values outside the constraints of the schema, if there is one
+    fn check_property_constraints(&self, entity_id: &EntityId, property_key: &str, value: &Pon) -> Result<(), DocError> {
+        if let (Some(schema), Some(entity)) = (self.schema.as_ref(), self.entities.get(entity_id)) {
+            if let Err(message) = schema.check_property(&entity.type_name, property_key, value) {
+                return Err(DocError::ValidationFailed(vec![ValidationError::ConstraintViolated(PropRef::new(entity_id, property_key), message)]));
+            }
+        }
+        Ok(())
+    }
+    // Like append_entity, but the new entity becomes child number index of parent_id (the last child if index
+    // is past the end)
+    pub fn insert_entity_at(&mut self, parent_id: &EntityId, index: usize, type_name: &str, name: Option<String>) -> Result<EntityId, DocError> {
+        let id = try!(self.append_entity(Some(*parent_id), type_name, name));
+        let last = self.entities[parent_id].children_ids.len() - 1;
+        if index < last {
+            try!(self.move_child(parent_id, last, index));
+        }
+        Ok(id)
+    }
+    // Moves child number from of parent_id so it becomes child number to, shifting the ones in between
+    pub fn move_child(&mut self, parent_id: &EntityId, from: usize, to: usize) -> Result<(), DocError> {
+        try!(self.remove_released_scopes());
+        {
+            let parent = match self.entities.get_mut(parent_id) {
+                Some(parent) => parent,
+                None => return Err(DocError::NoSuchEntity(*parent_id))
+            };
+            let len = parent.children_ids.len();
+            if from >= len {
+                return Err(DocError::NoSuchChild(*parent_id, from));
+            }
+            if to >= len {
+                return Err(DocError::NoSuchChild(*parent_id, to));
+            }
+            let child = parent.children_ids.remove(from);
+            parent.children_ids.insert(to, child);
+        }
+        self.forget_ordinals_after(parent_id);
+        self.dirty_entities.insert(*parent_id);
+        self.versions.touch_entity(*parent_id);
+        if let Some(ref mut log) = self.write_ahead_log {
+            try!(log.log_move_child(parent_id, from, to));
+        }
+        Ok(())
+    }
+    pub fn set_duplicate_names(&mut self, policy: DuplicateNames) {
+        self.duplicate_names = policy;
+    }
+    fn name_for_new_entity(&self, name: Option<String>) -> Result<Option<String>, DocError> {
+        let name = match name {
+            Some(ref name) if self.entity_ids_by_name.contains_key(name) => name.clone(),
+            name => return Ok(name)
+        };
+        match self.duplicate_names {
+            DuplicateNames::Error => Err(DocError::NameTaken(name)),
+            DuplicateNames::Suffix => {
+                let mut n = 1;
+                while self.entity_ids_by_name.contains_key(&format!("{}_{}", name, n)) {
+                    n += 1;
+                }
+                Ok(Some(format!("{}_{}", name, n)))
+            },
+            DuplicateNames::KeepFirst | DuplicateNames::KeepLast => Ok(Some(name))
+        }
+    }
+    // Every entity with the name, in document order, not just the one get_entity_by_name returns. Scans the
+    // whole document.
+    pub fn get_entities_by_name(&self, name: &str) -> Vec<EntityId> {
+        let ids = match self.root {
+            Some(root) => self.subtree_ids(&root).unwrap_or(vec![]),
+            None => vec![]
+        };
+        ids.into_iter().filter(|id| self.entities[id].name.as_ref().map(|x| &x[..]) == Some(name)).collect()
+    }
     pub fn get_entity_by_name(&self, name: &str) -> Option<EntityId> {
         match self.entity_ids_by_name.get(&name.to_string()) {
             Some(id) => Some(id.clone()),
@@ -140,135 +1028,2647 @@ impl Document {
     pub fn get_root(&self) -> Option<EntityId> {
         self.root.clone()
     }
-    // returns all props that were invalidated
-    pub fn set_property(&mut self, entity_id: &EntityId, property_key: &str, mut expression: Pon) -> Result<(), DocError> {
-        //println!("set property {} {:?}", property_key, expression);
-        let dependencies: Vec<PropRef> = {
-            let entity = match self.entities.get(entity_id) {
-                Some(entity) => entity,
-                None => return Err(DocError::NoSuchEntity(*entity_id))
-            };
-            try!(self.build_property_node_dependencies(entity, &expression))
-        };
-        for PropRef { entity_id: dep_ent_id, property_key: dep_prop_key } in dependencies {
-            match self.entities.get_mut(&dep_ent_id) {
-                Some(dep_ent) => {
-                    let prop_ref = PropRef { entity_id: entity_id.clone(), property_key: property_key.to_string() };
-                    let mut prop = dep_ent.get_or_create_property(&dep_prop_key);
-                    prop.dependants.push(prop_ref);
+    // A key of the form `key--qualifier` (see QUALIFIER_SEPARATOR) sets a variant of key which is used
+    // instead of key while the qualifier is active, see `set_qualifiers`
+    pub fn set_property(&mut self, entity_id: &EntityId, property_key: &str, expression: Pon) -> Result<(), DocError> {
+        try!(self.remove_released_scopes());
+        if self.frozen.contains_key(entity_id) {
+            return Err(DocError::EntityFrozen(*entity_id));
+        }
+        if cfg!(debug_assertions) {
+            try!(self.check_property_constraints(entity_id, property_key, &expression));
+        }
+        self.record_previous(entity_id, property_key);
+        if self.double_buffered {
+            return self.buffer_write(entity_id, property_key, Some(expression));
+        }
+        if let Some(at) = property_key.find(QUALIFIER_SEPARATOR) {
+            try!(self.set_property_expression(entity_id, property_key, expression, true));
+            return self.select_qualified_variant(entity_id, &property_key[0..at]);
+        }
+        let has_variants = match self.entities.get_mut(entity_id) {
+            Some(entity) => match entity.qualified_defaults.get_mut(property_key) {
+                Some(default) => {
+                    *default = Some(expression.clone());
+                    true
                 },
-                None => return Err(DocError::NoSuchEntity(dep_ent_id))
+                None => false
+            },
+            None => return Err(DocError::NoSuchEntity(*entity_id))
+        };
+        if has_variants {
+            // Logged as the default; the variant selected from it is derived, and replaying it would overwrite the default
+            if let Some(ref mut log) = self.write_ahead_log {
+                try!(log.log_set_property(entity_id, property_key, &expression.to_string()));
             }
+            self.select_qualified_variant(entity_id, property_key)
+        } else {
+            self.set_property_expression(entity_id, property_key, expression, true)
         }
-        {
-            try!(self.resolve_pon_dependencies(&entity_id, &mut expression));
-        }
-        {
-            let mut ent_mut = self.entities.get_mut(entity_id).unwrap();
-            let prop = ent_mut.get_or_create_property(property_key);
-            *prop.expression.borrow_mut() = Some(expression);
+    }
+    // In double buffered mode set_property and unset_property only queue the write; readers keep seeing the
+    // old values until flip applies everything queued, so a frame sees one consistent state whatever order
+    // systems write in. Turning it off flips.
+    pub fn set_double_buffered(&mut self, enabled: bool) -> Result<(), DocError> {
+        if !enabled {
+            try!(self.flip());
         }
-        if let &Some(ref cb) = &self.on_property_set {
-            cb(entity_id, property_key);
+        self.double_buffered = enabled;
+        Ok(())
+    }
+    pub fn is_double_buffered(&self) -> bool {
+        self.double_buffered
+    }
+    fn buffer_write(&mut self, entity_id: &EntityId, property_key: &str, expression: Option<Pon>) -> Result<(), DocError> {
+        if !self.entities.contains_key(entity_id) {
+            return Err(DocError::NoSuchEntity(*entity_id));
+        }
+        self.back_buffer.push((*entity_id, property_key.to_string(), expression));
+        Ok(())
+    }
+    // Publishes the queued writes. They are all attempted; the first failure is returned.
+    pub fn flip(&mut self) -> Result<(), DocError> {
+        let writes = ::std::mem::replace(&mut self.back_buffer, vec![]);
+        let double_buffered = self.double_buffered;
+        self.double_buffered = false;
+        let mut result = Ok(());
+        for (entity_id, property_key, expression) in writes {
+            let res = match expression {
+                Some(expression) => self.set_property(&entity_id, &property_key, expression),
+                None => self.unset_property(&entity_id, &property_key).map(|_| ())
+            };
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        self.double_buffered = double_buffered;
+        result
+    }
+    // Until commit or rollback, remembers enough about every set_property, unset_property and append_entity
+    // to undo them. Transactions don't nest.
+    pub fn begin_transaction(&mut self) -> Result<(), DocError> {
+        if self.transaction.is_some() {
+            return Err(DocError::TransactionInProgress);
+        }
+        self.transaction = Some(Transaction { previous: vec![], appended: vec![], back_buffer_len: self.back_buffer.len() });
+        Ok(())
+    }
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+    pub fn commit(&mut self) -> Result<(), DocError> {
+        match self.transaction.take() {
+            Some(_) => Ok(()),
+            None => Err(DocError::NoTransaction)
+        }
+    }
+    // Puts back the properties changed since begin_transaction and removes the entities appended since, so
+    // the document is as it was even if the transaction stopped halfway through a batch
+    pub fn rollback(&mut self) -> Result<(), DocError> {
+        let transaction = match self.transaction.take() {
+            Some(transaction) => transaction,
+            None => return Err(DocError::NoTransaction)
+        };
+        self.back_buffer.truncate(transaction.back_buffer_len);
+        let double_buffered = self.double_buffered;
+        self.double_buffered = false;
+        let mut result = Ok(());
+        for (prop_ref, previous) in transaction.previous.into_iter().rev() {
+            if transaction.appended.contains(&prop_ref.entity_id) || !self.entities.contains_key(&prop_ref.entity_id) {
+                continue;
+            }
+            let res = match previous {
+                Some(expression) => self.set_property(&prop_ref.entity_id, &prop_ref.property_key, expression),
+                None => match self.has_property(&prop_ref.entity_id, &prop_ref.property_key) {
+                    Ok(true) => self.unset_property(&prop_ref.entity_id, &prop_ref.property_key).map(|_| ()),
+                    _ => Ok(())
+                }
+            };
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        for entity_id in transaction.appended.iter().rev() {
+            if self.entities.contains_key(entity_id) {
+                let res = self.remove_entity(entity_id).map(|_| ());
+                if result.is_ok() {
+                    result = res;
+                }
+            }
+        }
+        self.double_buffered = double_buffered;
+        result
+    }
+    // get_property reads the writes of the open transaction; this reads the expression as of begin_transaction.
+    // None if the property wasn't set then.
+    pub fn get_committed_property(&self, entity_id: &EntityId, property_key: &str) -> Result<Option<Pon>, DocError> {
+        if let Some(ref transaction) = self.transaction {
+            if let Some(&(_, ref previous)) = transaction.previous.iter().find(|&&(ref prop_ref, _)| prop_ref.entity_id == *entity_id && prop_ref.property_key == property_key) {
+                return Ok(previous.clone());
+            }
+        }
+        self.current_expression(entity_id, property_key)
+    }
+    // In double buffered mode get_property keeps returning the published state; this reads the last write queued
+    // for the property instead, so a system can build on its own writes before the flip
+    pub fn get_pending_property(&self, entity_id: &EntityId, property_key: &str) -> Result<Option<Pon>, DocError> {
+        if let Some(&(_, _, ref expression)) = self.back_buffer.iter().rev().find(|&&(ref id, ref key, _)| id == entity_id && key == property_key) {
+            return Ok(expression.clone());
+        }
+        self.current_expression(entity_id, property_key)
+    }
+    fn current_expression(&self, entity_id: &EntityId, property_key: &str) -> Result<Option<Pon>, DocError> {
+        if try!(self.has_property(entity_id, property_key)) {
+            Ok(Some((*try!(self.get_property(entity_id, property_key))).clone()))
+        } else {
+            Ok(None)
+        }
+    }
+    fn record_previous(&mut self, entity_id: &EntityId, property_key: &str) {
+        let previous = match self.entities.get(entity_id) {
+            Some(entity) => match entity.qualified_defaults.get(property_key) {
+                Some(default) => default.clone(),
+                None => entity.properties.get(property_key).and_then(|prop| prop.expression.borrow().clone())
+            },
+            None => return
+        };
+        if let Some(ref mut transaction) = self.transaction {
+            if !transaction.previous.iter().any(|&(ref prop_ref, _)| prop_ref.entity_id == *entity_id && prop_ref.property_key == property_key) {
+                transaction.previous.push((PropRef::new(entity_id, property_key), previous));
+            }
+        }
+    }
+    // Compare-and-set: sets the property only if its expression is still expected (compared as written), so
+    // clients that read, edit and write back don't overwrite each other's changes
+    pub fn set_property_if(&mut self, entity_id: &EntityId, property_key: &str, expected: &Pon, expression: Pon) -> Result<(), DocError> {
+        let current = try!(self.current_expression(entity_id, property_key));
+        if current.as_ref().map(|x| x.to_string()) != Some(expected.to_string()) {
+            return Err(DocError::Conflict(PropRef::new(entity_id, property_key), current));
+        }
+        self.set_property(entity_id, property_key, expression)
+    }
+    // Sets all the properties, or none of them if one fails, and returns a single cascade for the lot, ordered so
+    // every property comes after the ones it depends on
+    pub fn set_properties(&mut self, entity_id: &EntityId, properties: Vec<(String, Pon)>) -> Result<Vec<PropRef>, DocError> {
+        let own_transaction = !self.in_transaction();
+        if own_transaction {
+            try!(self.begin_transaction());
+        }
+        let mut changed = vec![];
+        for (property_key, expression) in properties {
+            if let Err(err) = self.set_property(entity_id, &property_key, expression) {
+                if own_transaction {
+                    try!(self.rollback());
+                }
+                return Err(err);
+            }
+            let prop_ref = PropRef::new(entity_id, &property_key);
+            if !changed.contains(&prop_ref) {
+                changed.push(prop_ref);
+            }
+        }
+        if own_transaction {
+            try!(self.commit());
+        }
+        let cascade = self.build_cascade(changed.clone());
+        Ok(self.dependency_order(&changed, cascade))
+    }
+    // Reverse post-order over the dependants edges within cascade, so dependencies come first; cycles are
+    // broken wherever the walk finds them
+    fn dependency_order(&self, roots: &Vec<PropRef>, cascade: Vec<PropRef>) -> Vec<PropRef> {
+        let members: HashSet<PropRef> = cascade.iter().cloned().collect();
+        let mut visited = HashSet::new();
+        let mut order = vec![];
+        for prop_ref in roots.iter().chain(cascade.iter()) {
+            self.visit_dependants(prop_ref, &members, &mut visited, &mut order);
+        }
+        order.reverse();
+        order
+    }
+    fn visit_dependants(&self, prop_ref: &PropRef, members: &HashSet<PropRef>, visited: &mut HashSet<PropRef>, order: &mut Vec<PropRef>) {
+        if !members.contains(prop_ref) || !visited.insert(prop_ref.clone()) {
+            return;
+        }
+        if let Ok(dependants) = self.get_property_dependants(&prop_ref.entity_id, &prop_ref.property_key) {
+            for dependant in dependants {
+                self.visit_dependants(dependant, members, visited, order);
+            }
+        }
+        order.push(prop_ref.clone());
+    }
+    // Like set_property, but returns everything whose value changed as a result, partitioned by interest set
+    pub fn set_property_partitioned(&mut self, entity_id: &EntityId, property_key: &str, expression: Pon) -> Result<HashMap<String, Vec<PropRef>>, DocError> {
+        try!(self.set_property(entity_id, property_key, expression));
+        let cascade = self.build_cascade(vec![PropRef::new(entity_id, property_key)]);
+        Ok(partition_cascade(self, &self.interest_sets, &cascade))
+    }
+    // Replaces any interest set with the same name
+    pub fn register_interest(&mut self, name: &str, interest: InterestSet) {
+        self.unregister_interest(name);
+        self.interest_sets.push((name.to_string(), interest));
+    }
+    pub fn unregister_interest(&mut self, name: &str) {
+        self.interest_sets.retain(|&(ref n, _)| n != name);
+    }
+    // Cascades bigger or deeper than limits are cut short, so an accidentally explosive dependency graph
+    // costs a bounded amount of work; see take_cascade_diagnostic
+    pub fn set_cascade_limits(&mut self, limits: Option<CascadeLimits>) {
+        self.cascade_limits = limits;
+    }
+    // Why the last cut short cascade was, if one was since the previous call
+    pub fn take_cascade_diagnostic(&self) -> Option<CascadeDiagnostic> {
+        self.cascade_diagnostic.borrow_mut().take()
+    }
+    // The changed properties and everything depending on them, transitively. Trashed and frozen entities
+    // are left out since their values can't change.
+    pub fn build_cascade(&self, changed: Vec<PropRef>) -> Vec<PropRef> {
+        let start = self.now();
+        let mut ips: HashSet<PropRef> = changed.iter().cloned().collect();
+        // Breadth first, so depths are the shortest distance and every property knows how it was reached
+        let mut reached_from: HashMap<PropRef, PropRef> = HashMap::new();
+        let mut queue: VecDeque<(PropRef, usize)> = changed.into_iter().map(|prop_ref| (prop_ref, 0)).collect();
+        'cascade: while let Some((prop_ref, depth)) = queue.pop_front() {
+            let deps = match self.get_property_dependants(&prop_ref.entity_id, &prop_ref.property_key) {
+                Ok(deps) => deps,
+                Err(_) => continue
+            };
+            for pr in deps {
+                if !self.entities.contains_key(&pr.entity_id) || self.is_trashed(&pr.entity_id) || self.is_frozen(&pr.entity_id) || ips.contains(pr) {
+                    continue;
+                }
+                if let Some(limits) = self.cascade_limits {
+                    let exceeded = match (limits.max_depth, limits.max_size) {
+                        (Some(max_depth), _) if depth + 1 > max_depth => Some(CascadeLimit::Depth(max_depth)),
+                        (_, Some(max_size)) if ips.len() >= max_size => Some(CascadeLimit::Size(max_size)),
+                        _ => None
+                    };
+                    if let Some(limit) = exceeded {
+                        self.report_cascade_limit(limit, &reached_from, &prop_ref, pr);
+                        match limit {
+                            CascadeLimit::Depth(_) => continue,
+                            CascadeLimit::Size(_) => break 'cascade
+                        }
+                    }
+                }
+                ips.insert(pr.clone());
+                reached_from.insert(pr.clone(), prop_ref.clone());
+                queue.push_back((pr.clone(), depth + 1));
+            }
+        }
+        self.metrics.cascade(ips.len(), self.now().saturating_sub(start));
+        let cascade: Vec<PropRef> = ips.into_iter().collect();
+        if let Some(ref patterns) = self.access_patterns {
+            patterns.borrow_mut().record_cascade(&cascade);
+        }
+        cascade
+    }
+    // The property is still invalidated along with what it depends on, but its dependants are left to the
+    // stage named name, so the host can do its own work in between
+    pub fn set_cascade_barrier(&mut self, prop_ref: PropRef, name: &str) {
+        self.cascade_barriers.insert(prop_ref, name.to_string());
+    }
+    pub fn remove_cascade_barrier(&mut self, prop_ref: &PropRef) {
+        self.cascade_barriers.remove(prop_ref);
+    }
+    // Like build_cascade, but in stages: the first stops at barriers, and each following one continues
+    // from the barriers of one name, in the order they were reached. Cascade limits don't apply.
+    pub fn build_cascade_stages(&self, changed: Vec<PropRef>) -> Vec<CascadeStage> {
+        let mut seen: HashSet<PropRef> = changed.iter().cloned().collect();
+        let mut held: Vec<(String, Vec<PropRef>)> = vec![];
+        let mut queue = VecDeque::new();
+        for prop_ref in &changed {
+            hold_at_barrier(&self.cascade_barriers, prop_ref, &mut held, &mut queue);
+        }
+        let mut stages = vec![];
+        let mut stage = CascadeStage { barrier: None, properties: changed };
+        loop {
+            while let Some(prop_ref) = queue.pop_front() {
+                let deps = match self.get_property_dependants(&prop_ref.entity_id, &prop_ref.property_key) {
+                    Ok(deps) => deps,
+                    Err(_) => continue
+                };
+                for pr in deps {
+                    if !self.entities.contains_key(&pr.entity_id) || self.is_trashed(&pr.entity_id) || self.is_frozen(&pr.entity_id) || seen.contains(pr) {
+                        continue;
+                    }
+                    seen.insert(pr.clone());
+                    stage.properties.push(pr.clone());
+                    hold_at_barrier(&self.cascade_barriers, pr, &mut held, &mut queue);
+                }
+            }
+            stages.push(stage);
+            if held.len() == 0 {
+                break;
+            }
+            let (name, barriers) = held.remove(0);
+            stage = CascadeStage { barrier: Some(name), properties: vec![] };
+            queue.extend(barriers);
+        }
+        stages
+    }
+    // Keeps the first limit hit until take_cascade_diagnostic
+    fn report_cascade_limit(&self, limit: CascadeLimit, reached_from: &HashMap<PropRef, PropRef>, from: &PropRef, left_out: &PropRef) {
+        let mut diagnostic = self.cascade_diagnostic.borrow_mut();
+        if diagnostic.is_some() {
+            return;
+        }
+        let mut chain = vec![left_out.clone(), from.clone()];
+        let mut current = from;
+        while let Some(previous) = reached_from.get(current) {
+            chain.push(previous.clone());
+            current = previous;
+        }
+        chain.reverse();
+        *diagnostic = Some(CascadeDiagnostic { limit: limit, chain: chain });
+    }
+    // Starts (or stops and forgets) recording what cascades reach and how long expressions take to resolve
+    pub fn record_access_patterns(&mut self, enabled: bool) {
+        self.access_patterns = if enabled { Some(RefCell::new(AccessPatterns::new())) } else { None };
+    }
+    // The property's concrete value; resolve time is recorded when recording access patterns
+    pub fn evaluate_property(&self, entity_id: &EntityId, property_key: &str) -> Result<Pon, DocError> {
+        let start = self.now();
+        let value = try!(try!(self.get_property(entity_id, property_key)).concretize());
+        if let Some(ref patterns) = self.access_patterns {
+            patterns.borrow_mut().record_resolve(&PropRef::new(entity_id, property_key), self.now().saturating_sub(start));
+        }
+        Ok(value)
+    }
+    // The top properties by cascade appearances and resolve time since recording started; None if it isn't
+    pub fn hot_report(&self, top: usize) -> Option<HotReport> {
+        self.access_patterns.as_ref().map(|patterns| patterns.borrow().report(top))
+    }
+    // Sends entity count, mutation and cascade statistics to sink. Call it periodically, passing the time
+    // since the last call. Cascade times are measured with clock, and are 0 without one.
+    pub fn publish_metrics(&self, sink: &MetricsSink, elapsed_seconds: f64) {
+        self.metrics.publish(sink, self.entities.len(), elapsed_seconds);
+    }
+    // With a window, on_property_set is called once per property however many times it changes, after the
+    // final change, when flush_notifications is called (e.g. once a frame) or when a change comes in window
+    // clock ticks after the first pending one. A window of 0 only flushes explicitly, as System::update does.
+    // None notifies right away.
+    pub fn set_notification_window(&mut self, window: Option<u64>) {
+        self.notification_window = window;
+        if window.is_none() {
+            self.flush_notifications();
+        }
+    }
+    pub fn flush_notifications(&self) {
+        let prop_refs = {
+            let mut pending = self.pending_notifications.borrow_mut();
+            pending.seen.clear();
+            ::std::mem::replace(&mut pending.prop_refs, vec![])
+        };
+        if let Some(ref cb) = self.on_property_set {
+            for prop_ref in prop_refs {
+                if self.entities.contains_key(&prop_ref.entity_id) {
+                    cb(&prop_ref.entity_id, &prop_ref.property_key);
+                }
+            }
+        }
+    }
+    // Listeners are called in the order they were added, and aren't affected by the notification window
+    pub fn on_change(&mut self, listener: Box<Fn(&DocEvent) -> ()>) {
+        self.change_listeners.push(listener);
+    }
+    // The same events as on_change, sent over a channel; dropping the receiver unsubscribes
+    pub fn subscribe(&mut self) -> Receiver<DocEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.get_mut().push(sender);
+        receiver
+    }
+    fn emit(&self, event: DocEvent) {
+        for listener in &self.change_listeners {
+            listener(&event);
+        }
+        self.subscribers.borrow_mut().retain(|sender| sender.send(event.clone()).is_ok());
+    }
+    // The observer is called with the property that was set or unset whenever prop_ref is in its cascade,
+    // i.e. when prop_ref itself changed or depends on what did
+    pub fn observe(&mut self, prop_ref: PropRef, observer: Box<Fn(&PropRef) -> ()>) {
+        self.property_observers.entry(prop_ref).or_insert(vec![]).push(observer);
+    }
+    // Removes all observers of prop_ref
+    pub fn unobserve(&mut self, prop_ref: &PropRef) {
+        self.property_observers.remove(prop_ref);
+    }
+    // What build_cascade gives for the one property, but without recording metrics or access patterns or
+    // applying cascade limits, since it's only for telling listeners and observers
+    fn cascade_of(&self, changed: PropRef) -> Vec<PropRef> {
+        let mut seen: HashSet<PropRef> = HashSet::new();
+        seen.insert(changed.clone());
+        let mut cascade = vec![changed.clone()];
+        let mut queue = VecDeque::new();
+        queue.push_back(changed);
+        while let Some(prop_ref) = queue.pop_front() {
+            let deps = match self.get_property_dependants(&prop_ref.entity_id, &prop_ref.property_key) {
+                Ok(deps) => deps,
+                Err(_) => continue
+            };
+            for pr in deps {
+                if self.is_trashed(&pr.entity_id) || self.is_frozen(&pr.entity_id) || seen.contains(pr) {
+                    continue;
+                }
+                seen.insert(pr.clone());
+                cascade.push(pr.clone());
+                queue.push_back(pr.clone());
+            }
+        }
+        cascade
+    }
+    fn notify_property_set(&self, entity_id: &EntityId, property_key: &str) {
+        if self.change_listeners.len() > 0 || self.subscribers.borrow().len() > 0 || self.property_observers.len() > 0 {
+            let prop_ref = PropRef::new(entity_id, property_key);
+            let cascade = self.cascade_of(prop_ref.clone());
+            for pr in &cascade {
+                if let Some(observers) = self.property_observers.get(pr) {
+                    for observer in observers {
+                        observer(&prop_ref);
+                    }
+                }
+            }
+            self.emit(DocEvent::PropertyChanged { prop_ref: prop_ref, cascade: cascade });
+        }
+        let window = match self.notification_window {
+            Some(window) => window,
+            None => {
+                if let Some(ref cb) = self.on_property_set {
+                    cb(entity_id, property_key);
+                }
+                return;
+            }
+        };
+        let since = {
+            let mut pending = self.pending_notifications.borrow_mut();
+            if pending.prop_refs.len() == 0 {
+                pending.since = self.now();
+            }
+            let prop_ref = PropRef::new(entity_id, property_key);
+            if pending.seen.insert(prop_ref.clone()) {
+                pending.prop_refs.push(prop_ref);
+            }
+            pending.since
+        };
+        if window > 0 && self.now().saturating_sub(since) >= window {
+            self.flush_notifications();
+        }
+    }
+    fn now(&self) -> u64 {
+        match self.clock {
+            Some(ref clock) => clock(),
+            None => 0
+        }
+    }
+    // Active qualifiers, highest priority first
+    pub fn get_qualifiers(&self) -> &Vec<String> {
+        &self.qualifiers
+    }
+    // Re-selects the variant of every qualified property, so dependants of the ones that change are invalidated
+    pub fn set_qualifiers(&mut self, qualifiers: Vec<String>) -> Result<(), DocError> {
+        self.qualifiers = qualifiers;
+        self.select_qualified_variants()
+    }
+    pub fn get_locale(&self) -> Option<&String> {
+        self.locale.as_ref()
+    }
+    // The locale acts as a qualifier with priority over all others, so `text--de` is picked over `text` while it's "de"
+    pub fn set_locale(&mut self, locale: Option<String>) -> Result<(), DocError> {
+        self.locale = locale;
+        self.select_qualified_variants()
+    }
+    fn select_qualified_variants(&mut self) -> Result<(), DocError> {
+        let mut qualified = vec![];
+        for (entity_id, entity) in &self.entities {
+            if self.frozen.contains_key(entity_id) {
+                continue;
+            }
+            for key in entity.qualified_defaults.keys() {
+                qualified.push(PropRef::new(entity_id, key));
+            }
+        }
+        for prop_ref in qualified {
+            try!(self.select_qualified_variant(&prop_ref.entity_id, &prop_ref.property_key));
+        }
+        Ok(())
+    }
+    fn select_qualified_variant(&mut self, entity_id: &EntityId, property_key: &str) -> Result<(), DocError> {
+        let selected = {
+            let qualifiers: Vec<&String> = self.locale.iter().chain(self.qualifiers.iter()).collect();
+            let entity = match self.entities.get_mut(entity_id) {
+                Some(entity) => entity,
+                None => return Err(DocError::NoSuchEntity(*entity_id))
+            };
+            if !entity.qualified_defaults.contains_key(property_key) {
+                let default = match entity.properties.get(property_key) {
+                    Some(prop) => (*prop.expression.borrow()).clone(),
+                    None => None
+                };
+                entity.qualified_defaults.insert(property_key.to_string(), default);
+            }
+            let mut selected = None;
+            for qualifier in qualifiers {
+                if let Some(prop) = entity.properties.get(&format!("{}{}{}", property_key, QUALIFIER_SEPARATOR, qualifier)) {
+                    if let &Some(ref expression) = &*prop.expression.borrow() {
+                        selected = Some(expression.clone());
+                        break;
+                    }
+                }
+            }
+            match selected {
+                Some(expression) => Some(expression),
+                None => entity.qualified_defaults.get(property_key).unwrap().clone()
+            }
+        };
+        match selected {
+            Some(expression) => self.set_property_expression(entity_id, property_key, expression, false),
+            None => Ok(())
+        }
+    }
+    // log is false for values derived from others, which the write-ahead log gets from replaying those
+    fn set_property_expression(&mut self, entity_id: &EntityId, property_key: &str, mut expression: Pon, log: bool) -> Result<(), DocError> {
+        //println!("set property {} {:?}", property_key, expression);
+        let resolve_start = self.now();
+        let mut dependencies: Vec<PropRef> = {
+            let entity = match self.entities.get(entity_id) {
+                Some(entity) => entity,
+                None => return Err(DocError::NoSuchEntity(*entity_id))
+            };
+            try!(self.build_property_node_dependencies(entity, &expression))
+        };
+        // So the property is told when the links it goes through change
+        if uses_links(&expression) {
+            dependencies.push(PropRef::new(entity_id, LINKS_PROPERTY));
+        }
+        for PropRef { entity_id: dep_ent_id, property_key: dep_prop_key } in dependencies {
+            match self.entities.get_mut(&dep_ent_id) {
+                Some(dep_ent) => {
+                    let prop_ref = PropRef { entity_id: entity_id.clone(), property_key: property_key.to_string() };
+                    let mut prop = dep_ent.get_or_create_property(&dep_prop_key);
+                    prop.dependants.push(prop_ref);
+                },
+                None => return Err(DocError::NoSuchEntity(dep_ent_id))
+            }
+        }
+        {
+            try!(self.resolve_pon_dependencies(&entity_id, &mut expression));
+        }
+        if let Some(ref patterns) = self.access_patterns {
+            patterns.borrow_mut().record_resolve(&PropRef::new(entity_id, property_key), self.now().saturating_sub(resolve_start));
+        }
+        self.record_history(entity_id, property_key, &expression);
+        let break_change = self.pending_break(entity_id, property_key, Some(expression.clone()));
+        let logged_expression = match self.write_ahead_log {
+            Some(_) if log => Some(expression.to_string()),
+            _ => None
+        };
+        {
+            let mut ent_mut = self.entities.get_mut(entity_id).unwrap();
+            let prop = ent_mut.get_or_create_property(property_key);
+            *prop.expression.borrow_mut() = Some(expression);
+        }
+        self.dirty_entities.insert(*entity_id);
+        self.versions.touch_property(entity_id, property_key);
+        self.metrics.property_set();
+        if property_key == SETS_PROPERTY {
+            self.index_entity_sets(entity_id);
+        }
+        if property_key == TAGS_PROPERTY {
+            self.index_entity_tags(entity_id);
+        }
+        if property_key == LINKS_PROPERTY {
+            self.index_entity_links(entity_id);
+        }
+        self.update_value_indexes(entity_id, property_key);
+        if let Some(expression) = logged_expression {
+            if let Some(ref mut log) = self.write_ahead_log {
+                try!(log.log_set_property(entity_id, property_key, &expression));
+            }
+        }
+        self.notify_property_set(entity_id, property_key);
+        if let Some(change) = break_change {
+            self.breakpoints[&change.prop_ref](&change);
         }
         Ok(())
     }
     pub fn get_property(&self, entity_id: &EntityId, property_key: &str) -> Result<Ref<Pon>, DocError> {
         match self.entities.get(entity_id) {
-            Some(entity) => self.get_entity_property(entity, property_key),
+            Some(entity) => match self.get_entity_property(entity, property_key) {
+                Err(DocError::NoSuchProperty(key)) => match self.get_alias_target(entity_id) {
+                    Some(target_id) => self.get_property(&target_id, property_key),
+                    None => Err(DocError::NoSuchProperty(key))
+                },
+                res => res
+            },
             None => Err(DocError::NoSuchEntity(*entity_id))
         }
     }
-    pub fn has_property(&self, entity_id: &EntityId, name: &str) -> Result<bool, DocError> {
+    // The property as packed floats. Float arrays are borrowed without copying, integer and plain numeric
+    // arrays are converted. The stored expression is left as it is.
+    pub fn get_property_floats(&self, entity_id: &EntityId, property_key: &str) -> Result<NumericSlice<f32>, DocError> {
+        let value = try!(self.get_property(entity_id, property_key));
+        let packed = match &*value {
+            &Pon::FloatArray(_) => true,
+            _ => false
+        };
+        if packed {
+            return Ok(NumericSlice::Borrowed(Ref::filter_map(value, |v| match v {
+                &Pon::FloatArray(ref arr) => Some(&arr[..]),
+                _ => None
+            }).unwrap()));
+        }
+        match &*value {
+            &Pon::IntegerArray(ref arr) => return Ok(NumericSlice::Converted(arr.iter().map(|v| *v as f32).collect())),
+            &Pon::Array(ref arr) => {
+                let floats: Vec<f32> = arr.iter().filter_map(|v| match v {
+                    &Pon::Float(v) => Some(v),
+                    &Pon::Integer(v) => Some(v as f32),
+                    _ => None
+                }).collect();
+                if floats.len() == arr.len() {
+                    return Ok(NumericSlice::Converted(floats));
+                }
+            },
+            _ => {}
+        }
+        Err(DocError::PonTranslateErr(PonTranslateErr::MismatchType { expected: "FloatArray".to_string(), found: format!("{:?}", *value) }))
+    }
+    // Like get_property_floats, for integer or plain integer arrays
+    pub fn get_property_integers(&self, entity_id: &EntityId, property_key: &str) -> Result<NumericSlice<i64>, DocError> {
+        let value = try!(self.get_property(entity_id, property_key));
+        let packed = match &*value {
+            &Pon::IntegerArray(_) => true,
+            _ => false
+        };
+        if packed {
+            return Ok(NumericSlice::Borrowed(Ref::filter_map(value, |v| match v {
+                &Pon::IntegerArray(ref arr) => Some(&arr[..]),
+                _ => None
+            }).unwrap()));
+        }
+        match &*value {
+            &Pon::Array(ref arr) => {
+                let integers: Vec<i64> = arr.iter().filter_map(|v| match v {
+                    &Pon::Integer(v) => Some(v),
+                    _ => None
+                }).collect();
+                if integers.len() == arr.len() {
+                    return Ok(NumericSlice::Converted(integers));
+                }
+            },
+            _ => {}
+        }
+        Err(DocError::PonTranslateErr(PonTranslateErr::MismatchType { expected: "IntegerArray".to_string(), found: format!("{:?}", *value) }))
+    }
+    pub fn set_property_floats(&mut self, entity_id: &EntityId, property_key: &str, values: Vec<f32>) -> Result<(), DocError> {
+        self.set_property(entity_id, property_key, Pon::FloatArray(values))
+    }
+    pub fn set_property_integers(&mut self, entity_id: &EntityId, property_key: &str, values: Vec<i64>) -> Result<(), DocError> {
+        self.set_property(entity_id, property_key, Pon::IntegerArray(values))
+    }
+    // Lets func change the packed floats, then sets them through set_property, so transactions, double
+    // buffering and the schema apply as usual
+    pub fn modify_property_floats<F: FnOnce(&mut [f32])>(&mut self, entity_id: &EntityId, property_key: &str, func: F) -> Result<(), DocError> {
+        let mut floats = try!(self.get_property_floats(entity_id, property_key)).to_vec();
+        func(&mut floats[..]);
+        self.set_property_floats(entity_id, property_key, floats)
+    }
+    pub fn modify_property_integers<F: FnOnce(&mut [i64])>(&mut self, entity_id: &EntityId, property_key: &str, func: F) -> Result<(), DocError> {
+        let mut integers = try!(self.get_property_integers(entity_id, property_key)).to_vec();
+        func(&mut integers[..]);
+        self.set_property_integers(entity_id, property_key, integers)
+    }
+    // Clears the property's expression, returning it. Dependants keep pointing at the (now empty) property.
+    pub fn unset_property(&mut self, entity_id: &EntityId, property_key: &str) -> Result<Pon, DocError> {
+        try!(self.remove_released_scopes());
+        if self.frozen.contains_key(entity_id) {
+            return Err(DocError::EntityFrozen(*entity_id));
+        }
+        self.record_previous(entity_id, property_key);
+        if self.double_buffered {
+            // The value returned is the one readers see until the flip
+            let current = try!(self.get_property(entity_id, property_key)).clone();
+            try!(self.buffer_write(entity_id, property_key, None));
+            return Ok(current);
+        }
+        let old = match self.entities.get(entity_id) {
+            Some(entity) => match entity.properties.get(property_key) {
+                Some(prop) => prop.expression.borrow_mut().take(),
+                None => None
+            },
+            None => return Err(DocError::NoSuchEntity(*entity_id))
+        };
+        match old {
+            Some(old) => {
+                self.record_history(entity_id, property_key, &Pon::Nil);
+                self.dirty_entities.insert(*entity_id);
+                self.versions.touch_property(entity_id, property_key);
+                if property_key == SETS_PROPERTY {
+                    self.index_entity_sets(entity_id);
+                }
+                if property_key == TAGS_PROPERTY {
+                    self.index_entity_tags(entity_id);
+                }
+                if property_key == LINKS_PROPERTY {
+                    self.index_entity_links(entity_id);
+                }
+                self.update_value_indexes(entity_id, property_key);
+                if let Some(ref mut log) = self.write_ahead_log {
+                    try!(log.log_unset_property(entity_id, property_key));
+                }
+                self.notify_property_set(entity_id, property_key);
+                let prop_ref = PropRef::new(entity_id, property_key);
+                if let Some(cb) = self.breakpoints.get(&prop_ref) {
+                    cb(&PropertyChange {
+                        prop_ref: prop_ref.clone(),
+                        old_value: Some(old.clone()),
+                        new_value: None,
+                        source: self.mutation_source.clone()
+                    });
+                }
+                Ok(old)
+            },
+            None => Err(DocError::NoSuchProperty(property_key.to_string()))
+        }
+    }
+    // Deletes the property, including its qualified variants' default, instead of just unsetting it. Properties
+    // that @-referenced it fail to resolve until they're set again; they're returned with everything depending
+    // on them. Unlike set_property this isn't delayed in double buffered mode.
+    pub fn remove_property(&mut self, entity_id: &EntityId, property_key: &str) -> Result<Vec<PropRef>, DocError> {
+        try!(self.remove_released_scopes());
+        if self.frozen.contains_key(entity_id) {
+            return Err(DocError::EntityFrozen(*entity_id));
+        }
+        // Recorded even when there's nothing to remove, like set_property, so rollback sees the property as it was
+        self.record_previous(entity_id, property_key);
+        let prop = match self.entities.get_mut(entity_id) {
+            Some(entity) => {
+                entity.qualified_defaults.remove(property_key);
+                match entity.properties.remove(property_key) {
+                    Some(prop) => prop,
+                    None => return Err(DocError::NoSuchProperty(property_key.to_string()))
+                }
+            },
+            None => return Err(DocError::NoSuchEntity(*entity_id))
+        };
+        if let Some(ref mut log) = self.write_ahead_log {
+            try!(log.log_remove_property(entity_id, property_key));
+        }
+        let prop_ref = PropRef::new(entity_id, property_key);
+        let old = prop.expression.borrow_mut().take();
+        if let Some(ref old) = old {
+            let mut dependencies = vec![];
+            collect_resolved_dependencies(old, &mut dependencies);
+            for dep in dependencies {
+                if let Some(dep_prop) = self.entities.get_mut(&dep.entity_id).and_then(|entity| entity.properties.get_mut(&dep.property_key)) {
+                    dep_prop.dependants.retain(|p| *p != prop_ref);
+                }
+            }
+            self.record_history(entity_id, property_key, &Pon::Nil);
+        }
+        // Dependants may be stale, only the ones still referencing the property break
+        let broken: Vec<PropRef> = prop.dependants.into_iter().filter(|dependant| {
+            match self.entities.get(&dependant.entity_id).and_then(|entity| entity.properties.get(&dependant.property_key)) {
+                Some(dependant_prop) => match &*dependant_prop.expression.borrow() {
+                    &Some(ref expression) => {
+                        let mut dependencies = vec![];
+                        collect_resolved_dependencies(expression, &mut dependencies);
+                        dependencies.contains(&prop_ref)
+                    },
+                    &None => false
+                },
+                None => false
+            }
+        }).collect();
+        self.dirty_entities.insert(*entity_id);
+        self.versions.touch_property(entity_id, property_key);
+        if property_key == SETS_PROPERTY {
+            self.index_entity_sets(entity_id);
+        }
+        if property_key == TAGS_PROPERTY {
+            self.index_entity_tags(entity_id);
+        }
+        if property_key == LINKS_PROPERTY {
+            self.index_entity_links(entity_id);
+        }
+        self.reindex_value(entity_id, property_key);
+        let cascade = self.build_cascade(broken);
+        for prop_ref in &cascade {
+            if self.value_indexes.contains_key(&prop_ref.property_key) {
+                self.reindex_value(&prop_ref.entity_id, &prop_ref.property_key);
+            }
+        }
+        self.notify_property_set(entity_id, property_key);
+        Ok(cascade)
+    }
+    // Stamps entities created or changed from now on with clock's time and author, see Provenance. Entities
+    // count as changed when their properties, name, type or children do.
+    pub fn record_provenance(&mut self, clock: Box<Fn() -> u64>, author: Option<String>) {
+        self.versions.provenance_source = Some(ProvenanceSource { clock: clock, author: author });
+    }
+    // What's been recorded (or loaded) is kept
+    pub fn stop_recording_provenance(&mut self) {
+        self.versions.provenance_source = None;
+    }
+    pub fn get_provenance(&self, entity_id: &EntityId) -> Option<&Provenance> {
+        self.versions.provenance.get(entity_id)
+    }
+    // Labels subsequent mutations (e.g. with the name of the system making them) for debugging
+    pub fn set_mutation_source(&mut self, source: Option<String>) {
+        self.mutation_source = source;
+    }
+    // Starts keeping the last capacity values written to the property
+    pub fn track_property_history(&mut self, prop_ref: &PropRef, capacity: usize) {
+        self.property_history.insert(prop_ref.clone(), PropertyHistory { capacity: capacity, entries: VecDeque::new() });
+    }
+    pub fn untrack_property_history(&mut self, prop_ref: &PropRef) {
+        self.property_history.remove(prop_ref);
+    }
+    // Calls callback every time the property is set or unset, until clear_debug_break
+    pub fn debug_break_on(&mut self, prop_ref: &PropRef, callback: Box<Fn(&PropertyChange) -> ()>) {
+        self.breakpoints.insert(prop_ref.clone(), callback);
+    }
+    pub fn clear_debug_break(&mut self, prop_ref: &PropRef) {
+        self.breakpoints.remove(prop_ref);
+    }
+    // Captures the old value before it's overwritten, if anyone is watching
+    fn pending_break(&self, entity_id: &EntityId, property_key: &str, new_value: Option<Pon>) -> Option<PropertyChange> {
+        let prop_ref = PropRef::new(entity_id, property_key);
+        if !self.breakpoints.contains_key(&prop_ref) {
+            return None;
+        }
+        let old_value = match self.entities.get(entity_id).and_then(|entity| entity.properties.get(property_key)) {
+            Some(prop) => (*prop.expression.borrow()).clone(),
+            None => None
+        };
+        Some(PropertyChange {
+            prop_ref: prop_ref,
+            old_value: old_value,
+            new_value: new_value,
+            source: self.mutation_source.clone()
+        })
+    }
+    // Oldest first
+    pub fn property_history(&self, prop_ref: &PropRef) -> Option<Vec<PropertyHistoryEntry>> {
+        self.property_history.get(prop_ref).map(|history| history.entries.iter().cloned().collect())
+    }
+    fn record_history(&mut self, entity_id: &EntityId, property_key: &str, value: &Pon) {
+        if self.property_history.len() == 0 {
+            return;
+        }
+        let timestamp = self.now();
+        if let Some(history) = self.property_history.get_mut(&PropRef::new(entity_id, property_key)) {
+            if history.entries.len() >= history.capacity {
+                history.entries.pop_front();
+            }
+            if history.capacity > 0 {
+                history.entries.push_back(PropertyHistoryEntry {
+                    value: value.clone(),
+                    timestamp: timestamp,
+                    source: self.mutation_source.clone()
+                });
+            }
+        }
+    }
+    // Named groups of entities, independent of the hierarchy. A set without members isn't saved.
+    pub fn create_set(&mut self, name: &str, entity_ids: Vec<EntityId>) -> Result<(), DocError> {
+        self.entity_sets.entry(name.to_string()).or_insert(vec![]);
+        for entity_id in entity_ids {
+            try!(self.add_to_set(name, &entity_id));
+        }
+        Ok(())
+    }
+    pub fn add_to_set(&mut self, name: &str, entity_id: &EntityId) -> Result<(), DocError> {
+        let mut sets = try!(self.get_entity_sets(entity_id));
+        if !sets.iter().any(|s| s == name) {
+            sets.push(name.to_string());
+            try!(self.set_property(entity_id, SETS_PROPERTY, Pon::Array(sets.into_iter().map(Pon::String).collect())));
+        }
+        Ok(())
+    }
+    pub fn remove_from_set(&mut self, name: &str, entity_id: &EntityId) -> Result<(), DocError> {
+        let sets = try!(self.get_entity_sets(entity_id));
+        if !sets.iter().any(|s| s == name) {
+            return Ok(());
+        }
+        let sets: Vec<Pon> = sets.into_iter().filter(|s| s != name).map(Pon::String).collect();
+        if sets.len() == 0 {
+            self.unset_property(entity_id, SETS_PROPERTY).map(|_| ())
+        } else {
+            self.set_property(entity_id, SETS_PROPERTY, Pon::Array(sets))
+        }
+    }
+    pub fn delete_set(&mut self, name: &str) -> Result<(), DocError> {
+        for entity_id in self.get_set(name).unwrap_or(vec![]) {
+            try!(self.remove_from_set(name, &entity_id));
+        }
+        self.entity_sets.remove(name);
+        Ok(())
+    }
+    // Members in the order they were added, leaving out trashed ones
+    pub fn get_set(&self, name: &str) -> Option<Vec<EntityId>> {
+        self.entity_sets.get(name).map(|members| members.iter().filter(|id| self.entities.contains_key(id)).cloned().collect())
+    }
+    pub fn get_set_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.entity_sets.keys().cloned().collect();
+        names.sort();
+        names
+    }
+    // The sets entity_id is a member of
+    pub fn get_entity_sets(&self, entity_id: &EntityId) -> Result<Vec<String>, DocError> {
+        self.get_names_property(entity_id, SETS_PROPERTY)
+    }
+    // A property listing names, like SETS_PROPERTY and TAGS_PROPERTY; a single string is a list of one
+    fn get_names_property(&self, entity_id: &EntityId, property_key: &str) -> Result<Vec<String>, DocError> {
+        if !try!(self.has_property(entity_id, property_key)) {
+            return Ok(vec![]);
+        }
+        let value = try!(try!(self.get_property(entity_id, property_key)).concretize());
+        let sets: Vec<Pon> = match value {
+            Pon::Array(sets) => sets,
+            value => vec![value]
+        };
+        let mut names = vec![];
+        for set in sets {
+            match set {
+                Pon::String(name) => names.push(name),
+                other => return Err(DocError::PonTranslateErr(PonTranslateErr::MismatchType { expected: "String".to_string(), found: format!("{:?}", other) }))
+            }
+        }
+        Ok(names)
+    }
+    // Tags label entities, e.g. "enemies" or "static", and are saved in TAGS_PROPERTY
+    pub fn add_tag(&mut self, entity_id: &EntityId, tag: &str) -> Result<(), DocError> {
+        let mut tags = try!(self.get_tags(entity_id));
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+            try!(self.set_property(entity_id, TAGS_PROPERTY, Pon::Array(tags.into_iter().map(Pon::String).collect())));
+        }
+        Ok(())
+    }
+    pub fn remove_tag(&mut self, entity_id: &EntityId, tag: &str) -> Result<(), DocError> {
+        let tags = try!(self.get_tags(entity_id));
+        if !tags.iter().any(|t| t == tag) {
+            return Ok(());
+        }
+        let tags: Vec<Pon> = tags.into_iter().filter(|t| t != tag).map(Pon::String).collect();
+        if tags.len() == 0 {
+            self.unset_property(entity_id, TAGS_PROPERTY).map(|_| ())
+        } else {
+            self.set_property(entity_id, TAGS_PROPERTY, Pon::Array(tags))
+        }
+    }
+    pub fn has_tag(&self, entity_id: &EntityId, tag: &str) -> Result<bool, DocError> {
+        Ok(try!(self.get_tags(entity_id)).iter().any(|t| t == tag))
+    }
+    pub fn get_tags(&self, entity_id: &EntityId) -> Result<Vec<String>, DocError> {
+        self.get_names_property(entity_id, TAGS_PROPERTY)
+    }
+    // From the index, in the order the entities were tagged
+    pub fn get_entities_by_tag(&self, tag: &str) -> Vec<EntityId> {
+        match self.entity_tags.get(tag) {
+            Some(tagged) => tagged.iter().filter(|id| self.entities.contains_key(id)).cloned().collect(),
+            None => vec![]
+        }
+    }
+    fn index_entity_sets(&mut self, entity_id: &EntityId) {
+        let sets = self.get_entity_sets(entity_id).unwrap_or(vec![]);
+        index_names(&mut self.entity_sets, entity_id, sets);
+    }
+    fn index_entity_tags(&mut self, entity_id: &EntityId) {
+        let tags = self.get_tags(entity_id).unwrap_or(vec![]);
+        index_names(&mut self.entity_tags, entity_id, tags);
+    }
+    // Keeps entities indexed by the value of property_key, making find_by_property on it proportional to the
+    // number of results. Values are kept up to date through cascades.
+    pub fn index_property(&mut self, property_key: &str) {
+        self.value_indexes.insert(property_key.to_string(), ValueIndex { entities_by_value: HashMap::new(), values: HashMap::new() });
+        let ids: Vec<EntityId> = self.entities.keys().cloned().collect();
+        for id in ids {
+            self.reindex_value(&id, property_key);
+        }
+    }
+    pub fn unindex_property(&mut self, property_key: &str) {
+        self.value_indexes.remove(property_key);
+    }
+    // Entities whose property_key concretizes to value, sorted by id. Scans every entity unless the key is indexed.
+    pub fn find_by_property(&self, property_key: &str, value: &Pon) -> Vec<EntityId> {
+        let mut found: Vec<EntityId> = match self.value_indexes.get(property_key) {
+            Some(index) => match index.entities_by_value.get(&value.to_string()) {
+                Some(ids) => ids.iter().filter(|id| self.entities.contains_key(id)).cloned().collect(),
+                None => vec![]
+            },
+            None => self.entities.keys().filter(|id| self.concrete_value(id, property_key).as_ref() == Some(value)).cloned().collect()
+        };
+        found.sort();
+        found
+    }
+    // The concrete values of keys for every entity that has at least one of them, in document order. Reading
+    // it doesn't count as property reads.
+    pub fn export_property_table(&self, keys: &[&str]) -> PropertyTable {
+        let mut table = PropertyTable {
+            entity_ids: vec![],
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            columns: keys.iter().map(|_| vec![]).collect()
+        };
+        let ids = match self.root {
+            Some(root) => self.subtree_ids(&root).unwrap_or(vec![]),
+            None => vec![]
+        };
+        for id in ids {
+            let entity = &self.entities[&id];
+            if !keys.iter().any(|key| entity.properties.contains_key(*key)) {
+                continue;
+            }
+            table.entity_ids.push(id);
+            for (i, key) in keys.iter().enumerate() {
+                table.columns[i].push(self.concrete_value(&id, key));
+            }
+        }
+        table
+    }
+    // Without counting it as a read
+    fn concrete_value(&self, entity_id: &EntityId, property_key: &str) -> Option<Pon> {
+        match self.entities.get(entity_id).and_then(|entity| entity.properties.get(property_key)) {
+            Some(prop) => match &*prop.expression.borrow() {
+                &Some(ref expression) => expression.concretize().ok(),
+                &None => None
+            },
+            None => None
+        }
+    }
+    // Reindexes the property and its dependants, for the keys that are indexed
+    fn update_value_indexes(&mut self, entity_id: &EntityId, property_key: &str) {
+        if self.value_indexes.len() == 0 {
+            return;
+        }
+        let mut visited = HashSet::new();
+        let mut queue = vec![PropRef::new(entity_id, property_key)];
+        while let Some(prop_ref) = queue.pop() {
+            if !visited.insert(prop_ref.clone()) {
+                continue;
+            }
+            if let Ok(dependants) = self.get_property_dependants(&prop_ref.entity_id, &prop_ref.property_key) {
+                queue.push_all(dependants);
+            }
+            if self.value_indexes.contains_key(&prop_ref.property_key) {
+                self.reindex_value(&prop_ref.entity_id, &prop_ref.property_key);
+            }
+        }
+    }
+    fn reindex_value(&mut self, entity_id: &EntityId, property_key: &str) {
+        let value = self.concrete_value(entity_id, property_key).map(|value| value.to_string());
+        let index = match self.value_indexes.get_mut(property_key) {
+            Some(index) => index,
+            None => return
+        };
+        if let Some(old) = index.values.remove(entity_id) {
+            if let Some(ids) = index.entities_by_value.get_mut(&old) {
+                ids.retain(|id| id != entity_id);
+            }
+        }
+        if let Some(value) = value {
+            index.entities_by_value.entry(value.clone()).or_insert(vec![]).push(*entity_id);
+            index.values.insert(*entity_id, value);
+        }
+    }
+    // Typed links between entities, independent of the hierarchy, e.g. add_link(&turret, "target", &enemy).
+    // Links are by name, so the target needs one; they're dropped when either end is removed.
+    pub fn add_link(&mut self, from: &EntityId, kind: &str, to: &EntityId) -> Result<(), DocError> {
+        let name = match try!(self.get_entity_name(to)) {
+            Some(name) => name.to_string(),
+            None => return Err(DocError::UnnamedLinkTarget(*to))
+        };
+        let mut links = try!(self.get_entity_links(from));
+        let link = (kind.to_string(), name);
+        if !links.contains(&link) {
+            links.push(link);
+            try!(self.set_entity_links(from, links));
+        }
+        Ok(())
+    }
+    pub fn remove_link(&mut self, from: &EntityId, kind: &str, to: &EntityId) -> Result<(), DocError> {
+        let name = match try!(self.get_entity_name(to)) {
+            Some(name) => name.to_string(),
+            None => return Ok(())
+        };
+        let links = try!(self.get_entity_links(from));
+        if links.iter().any(|&(ref k, ref n)| k == kind && *n == name) {
+            try!(self.set_entity_links(from, links.into_iter().filter(|&(ref k, ref n)| !(k == kind && *n == name)).collect()));
+        }
+        Ok(())
+    }
+    // The (kind, target) links from an entity, optionally of one kind. Targets that don't exist (or are trashed)
+    // are left out.
+    pub fn get_links(&self, from: &EntityId, kind: Option<&str>) -> Result<Vec<(String, EntityId)>, DocError> {
+        Ok(try!(self.get_entity_links(from)).into_iter()
+            .filter(|&(ref k, _)| kind.map(|kind| k == kind).unwrap_or(true))
+            .filter_map(|(k, name)| self.get_entity_by_name(&name).map(|id| (k, id)))
+            .collect())
+    }
+    // The (kind, source) links to an entity, optionally of one kind, sorted by source
+    pub fn get_backlinks(&self, to: &EntityId, kind: Option<&str>) -> Result<Vec<(String, EntityId)>, DocError> {
+        let name = match try!(self.get_entity_name(to)) {
+            Some(name) => name.to_string(),
+            None => return Ok(vec![])
+        };
+        let mut backlinks = vec![];
+        for (from, links) in &self.links {
+            if !self.entities.contains_key(from) {
+                continue;
+            }
+            for &(ref k, ref n) in links {
+                if *n == name && kind.map(|kind| k == kind).unwrap_or(true) {
+                    backlinks.push((k.clone(), *from));
+                }
+            }
+        }
+        backlinks.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(backlinks)
+    }
+    // The (kind, target name) pairs in the entity's LINKS_PROPERTY, sorted by kind
+    fn get_entity_links(&self, entity_id: &EntityId) -> Result<Vec<(String, String)>, DocError> {
+        if !try!(self.has_property(entity_id, LINKS_PROPERTY)) {
+            return Ok(vec![]);
+        }
+        let kinds = match try!(try!(self.get_property(entity_id, LINKS_PROPERTY)).concretize()) {
+            Pon::Object(kinds) => kinds,
+            other => return Err(DocError::PonTranslateErr(PonTranslateErr::MismatchType { expected: "Object".to_string(), found: format!("{:?}", other) }))
+        };
+        let mut kinds: Vec<(String, Pon)> = kinds.into_iter().collect();
+        kinds.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut links = vec![];
+        for (kind, targets) in kinds {
+            let targets = match targets {
+                Pon::Array(targets) => targets,
+                target => vec![target]
+            };
+            for target in targets {
+                match target {
+                    Pon::String(name) => links.push((kind.clone(), name)),
+                    other => return Err(DocError::PonTranslateErr(PonTranslateErr::MismatchType { expected: "String".to_string(), found: format!("{:?}", other) }))
+                }
+            }
+        }
+        Ok(links)
+    }
+    fn set_entity_links(&mut self, entity_id: &EntityId, links: Vec<(String, String)>) -> Result<(), DocError> {
+        if links.len() == 0 {
+            return self.unset_property(entity_id, LINKS_PROPERTY).map(|_| ());
+        }
+        let mut kinds: HashMap<String, Pon> = HashMap::new();
+        for (kind, name) in links {
+            match kinds.entry(kind).or_insert(Pon::Array(vec![])) {
+                &mut Pon::Array(ref mut targets) => targets.push(Pon::String(name)),
+                _ => unreachable!()
+            }
+        }
+        self.set_property(entity_id, LINKS_PROPERTY, Pon::Object(kinds))
+    }
+    fn index_entity_links(&mut self, entity_id: &EntityId) {
+        match self.get_entity_links(entity_id) {
+            Ok(ref links) if links.len() > 0 => { self.links.insert(*entity_id, links.clone()); },
+            _ => { self.links.remove(entity_id); }
+        }
+        self.resolve_link_references(entity_id);
+    }
+    // Properties of the entity going through its links are resolved again when the links change. The ones
+    // whose link is gone are unset and kept in unresolved, to be set again once it's back.
+    fn resolve_link_references(&mut self, entity_id: &EntityId) {
+        let users = match self.get_property_dependants(entity_id, LINKS_PROPERTY) {
+            Ok(dependants) => dependants.clone(),
+            Err(_) => vec![]
+        };
+        for prop_ref in users {
+            let expression = match self.entities[entity_id].properties.get(&prop_ref.property_key) {
+                Some(prop) => (*prop.expression.borrow()).clone(),
+                None => None
+            };
+            match expression {
+                Some(ref expression) if prop_ref.entity_id == *entity_id && uses_links(expression) => {},
+                _ => continue
+            }
+            let expression = expression.unwrap();
+            if self.set_property_expression(entity_id, &prop_ref.property_key, expression.clone(), false).is_err() {
+                self.entities[entity_id].properties[&prop_ref.property_key].expression.borrow_mut().take();
+                self.unresolved.push((*entity_id, prop_ref.property_key, expression));
+            }
+        }
+        let unresolved = ::std::mem::replace(&mut self.unresolved, vec![]);
+        for (id, key, expression) in unresolved {
+            if id != *entity_id || !uses_links(&expression) || self.set_property_expression(&id, &key, expression.clone(), false).is_err() {
+                self.unresolved.push((id, key, expression));
+            }
+        }
+    }
+    pub fn has_property(&self, entity_id: &EntityId, name: &str) -> Result<bool, DocError> {
+        match self.entities.get(entity_id) {
+            Some(entity) => match entity.properties.get(name) {
+                Some(prop) => Ok(prop.expression.borrow().is_some()),
+                None => Ok(false)
+            },
+            None => Err(DocError::NoSuchEntity(*entity_id))
+        }
+    }
+    pub fn get_properties(&self, entity_id: &EntityId) -> Result<Vec<PropRef>, DocError> {
+        match self.entities.get(&entity_id) {
+            Some(entity) => Ok(entity.properties.keys().map(|key| PropRef { entity_id: entity_id.clone(), property_key: key.clone() }).collect()),
+            None => Err(DocError::NoSuchEntity(*entity_id))
+        }
+    }
+    pub fn get_children(&self, entity_id: &EntityId) -> Result<&Vec<EntityId>, DocError> {
+        match self.entities.get(&entity_id) {
+            Some(entity) => Ok(&entity.children_ids),
+            None => Err(DocError::NoSuchEntity(*entity_id))
+        }
+    }
+    // The children an alias shows, i.e. those of the entity at the end of its chain of aliases; the entity's own
+    // children if it isn't an alias. Recursive walkers should use get_children, since aliases can point up the tree.
+    pub fn get_resolved_children(&self, entity_id: &EntityId) -> Result<&Vec<EntityId>, DocError> {
+        let mut visited = vec![*entity_id];
+        let mut current = *entity_id;
+        while let Some(target_id) = self.get_alias_target(&current) {
+            if visited.contains(&target_id) {
+                break;
+            }
+            visited.push(target_id);
+            current = target_id;
+        }
+        self.get_children(&current)
+    }
+    // An alias is an `Alias` entity with an `of` property naming another entity. It shows that entity's
+    // children and passes property reads through to it (unless it has the property itself), without copying.
+    pub fn append_alias(&mut self, parent_id: &EntityId, target_id: &EntityId, name: Option<String>) -> Result<EntityId, DocError> {
+        let target_name = match self.entities.get(target_id) {
+            Some(&Entity { name: Some(ref name), .. }) => name.clone(),
+            Some(_) => return Err(DocError::UnnamedAliasTarget(*target_id)),
+            None => return Err(DocError::NoSuchEntity(*target_id))
+        };
+        let id = try!(self.append_entity(Some(*parent_id), "Alias", name));
+        try!(self.set_property(&id, "of", Pon::String(target_name)));
+        Ok(id)
+    }
+    pub fn get_alias_target(&self, entity_id: &EntityId) -> Option<EntityId> {
+        let entity = match self.entities.get(entity_id) {
+            Some(entity) if entity.type_name == "Alias" => entity,
+            _ => return None
+        };
+        let target = match entity.properties.get("of") {
+            Some(prop) => match &*prop.expression.borrow() {
+                &Some(Pon::String(ref name)) => self.get_entity_by_name(name),
+                _ => None
+            },
+            None => None
+        };
+        match target {
+            Some(target_id) if target_id != *entity_id => Some(target_id),
+            _ => None
+        }
+    }
+    pub fn get_parent(&self, entity_id: &EntityId) -> Result<Option<EntityId>, DocError> {
+        match self.entities.get(entity_id) {
+            Some(entity) => Ok(entity.parent_id),
+            None => Err(DocError::NoSuchEntity(*entity_id))
+        }
+    }
+    // See builder::EntityBuilder
+    pub fn build_entity(&mut self, type_name: &str) -> DocumentBuilder {
+        DocumentBuilder::new(self, type_name)
+    }
+    pub fn entity(&self, entity_id: &EntityId) -> Option<EntityRef> {
+        if self.entities.contains_key(entity_id) {
+            Some(EntityRef { document: self, entity_id: *entity_id })
+        } else {
+            None
+        }
+    }
+    pub fn entity_by_name(&self, name: &str) -> Option<EntityRef> {
+        self.get_entity_by_name(name).map(|id| EntityRef { document: self, entity_id: id })
+    }
+    pub fn root_entity(&self) -> Option<EntityRef> {
+        self.root.map(|id| EntityRef { document: self, entity_id: id })
+    }
+    pub fn entity_mut(&mut self, entity_id: &EntityId) -> Option<EntityRefMut> {
+        if self.entities.contains_key(entity_id) {
+            Some(EntityRefMut { document: self, entity_id: *entity_id })
+        } else {
+            None
+        }
+    }
+    pub fn ancestors(&self, entity_id: &EntityId) -> AncestorIter {
+        AncestorIter { document: self, next: self.entities.get(entity_id).and_then(|entity| entity.parent_id) }
+    }
+    // Whether ancestor_id is above entity_id in the hierarchy (an entity isn't its own ancestor)
+    pub fn is_ancestor_of(&self, ancestor_id: &EntityId, entity_id: &EntityId) -> bool {
+        self.ancestors(entity_id).any(|id| id == *ancestor_id)
+    }
+    // Iterators over root and its descendants (nothing if root doesn't exist)
+    pub fn iter_tree_dfs(&self, root: &EntityId) -> DfsIter {
+        DfsIter { document: self, stack: if self.entities.contains_key(root) { vec![*root] } else { vec![] } }
+    }
+    pub fn iter_tree_bfs(&self, root: &EntityId) -> BfsIter {
+        let mut queue = VecDeque::new();
+        if self.entities.contains_key(root) {
+            queue.push_back(*root);
+        }
+        BfsIter { document: self, queue: queue }
+    }
+    // Every entity the predicate accepts, in document order
+    pub fn find_entities<F: Fn(&EntityView) -> bool>(&self, predicate: F) -> Vec<EntityId> {
+        let ids = match self.root {
+            Some(root) => self.subtree_ids(&root).unwrap_or(vec![]),
+            None => return vec![]
+        };
+        ids.into_iter().filter(|id| predicate(&EntityView { document: self, entity: &self.entities[id] })).collect()
+    }
+    // Entities matching a selector such as "Scene > Mesh[visible=true]", in document order, see selector.rs
+    pub fn query(&self, selector: &str) -> Result<Vec<EntityId>, DocError> {
+        let selector = try!(Selector::from_string(selector).map_err(|err| DocError::InvalidSelector(format!("{:?}", err))));
+        Ok(self.find_entities(|entity| selector.matches(self, &entity.id())))
+    }
+    pub fn search_children(&self, entity_id: &EntityId, name: &str) -> Result<EntityId, DocError> {
+        match self.entities.get(entity_id) {
+            Some(entity) => {
+                if let &Some(ref string) = &entity.name {
+                    if string == name {
+                        return Ok(entity.id);
+                    }
+                }
+                for c in &entity.children_ids {
+                    match self.search_children(&c, name) {
+                        Ok(id) => return Ok(id),
+                        _ => {}
+                    }
+                }
+                Err(DocError::CantFindEntityByName(name.to_string()))
+            },
+            None => Err(DocError::NoSuchEntity(*entity_id))
+        }
+    }
+    pub fn resolve_entity_path(&self, start_entity_id: &EntityId, path: &EntityPath) -> Result<EntityId, DocError> {
+        match path {
+            &EntityPath::This => Ok(*start_entity_id),
+            &EntityPath::Parent => match self.entities.get(start_entity_id) {
+                Some(entity) => match entity.parent_id {
+                    Some(parent_id) => Ok(parent_id),
+                    None => Err(DocError::NoParent(*start_entity_id))
+                },
+                None => Err(DocError::NoSuchEntity(*start_entity_id))
+            },
+            &EntityPath::Named(ref name) => match self.entity_ids_by_name.get(name) {
+                Some(entity_id) => Ok(entity_id.clone()),
+                None => Err(DocError::CantFindEntityByName(name.to_string()))
+            },
+            &EntityPath::Search(ref path, ref search) => {
+                match self.resolve_entity_path(start_entity_id, path) {
+                    Ok(ent) => self.search_children(&ent, search),
+                    Err(err) => Err(err)
+                }
+            },
+            &EntityPath::Link(ref kind) => match try!(self.get_links(start_entity_id, Some(kind))).first() {
+                Some(&(_, target_id)) => Ok(target_id),
+                None => Err(DocError::CantFindEntityByName(path.to_string()))
+            }
+        }
+    }
+    pub fn resolve_named_prop_ref(&self, start_entity_id: &EntityId, named_prop_ref: &NamedPropRef) -> Result<PropRef, DocError> {
+        let owner_entity_id = try!(self.resolve_entity_path(start_entity_id, &named_prop_ref.entity_path));
+        Ok(PropRef { entity_id: owner_entity_id, property_key: named_prop_ref.property_key.clone() })
+    }
+    pub fn get_entity_name(&self, entity_id: &EntityId) -> Result<Option<&String>, DocError> {
+        match self.entities.get(&entity_id) {
+            Some(entity) => Ok(entity.name.as_ref()),
+            None => Err(DocError::NoSuchEntity(*entity_id))
+        }
+    }
+    // References to the old name (and links to it) are retargeted to the new one, and properties that couldn't
+    // load because they referenced the new name are set now. Returns the cascade of everything re-resolved.
+    pub fn rename_entity(&mut self, entity_id: &EntityId, new_name: &str) -> Result<Vec<PropRef>, DocError> {
+        try!(self.remove_released_scopes());
+        match self.entity_ids_by_name.get(new_name) {
+            Some(id) if id == entity_id => return Ok(vec![]),
+            Some(_) => return Err(DocError::NameTaken(new_name.to_string())),
+            None => {}
+        }
+        let old_name = match self.entities.get_mut(entity_id) {
+            Some(entity) => ::std::mem::replace(&mut entity.name, Some(new_name.to_string())),
+            None => return Err(DocError::NoSuchEntity(*entity_id))
+        };
+        if let Some(ref mut log) = self.write_ahead_log {
+            try!(log.log_rename_entity(entity_id, new_name));
+        }
+        if let Some(ref old_name) = old_name {
+            if self.entity_ids_by_name.get(old_name) == Some(entity_id) {
+                self.entity_ids_by_name.remove(old_name);
+            }
+        }
+        self.entity_ids_by_name.insert(new_name.to_string(), *entity_id);
+        self.dirty_entities.insert(*entity_id);
+        self.versions.touch_entity(*entity_id);
+        let mut changed = vec![];
+        if let Some(ref old_name) = old_name {
+            changed = try!(self.replace_references(old_name, new_name, false));
+            let renamed_links: Vec<(EntityId, Vec<(String, String)>)> = self.links.iter()
+                .filter(|&(_, links)| links.iter().any(|&(_, ref name)| name == old_name))
+                .map(|(from, links)| (*from, links.iter().map(|&(ref kind, ref name)| {
+                    (kind.clone(), if name == old_name { new_name.to_string() } else { name.clone() })
+                }).collect()))
+                .collect();
+            for (from, links) in renamed_links {
+                try!(self.set_entity_links(&from, links));
+            }
+        }
+        let unresolved = ::std::mem::replace(&mut self.unresolved, vec![]);
+        for (id, key, expression) in unresolved {
+            if !self.entities.contains_key(&id) || !references_name(&expression, new_name) {
+                self.unresolved.push((id, key, expression));
+                continue;
+            }
+            match self.set_property(&id, &key, expression.clone()) {
+                Ok(()) => changed.push(PropRef::new(&id, &key)),
+                Err(DocError::CantFindEntityByName(_)) => self.unresolved.push((id, key, expression)),
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(self.build_cascade(changed))
+    }
+    // Sequence number of the entity's last change: its properties, name, type or children (not changes to values
+    // it depends on). Numbers only grow, so a cache holding an older number is stale.
+    pub fn entity_version(&self, entity_id: &EntityId) -> Result<u64, DocError> {
+        if !self.entities.contains_key(entity_id) {
+            return Err(DocError::NoSuchEntity(*entity_id));
+        }
+        Ok(self.versions.entities.get(entity_id).cloned().unwrap_or(0))
+    }
+    // 0 for properties that were never set
+    pub fn property_version(&self, entity_id: &EntityId, property_key: &str) -> Result<u64, DocError> {
+        if !self.entities.contains_key(entity_id) {
+            return Err(DocError::NoSuchEntity(*entity_id));
+        }
+        Ok(self.versions.properties.get(&PropRef::new(entity_id, property_key)).cloned().unwrap_or(0))
+    }
+    pub fn get_entities_by_type(&self, type_name: &str) -> EntityTypeIter {
+        const NONE: &'static [EntityId] = &[];
+        match self.entities_by_type.get(type_name) {
+            Some(ids) => ids.iter().cloned(),
+            None => NONE.iter().cloned()
+        }
+    }
+    pub fn get_entity_type_name(&self, entity_id: &EntityId) -> Result<&String, DocError> {
+        match self.entities.get(&entity_id) {
+            Some(entity) => Ok(&entity.type_name),
+            None => Err(DocError::NoSuchEntity(*entity_id))
+        }
+    }
+    pub fn set_entity_type_name(&mut self, entity_id: &EntityId, type_name: &str) -> Result<(), DocError> {
+        try!(self.remove_released_scopes());
+        let old_type_name = match self.entities.get_mut(&entity_id) {
+            Some(entity) => ::std::mem::replace(&mut entity.type_name, type_name.to_string()),
+            None => return Err(DocError::NoSuchEntity(*entity_id))
+        };
+        if let Some(ids) = self.entities_by_type.get_mut(&old_type_name) {
+            ids.retain(|id| id != entity_id);
+        }
+        self.entities_by_type.entry(type_name.to_string()).or_insert(vec![]).push(*entity_id);
+        self.dirty_entities.insert(*entity_id);
+        self.versions.touch_entity(*entity_id);
+        if let Some(ref mut log) = self.write_ahead_log {
+            try!(log.log_set_entity_type(entity_id, type_name));
+        }
+        Ok(())
+    }
+    // The entity's position in the whole document, depth first from the root at 0 (draw order); None for
+    // entities that aren't in the document
+    pub fn entity_ordinal(&self, entity_id: &EntityId) -> Option<usize> {
+        if let Some(ordinal) = self.ordinals.borrow().by_id.get(entity_id) {
+            return Some(*ordinal);
+        }
+        self.extend_ordinals(|ordinals| ordinals.by_id.contains_key(entity_id));
+        self.ordinals.borrow().by_id.get(entity_id).cloned()
+    }
+    pub fn entity_at_ordinal(&self, ordinal: usize) -> Option<EntityId> {
+        self.extend_ordinals(|ordinals| ordinals.order.len() > ordinal);
+        self.ordinals.borrow().order.get(ordinal).cloned()
+    }
+    // Walks on from the last known ordinal until done says so or the document ends
+    fn extend_ordinals<F: Fn(&Ordinals) -> bool>(&self, done: F) {
+        let mut ordinals = self.ordinals.borrow_mut();
+        while !ordinals.complete && !done(&*ordinals) {
+            let next = match ordinals.order.last() {
+                Some(last) => self.next_depth_first(last),
+                None => self.root
+            };
+            match next {
+                Some(id) => {
+                    let ordinal = ordinals.order.len();
+                    ordinals.order.push(id);
+                    ordinals.by_id.insert(id, ordinal);
+                },
+                None => ordinals.complete = true
+            }
+        }
+    }
+    // The first child, or else the next sibling of the entity or its closest ancestor that has one
+    fn next_depth_first(&self, entity_id: &EntityId) -> Option<EntityId> {
+        if let Some(child) = self.entities[entity_id].children_ids.first() {
+            return Some(*child);
+        }
+        let mut current = *entity_id;
+        while let Some(parent_id) = self.entities[&current].parent_id {
+            let siblings = &self.entities[&parent_id].children_ids;
+            let index = siblings.iter().position(|id| *id == current).unwrap();
+            if index + 1 < siblings.len() {
+                return Some(siblings[index + 1]);
+            }
+            current = parent_id;
+        }
+        None
+    }
+    fn forget_ordinals_from(&self, ordinal: usize) {
+        let mut ordinals = self.ordinals.borrow_mut();
+        if ordinal >= ordinals.order.len() && !ordinals.complete {
+            return;
+        }
+        let forgotten = ordinals.order[ordinal..].to_vec();
+        ordinals.order.truncate(ordinal);
+        for id in forgotten {
+            ordinals.by_id.remove(&id);
+        }
+        ordinals.complete = false;
+    }
+    // For changes that move entity_id and everything after it
+    fn forget_ordinals_of(&self, entity_id: &EntityId) {
+        let ordinal = self.ordinals.borrow().by_id.get(entity_id).cloned();
+        if let Some(ordinal) = ordinal {
+            self.forget_ordinals_from(ordinal);
+        }
+    }
+    // For changes among the descendants of entity_id
+    fn forget_ordinals_after(&self, entity_id: &EntityId) {
+        let ordinal = self.ordinals.borrow().by_id.get(entity_id).cloned();
+        if let Some(ordinal) = ordinal {
+            self.forget_ordinals_from(ordinal + 1);
+        }
+    }
+    // entity_id followed by all its descendants, depth first
+    fn subtree_ids(&self, entity_id: &EntityId) -> Result<Vec<EntityId>, DocError> {
+        let mut ids = vec![];
+        let mut stack = vec![*entity_id];
+        while let Some(id) = stack.pop() {
+            match self.entities.get(&id) {
+                Some(entity) => {
+                    ids.push(id);
+                    for c in entity.children_ids.iter().rev() {
+                        stack.push(*c);
+                    }
+                },
+                None => return Err(DocError::NoSuchEntity(id))
+            }
+        }
+        Ok(ids)
+    }
+    // Detaches the subtree at entity_id. Until it's restored its entities don't exist as far as lookups,
+    // serialization and cascades are concerned; references into it keep their last value. The trash itself
+    // isn't saved.
+    pub fn trash_entity(&mut self, entity_id: &EntityId) -> Result<(), DocError> {
+        try!(self.remove_released_scopes());
+        let parent_id = match self.entities.get(entity_id) {
+            Some(entity) => match entity.parent_id {
+                Some(parent_id) => parent_id,
+                None => return Err(DocError::InvalidParent)
+            },
+            None => return Err(DocError::NoSuchEntity(*entity_id))
+        };
+        let ids = try!(self.subtree_ids(entity_id));
+        self.forget_ordinals_of(entity_id);
+        let index = {
+            let parent = self.entities.get_mut(&parent_id).unwrap();
+            let index = parent.children_ids.iter().position(|id| id == entity_id).unwrap();
+            parent.children_ids.remove(index);
+            index
+        };
+        let mut entities = vec![];
+        for id in ids {
+            let entity = self.entities.remove(&id).unwrap();
+            if let Some(ref name) = entity.name {
+                if self.entity_ids_by_name.get(name) == Some(&id) {
+                    self.entity_ids_by_name.remove(name);
+                }
+            }
+            self.dirty_entities.remove(&id);
+            if let Some(ids) = self.entities_by_type.get_mut(&entity.type_name) {
+                ids.retain(|x| *x != id);
+            }
+            entities.push(entity);
+        }
+        self.trash.insert(*entity_id, TrashedSubtree { parent_id: parent_id, index: index, entities: entities });
+        self.dirty_entities.insert(parent_id);
+        self.versions.touch_entity(parent_id);
+        if let Some(ref mut log) = self.write_ahead_log {
+            try!(log.log_trash_entity(entity_id));
+        }
+        Ok(())
+    }
+    // Puts a trashed subtree back where it was
+    pub fn restore_entity(&mut self, entity_id: &EntityId) -> Result<(), DocError> {
+        try!(self.remove_released_scopes());
+        let parent_id = match self.trash.get(entity_id) {
+            Some(trashed) => trashed.parent_id,
+            None => return Err(DocError::NoSuchEntity(*entity_id))
+        };
+        if !self.entities.contains_key(&parent_id) {
+            return Err(DocError::InvalidParent);
+        }
+        let TrashedSubtree { index, entities, .. } = self.trash.remove(entity_id).unwrap();
+        {
+            let parent = self.entities.get_mut(&parent_id).unwrap();
+            let index = if index > parent.children_ids.len() { parent.children_ids.len() } else { index };
+            parent.children_ids.insert(index, *entity_id);
+        }
+        self.forget_ordinals_after(&parent_id);
+        for entity in entities {
+            if let Some(ref name) = entity.name {
+                self.entity_ids_by_name.insert(name.clone(), entity.id);
+            }
+            self.dirty_entities.insert(entity.id);
+            self.versions.touch_entity(entity.id);
+            self.entities_by_type.entry(entity.type_name.clone()).or_insert(vec![]).push(entity.id);
+            self.entities.insert(entity.id, entity);
+        }
+        self.dirty_entities.insert(parent_id);
+        self.versions.touch_entity(parent_id);
+        if let Some(ref mut log) = self.write_ahead_log {
+            try!(log.log_restore_entity(entity_id));
+        }
+        Ok(())
+    }
+    pub fn get_trashed(&self) -> Vec<EntityId> {
+        self.trash.keys().map(|x| *x).collect()
+    }
+    pub fn is_trashed(&self, entity_id: &EntityId) -> bool {
+        self.trash.values().any(|trashed| trashed.entities.iter().any(|e| e.id == *entity_id))
+    }
+    // Drops everything in the trash for good
+    pub fn empty_trash(&mut self) {
+        self.trash.clear();
+    }
+    pub fn create_scope(&self) -> ScopeToken {
+        ScopeToken { entity_ids: vec![], released: self.released_scopes.clone() }
+    }
+    // Like append_entity, but the entity (and whatever ends up below it) goes away with the scope token
+    pub fn append_scoped_entity(&mut self, scope: &mut ScopeToken, parent_id: Option<EntityId>, type_name: &str, name: Option<String>) -> Result<EntityId, DocError> {
+        let id = try!(self.append_entity(parent_id, type_name, name));
+        scope.entity_ids.push(id);
+        Ok(id)
+    }
+    // Removes the entities of the scope tokens dropped since the last call, returning the properties outside
+    // them that referenced them. The next mutation does this anyway; call it to get those properties, or to
+    // have the entities gone before then.
+    pub fn collect_scopes(&mut self) -> Result<Vec<PropRef>, DocError> {
+        let released = ::std::mem::replace(&mut *self.released_scopes.borrow_mut(), vec![]);
+        let mut invalidated = vec![];
+        for entity_id in released {
+            // Already gone, e.g. below another scoped entity
+            if !self.entities.contains_key(&entity_id) {
+                continue;
+            }
+            for prop_ref in try!(self.remove_entity(&entity_id)) {
+                if !invalidated.contains(&prop_ref) {
+                    invalidated.push(prop_ref);
+                }
+            }
+        }
+        invalidated.retain(|prop_ref: &PropRef| self.entities.contains_key(&prop_ref.entity_id));
+        Ok(invalidated)
+    }
+    // Called first thing by the mutating methods, so entities of dropped scopes are gone before anything else
+    // changes. Dependants of what was removed are told as usual.
+    fn remove_released_scopes(&mut self) -> Result<(), DocError> {
+        if self.released_scopes.borrow().len() == 0 {
+            return Ok(());
+        }
+        self.collect_scopes().map(|_| ())
+    }
+    // Deletes the subtree at entity_id for good. Properties elsewhere that @-reference into it are invalidated
+    // (resolving them fails with ReferenceToNonExistentProperty until they're set again); returns their cascade.
+    pub fn remove_entity(&mut self, entity_id: &EntityId) -> Result<Vec<PropRef>, DocError> {
+        try!(self.remove_released_scopes());
+        let ids = try!(self.subtree_ids(entity_id));
+        self.forget_ordinals_of(entity_id);
+        if let Some(ref mut log) = self.write_ahead_log {
+            try!(log.log_remove_entity(entity_id));
+        }
+        let parent_id = self.entities[entity_id].parent_id;
+        match parent_id {
+            Some(parent_id) => {
+                let parent = self.entities.get_mut(&parent_id).unwrap();
+                parent.children_ids.retain(|id| id != entity_id);
+                self.dirty_entities.insert(parent_id);
+                self.versions.touch_entity(parent_id);
+            },
+            None => self.root = None
+        }
+        let removed: HashSet<EntityId> = ids.iter().cloned().collect();
+        let removed_in_order = ids.clone();
+        let mut removed_names = vec![];
+        let mut invalidated = vec![];
+        for id in ids {
+            let entity = self.entities.remove(&id).unwrap();
+            if let Some(ref name) = entity.name {
+                if self.entity_ids_by_name.get(name) == Some(&id) {
+                    self.entity_ids_by_name.remove(name);
+                    removed_names.push(name.clone());
+                }
+            }
+            for (key, prop) in entity.properties {
+                // Otherwise what it depended on outside the subtree would keep cascading into it
+                if let Some(old) = prop.expression.borrow_mut().take() {
+                    let prop_ref = PropRef::new(&id, &key);
+                    let mut dependencies = vec![];
+                    collect_resolved_dependencies(&old, &mut dependencies);
+                    for dep in dependencies {
+                        if removed.contains(&dep.entity_id) {
+                            continue;
+                        }
+                        if let Some(dep_prop) = self.entities.get_mut(&dep.entity_id).and_then(|entity| entity.properties.get_mut(&dep.property_key)) {
+                            dep_prop.dependants.retain(|p| *p != prop_ref);
+                        }
+                    }
+                }
+                for dependant in prop.dependants {
+                    if !removed.contains(&dependant.entity_id) && self.entities.contains_key(&dependant.entity_id) &&
+                        !invalidated.contains(&dependant) {
+                        invalidated.push(dependant);
+                    }
+                }
+            }
+            self.dirty_entities.remove(&id);
+            self.frozen.remove(&id);
+        }
+        self.trash.retain(|_, trashed| !removed.contains(&trashed.parent_id));
+        self.property_history.retain(|prop_ref, _| !removed.contains(&prop_ref.entity_id));
+        self.breakpoints.retain(|prop_ref, _| !removed.contains(&prop_ref.entity_id));
+        self.unresolved.retain(|&(ref id, _, _)| !removed.contains(id));
+        self.back_buffer.retain(|&(ref id, _, _)| !removed.contains(id));
+        self.xml_trivia.retain(|id, _| !removed.contains(id));
+        self.versions.entities.retain(|id, _| !removed.contains(id));
+        self.versions.properties.retain(|prop_ref, _| !removed.contains(&prop_ref.entity_id));
+        self.versions.provenance.retain(|id, _| !removed.contains(id));
+        self.entity_sources.retain(|id, _| !removed.contains(id));
+        self.cascade_barriers.retain(|prop_ref, _| !removed.contains(&prop_ref.entity_id));
+        self.property_observers.retain(|prop_ref, _| !removed.contains(&prop_ref.entity_id));
+        {
+            let mut pending = self.pending_notifications.borrow_mut();
+            pending.prop_refs.retain(|prop_ref| !removed.contains(&prop_ref.entity_id));
+            pending.seen = pending.prop_refs.iter().cloned().collect();
+        }
+        for (_, ids) in self.entities_by_type.iter_mut() {
+            ids.retain(|id| !removed.contains(id));
+        }
+        for (_, members) in self.entity_sets.iter_mut() {
+            members.retain(|id| !removed.contains(id));
+        }
+        for (_, tagged) in self.entity_tags.iter_mut() {
+            tagged.retain(|id| !removed.contains(id));
+        }
+        let broken_links: Vec<(EntityId, Vec<(String, String)>)> = self.links.iter()
+            .filter(|&(from, links)| !removed.contains(from) && links.iter().any(|&(_, ref name)| removed_names.contains(name)))
+            .map(|(from, links)| (*from, links.iter().filter(|&&(_, ref name)| !removed_names.contains(name)).cloned().collect()))
+            .collect();
+        for id in &removed {
+            self.links.remove(id);
+        }
+        for (from, links) in broken_links {
+            try!(self.set_entity_links(&from, links));
+        }
+        for (_, index) in self.value_indexes.iter_mut() {
+            for id in &removed {
+                if let Some(old) = index.values.remove(id) {
+                    index.entities_by_value.get_mut(&old).unwrap().retain(|x| x != id);
+                }
+            }
+        }
+        for id in removed_in_order {
+            self.emit(DocEvent::EntityRemoved(id));
+        }
+        for prop_ref in &invalidated {
+            self.notify_property_set(&prop_ref.entity_id, &prop_ref.property_key);
+        }
+        Ok(self.build_cascade(invalidated))
+    }
+    // Moves the subtree at entity_id to be child number index of new_parent_id (the last child if index is past
+    // the end). @-references in the subtree are resolved again from the new position; returns the cascade of
+    // the properties that now depend on something else.
+    pub fn reparent_entity(&mut self, entity_id: &EntityId, new_parent_id: &EntityId, index: usize) -> Result<Vec<PropRef>, DocError> {
+        try!(self.remove_released_scopes());
+        let ids = try!(self.subtree_ids(entity_id));
+        let old_parent_id = match self.entities[entity_id].parent_id {
+            Some(parent_id) => parent_id,
+            None => return Err(DocError::InvalidParent)
+        };
+        if !self.entities.contains_key(new_parent_id) || new_parent_id == entity_id || self.is_ancestor_of(entity_id, new_parent_id) {
+            return Err(DocError::InvalidParent);
+        }
+        let type_name = self.entities[entity_id].type_name.clone();
+        try!(self.check_child_allowed(new_parent_id, &type_name));
+        if let Some(ref mut log) = self.write_ahead_log {
+            try!(log.log_reparent_entity(entity_id, new_parent_id, index));
+        }
+        self.forget_ordinals_of(entity_id);
+        self.forget_ordinals_after(new_parent_id);
+        self.entities.get_mut(&old_parent_id).unwrap().children_ids.retain(|id| id != entity_id);
+        {
+            let new_parent = self.entities.get_mut(new_parent_id).unwrap();
+            let index = if index > new_parent.children_ids.len() { new_parent.children_ids.len() } else { index };
+            new_parent.children_ids.insert(index, *entity_id);
+        }
+        self.entities.get_mut(entity_id).unwrap().parent_id = Some(*new_parent_id);
+        self.dirty_entities.insert(old_parent_id);
+        self.versions.touch_entity(old_parent_id);
+        self.dirty_entities.insert(*new_parent_id);
+        self.versions.touch_entity(*new_parent_id);
+        self.dirty_entities.insert(*entity_id);
+        self.versions.touch_entity(*entity_id);
+        self.emit(DocEvent::EntityMoved { entity_id: *entity_id, old_parent_id: old_parent_id, new_parent_id: *new_parent_id });
+
+        let mut expressions = vec![];
+        for id in ids {
+            if self.frozen.contains_key(&id) {
+                continue;
+            }
+            for (key, prop) in &self.entities[&id].properties {
+                if let &Some(ref expression) = &*prop.expression.borrow() {
+                    let mut named_refs = vec![];
+                    expression.get_dependency_references(&mut named_refs);
+                    if named_refs.len() > 0 {
+                        expressions.push((id, key.to_string(), expression.clone()));
+                    }
+                }
+            }
+        }
+        let mut changed = vec![];
+        for (id, key, mut expression) in expressions {
+            let mut before = vec![];
+            collect_resolved_dependencies(&expression, &mut before);
+            let dependencies = try!(self.build_property_node_dependencies(&self.entities[&id], &expression));
+            if dependencies == before {
+                continue;
+            }
+            // Otherwise changes to what it used to depend on would still cascade into it
+            for dep in &before {
+                if dependencies.contains(dep) {
+                    continue;
+                }
+                if let Some(prop) = self.entities.get_mut(&dep.entity_id).and_then(|entity| entity.properties.get_mut(&dep.property_key)) {
+                    prop.dependants.retain(|p| p.entity_id != id || p.property_key != key);
+                }
+            }
+            for dep in dependencies {
+                let prop = self.entities.get_mut(&dep.entity_id).unwrap().get_or_create_property(&dep.property_key);
+                if !prop.dependants.iter().any(|p| p.entity_id == id && p.property_key == key) {
+                    prop.dependants.push(PropRef::new(&id, &key));
+                }
+            }
+            try!(self.resolve_pon_dependencies(&id, &mut expression));
+            *self.entities[&id].properties[&key].expression.borrow_mut() = Some(expression);
+            self.notify_property_set(&id, &key);
+            changed.push(PropRef::new(&id, &key));
+        }
+        Ok(self.build_cascade(changed))
+    }
+    // Copies the subtree at entity_id to the end of new_parent_id's children and returns the copy. Properties are
+    // copied as expressions; named entities in the copy get a name with a numeric suffix, and references to
+    // entities in the subtree are renamed along, so copies reference each other rather than the originals.
+    pub fn clone_entity(&mut self, entity_id: &EntityId, new_parent_id: &EntityId) -> Result<EntityId, DocError> {
+        let ids = try!(self.subtree_ids(entity_id));
+        if !self.entities.contains_key(new_parent_id) {
+            return Err(DocError::InvalidParent);
+        }
+        let mut renames = HashMap::new();
+        let mut clone_ids: HashMap<EntityId, EntityId> = HashMap::new();
+        let mut properties = vec![];
+        for id in &ids {
+            let (type_name, name, parent_id) = {
+                let entity = &self.entities[id];
+                (entity.type_name.clone(), entity.name.clone(), entity.parent_id)
+            };
+            let name = name.map(|name| {
+                let mut n = 1;
+                while self.entity_ids_by_name.contains_key(&format!("{}_{}", name, n)) {
+                    n += 1;
+                }
+                renames.insert(name.clone(), format!("{}_{}", name, n));
+                format!("{}_{}", name, n)
+            });
+            let parent_id = if id == entity_id { *new_parent_id } else { clone_ids[&parent_id.unwrap()] };
+            let clone_id = try!(self.append_entity(Some(parent_id), &type_name, name));
+            clone_ids.insert(*id, clone_id);
+            for (key, expression) in self.saved_expressions(id) {
+                properties.push((clone_id, key, expression));
+            }
+        }
+        for (clone_id, key, mut expression) in properties {
+            rename_entities_in(&key, &mut expression, &renames);
+            try!(self.set_property(&clone_id, &key, expression));
+        }
+        Ok(clone_ids[entity_id])
+    }
+    // Grafts the entities of other under mount_point (its root becomes the last child), without going through
+    // xml. Returns the ids the entities of other got in this document.
+    pub fn merge(&mut self, other: Document, mount_point: &EntityId, on_collision: NameCollision) -> Result<HashMap<EntityId, EntityId>, DocError> {
+        if !self.entities.contains_key(mount_point) {
+            return Err(DocError::InvalidParent);
+        }
+        let other_root = match other.root {
+            Some(root) => root,
+            None => return Ok(HashMap::new())
+        };
+        let ids = try!(other.subtree_ids(&other_root));
+        let mut names = HashMap::new();
+        let mut renames = HashMap::new();
+        for id in &ids {
+            let name = match other.entities[id].name {
+                Some(ref name) => name.clone(),
+                None => continue
+            };
+            if !self.entity_ids_by_name.contains_key(&name) {
+                names.insert(*id, name);
+                continue;
+            }
+            match on_collision {
+                NameCollision::Rename => {
+                    let mut n = 1;
+                    while self.entity_ids_by_name.contains_key(&format!("{}_{}", name, n)) ||
+                        other.entity_ids_by_name.contains_key(&format!("{}_{}", name, n)) {
+                        n += 1;
+                    }
+                    renames.insert(name.clone(), format!("{}_{}", name, n));
+                    names.insert(*id, format!("{}_{}", name, n));
+                },
+                NameCollision::KeepExisting => {},
+                NameCollision::Fail => return Err(DocError::NameTaken(name))
+            }
+        }
+        let mut merged_ids: HashMap<EntityId, EntityId> = HashMap::new();
+        let mut properties = vec![];
+        for id in &ids {
+            let (type_name, parent_id) = {
+                let entity = &other.entities[id];
+                (entity.type_name.clone(), entity.parent_id)
+            };
+            let parent_id = if *id == other_root { *mount_point } else { merged_ids[&parent_id.unwrap()] };
+            let merged_id = try!(self.append_entity(Some(parent_id), &type_name, names.remove(id)));
+            merged_ids.insert(*id, merged_id);
+            for (key, expression) in other.saved_expressions(id) {
+                properties.push((merged_id, key, expression));
+            }
+        }
+        for (merged_id, key, mut expression) in properties {
+            rename_entities_in(&key, &mut expression, &renames);
+            try!(self.set_property(&merged_id, &key, expression));
+        }
+        Ok(merged_ids)
+    }
+    // Three-way merge of the properties theirs changed relative to base, settling conflicts with the
+    // strategies in each entity's MERGE_PROPERTY. Returns the conflicts that couldn't be settled.
+    pub fn merge3(&mut self, base: &Document, theirs: &Document) -> Result<Vec<MergeConflict>, DocError> {
+        merge3(self, base, theirs)
+    }
+    // The changes that turn this document into other
+    pub fn diff(&self, other: &Document) -> DocumentPatch {
+        diff_documents(self, other)
+    }
+    // Applies a patch computed against this document (see diff): removals first, then additions and
+    // retypings, then properties, so expressions can refer to entities the patch adds. Returns the properties
+    // that need to be re-evaluated, including those left dangling by the removals.
+    pub fn apply_patch(&mut self, patch: &DocumentPatch) -> Result<Vec<PropRef>, DocError> {
+        let mut cascade = vec![];
+        for entity_id in &patch.removed {
+            cascade.extend(try!(self.remove_entity(entity_id)));
+        }
+        let mut properties = vec![];
+        for added in &patch.added {
+            try!(self.append_patch_entity(added.parent_id, Some(added.index), &added.entity, &mut properties));
+        }
+        for &(ref entity_id, ref type_name) in &patch.retyped {
+            try!(self.set_entity_type_name(entity_id, type_name));
+        }
+        for &(ref prop_ref, ref expression) in &patch.properties {
+            match expression {
+                &Some(ref expression) => properties.push((prop_ref.entity_id, prop_ref.property_key.clone(), expression.clone())),
+                &None => cascade.extend(try!(self.remove_property(&prop_ref.entity_id, &prop_ref.property_key)))
+            }
+        }
+        let mut changed = vec![];
+        for (entity_id, key, expression) in properties {
+            try!(self.set_property(&entity_id, &key, expression));
+            changed.push(PropRef::new(&entity_id, &key));
+        }
+        cascade.extend(self.build_cascade(changed));
+        let mut seen = HashSet::new();
+        let entities = &self.entities;
+        cascade.retain(|prop_ref| {
+            let exists = entities.get(&prop_ref.entity_id).map(|entity| entity.properties.contains_key(&prop_ref.property_key)).unwrap_or(false);
+            exists && seen.insert(prop_ref.clone())
+        });
+        Ok(cascade)
+    }
+    // See prefab.rs
+    pub fn propagate_template(&mut self, old: &Document, new: &Document, instances: &[EntityId]) -> Result<Vec<TemplateConflict>, DocError> {
+        propagate_template(self, old, new, instances)
+    }
+    // The changes from base as an override file, see overrides.rs
+    pub fn export_overrides(&self, base: &Document) -> String {
+        export_overrides(base, self)
+    }
+    // Applies all lines of an override file, or none if one of them can't be read
+    pub fn apply_overrides(&mut self, source: &str) -> Result<Vec<PropRef>, DocError> {
+        let patch = try!(parse_overrides(self, source));
+        self.apply_patch(&patch)
+    }
+    // For golden files, see golden.rs
+    pub fn canonical_xml(&self) -> String {
+        canonical_xml(self)
+    }
+    // The differences from the golden file at path; writes it instead if it doesn't exist yet
+    #[cfg(feature = "fs")]
+    pub fn compare_snapshot(&self, path: &Path) -> Result<Vec<String>, DocError> {
+        compare_snapshot(self, path)
+    }
+    // See binary.rs; read it back with MappedDocument
+    pub fn write_binary(&self, out: &mut Write) -> Result<(), DocError> {
+        write_binary(self, out)
+    }
+    // Creates the entities of a patch subtree, collecting their properties to set once all entities exist
+    fn append_patch_entity(&mut self, parent_id: Option<EntityId>, index: Option<usize>, entity: &PatchEntity, properties: &mut Vec<(EntityId, String, Pon)>) -> Result<(), DocError> {
+        let entity_id = match (parent_id, index) {
+            (Some(parent_id), Some(index)) => try!(self.insert_entity_at(&parent_id, index, &entity.type_name, entity.name.clone())),
+            _ => try!(self.append_entity(parent_id, &entity.type_name, entity.name.clone()))
+        };
+        for &(ref key, ref expression) in &entity.properties {
+            properties.push((entity_id, key.clone(), expression.clone()));
+        }
+        for child in &entity.children {
+            try!(self.append_patch_entity(Some(entity_id), None, child, properties));
+        }
+        Ok(())
+    }
+    // The expressions of the entity the way they'd be saved, plain keys before their qualified variants
+    fn saved_expressions(&self, entity_id: &EntityId) -> Vec<(String, Pon)> {
+        let entity = &self.entities[entity_id];
+        let mut keys: Vec<&String> = entity.properties.keys().collect();
+        keys.sort();
+        keys.into_iter().filter_map(|key| {
+            let original = self.frozen.get(entity_id).and_then(|originals| originals.get(key)).cloned();
+            let expression = match (original, entity.qualified_defaults.get(key)) {
+                (Some(original), _) => Some(original),
+                (None, Some(default)) => default.clone(),
+                (None, None) => (*entity.properties[key].expression.borrow()).clone()
+            };
+            expression.map(|expression| (key.to_string(), expression))
+        }).collect()
+    }
+    // Entities with bounds intersecting volume, depth first. Subtrees are skipped when their root's bounds don't
+    // intersect; entities without (valid) bounds are never returned, but their children are considered.
+    pub fn query_visible(&self, volume: &CullVolume) -> Vec<EntityId> {
+        let mut visible = vec![];
+        let mut stack: Vec<EntityId> = self.root.iter().cloned().collect();
+        while let Some(id) = stack.pop() {
+            let bounds: Option<Aabb> = match self.get_entity_property(&self.entities[&id], BOUNDS_PROPERTY) {
+                Ok(bounds) => bounds.translate(&mut TranslateContext { document: Some(self) }).ok(),
+                Err(_) => None
+            };
+            match bounds {
+                Some(ref bounds) if !volume.intersects(bounds) => continue,
+                Some(_) => visible.push(id),
+                None => {}
+            }
+            for c in self.entities[&id].children_ids.iter().rev() {
+                stack.push(*c);
+            }
+        }
+        visible
+    }
+    // Replaces every property in the subtree with its current value, so changes elsewhere don't reach it, and
+    // refuses set_property on it until it's unfrozen. Properties that can't be resolved are left alone.
+    pub fn freeze_subtree(&mut self, entity_id: &EntityId) -> Result<(), DocError> {
+        for id in try!(self.subtree_ids(entity_id)) {
+            if self.frozen.contains_key(&id) {
+                continue;
+            }
+            let mut originals = HashMap::new();
+            for (key, prop) in &self.entities[&id].properties {
+                let value = match &*prop.expression.borrow() {
+                    &Some(ref expression) => match expression.concretize() {
+                        Ok(value) => value,
+                        Err(_) => continue
+                    },
+                    &None => continue
+                };
+                let original = prop.expression.borrow_mut().take().unwrap();
+                *prop.expression.borrow_mut() = Some(value);
+                originals.insert(key.to_string(), original);
+            }
+            self.frozen.insert(id, originals);
+        }
+        Ok(())
+    }
+    // Puts the expressions back; properties are reported as set since their values may have moved on
+    pub fn unfreeze_subtree(&mut self, entity_id: &EntityId) -> Result<(), DocError> {
+        for id in try!(self.subtree_ids(entity_id)) {
+            let originals = match self.frozen.remove(&id) {
+                Some(originals) => originals,
+                None => continue
+            };
+            for (key, original) in originals {
+                *self.entities[&id].properties[&key].expression.borrow_mut() = Some(original);
+                self.notify_property_set(&id, &key);
+            }
+        }
+        Ok(())
+    }
+    pub fn is_frozen(&self, entity_id: &EntityId) -> bool {
+        self.frozen.contains_key(entity_id)
+    }
+    // Walks the subtree at root, letting func modify properties as it goes. Structural edits made through
+    // the visitor are queued and applied once the walk is done, so the tree never changes under the walker.
+    pub fn visit_mut<F: FnMut(&mut EntityVisitor)>(&mut self, root: &EntityId, mut func: F) -> Result<(), DocError> {
+        let ids = try!(self.subtree_ids(root));
+        let mut edits = vec![];
+        for entity_id in ids {
+            let mut visitor = EntityVisitor {
+                entity_id: entity_id,
+                document: self,
+                edits: &mut edits
+            };
+            func(&mut visitor);
+        }
+        // In the order they were queued; edits of entities an earlier removal took away are dropped
+        for edit in edits {
+            match edit {
+                StructuralEdit::AppendEntity { parent_id, type_name, name } => {
+                    if self.entities.contains_key(&parent_id) {
+                        try!(self.append_entity(Some(parent_id), &type_name, name));
+                    }
+                },
+                StructuralEdit::RemoveEntity { entity_id } => {
+                    if self.entities.contains_key(&entity_id) {
+                        try!(self.remove_entity(&entity_id));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Retargets every reference (both @-dependencies and plain references) to the entity named old_target
+    // so it points at new_target instead. Returns the properties that were (or with dry_run, would be) changed.
+    pub fn replace_references(&mut self, old_target: &str, new_target: &str, dry_run: bool) -> Result<Vec<PropRef>, DocError> {
+        self.rewrite_expressions(dry_run, |node| {
+            match node {
+                &mut Pon::DependencyReference(ref mut named_prop_ref, _) =>
+                    named_prop_ref.entity_path.rename_entity(old_target, new_target),
+                &mut Pon::Reference(ref mut named_prop_ref) =>
+                    named_prop_ref.entity_path.rename_entity(old_target, new_target),
+                _ => false
+            }
+        })
+    }
+    // Replaces every occurrence of find, anywhere inside an expression, with replace
+    pub fn replace_values(&mut self, find: &Pon, replace: &Pon, dry_run: bool) -> Result<Vec<PropRef>, DocError> {
+        self.rewrite_expressions(dry_run, |node| {
+            if *node == *find {
+                *node = replace.clone();
+                return true;
+            }
+            false
+        })
+    }
+    fn rewrite_expressions<F: FnMut(&mut Pon) -> bool>(&mut self, dry_run: bool, mut func: F) -> Result<Vec<PropRef>, DocError> {
+        let mut rewritten = vec![];
+        for (entity_id, entity) in &self.entities {
+            for (key, prop) in &entity.properties {
+                if let &Some(ref expression) = &*prop.expression.borrow() {
+                    let mut expression = expression.clone();
+                    let mut changed = false;
+                    expression.visit_mut(&mut |node| {
+                        if func(node) {
+                            changed = true;
+                        }
+                    });
+                    if changed {
+                        rewritten.push((PropRef::new(entity_id, key), expression));
+                    }
+                }
+            }
+        }
+        let prop_refs = rewritten.iter().map(|&(ref prop_ref, _)| prop_ref.clone()).collect();
+        if !dry_run {
+            for (prop_ref, expression) in rewritten {
+                try!(self.set_property(&prop_ref.entity_id, &prop_ref.property_key, expression));
+            }
+        }
+        Ok(prop_refs)
+    }
+
+    // Number of times the property has been read through get_property
+    pub fn get_property_reads(&self, entity_id: &EntityId, property_key: &str) -> Result<u64, DocError> {
         match self.entities.get(entity_id) {
-            Some(entity) => match entity.properties.get(name) {
-                Some(prop) => Ok(prop.expression.borrow().is_some()),
-                None => Ok(false)
+            Some(entity) => match entity.properties.get(property_key) {
+                Some(prop) => Ok(prop.reads.get()),
+                None => Err(DocError::NoSuchProperty(property_key.to_string()))
             },
             None => Err(DocError::NoSuchEntity(*entity_id))
         }
     }
-    pub fn get_properties(&self, entity_id: &EntityId) -> Result<Vec<PropRef>, DocError> {
-        match self.entities.get(&entity_id) {
-            Some(entity) => Ok(entity.properties.keys().map(|key| PropRef { entity_id: entity_id.clone(), property_key: key.clone() }).collect()),
-            None => Err(DocError::NoSuchEntity(*entity_id))
+    // Finds data and dependency edges that could be trimmed from a document
+    pub fn prune_report(&self) -> PruneReport {
+        let mut report = PruneReport { unused_properties: vec![], nil_references: vec![] };
+        for (entity_id, entity) in &self.entities {
+            for (key, prop) in &entity.properties {
+                if let &Some(ref expression) = &*prop.expression.borrow() {
+                    if prop.dependants.len() == 0 && prop.reads.get() == 0 {
+                        report.unused_properties.push(PropRef::new(entity_id, key));
+                    }
+                    if has_nil_reference(expression) {
+                        report.nil_references.push(PropRef::new(entity_id, key));
+                    }
+                }
+            }
         }
+        report
     }
-    pub fn get_children(&self, entity_id: &EntityId) -> Result<&Vec<EntityId>, DocError> {
-        match self.entities.get(&entity_id) {
-            Some(entity) => Ok(&entity.children_ids),
-            None => Err(DocError::NoSuchEntity(*entity_id))
+
+    // References to unset properties and dependency cycles, sorted so the result is stable
+    pub fn integrity_errors(&self) -> Vec<ValidationError> {
+        let mut dependencies: HashMap<PropRef, Vec<PropRef>> = HashMap::new();
+        for (entity_id, entity) in &self.entities {
+            for (key, prop) in &entity.properties {
+                if let &Some(ref expression) = &*prop.expression.borrow() {
+                    let mut deps = vec![];
+                    collect_resolved_dependencies(expression, &mut deps);
+                    dependencies.insert(PropRef::new(entity_id, key), deps);
+                }
+            }
+        }
+        let mut prop_refs: Vec<&PropRef> = dependencies.keys().collect();
+        prop_refs.sort_by(|a, b| (a.entity_id, &a.property_key).cmp(&(b.entity_id, &b.property_key)));
+        let mut errors = vec![];
+        for prop_ref in &prop_refs {
+            for dep in &dependencies[*prop_ref] {
+                if !dependencies.contains_key(dep) {
+                    errors.push(ValidationError::DanglingReference((*prop_ref).clone()));
+                    break;
+                }
+            }
+        }
+        // 1 while on the dfs stack, 2 when done
+        let mut state: HashMap<PropRef, u8> = HashMap::new();
+        for prop_ref in &prop_refs {
+            if !state.contains_key(*prop_ref) {
+                find_cycles(&dependencies, prop_ref, &mut state, &mut errors);
+            }
         }
+        errors
     }
-    pub fn search_children(&self, entity_id: &EntityId, name: &str) -> Result<EntityId, DocError> {
-        match self.entities.get(entity_id) {
-            Some(entity) => {
-                if let &Some(ref string) = &entity.name {
-                    if string == name {
-                        return Ok(entity.id);
+    // Like save_dirty, but writes nothing and returns ValidationFailed if the document doesn't pass schema
+    // validation and the integrity checks
+    #[cfg(feature = "fs")]
+    pub fn save_validated(&mut self, path: &Path, schema: &Schema) -> Result<bool, DocError> {
+        let mut errors = schema.validate(self);
+        errors.extend(self.integrity_errors());
+        if errors.len() > 0 {
+            return Err(DocError::ValidationFailed(errors));
+        }
+        self.save_dirty(path)
+    }
+
+    // Finds references to entities or properties that don't exist, including the ones that kept properties
+    // from loading, with suggestions for what they may have meant. In AutoFix mode the callback can pick
+    // a replacement for each, and properties whose references are all fixed are set again.
+    pub fn repair(&mut self, mode: &RepairMode) -> Result<Vec<BrokenReference>, DocError> {
+        let mut candidates: Vec<(EntityId, String, Pon, bool)> = self.unresolved.iter()
+            .map(|&(id, ref key, ref expression)| (id, key.clone(), expression.clone(), true)).collect();
+        for (entity_id, entity) in &self.entities {
+            for (key, prop) in &entity.properties {
+                if let &Some(ref expression) = &*prop.expression.borrow() {
+                    let mut deps = vec![];
+                    collect_resolved_dependencies(expression, &mut deps);
+                    if deps.iter().any(|dep| !self.has_property(&dep.entity_id, &dep.property_key).unwrap_or(false)) {
+                        candidates.push((*entity_id, key.clone(), expression.clone(), false));
                     }
                 }
-                for c in &entity.children_ids {
-                    match self.search_children(&c, name) {
-                        Ok(id) => return Ok(id),
-                        _ => {}
+            }
+        }
+        candidates.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        let mut report = vec![];
+        for (entity_id, key, mut expression, unresolved) in candidates {
+            let mut references = vec![];
+            expression.get_dependency_references(&mut references);
+            let mut all_fixed = true;
+            let mut any_fixed = false;
+            for reference in references {
+                let missing = match self.resolve_named_prop_ref(&entity_id, &reference) {
+                    Err(DocError::CantFindEntityByName(name)) => MissingTarget::Entity(name),
+                    Ok(target) => match self.has_property(&target.entity_id, &target.property_key) {
+                        Ok(true) => continue,
+                        _ => MissingTarget::Property(target.property_key.clone())
+                    },
+                    Err(err) => return Err(err)
+                };
+                let suggestions = match missing {
+                    MissingTarget::Entity(ref name) => closest_names(name, self.entity_ids_by_name.keys().cloned().collect()),
+                    MissingTarget::Property(ref property_key) => {
+                        let target = try!(self.resolve_named_prop_ref(&entity_id, &reference)).entity_id;
+                        let keys = try!(self.get_properties(&target)).into_iter()
+                            .filter(|p| self.has_property(&target, &p.property_key).unwrap_or(false))
+                            .map(|p| p.property_key).collect();
+                        closest_names(property_key, keys)
+                    }
+                };
+                let mut broken = BrokenReference {
+                    prop_ref: PropRef::new(&entity_id, &key),
+                    reference: reference,
+                    missing: missing,
+                    suggestions: suggestions,
+                    fixed: false
+                };
+                if let &RepairMode::AutoFix(ref choose) = mode {
+                    if let Some(replacement) = choose(&broken) {
+                        rewrite_reference(&mut expression, &broken, &replacement);
+                        broken.fixed = true;
+                        any_fixed = true;
                     }
                 }
-                Err(DocError::CantFindEntityByName(name.to_string()))
-            },
-            None => Err(DocError::NoSuchEntity(*entity_id))
+                all_fixed = all_fixed && broken.fixed;
+                report.push(broken);
+            }
+            if let &RepairMode::AutoFix(_) = mode {
+                // Unresolved properties can also just need setting again, e.g. if their target was added since
+                if all_fixed && (any_fixed || unresolved) {
+                    try!(self.set_property(&entity_id, &key, expression));
+                    if unresolved {
+                        self.unresolved.retain(|&(id, ref k, _)| !(id == entity_id && *k == key));
+                    }
+                }
+            }
         }
+        Ok(report)
     }
-    pub fn resolve_entity_path(&self, start_entity_id: &EntityId, path: &EntityPath) -> Result<EntityId, DocError> {
-        match path {
-            &EntityPath::This => Ok(*start_entity_id),
-            &EntityPath::Parent => match self.entities.get(start_entity_id) {
-                Some(entity) => Ok(entity.parent_id.unwrap().clone()),
-                None => Err(DocError::NoSuchEntity(*start_entity_id))
-            },
-            &EntityPath::Named(ref name) => match self.entity_ids_by_name.get(name) {
-                Some(entity_id) => Ok(entity_id.clone()),
-                None => Err(DocError::CantFindEntityByName(name.to_string()))
-            },
-            &EntityPath::Search(ref path, ref search) => {
-                match self.resolve_entity_path(start_entity_id, path) {
-                    Ok(ent) => self.search_children(&ent, search),
-                    Err(err) => Err(err)
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_entities.len() > 0
+    }
+    // The top-most entities modified since the last save; everything that changed is in one of these subtrees
+    pub fn dirty_subtrees(&self) -> Vec<EntityId> {
+        self.dirty_entities.iter().filter(|id| {
+            let mut parent = self.entities.get(id).and_then(|e| e.parent_id);
+            while let Some(parent_id) = parent {
+                if self.dirty_entities.contains(&parent_id) {
+                    return false;
                 }
+                parent = self.entities.get(&parent_id).and_then(|e| e.parent_id);
             }
+            true
+        }).map(|id| *id).collect()
+    }
+    // Hash of what the subtree saves as, so it changes exactly when something in the subtree does. Doesn't
+    // depend on entity ids, the platform or the run, so it can be persisted to find what needs re-cooking.
+    pub fn subtree_hash(&self, entity_id: &EntityId) -> Result<u64, DocError> {
+        if !self.entities.contains_key(entity_id) {
+            return Err(DocError::NoSuchEntity(*entity_id));
         }
+        let mut hashes = vec![];
+        Ok(self.hash_subtree(entity_id, &mut hashes))
     }
-    pub fn resolve_named_prop_ref(&self, start_entity_id: &EntityId, named_prop_ref: &NamedPropRef) -> Result<PropRef, DocError> {
-        let owner_entity_id = try!(self.resolve_entity_path(start_entity_id, &named_prop_ref.entity_path));
-        Ok(PropRef { entity_id: owner_entity_id, property_key: named_prop_ref.property_key.clone() })
+    // Every entity's subtree hash, in the order entities are saved (depth first from the root)
+    pub fn subtree_hashes(&self) -> Vec<(EntityId, u64)> {
+        let mut hashes = vec![];
+        if let Some(root) = self.root {
+            self.hash_subtree(&root, &mut hashes);
+        }
+        hashes
     }
-    pub fn get_entity_type_name(&self, entity_id: &EntityId) -> Result<&String, DocError> {
-        match self.entities.get(&entity_id) {
-            Some(entity) => Ok(&entity.type_name),
-            None => Err(DocError::NoSuchEntity(*entity_id))
+    fn hash_subtree(&self, entity_id: &EntityId, hashes: &mut Vec<(EntityId, u64)>) -> u64 {
+        let entity = &self.entities[entity_id];
+        let index = hashes.len();
+        hashes.push((*entity_id, 0));
+        let mut hasher = SipHasher::new();
+        // Lengths are included so different splits of the same bytes hash differently
+        let write_str = |hasher: &mut SipHasher, value: &str| {
+            hasher.write(&u64_to_le_bytes(value.len() as u64));
+            hasher.write(value.as_bytes());
+        };
+        write_str(&mut hasher, &entity.type_name);
+        for attr in self.entity_attributes(entity) {
+            write_str(&mut hasher, &attr.name.local_name);
+            write_str(&mut hasher, &attr.value);
+        }
+        for child in &entity.children_ids {
+            let child_hash = self.hash_subtree(child, hashes);
+            hasher.write(&u64_to_le_bytes(child_hash));
+        }
+        let hash = hasher.finish();
+        hashes[index].1 = hash;
+        hash
+    }
+    // Writes the document to path if anything changed since the last save, going through a temp file and a
+    // rename so a crash mid-write never leaves a truncated file behind. Returns whether a write happened.
+    // Included files are written back on their own, and only when something in them changed; path keeps
+    // the Include elements.
+    #[cfg(feature = "fs")]
+    pub fn save_dirty(&mut self, path: &Path) -> Result<bool, DocError> {
+        if !self.is_dirty() {
+            return Ok(false);
+        }
+        let mut dirty_includes = vec![];
+        let mut top_level_dirty = false;
+        for entity_id in &self.dirty_entities {
+            match self.included_subtree_of(entity_id) {
+                Some(index) => if !dirty_includes.contains(&index) {
+                    dirty_includes.push(index);
+                },
+                None => top_level_dirty = true
+            }
+        }
+        let imported: Vec<PathBuf> = dirty_includes.iter().map(|index| &self.included_subtrees[*index])
+            .filter(|included| !included.is_xml).map(|included| included.path.clone()).collect();
+        if imported.len() > 0 {
+            return Err(DocError::IncludesNotSaved(imported));
+        }
+        for index in dirty_includes {
+            let included = &self.included_subtrees[index];
+            let entities: Vec<EntityId> = included.entities.iter()
+                .filter(|id| self.entities.get(id).map(|entity| entity.parent_id == included.parent_id).unwrap_or(false))
+                .cloned().collect();
+            try!(write_atomic_with(&included.path, |file| self.write_entities_xml(file, &entities, None, true, false)));
+        }
+        if top_level_dirty {
+            let top_level = match self.root {
+                Some(root) => self.entities[&root].children_ids.clone(),
+                None => vec![]
+            };
+            try!(write_atomic_with(path, |file| self.write_entities_xml(file, &top_level, None, true, true)));
+        }
+        self.dirty_entities.clear();
+        Ok(true)
+    }
+    // The included subtree the entity was loaded into, if any; the innermost one for nested includes.
+    // Entities moved out from under the Include's parent no longer count as included.
+    fn included_subtree_of(&self, entity_id: &EntityId) -> Option<usize> {
+        let mut id = *entity_id;
+        while let Some(entity) = self.entities.get(&id) {
+            let found = self.included_subtrees.iter().position(|included| included.parent_id == entity.parent_id && included.entities.contains(&id));
+            if found.is_some() {
+                return found;
+            }
+            match entity.parent_id {
+                Some(parent_id) => id = parent_id,
+                None => return None
+            }
+        }
+        None
+    }
+
+    // Saves a snapshot to snapshot_path and from then on logs every mutation next to it, see `recover`
+    #[cfg(feature = "fs")]
+    pub fn enable_write_ahead_log(&mut self, snapshot_path: &Path) -> Result<(), DocError> {
+        try!(write_atomic_with(snapshot_path, |file| self.write_xml(file)));
+        let order = try!(self.snapshot_order());
+        self.write_ahead_log = Some(try!(WriteAheadLog::create(snapshot_path, &order)));
+        self.dirty_entities.clear();
+        Ok(())
+    }
+    // Folds the log into a fresh snapshot
+    #[cfg(feature = "fs")]
+    pub fn checkpoint(&mut self) -> Result<(), DocError> {
+        let order = try!(self.snapshot_order());
+        let snapshot_path = match self.write_ahead_log {
+            Some(ref log) => log.snapshot_path().to_path_buf(),
+            None => return Ok(())
+        };
+        try!(write_atomic_with(&snapshot_path, |file| self.write_xml(file)));
+        try!(self.write_ahead_log.as_mut().unwrap().reset(&order));
+        self.dirty_entities.clear();
+        Ok(())
+    }
+    // Loads the snapshot and replays the write-ahead log on top of it
+    #[cfg(feature = "fs")]
+    pub fn recover(snapshot_path: &Path) -> Result<Document, DocError> {
+        let mut doc = try!(Document::from_file(snapshot_path));
+        try!(WriteAheadLog::replay(&mut doc, snapshot_path));
+        Ok(doc)
+    }
+    // Rebuilds the document as it was just before write-ahead log entry seq, i.e. after seq mutations since the
+    // last checkpoint. Only states since that checkpoint can be reached.
+    #[cfg(feature = "fs")]
+    pub fn replay_to(&self, seq: usize) -> Result<Document, DocError> {
+        let snapshot_path = match self.write_ahead_log {
+            Some(ref log) => log.snapshot_path().to_path_buf(),
+            None => return Err(DocError::IoError("Write-ahead log isn't enabled".to_string()))
+        };
+        let mut doc = try!(Document::from_file(&snapshot_path));
+        try!(WriteAheadLog::replay_until(&mut doc, &snapshot_path, Some(seq)));
+        Ok(doc)
+    }
+    // Sequence number of the next mutation, if the write-ahead log is enabled
+    pub fn journal_seq(&self) -> Option<usize> {
+        self.write_ahead_log.as_ref().map(|log| log.next_seq())
+    }
+    #[cfg(feature = "fs")]
+    fn snapshot_order(&self) -> Result<Vec<EntityId>, DocError> {
+        match self.root {
+            Some(root) => self.subtree_ids(&root),
+            None => Ok(vec![])
         }
     }
 
+    #[cfg(feature = "fs")]
     pub fn from_file(path: &Path) -> Result<Document, DocError> {
         let mut doc = Document::new();
+        try!(doc.append_from_file(None, path));
+        Ok(doc)
+    }
+    // Rewrites the file at path in the given style, refusing to touch it if the result wouldn't load to the same document
+    #[cfg(feature = "fs")]
+    pub fn format_file(path: &Path, style: &FormatStyle) -> Result<(), DocError> {
+        let mut source = String::new();
+        {
+            let mut file = try!(File::open(path).map_err(|err| DocError::IoError(err.to_string())));
+            try!(file.read_to_string(&mut source).map_err(|err| DocError::IoError(err.to_string())));
+        }
+        let formatted = try!(format_xml(&source, style));
+        write_atomic(path, &formatted)
+    }
+    // Applies the migration script at path, see migrate.rs for the format. Returns a line per change made.
+    #[cfg(feature = "fs")]
+    pub fn apply_migrations(&mut self, path: &Path) -> Result<Vec<String>, DocError> {
+        let mut source = String::new();
+        {
+            let mut file = try!(File::open(path).map_err(|err| DocError::IoError(err.to_string())));
+            try!(file.read_to_string(&mut source).map_err(|err| DocError::IoError(err.to_string())));
+        }
+        let migrations = try!(::migrate::parse_migrations(&source));
+        ::migrate::apply_migrations(self, &migrations)
+    }
+    // Loads the xml document at path into this document, under parent_id
+    #[cfg(feature = "fs")]
+    pub fn append_from_file(&mut self, parent_id: Option<EntityId>, path: &Path) -> Result<(), DocError> {
         let mut warnings = vec![];
-        try!(doc.append_from_event_reader(&mut vec![], event_reader_from_file(path).events(), &mut warnings));
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        let mut parser = try!(event_reader_from_file(path));
+        self.loading_files.push(path.to_path_buf());
+        let result = self.append_from_event_reader(&mut parent_id.into_iter().collect::<Vec<EntityId>>(), base_dir, positioned(&mut parser), &mut warnings);
+        self.loading_files.pop();
+        try!(result.map_err(|err| in_file(err, path)));
         if warnings.len() > 0 {
             println!("{} WARNINGS PARSING DOCUMENT:", warnings.len());
             println!("{}", warnings.join("\n"));
         }
+        Ok(())
+    }
+    // Checks the xml against schema first, building nothing if it doesn't pass. The document keeps
+    // enforcing the schema's child types afterwards.
+    pub fn from_string_validated(string: &str, schema: &Schema) -> Result<Document, DocError> {
+        let errors = schema.validate_xml(string);
+        if errors.len() > 0 {
+            return Err(DocError::XmlValidationFailed(errors));
+        }
+        let mut doc = try!(Document::from_string(string));
+        doc.set_schema(Some(schema.clone()));
         Ok(doc)
     }
+    // See dsl.rs
+    pub fn from_dsl(source: &str) -> Result<Document, DocError> {
+        document_from_dsl(source)
+    }
     pub fn from_string(string: &str) -> Result<Document, DocError> {
+        Document::from_string_with_numeric_options(string, NumericOptions::default())
+    }
+    pub fn from_string_with_numeric_options(string: &str, numeric_options: NumericOptions) -> Result<Document, DocError> {
         let mut doc = Document::new();
-        let mut parser = EventReader::from_str(string);
+        doc.set_numeric_options(numeric_options);
+        try!(doc.append_from_string(None, string));
+        Ok(doc)
+    }
+    // Loads the xml document in string into this document, under parent_id
+    pub fn append_from_string(&mut self, parent_id: Option<EntityId>, string: &str) -> Result<(), DocError> {
+        let mut parser = EventReader::new_with_config(string.as_bytes(), parser_config());
         let mut warnings = vec![];
-        try!(doc.append_from_event_reader(&mut vec![], parser.events(), &mut warnings));
+        try!(self.append_from_event_reader(&mut parent_id.into_iter().collect::<Vec<EntityId>>(), Path::new(""), positioned(&mut parser), &mut warnings));
         if warnings.len() > 0 {
             println!("{} WARNINGS PARSING DOCUMENT:", warnings.len());
             println!("{}", warnings.join("\n"));
         }
-        Ok(doc)
+        Ok(())
+    }
+    // Where the entity's element is, for entities loaded from xml
+    pub fn get_entity_source(&self, entity_id: &EntityId) -> Option<&EntitySource> {
+        self.entity_sources.get(entity_id)
+    }
+    // Properties loaded from now on that don't parse are kept as Pon::Unknown and saved back as they were,
+    // rather than dropped with a warning, so documents from newer versions survive a round trip
+    pub fn set_keep_unknown_expressions(&mut self, keep: bool) {
+        self.keep_unknown_expressions = keep;
     }
 
 
@@ -325,6 +3725,9 @@ impl Document {
     }
 
     fn get_entity_property<'a>(&self, entity: &'a Entity, property_key: &str) -> Result<Ref<'a, Pon>, DocError> {
+        if let Some(property) = entity.properties.get(property_key) {
+            property.reads.set(property.reads.get() + 1);
+        }
         match entity.properties.get(property_key) {
             Some(property) => match Ref::filter_map(property.expression.borrow(), |x| match x {
                 &Some(ref r) => Some(r),
@@ -337,10 +3740,100 @@ impl Document {
         }
     }
 
-    fn append_from_event_reader<T: Iterator<Item=XmlEvent>>(&mut self, mut entity_stack: &mut Vec<EntityId>, mut events: T, warnings: &mut Vec<String>) -> Result<(), DocError> {
-        while let Some(e) = events.next() {
+    fn append_include(&mut self, parent_id: Option<EntityId>, path: &Path, attributes: &Vec<xml::attribute::OwnedAttribute>, warnings: &mut Vec<String>) -> Result<(), DocError> {
+        let parent_id = parent_id.or(self.root);
+        let before = match parent_id {
+            Some(parent_id) => self.entities[&parent_id].children_ids.clone(),
+            None => vec![]
+        };
+        let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("").to_string();
+        try!(self.load_include(parent_id, path, &extension, attributes, warnings));
+        let entities = match parent_id {
+            Some(parent_id) => self.entities[&parent_id].children_ids.iter().filter(|id| !before.contains(id)).cloned().collect(),
+            None => self.root.into_iter().collect()
+        };
+        self.included_subtrees.push(IncludedSubtree {
+            path: path.to_path_buf(),
+            parent_id: parent_id,
+            attributes: attributes.clone(),
+            entities: entities,
+            is_xml: extension == "xml"
+        });
+        Ok(())
+    }
+    fn load_include(&mut self, parent_id: Option<EntityId>, path: &Path, extension: &str, attributes: &Vec<xml::attribute::OwnedAttribute>, warnings: &mut Vec<String>) -> Result<(), DocError> {
+        let bytes = match self.include_resolver {
+            Some(ref resolver) => try!(resolver.read(path)),
+            None => return Err(DocError::IoError(format!("{}: no include resolver", path.display())))
+        };
+        if !self.included_files.iter().any(|file| file == path) {
+            self.included_files.push(path.to_path_buf());
+        }
+        if extension == "xml" {
+            let base_dir = path.parent().unwrap_or(Path::new(""));
+            self.loading_files.push(path.to_path_buf());
+            let result = match self.include_cache.clone() {
+                Some(cache) => {
+                    let events = cache.events(path, &bytes);
+                    self.append_from_event_reader(&mut parent_id.into_iter().collect::<Vec<EntityId>>(), base_dir, events.iter().cloned(), warnings)
+                },
+                None => {
+                    let mut parser = EventReader::new_with_config(&bytes[..], parser_config());
+                    self.append_from_event_reader(&mut parent_id.into_iter().collect::<Vec<EntityId>>(), base_dir, positioned(&mut parser), warnings)
+                }
+            };
+            self.loading_files.pop();
+            return result.map_err(|err| in_file(err, path));
+        }
+        let ops = {
+            let importer = match self.importers.get(extension) {
+                Some(importer) => importer,
+                None => return Err(DocError::ImportError(format!("No importer registered for {}", path.display())))
+            };
+            let mut options = HashMap::new();
+            for attribute in attributes {
+                if attribute.name.local_name == "file" { continue; }
+                let value = Pon::from_string(&attribute.value).unwrap_or(Pon::String(attribute.value.to_string()));
+                options.insert(attribute.name.local_name.to_string(), value);
+            }
+            try!(importer.import(&bytes, &options))
+        };
+        try!(apply_subtree_ops(self, parent_id, ops));
+        Ok(())
+    }
+
+    fn append_from_event_reader<T: Iterator<Item=PositionedEvent>>(&mut self, mut entity_stack: &mut Vec<EntityId>, base_dir: &Path, mut events: T, warnings: &mut Vec<String>) -> Result<(), DocError> {
+        // Comments and processing instructions seen since the last tag
+        let mut trivia = vec![];
+        // Applied at the end, since loading children and properties counts as changing the entities
+        let mut loaded_provenance = vec![];
+        while let Some((e, position)) = events.next() {
             match e {
+                XmlEvent::StartElement { ref name, ref attributes, .. } if name.local_name == "Include" => {
+                    let parent = entity_stack.last().map(|x| *x);
+                    match attributes.iter().find(|x| x.name.local_name == "file") {
+                        Some(file) => match self.append_include(parent, &base_dir.join(&file.value), attributes, warnings) {
+                            Ok(_) => {},
+                            Err(DocError::LoadError(err)) => return Err(DocError::LoadError(err)),
+                            Err(err) => warnings.push(format!("Failed to include {}: {:?}", file.value, err))
+                        },
+                        None => warnings.push("Include without a file attribute".to_string())
+                    }
+                    // The Include element isn't an entity; pushing the parent again lets its end tag pop as usual
+                    if let Some(parent) = parent {
+                        entity_stack.push(parent);
+                    }
+                }
                 XmlEvent::StartElement { name: type_name, attributes, .. } => {
+                    for (i, attribute) in attributes.iter().enumerate() {
+                        if attributes[..i].iter().any(|x| x.name.local_name == attribute.name.local_name) {
+                            return Err(DocError::LoadError(LoadError {
+                                file: None,
+                                position: position,
+                                message: format!("Duplicate attribute {} on {}", attribute.name.local_name, type_name.local_name)
+                            }));
+                        }
+                    }
                     let entity_name = match attributes.iter().find(|x| x.name.local_name == "name") {
                         Some(attr) => Some(attr.value.to_string()),
                         None => None
@@ -351,44 +3844,125 @@ impl Document {
                     };
                     let entity_id = match self.append_entity(parent, &type_name.local_name, entity_name) {
                         Ok(id) => id,
+                        Err(DocError::ValidationFailed(_)) => {
+                            return Err(DocError::LoadError(LoadError {
+                                file: None,
+                                position: position,
+                                message: format!("{} can't be a child of {}", type_name.local_name, self.entities[&parent.unwrap()].type_name)
+                            }));
+                        },
+                        // Skipping the element would leave its end tag to pop the parent off the stack
+                        Err(DocError::NameTaken(taken)) => {
+                            return Err(DocError::LoadError(LoadError {
+                                file: None,
+                                position: position,
+                                message: format!("The name {} is already taken", taken)
+                            }));
+                        },
                         Err(err) => {
-                            warnings.push(format!("Failed to append entity {:?}: {:?}", type_name.local_name, err));
-                            continue;
+                            return Err(DocError::LoadError(LoadError {
+                                file: None,
+                                position: position,
+                                message: format!("Failed to append entity {}: {:?}", type_name.local_name, err)
+                            }));
                         }
                     };
 
+                    let file_count = self.loading_files.len();
+                    self.entity_sources.insert(entity_id, EntitySource {
+                        file: self.loading_files.last().cloned(),
+                        position: position,
+                        include_stack: self.loading_files[..file_count.saturating_sub(1)].to_vec()
+                    });
                     for attribute in attributes {
                         if attribute.name.local_name == "name" { continue; }
+                        if attribute.name.local_name == PROVENANCE_ATTRIBUTE {
+                            match Provenance::from_attribute(&attribute.value) {
+                                Some(provenance) => loaded_provenance.push((entity_id, provenance)),
+                                None => warnings.push(format!("Bad {} on entity {:?}: {}", PROVENANCE_ATTRIBUTE, type_name.local_name, attribute.value))
+                            }
+                            continue;
+                        }
                         match Pon::from_string(&attribute.value) {
-                            Ok(node) => match self.set_property(&entity_id, &attribute.name.local_name, node) {
-                                Ok(_) => {},
-                                Err(err) => warnings.push(format!("Failed to set property {} for entity {:?}: {:?}", attribute.name.local_name, type_name.local_name, err))
+                            Ok(mut node) => {
+                                if self.numeric_options.round_on_load {
+                                    node.round_floats(self.numeric_options.float_decimals);
+                                }
+                                match self.set_property(&entity_id, &attribute.name.local_name, node) {
+                                    Ok(_) => {},
+                                    Err(DocError::CantFindEntityByName(name)) => {
+                                        warnings.push(format!("Failed to set property {} for entity {:?}: no entity named {}", attribute.name.local_name, type_name.local_name, name));
+                                        self.unresolved.push((entity_id, attribute.name.local_name.to_string(), Pon::from_string(&attribute.value).unwrap()));
+                                    },
+                                    Err(err) => warnings.push(format!("Failed to set property {} for entity {:?}: {:?}", attribute.name.local_name, type_name.local_name, err))
+                                }
                             },
-                            Err(err) => warnings.push(format!("Error parsing property {} of entity {:?}: {} with error: {:?}", attribute.name.local_name, type_name.local_name, attribute.value, err))
+                            Err(err) => {
+                                warnings.push(format!("Error parsing property {} of entity {:?}: {} with error: {:?}", attribute.name.local_name, type_name.local_name, attribute.value, err));
+                                if self.keep_unknown_expressions {
+                                    if let Err(err) = self.set_property(&entity_id, &attribute.name.local_name, Pon::Unknown(attribute.value.to_string())) {
+                                        warnings.push(format!("Failed to keep property {} for entity {:?}: {:?}", attribute.name.local_name, type_name.local_name, err));
+                                    }
+                                }
+                            }
                         };
                     }
+                    if trivia.len() > 0 {
+                        let leading = ::std::mem::replace(&mut trivia, vec![]);
+                        self.xml_trivia.insert(entity_id, EntityTrivia { leading: leading, trailing: vec![] });
+                    }
                     entity_stack.push(entity_id);
                 }
-                XmlEvent::EndElement { .. } => {
+                XmlEvent::EndElement { ref name } if name.local_name == "Include" => {
+                    // Trivia before an include stays pending for whatever follows it
                     entity_stack.pop();
                 }
+                XmlEvent::EndElement { .. } => {
+                    if let Some(entity_id) = entity_stack.pop() {
+                        if trivia.len() > 0 {
+                            let trailing = ::std::mem::replace(&mut trivia, vec![]);
+                            self.xml_trivia.entry(entity_id).or_insert(EntityTrivia { leading: vec![], trailing: vec![] }).trailing = trailing;
+                        }
+                    }
+                }
+                XmlEvent::Comment(text) => trivia.push(XmlTrivia::Comment(text)),
+                XmlEvent::ProcessingInstruction { name, data } => trivia.push(XmlTrivia::ProcessingInstruction { name: name, data: data }),
                 XmlEvent::Error(e) => {
-                    warnings.push(format!("Xml parsing error: {}", e));
+                    return Err(DocError::LoadError(LoadError {
+                        file: None,
+                        position: Some((e.row() + 1, e.col() + 1)),
+                        message: e.msg().to_string()
+                    }));
                 }
                 _ => {}
             }
         }
+        if trivia.len() > 0 {
+            match entity_stack.last() {
+                Some(entity_id) => self.xml_trivia.entry(*entity_id).or_insert(EntityTrivia { leading: vec![], trailing: vec![] }).trailing.extend(trivia),
+                None => self.trailing_trivia.extend(trivia)
+            }
+        }
+        for (entity_id, provenance) in loaded_provenance {
+            self.versions.provenance.insert(entity_id, provenance);
+        }
         Ok(())
     }
 
-    fn entity_to_xml<T: Write>(&self, entity_id: &EntityId, writer: &mut xml::writer::EventWriter<T>) {
-        let entity = self.entities.get(entity_id).unwrap();
-        let type_name = xml::name::Name::local(&entity.type_name);
+    // The attributes the entity is saved with, sorted by name
+    fn entity_attributes(&self, entity: &Entity) -> Vec<xml::attribute::OwnedAttribute> {
+        let options = self.numeric_options.stringify_options();
         let mut attrs: Vec<xml::attribute::OwnedAttribute> = entity.properties.iter().filter_map(|(name, prop)| {
+            if let Some(default) = entity.qualified_defaults.get(name) {
+                return default.as_ref().map(|expression| xml::attribute::OwnedAttribute {
+                    name: xml::name::OwnedName::local(name.to_string()),
+                    value: expression.stringify(&options)
+                });
+            }
             match &*prop.expression.borrow() {
                 &Some(ref expression) => Some(xml::attribute::OwnedAttribute {
                     name: xml::name::OwnedName::local(name.to_string()),
-                    value: expression.to_string()
+                    value: expression.stringify(&options)
                 }),
                 &None => None
             }
@@ -399,43 +3973,314 @@ impl Document {
                 value: name.to_string()
             });
         }
+        if let Some(provenance) = self.versions.provenance.get(&entity.id) {
+            attrs.push(xml::attribute::OwnedAttribute {
+                name: xml::name::OwnedName::local(PROVENANCE_ATTRIBUTE),
+                value: provenance.to_attribute()
+            });
+        }
         attrs.sort_by(|a, b| a.name.local_name.cmp(&b.name.local_name) );
-        writer.write(xml::writer::events::XmlEvent::StartElement {
+        attrs
+    }
+    // With split_includes, entities an Include element brought in are written as that element again
+    fn children_to_xml<T: Write>(&self, children: &Vec<EntityId>, writer: &mut xml::writer::EventWriter<T>, written: &mut usize, progress: Option<&Fn(usize, usize)>, split_includes: bool) -> Result<(), DocError> {
+        let mut written_includes = vec![];
+        for child in children {
+            let parent_id = self.entities[child].parent_id;
+            let included = if split_includes {
+                self.included_subtrees.iter().position(|included| included.parent_id == parent_id && included.entities.contains(child))
+            } else {
+                None
+            };
+            match included {
+                Some(index) => if !written_includes.contains(&index) {
+                    written_includes.push(index);
+                    let name = xml::name::Name::local("Include");
+                    try!(writer.write(xml::writer::events::XmlEvent::StartElement {
+                        name: name.clone(),
+                        attributes: self.included_subtrees[index].attributes.iter().map(|x| x.borrow()).collect(),
+                        namespace: &xml::namespace::Namespace::empty()
+                    }).map_err(emitter_err));
+                    try!(writer.write(xml::writer::events::XmlEvent::EndElement { name: name }).map_err(emitter_err));
+                },
+                None => try!(self.entity_to_xml(child, writer, written, progress, split_includes))
+            }
+        }
+        Ok(())
+    }
+    // written counts the entities written so far, for progress
+    fn entity_to_xml<T: Write>(&self, entity_id: &EntityId, writer: &mut xml::writer::EventWriter<T>, written: &mut usize, progress: Option<&Fn(usize, usize)>, split_includes: bool) -> Result<(), DocError> {
+        let entity = self.entities.get(entity_id).unwrap();
+        let type_name = xml::name::Name::local(&entity.type_name);
+        let attrs = self.entity_attributes(entity);
+        let trivia = self.xml_trivia.get(entity_id);
+        if let Some(trivia) = trivia {
+            try!(write_trivia(&trivia.leading, writer));
+        }
+        try!(writer.write(xml::writer::events::XmlEvent::StartElement {
             name: type_name.clone(),
             attributes: attrs.iter().map(|x| x.borrow()).collect(),
             namespace: &xml::namespace::Namespace::empty()
-        }).unwrap();
-        for e in &entity.children_ids {
-            self.entity_to_xml(e, writer);
+        }).map_err(emitter_err));
+        *written += 1;
+        if let Some(progress) = progress {
+            progress(*written, self.entities.len() - 1);
+        }
+        try!(self.children_to_xml(&entity.children_ids, writer, written, progress, split_includes));
+        if let Some(trivia) = trivia {
+            try!(write_trivia(&trivia.trailing, writer));
         }
         writer.write(xml::writer::events::XmlEvent::EndElement {
             name: type_name.clone()
-        }).unwrap();
+        }).map_err(emitter_err)
+    }
+    // Writes the document as xml one entity at a time, without building it in memory first
+    pub fn write_xml<W: Write>(&self, out: &mut W) -> Result<(), DocError> {
+        self.write_xml_with_progress(out, None)
+    }
+    // Like write_xml, calling progress with the number of entities written so far and the total after each
+    pub fn write_xml_with_progress<W: Write>(&self, out: &mut W, progress: Option<&Fn(usize, usize)>) -> Result<(), DocError> {
+        // Xml documents have one top level element, but ours may have any number below the root
+        let top_level = match self.root {
+            Some(root) => self.entities[&root].children_ids.clone(),
+            None => vec![]
+        };
+        self.write_entities_xml(out, &top_level, progress, false, true)
+    }
+    // The trailing trivia only goes in the top level file
+    fn write_entities_xml<W: Write>(&self, out: &mut W, entities: &Vec<EntityId>, progress: Option<&Fn(usize, usize)>, split_includes: bool, with_trailing_trivia: bool) -> Result<(), DocError> {
+        let mut writer = xml::writer::EventWriter::new(out);
+        try!(writer.write(xml::writer::events::XmlEvent::StartDocument {
+            version: xml::common::XmlVersion::Version11,
+            encoding: None,
+            standalone: None
+        }).map_err(emitter_err));
+        try!(self.children_to_xml(entities, &mut writer, &mut 0, progress, split_includes));
+        if with_trailing_trivia {
+            try!(write_trivia(&self.trailing_trivia, &mut writer));
+        }
+        Ok(())
     }
     fn to_xml(&self) -> String {
         let mut buff = vec![];
-        {
-            let mut writer = xml::writer::EventWriter::new(&mut buff);
-            writer.write(xml::writer::events::XmlEvent::StartDocument {
-                version: xml::common::XmlVersion::Version11,
-                encoding: None,
-                standalone: None
-            }).unwrap();
-            if self.root.is_some() {
-                self.entity_to_xml(&self.root.unwrap(), &mut writer);
+        self.write_xml(&mut buff).unwrap();
+        String::from_utf8(buff).unwrap()
+    }
+}
+
+#[derive(Debug)]
+enum StructuralEdit {
+    AppendEntity { parent_id: EntityId, type_name: String, name: Option<String> },
+    RemoveEntity { entity_id: EntityId }
+}
+
+pub struct EntityVisitor<'a> {
+    pub entity_id: EntityId,
+    document: &'a mut Document,
+    edits: &'a mut Vec<StructuralEdit>
+}
+
+impl<'a> EntityVisitor<'a> {
+    pub fn document(&self) -> &Document {
+        &*self.document
+    }
+    pub fn get_property(&self, property_key: &str) -> Result<Ref<Pon>, DocError> {
+        self.document.get_property(&self.entity_id, property_key)
+    }
+    pub fn set_property(&mut self, property_key: &str, expression: Pon) -> Result<(), DocError> {
+        self.document.set_property(&self.entity_id, property_key, expression)
+    }
+    // Queued; the child is appended after the walk and is not visited
+    pub fn append_child(&mut self, type_name: &str, name: Option<String>) {
+        self.edits.push(StructuralEdit::AppendEntity {
+            parent_id: self.entity_id,
+            type_name: type_name.to_string(),
+            name: name
+        });
+    }
+    // Queued; the entity and its subtree are removed after the walk, which still visits its descendants
+    pub fn remove(&mut self) {
+        self.edits.push(StructuralEdit::RemoveEntity { entity_id: self.entity_id });
+    }
+}
+
+fn has_nil_reference(node: &Pon) -> bool {
+    match node {
+        &Pon::DependencyReference(_, Some(ref resolved)) => match &*resolved.value.borrow() {
+            &Some(Pon::Nil) | &None => true,
+            &Some(_) => false
+        },
+        &Pon::TypedPon(box TypedPon { ref data, .. }) => has_nil_reference(data),
+        &Pon::Object(ref hm) => hm.values().any(|v| has_nil_reference(v)),
+        &Pon::Array(ref arr) => arr.iter().any(|v| has_nil_reference(v)),
+        _ => false
+    }
+}
+
+// Renames entities in the references of expression, and in its targets if it's the LINKS_PROPERTY, in one pass
+fn rename_entities_in(property_key: &str, expression: &mut Pon, renames: &HashMap<String, String>) {
+    fn rename_path(path: &mut EntityPath, renames: &HashMap<String, String>) {
+        match path {
+            &mut EntityPath::Named(ref mut name) => if let Some(new_name) = renames.get(name) {
+                *name = new_name.clone();
+            },
+            &mut EntityPath::Search(ref mut path, ref mut name) => {
+                rename_path(path, renames);
+                if let Some(new_name) = renames.get(name) {
+                    *name = new_name.clone();
+                }
+            },
+            _ => {}
+        }
+    }
+    let is_links = property_key == LINKS_PROPERTY;
+    expression.visit_mut(&mut |node| match node {
+        &mut Pon::DependencyReference(ref mut named_prop_ref, _) => rename_path(&mut named_prop_ref.entity_path, renames),
+        &mut Pon::Reference(ref mut named_prop_ref) => rename_path(&mut named_prop_ref.entity_path, renames),
+        &mut Pon::String(ref mut name) => if is_links {
+            if let Some(new_name) = renames.get(name) {
+                *name = new_name.clone();
+            }
+        },
+        _ => {}
+    });
+}
+
+// Whether any @-reference in node goes through a link
+fn uses_links(node: &Pon) -> bool {
+    fn path_uses_links(path: &EntityPath) -> bool {
+        match path {
+            &EntityPath::Link(_) => true,
+            &EntityPath::Search(ref path, _) => path_uses_links(path),
+            _ => false
+        }
+    }
+    let mut references = vec![];
+    node.get_dependency_references(&mut references);
+    references.iter().any(|reference| path_uses_links(&reference.entity_path))
+}
+
+// Whether any @-reference in node goes through the entity named name
+fn references_name(node: &Pon, name: &str) -> bool {
+    fn path_references(path: &EntityPath, name: &str) -> bool {
+        match path {
+            &EntityPath::Named(ref n) => n == name,
+            &EntityPath::Search(ref path, ref n) => n == name || path_references(path, name),
+            _ => false
+        }
+    }
+    let mut references = vec![];
+    node.get_dependency_references(&mut references);
+    references.iter().any(|reference| path_references(&reference.entity_path, name))
+}
+
+fn u64_to_le_bytes(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for i in 0..8 {
+        bytes[i] = (value >> (i * 8)) as u8;
+    }
+    bytes
+}
+
+fn collect_resolved_dependencies(node: &Pon, out: &mut Vec<PropRef>) {
+    match node {
+        &Pon::DependencyReference(_, Some(ref resolved)) => out.push(resolved.prop_ref.clone()),
+        &Pon::TypedPon(box TypedPon { ref data, .. }) => collect_resolved_dependencies(data, out),
+        &Pon::Object(ref hm) => for v in hm.values() { collect_resolved_dependencies(v, out) },
+        &Pon::Array(ref arr) => for v in arr { collect_resolved_dependencies(v, out) },
+        _ => {}
+    }
+}
+
+fn find_cycles(dependencies: &HashMap<PropRef, Vec<PropRef>>, prop_ref: &PropRef, state: &mut HashMap<PropRef, u8>, errors: &mut Vec<ValidationError>) {
+    state.insert(prop_ref.clone(), 1);
+    if let Some(deps) = dependencies.get(prop_ref) {
+        for dep in deps {
+            match state.get(dep).map(|x| *x) {
+                Some(1) => errors.push(ValidationError::DependencyCycle(dep.clone())),
+                Some(_) => {},
+                None => find_cycles(dependencies, dep, state, errors)
             }
         }
-        String::from_utf8(buff).unwrap()
+    }
+    state.insert(prop_ref.clone(), 2);
+}
+
+#[cfg(feature = "fs")]
+fn write_atomic(path: &Path, contents: &str) -> Result<(), DocError> {
+    write_atomic_with(path, |file| file.write_all(contents.as_bytes()).map_err(|err| DocError::IoError(err.to_string())))
+}
+
+// Like write_atomic, with the file's contents written by write
+#[cfg(feature = "fs")]
+fn write_atomic_with<F: Fn(&mut BufWriter<File>) -> Result<(), DocError>>(path: &Path, write: F) -> Result<(), DocError> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = BufWriter::new(try!(File::create(&tmp_path).map_err(|err| DocError::IoError(err.to_string()))));
+        try!(write(&mut file));
+        try!(file.flush().map_err(|err| DocError::IoError(err.to_string())));
+        try!(file.get_ref().sync_all().map_err(|err| DocError::IoError(err.to_string())));
+    }
+    fs::rename(&tmp_path, path).map_err(|err| DocError::IoError(err.to_string()))
+}
+
+#[cfg(feature = "fs")]
+fn event_reader_from_file(path: &Path) -> Result<EventReader<BufReader<File>>, DocError> {
+    let file = try!(File::open(path).map_err(|err| DocError::IoError(format!("{}: {}", path.display(), err))));
+    let file = BufReader::new(file);
+
+    Ok(EventReader::new_with_config(file, parser_config()))
+}
+
+fn emitter_err<E: ::std::fmt::Debug>(err: E) -> DocError {
+    DocError::IoError(format!("{:?}", err))
+}
+
+fn write_trivia<T: Write>(trivia: &Vec<XmlTrivia>, writer: &mut xml::writer::EventWriter<T>) -> Result<(), DocError> {
+    for item in trivia {
+        try!(match item {
+            &XmlTrivia::Comment(ref text) => writer.write(xml::writer::events::XmlEvent::Comment(text)),
+            &XmlTrivia::ProcessingInstruction { ref name, ref data } => writer.write(xml::writer::events::XmlEvent::ProcessingInstruction {
+                name: name,
+                data: data.as_ref().map(|x| &x[..])
+            })
+        }.map_err(emitter_err));
+    }
+    Ok(())
+}
+
+// Keeps comments, so they can be saved again
+fn parser_config() -> ParserConfig {
+    ParserConfig::new().ignore_comments(false)
+}
+
+// Queues prop_ref to have its dependants invalidated, unless it's a barrier
+fn hold_at_barrier(barriers: &HashMap<PropRef, String>, prop_ref: &PropRef, held: &mut Vec<(String, Vec<PropRef>)>, queue: &mut VecDeque<PropRef>) {
+    let name = match barriers.get(prop_ref) {
+        Some(name) => name,
+        None => return queue.push_back(prop_ref.clone())
+    };
+    match held.iter().position(|&(ref held_name, _)| held_name == name) {
+        Some(i) => held[i].1.push(prop_ref.clone()),
+        None => held.push((name.clone(), vec![prop_ref.clone()]))
+    }
+}
+
+// Puts entity_id under each of names in index, and takes it out from under the others
+fn index_names(index: &mut HashMap<String, Vec<EntityId>>, entity_id: &EntityId, names: Vec<String>) {
+    for (name, members) in index.iter_mut() {
+        if !names.contains(name) {
+            members.retain(|id| id != entity_id);
+        }
+    }
+    for name in names {
+        let members = index.entry(name).or_insert(vec![]);
+        if !members.contains(entity_id) {
+            members.push(*entity_id);
+        }
     }
 }
 
-fn event_reader_from_file(path: &Path) -> EventReader<BufReader<File>> {
-    let file = File::open(path).unwrap();
-    let file = BufReader::new(file);
-
-    EventReader::new(file)
-}
-
 impl ToString for Document {
     fn to_string(&self) -> String {
         self.to_xml()
@@ -530,3 +4375,1128 @@ fn test_document_to_string_empty() {
     let doc = Document::new();
     assert_eq!(doc.to_string(), "<?xml version=\"1.1\" encoding=\"UTF-8\"?>");
 }
+
+#[test]
+fn test_visit_mut() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.visit_mut(&root, |visitor| {
+        visitor.set_property("visited", Pon::Boolean(true)).unwrap();
+        visitor.append_child("Entity", None);
+    }).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    assert_eq!(*doc.get_property(&a, "visited").unwrap(), Pon::Boolean(true));
+    assert_eq!(doc.get_children(&root).unwrap().len(), 2);
+    assert_eq!(doc.get_children(&a).unwrap().len(), 1);
+    doc.visit_mut(&root, |visitor| if visitor.entity_id != root { visitor.remove() }).unwrap();
+    assert_eq!(doc.get_children(&root).unwrap().len(), 0);
+    assert!(doc.get_entity_by_name("a").is_none());
+}
+
+#[test]
+fn test_replace_references() {
+    let mut doc = Document::from_string(r#"<Entity><Entity name="old" x="1.0" /><Entity name="new" x="2.0" /><Entity name="tmp" y="@old.x" /></Entity>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let affected = doc.replace_references("old", "new", true).unwrap();
+    assert_eq!(affected, vec![PropRef::new(&ent, "y")]);
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(1.0));
+    doc.replace_references("old", "new", false).unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(2.0));
+}
+
+#[test]
+fn test_prune_report() {
+    let doc = Document::from_string(r#"<Entity name="tmp" x="5.0" y="@this.x" z="@this.w" unread="1" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.get_property(&ent, "y").unwrap();
+    let report = doc.prune_report();
+    assert!(report.unused_properties.contains(&PropRef::new(&ent, "unread")));
+    assert!(!report.unused_properties.contains(&PropRef::new(&ent, "x")));
+    assert!(!report.unused_properties.contains(&PropRef::new(&ent, "y")));
+    assert_eq!(report.nil_references, vec![PropRef::new(&ent, "z")]);
+}
+
+#[test]
+fn test_dirty_subtrees() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a"><Entity name="b" /></Entity><Entity name="c" /></Entity>"#).unwrap();
+    doc.dirty_entities.clear();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    doc.set_property(&b, "x", Pon::Integer(1)).unwrap();
+    doc.set_property(&a, "x", Pon::Integer(1)).unwrap();
+    assert_eq!(doc.dirty_subtrees(), vec![a]);
+}
+
+#[test]
+#[cfg(feature = "fs")]
+fn test_write_ahead_log_recover() {
+    let path = ::std::env::temp_dir().join("pyramid_test_write_ahead_log.xml");
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" x="1.0" /></Entity>"#).unwrap();
+    doc.enable_write_ahead_log(&path).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let b = doc.append_entity(Some(root), "Entity", Some("b".to_string())).unwrap();
+    doc.set_property(&b, "y", Pon::from_string("@a.x").unwrap()).unwrap();
+    let recovered = Document::recover(&path).unwrap();
+    let b = recovered.get_entity_by_name("b").unwrap();
+    assert_eq!(recovered.get_property(&b, "y").unwrap().concretize().unwrap(), Pon::Float(1.0));
+}
+
+#[test]
+#[cfg(feature = "fs")]
+fn test_write_ahead_log_unset_and_retype() {
+    let path = ::std::env::temp_dir().join("pyramid_test_write_ahead_log_unset.xml");
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1.0" />"#).unwrap();
+    doc.enable_write_ahead_log(&path).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.unset_property(&root, "x").unwrap();
+    doc.set_entity_type_name(&root, "Mesh").unwrap();
+    let recovered = Document::recover(&path).unwrap();
+    let root = recovered.get_entity_by_name("root").unwrap();
+    assert_eq!(recovered.has_property(&root, "x"), Ok(false));
+    assert_eq!(recovered.get_entity_type_name(&root).unwrap(), "Mesh");
+}
+
+#[test]
+#[cfg(feature = "fs")]
+fn test_replay_to() {
+    let path = ::std::env::temp_dir().join("pyramid_test_replay_to.xml");
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" />"#).unwrap();
+    doc.enable_write_ahead_log(&path).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.set_property(&root, "x", Pon::Integer(2)).unwrap();
+    let seq = doc.journal_seq().unwrap();
+    doc.set_property(&root, "x", Pon::Integer(3)).unwrap();
+    assert_eq!(*doc.replay_to(0).unwrap().get_property(&root, "x").unwrap(), Pon::Integer(1));
+    assert_eq!(*doc.replay_to(seq).unwrap().get_property(&root, "x").unwrap(), Pon::Integer(2));
+}
+
+#[test]
+fn test_alias() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="props" x="5.0"><Entity name="chair" /></Entity><Entity name="room" /></Entity>"#).unwrap();
+    let props = doc.get_entity_by_name("props").unwrap();
+    let room = doc.get_entity_by_name("room").unwrap();
+    let chair = doc.get_entity_by_name("chair").unwrap();
+    let alias = doc.append_alias(&room, &props, None).unwrap();
+    assert_eq!(*doc.get_property(&alias, "x").unwrap(), Pon::Float(5.0));
+    assert_eq!(*doc.get_resolved_children(&alias).unwrap(), vec![chair]);
+    assert_eq!(doc.get_children(&alias).unwrap().len(), 0);
+}
+
+#[test]
+fn test_qualified_properties() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" shadow_res="1024" shadow_res--low="512" shadow_res--high="2048" y="@this.shadow_res" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(*doc.get_property(&ent, "shadow_res").unwrap(), Pon::Integer(1024));
+    doc.set_qualifiers(vec!["low".to_string()]).unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Integer(512));
+    doc.set_qualifiers(vec![]).unwrap();
+    assert_eq!(*doc.get_property(&ent, "shadow_res").unwrap(), Pon::Integer(1024));
+}
+
+#[test]
+#[cfg(feature = "fs")]
+fn test_write_ahead_log_qualified_properties() {
+    let path = ::std::env::temp_dir().join("pyramid_test_write_ahead_log_qualified.xml");
+    let mut doc = Document::from_string(r#"<Entity name="tmp" shadow_res="1024" />"#).unwrap();
+    doc.enable_write_ahead_log(&path).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.set_qualifiers(vec!["low".to_string()]).unwrap();
+    doc.set_property(&ent, "shadow_res--low", Pon::Integer(512)).unwrap();
+    let mut recovered = Document::recover(&path).unwrap();
+    let ent = recovered.get_entity_by_name("tmp").unwrap();
+    assert_eq!(*recovered.get_property(&ent, "shadow_res").unwrap(), Pon::Integer(1024));
+    recovered.set_qualifiers(vec!["low".to_string()]).unwrap();
+    assert_eq!(*recovered.get_property(&ent, "shadow_res").unwrap(), Pon::Integer(512));
+}
+
+#[test]
+fn test_locale_properties() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" text="'Hello'" text--de="'Hallo'" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.set_locale(Some("de".to_string())).unwrap();
+    assert_eq!(*doc.get_property(&ent, "text").unwrap(), Pon::String("Hallo".to_string()));
+    doc.set_locale(Some("fr".to_string())).unwrap();
+    assert_eq!(*doc.get_property(&ent, "text").unwrap(), Pon::String("Hello".to_string()));
+}
+
+#[test]
+#[cfg(feature = "fs")]
+fn test_include_importer() {
+    struct CsvImporter;
+    impl DocumentImporter for CsvImporter {
+        fn import(&self, bytes: &[u8], _: &HashMap<String, Pon>) -> Result<Vec<SubtreeOp>, DocError> {
+            let text = String::from_utf8_lossy(bytes).to_string();
+            Ok(text.lines().map(|line| SubtreeOp::AppendEntity { parent: None, type_name: "Row".to_string(), name: Some(line.to_string()) }).collect())
+        }
+    }
+    let path = ::std::env::temp_dir().join("pyramid_test_include.csv");
+    File::create(&path).unwrap().write_all(b"a\nb").unwrap();
+    let mut doc = Document::new();
+    doc.importers.register("csv", Box::new(CsvImporter));
+    let root = doc.append_entity(None, "Entity", None).unwrap();
+    let mut parser = EventReader::from_str(&format!(r#"<Include file="{}" />"#, path.display()));
+    doc.append_from_event_reader(&mut vec![root], Path::new(""), positioned(&mut parser), &mut vec![]).unwrap();
+    assert_eq!(doc.get_children(&root).unwrap().len(), 2);
+    assert!(doc.get_entity_by_name("b").is_some());
+}
+
+#[test]
+#[cfg(feature = "fs")]
+fn test_include_cache() {
+    let path = ::std::env::temp_dir().join("pyramid_test_include_cache.xml");
+    File::create(&path).unwrap().write_all(br#"<Entity name="lamp" x="1" />"#).unwrap();
+    let cache = Rc::new(IncludeCache::new());
+    for _ in 0..2 {
+        let mut doc = Document::new();
+        doc.include_cache = Some(cache.clone());
+        let root = doc.append_entity(None, "Entity", None).unwrap();
+        let mut parser = EventReader::from_str(&format!(r#"<Include file="{}" />"#, path.display()));
+        doc.append_from_event_reader(&mut vec![root], Path::new(""), positioned(&mut parser), &mut vec![]).unwrap();
+        let lamp = doc.get_entity_by_name("lamp").unwrap();
+        assert_eq!(*doc.get_property(&lamp, "x").unwrap(), Pon::Integer(1));
+    }
+    assert_eq!(cache.hits(), 1);
+}
+
+#[test]
+fn test_property_history() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="1" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.track_property_history(&PropRef::new(&ent, "x"), 2);
+    doc.set_property(&ent, "x", Pon::Integer(2)).unwrap();
+    doc.set_mutation_source(Some("physics".to_string()));
+    doc.set_property(&ent, "x", Pon::Integer(3)).unwrap();
+    doc.set_property(&ent, "x", Pon::Integer(4)).unwrap();
+    let history = doc.property_history(&PropRef::new(&ent, "x")).unwrap();
+    assert_eq!(history.iter().map(|x| x.value.clone()).collect::<Vec<Pon>>(), vec![Pon::Integer(3), Pon::Integer(4)]);
+    assert_eq!(history[1].source, Some("physics".to_string()));
+}
+
+#[test]
+fn test_debug_break_on() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="1" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let changes = Rc::new(RefCell::new(vec![]));
+    let changes_cb = changes.clone();
+    doc.debug_break_on(&PropRef::new(&ent, "x"), Box::new(move |change| changes_cb.borrow_mut().push(change.clone())));
+    doc.set_mutation_source(Some("animation".to_string()));
+    doc.set_property(&ent, "x", Pon::Integer(2)).unwrap();
+    doc.set_property(&ent, "y", Pon::Integer(2)).unwrap();
+    assert_eq!(*changes.borrow(), vec![PropertyChange {
+        prop_ref: PropRef::new(&ent, "x"),
+        old_value: Some(Pon::Integer(1)),
+        new_value: Some(Pon::Integer(2)),
+        source: Some("animation".to_string())
+    }]);
+}
+
+#[test]
+fn test_integrity_errors() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="@this.y" a="@this.missing" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.set_property(&ent, "y", Pon::from_string("@this.x").unwrap()).unwrap();
+    assert_eq!(doc.integrity_errors(), vec![
+        ValidationError::DanglingReference(PropRef::new(&ent, "a")),
+        ValidationError::DependencyCycle(PropRef::new(&ent, "x"))
+    ]);
+}
+
+#[test]
+fn test_trash_entity() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a"><Entity name="b" /></Entity><Entity name="c" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let c = doc.get_entity_by_name("c").unwrap();
+    doc.trash_entity(&a).unwrap();
+    assert_eq!(doc.get_entity_by_name("b"), None);
+    assert_eq!(*doc.get_children(&root).unwrap(), vec![c]);
+    assert!(doc.is_trashed(&b));
+    doc.restore_entity(&a).unwrap();
+    assert_eq!(*doc.get_children(&root).unwrap(), vec![a, c]);
+    assert_eq!(doc.get_entity_by_name("b"), Some(b));
+}
+
+#[test]
+fn test_remove_entity() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="2"><Entity name="a" x="1"><Entity name="b" w="@root.x" /></Entity><Entity name="c" y="@a.x" /><Entity name="d" z="@c.y" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let c = doc.get_entity_by_name("c").unwrap();
+    let d = doc.get_entity_by_name("d").unwrap();
+    let mut cascade = doc.remove_entity(&a).unwrap();
+    cascade.sort_by(|a, b| a.property_key.cmp(&b.property_key));
+    assert_eq!(cascade, vec![PropRef::new(&c, "y"), PropRef::new(&d, "z")]);
+    assert_eq!(doc.get_entity_by_name("b"), None);
+    assert_eq!(*doc.get_children(&root).unwrap(), vec![c, d]);
+    assert!(doc.get_property(&c, "y").unwrap().concretize().is_err());
+    // b.w no longer depends on root.x
+    assert_eq!(doc.get_property_dependants(&root, "x").unwrap().len(), 0);
+    assert_eq!(doc.build_cascade(vec![PropRef::new(&root, "x")]), vec![PropRef::new(&root, "x")]);
+    // Nothing keyed by the removed entities is left behind
+    let b = doc.append_entity(Some(root), "Entity", None).unwrap();
+    doc.observe(PropRef::new(&b, "x"), Box::new(|_| {}));
+    doc.set_cascade_barrier(PropRef::new(&b, "x"), "late");
+    doc.remove_entity(&b).unwrap();
+    assert_eq!(doc.property_observers.len(), 0);
+    assert_eq!(doc.cascade_barriers.len(), 0);
+    assert!(doc.get_entity_source(&a).is_none());
+}
+
+#[test]
+fn test_reparent_entity() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" x="1"><Entity name="c" y="@parent.x" /></Entity><Entity name="b" x="2" /></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let c = doc.get_entity_by_name("c").unwrap();
+    let events = doc.subscribe();
+    assert_eq!(doc.reparent_entity(&c, &b, 0).unwrap(), vec![PropRef::new(&c, "y")]);
+    assert_eq!(events.try_recv().unwrap(), DocEvent::EntityMoved { entity_id: c, old_parent_id: a, new_parent_id: b });
+    assert_eq!(doc.build_cascade(vec![PropRef::new(&a, "x")]), vec![PropRef::new(&a, "x")]);
+    assert_eq!(doc.get_children(&a).unwrap().len(), 0);
+    assert_eq!(*doc.get_children(&b).unwrap(), vec![c]);
+    assert_eq!(doc.get_property(&c, "y").unwrap().concretize().unwrap(), Pon::Integer(2));
+    assert_eq!(doc.reparent_entity(&b, &c, 0).err(), Some(DocError::InvalidParent));
+}
+
+#[test]
+fn test_clone_entity() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="enemy" hp="10"><Entity name="bar" value="@enemy.hp" /></Entity></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let enemy = doc.get_entity_by_name("enemy").unwrap();
+    let clone = doc.clone_entity(&enemy, &root).unwrap();
+    assert_eq!(doc.get_entity_name(&clone).unwrap(), Some(&"enemy_1".to_string()));
+    let bar = doc.get_entity_by_name("bar_1").unwrap();
+    assert_eq!(doc.get_property(&bar, "value").unwrap().to_string(), "@enemy_1.hp");
+    doc.set_property(&clone, "hp", Pon::Integer(5)).unwrap();
+    assert_eq!(doc.get_property(&bar, "value").unwrap().concretize().unwrap(), Pon::Integer(5));
+    assert_eq!(doc.get_property(&enemy, "hp").unwrap().concretize().unwrap(), Pon::Integer(10));
+}
+
+#[test]
+fn test_find_by_property() {
+    let mut doc = Document::from_string(r#"<Entity name="root" default_team="2"><Entity name="a" team="1" /><Entity name="b" team="@root.default_team" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    assert_eq!(doc.find_by_property("team", &Pon::Integer(2)), vec![b]);
+    doc.index_property("team");
+    doc.set_property(&root, "default_team", Pon::Integer(1)).unwrap();
+    assert_eq!(doc.find_by_property("team", &Pon::Integer(1)), vec![a, b]);
+    assert_eq!(doc.find_by_property("team", &Pon::Integer(2)), vec![]);
+}
+
+#[test]
+fn test_remove_property() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" y="@this.x" z="@this.y" w="2" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let mut cascade = doc.remove_property(&root, "x").unwrap();
+    cascade.sort_by(|a, b| a.property_key.cmp(&b.property_key));
+    assert_eq!(cascade, vec![PropRef::new(&root, "y"), PropRef::new(&root, "z")]);
+    assert!(!doc.get_properties(&root).unwrap().contains(&PropRef::new(&root, "x")));
+    assert!(doc.get_property(&root, "z").unwrap().concretize().is_err());
+    assert_eq!(doc.remove_property(&root, "w").unwrap(), vec![]);
+    assert_eq!(doc.remove_property(&root, "w"), Err(DocError::NoSuchProperty("w".to_string())));
+}
+
+#[test]
+fn test_remove_property_rollback() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" y="@this.x" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.begin_transaction().unwrap();
+    doc.remove_property(&root, "x").unwrap();
+    assert!(doc.remove_property(&root, "w").is_err());
+    doc.set_property(&root, "w", Pon::Integer(2)).unwrap();
+    doc.remove_property(&root, "w").unwrap();
+    doc.rollback().unwrap();
+    assert_eq!(*doc.get_property(&root, "x").unwrap(), Pon::Integer(1));
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert!(!doc.has_property(&root, "w").unwrap());
+}
+
+#[test]
+fn test_sibling_order() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" /><Entity name="b" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let c = doc.insert_entity_at(&root, 0, "Entity", Some("c".to_string())).unwrap();
+    assert_eq!(*doc.get_children(&root).unwrap(), vec![c, a, b]);
+    doc.move_child(&root, 0, 2).unwrap();
+    assert_eq!(*doc.get_children(&root).unwrap(), vec![a, b, c]);
+    assert_eq!(doc.move_child(&root, 3, 0), Err(DocError::NoSuchChild(root, 3)));
+    let reloaded = Document::from_string(&doc.to_string()).unwrap();
+    let names: Vec<String> = reloaded.get_children(&reloaded.get_entity_by_name("root").unwrap()).unwrap().iter()
+        .map(|id| reloaded.get_entity_name(id).unwrap().unwrap().to_string()).collect();
+    assert_eq!(names, vec!["a", "b", "c"]);
+}
+
+#[test]
+fn test_query_visible() {
+    let doc = Document::from_string(r#"<Entity name="root">
+        <Entity name="near" bounds="aabb { min: vec3 { x: 0.0, y: 0.0, z: 0.0 }, max: vec3 { x: 2.0, y: 2.0, z: 2.0 } }">
+            <Entity name="inside" bounds="aabb { min: vec3 { x: 0.0, y: 0.0, z: 0.0 }, max: vec3 { x: 1.0, y: 1.0, z: 1.0 } }" />
+        </Entity>
+        <Entity name="far" bounds="aabb { min: vec3 { x: 10.0, y: 0.0, z: 0.0 }, max: vec3 { x: 12.0, y: 2.0, z: 2.0 } }">
+            <Entity name="escaped" bounds="aabb { min: vec3 { x: 0.0, y: 0.0, z: 0.0 }, max: vec3 { x: 1.0, y: 1.0, z: 1.0 } }" />
+        </Entity>
+    </Entity>"#).unwrap();
+    let volume = CullVolume::Aabb(Aabb::new(::cgmath::Vector3::new(-1.0, -1.0, -1.0), ::cgmath::Vector3::new(5.0, 5.0, 5.0)));
+    assert_eq!(doc.query_visible(&volume), vec![doc.get_entity_by_name("near").unwrap(), doc.get_entity_by_name("inside").unwrap()]);
+}
+
+#[test]
+fn test_links() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="turret" links="{ target: ['enemy'] }" /><Entity name="enemy" /><Entity name="ship" /></Entity>"#).unwrap();
+    let turret = doc.get_entity_by_name("turret").unwrap();
+    let enemy = doc.get_entity_by_name("enemy").unwrap();
+    let ship = doc.get_entity_by_name("ship").unwrap();
+    doc.add_link(&turret, "attached_to", &ship).unwrap();
+    assert_eq!(doc.get_links(&turret, None).unwrap(), vec![("attached_to".to_string(), ship), ("target".to_string(), enemy)]);
+    assert_eq!(doc.get_backlinks(&enemy, Some("target")).unwrap(), vec![("target".to_string(), turret)]);
+    doc.remove_entity(&enemy).unwrap();
+    assert_eq!(doc.get_links(&turret, None).unwrap(), vec![("attached_to".to_string(), ship)]);
+    assert_eq!(*doc.get_property(&turret, LINKS_PROPERTY).unwrap(), Pon::from_string("{ attached_to: ['ship'] }").unwrap());
+}
+
+#[test]
+fn test_rename_entity() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" x="1" /><Entity name="b" y="@a.x" z="@c.x" /></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    doc.rename_entity(&a, "c").unwrap();
+    assert_eq!(doc.get_entity_by_name("a"), None);
+    assert_eq!(doc.get_entity_by_name("c"), Some(a));
+    assert_eq!(doc.get_property(&b, "y").unwrap().to_string(), "@c.x");
+    assert_eq!(doc.get_property(&b, "z").unwrap().concretize().unwrap(), Pon::Integer(1));
+    assert_eq!(doc.rename_entity(&b, "c"), Err(DocError::NameTaken("c".to_string())));
+}
+
+#[test]
+fn test_merge() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="door" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let level = Document::from_string(r#"<Entity name="level"><Entity name="door" open="true" /><Entity name="switch" opens="@door.open" /></Entity>"#).unwrap();
+    let level_switch = level.get_entity_by_name("switch").unwrap();
+    let merged = doc.merge(level, &root, NameCollision::Rename).unwrap();
+    let door = doc.get_entity_by_name("door_1").unwrap();
+    assert_eq!(doc.get_parent(&door).unwrap(), doc.get_entity_by_name("level"));
+    assert_eq!(doc.get_property(&merged[&level_switch], "opens").unwrap().to_string(), "@door_1.open");
+    let other = Document::from_string(r#"<Entity name="door" />"#).unwrap();
+    assert_eq!(doc.merge(other, &root, NameCollision::Fail), Err(DocError::NameTaken("door".to_string())));
+}
+
+#[test]
+fn test_link_references() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" position="1" /><Entity name="b" position="2" /><Entity name="turret" links="{ target: ['a'] }" aim="@link(target).position" /></Entity>"#).unwrap();
+    let turret = doc.get_entity_by_name("turret").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    assert_eq!(doc.get_property(&turret, "aim").unwrap().concretize().unwrap(), Pon::Integer(1));
+    doc.remove_link(&turret, "target", &a).unwrap();
+    assert!(!doc.has_property(&turret, "aim").unwrap());
+    doc.add_link(&turret, "target", &b).unwrap();
+    assert_eq!(doc.get_property(&turret, "aim").unwrap().concretize().unwrap(), Pon::Integer(2));
+    doc.set_property(&b, "position", Pon::Integer(3)).unwrap();
+    assert_eq!(doc.get_property(&turret, "aim").unwrap().concretize().unwrap(), Pon::Integer(3));
+}
+
+#[test]
+fn test_apply_patch() {
+    let mut old = Document::from_string(r#"<Entity name="root"><Entity name="a" x="1" /><Entity name="b" /><Entity name="d" y="b.x" /></Entity>"#).unwrap();
+    let new = Document::from_string(r#"<Entity name="root"><Light name="c" x="2" /><Entity name="a" x="c.x" /><Entity name="d" /></Entity>"#).unwrap();
+    let patch = old.diff(&new);
+    let a = old.get_entity_by_name("a").unwrap();
+    let cascade = old.apply_patch(&patch).unwrap();
+    assert!(cascade.contains(&PropRef::new(&a, "x")));
+    assert_eq!(old.get_property(&a, "x").unwrap().to_string(), "c.x");
+    assert!(!old.has_property(&old.get_entity_by_name("d").unwrap(), "y").unwrap());
+    assert!(old.diff(&new).is_empty());
+}
+
+#[test]
+fn test_numeric_options() {
+    let options = NumericOptions { float_decimals: 2, round_on_load: true, integers_equal_floats: false };
+    let doc = Document::from_string_with_numeric_options(r#"<Entity x="0.123456" />"#, options).unwrap();
+    let root = doc.get_children(&doc.get_root().unwrap()).unwrap()[0];
+    assert_eq!(*doc.get_property(&root, "x").unwrap(), Pon::Float(0.12));
+    assert!(doc.to_string().contains(r#"x="0.12""#));
+    assert!(Pon::Integer(1).numeric_eq(&Pon::Float(1.0), &NumericOptions { float_decimals: 2, round_on_load: false, integers_equal_floats: true }));
+}
+
+#[test]
+fn test_transaction_rollback() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.begin_transaction().unwrap();
+    doc.set_property(&root, "x", Pon::Integer(2)).unwrap();
+    doc.set_property(&root, "y", Pon::Integer(3)).unwrap();
+    let child = doc.append_entity(Some(root), "Entity", Some("child".to_string())).unwrap();
+    doc.set_property(&child, "z", Pon::from_string("@root.x").unwrap()).unwrap();
+    assert!(doc.set_property(&root, "w", Pon::from_string("@missing.x").unwrap()).is_err());
+    doc.rollback().unwrap();
+    assert_eq!(*doc.get_property(&root, "x").unwrap(), Pon::Integer(1));
+    assert!(!doc.has_property(&root, "y").unwrap());
+    assert_eq!(doc.get_entity_by_name("child"), None);
+    assert_eq!(doc.get_children(&root).unwrap().len(), 0);
+    assert_eq!(doc.commit(), Err(DocError::NoTransaction));
+}
+
+#[test]
+fn test_xml_trivia() {
+    let doc = Document::from_string(r#"<Entity name="root"><!-- the player --><Entity name="player" /><?editor folded?></Entity><!-- end -->"#).unwrap();
+    let player = doc.get_entity_by_name("player").unwrap();
+    assert_eq!(doc.get_xml_trivia(&player).unwrap().leading, vec![XmlTrivia::Comment(" the player ".to_string())]);
+    let reloaded = Document::from_string(&doc.to_string()).unwrap();
+    assert_eq!(reloaded.get_xml_trivia(&reloaded.get_entity_by_name("player").unwrap()), doc.get_xml_trivia(&player));
+    assert_eq!(reloaded.get_xml_trivia(&reloaded.get_entity_by_name("root").unwrap()).unwrap().trailing,
+        vec![XmlTrivia::ProcessingInstruction { name: "editor".to_string(), data: Some("folded".to_string()) }]);
+    assert_eq!(reloaded.trailing_trivia, vec![XmlTrivia::Comment(" end ".to_string())]);
+}
+
+#[test]
+fn test_set_properties() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" y="2"><Entity name="c" p="@root.x" q="@c.p" r="@root.y" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let c = doc.get_entity_by_name("c").unwrap();
+    let cascade = doc.set_properties(&root, vec![("x".to_string(), Pon::Integer(5)), ("y".to_string(), Pon::Integer(6))]).unwrap();
+    assert_eq!(cascade.len(), 5);
+    let position = |key: &str, entity_id: &EntityId| cascade.iter().position(|p| p == &PropRef::new(entity_id, key)).unwrap();
+    assert!(position("x", &root) < position("p", &c));
+    assert!(position("p", &c) < position("q", &c));
+    assert!(position("y", &root) < position("r", &c));
+    assert!(doc.set_properties(&root, vec![("x".to_string(), Pon::Integer(7)), ("z".to_string(), Pon::from_string("@missing.x").unwrap())]).is_err());
+    assert_eq!(*doc.get_property(&root, "x").unwrap(), Pon::Integer(5));
+}
+
+#[test]
+fn test_set_property_if() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.set_property_if(&root, "x", &Pon::Integer(1), Pon::Integer(2)).unwrap();
+    assert_eq!(doc.set_property_if(&root, "x", &Pon::Integer(1), Pon::Integer(3)),
+        Err(DocError::Conflict(PropRef::new(&root, "x"), Some(Pon::Integer(2)))));
+    assert_eq!(*doc.get_property(&root, "x").unwrap(), Pon::Integer(2));
+}
+
+#[test]
+fn test_tags() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" tags="['enemies']" /><Entity name="b" /></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    doc.add_tag(&b, "enemies").unwrap();
+    doc.add_tag(&b, "static").unwrap();
+    assert_eq!(doc.get_entities_by_tag("enemies"), vec![a, b]);
+    doc.remove_tag(&a, "enemies").unwrap();
+    assert_eq!(doc.get_entities_by_tag("enemies"), vec![b]);
+    assert_eq!(doc.get_tags(&b).unwrap(), vec!["enemies".to_string(), "static".to_string()]);
+    assert!(!doc.has_tag(&a, "enemies").unwrap());
+}
+
+#[test]
+fn test_tags_and_sets() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" /><Entity name="b" /></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    doc.add_tag(&a, "enemies").unwrap();
+    doc.create_set("enemies", vec![b]).unwrap();
+    assert_eq!(doc.get_entities_by_tag("enemies"), vec![a]);
+    assert_eq!(doc.get_set("enemies"), Some(vec![b]));
+    assert!(doc.get_entity_sets(&a).unwrap().is_empty());
+    assert!(!doc.has_tag(&b, "enemies").unwrap());
+    doc.delete_set("enemies").unwrap();
+    assert_eq!(doc.get_entities_by_tag("enemies"), vec![a]);
+    let reloaded = Document::from_string(&doc.to_string()).unwrap();
+    assert_eq!(reloaded.get_entities_by_tag("enemies"), vec![reloaded.get_entity_by_name("a").unwrap()]);
+    assert_eq!(reloaded.get_set("enemies"), None);
+}
+
+#[test]
+fn test_notification_window() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let notified = Rc::new(RefCell::new(vec![]));
+    let notified_cb = notified.clone();
+    doc.on_property_set = Some(Box::new(move |_, key| notified_cb.borrow_mut().push(key.to_string())));
+    doc.set_notification_window(Some(0));
+    for i in 0..10 {
+        doc.set_property(&root, "x", Pon::Integer(i)).unwrap();
+    }
+    assert_eq!(notified.borrow().len(), 0);
+    doc.flush_notifications();
+    assert_eq!(*notified.borrow(), vec!["x".to_string()]);
+}
+
+#[test]
+fn test_entities_by_type() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Mesh name="a" /><Light name="b" /><Mesh name="c" /></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let c = doc.get_entity_by_name("c").unwrap();
+    assert_eq!(doc.get_entities_by_type("Mesh").collect::<Vec<EntityId>>(), vec![a, c]);
+    doc.set_entity_type_name(&b, "Mesh").unwrap();
+    doc.remove_entity(&a).unwrap();
+    assert_eq!(doc.get_entities_by_type("Mesh").collect::<Vec<EntityId>>(), vec![c, b]);
+    assert_eq!(doc.get_entities_by_type("Light").count(), 0);
+}
+
+#[test]
+fn test_entity_version() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1"><Entity name="child" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let child = doc.get_entity_by_name("child").unwrap();
+    let root_version = doc.entity_version(&root).unwrap();
+    let x_version = doc.property_version(&root, "x").unwrap();
+    let child_version = doc.entity_version(&child).unwrap();
+    doc.set_property(&root, "x", Pon::Integer(2)).unwrap();
+    assert!(doc.entity_version(&root).unwrap() > root_version);
+    assert!(doc.property_version(&root, "x").unwrap() > x_version);
+    assert_eq!(doc.entity_version(&child).unwrap(), child_version);
+    assert_eq!(doc.property_version(&root, "y").unwrap(), 0);
+}
+
+#[test]
+fn test_find_entities() {
+    let doc = Document::from_string(r#"<Entity name="root"><Mesh name="a" visible="true" /><Mesh name="b" visible="false" /><Mesh visible="true" /></Entity>"#).unwrap();
+    let found = doc.find_entities(|entity| {
+        entity.type_name() == "Mesh" && entity.get_property("visible").map(|v| *v == Pon::Boolean(true)).unwrap_or(false)
+    });
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0], doc.get_entity_by_name("a").unwrap());
+    assert_eq!(doc.find_entities(|entity| entity.name() == Some("b")), vec![doc.get_entity_by_name("b").unwrap()]);
+}
+
+#[test]
+fn test_pending_and_committed_reads() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.begin_transaction().unwrap();
+    doc.set_property(&root, "x", Pon::Integer(2)).unwrap();
+    assert_eq!(*doc.get_property(&root, "x").unwrap(), Pon::Integer(2));
+    assert_eq!(doc.get_committed_property(&root, "x").unwrap(), Some(Pon::Integer(1)));
+    doc.commit().unwrap();
+    doc.set_double_buffered(true).unwrap();
+    doc.set_property(&root, "x", Pon::Integer(3)).unwrap();
+    assert_eq!(*doc.get_property(&root, "x").unwrap(), Pon::Integer(2));
+    assert_eq!(doc.get_pending_property(&root, "x").unwrap(), Some(Pon::Integer(3)));
+}
+
+#[test]
+fn test_tree_iterators() {
+    let doc = Document::from_string(r#"<Entity name="root"><Entity name="a"><Entity name="a1" /></Entity><Entity name="b" /></Entity>"#).unwrap();
+    let names = |ids: Vec<EntityId>| ids.iter().map(|id| doc.get_entity_name(id).unwrap().unwrap().to_string()).collect::<Vec<String>>();
+    let root = doc.get_entity_by_name("root").unwrap();
+    assert_eq!(names(doc.iter_tree_dfs(&root).collect()), vec!["root", "a", "a1", "b"]);
+    assert_eq!(names(doc.iter_tree_bfs(&root).collect()), vec!["root", "a", "b", "a1"]);
+}
+
+#[test]
+fn test_ancestors() {
+    let doc = Document::from_string(r#"<Entity name="root"><Entity name="a"><Entity name="a1" /></Entity><Entity name="b" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let a1 = doc.get_entity_by_name("a1").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    assert_eq!(doc.ancestors(&a1).collect::<Vec<EntityId>>(), vec![a, root, doc.get_root().unwrap()]);
+    assert_eq!(doc.ancestors(&root).count(), 1);
+    assert!(doc.is_ancestor_of(&root, &a1));
+    assert!(!doc.is_ancestor_of(&b, &a1));
+    assert!(!doc.is_ancestor_of(&a1, &a1));
+}
+
+#[test]
+fn test_scope_token() {
+    let mut doc = Document::from_string(r#"<Entity name="root" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let gizmo = {
+        let mut scope = doc.create_scope();
+        let gizmo = doc.append_scoped_entity(&mut scope, Some(root), "Gizmo", Some("gizmo".to_string())).unwrap();
+        doc.append_entity(Some(gizmo), "Handle", None).unwrap();
+        doc.set_property(&gizmo, "x", Pon::Integer(1)).unwrap();
+        doc.set_property(&root, "target", Pon::from_string("@gizmo.x").unwrap()).unwrap();
+        doc.collect_scopes().unwrap();
+        assert!(doc.get_entity_by_name("gizmo").is_some());
+        gizmo
+    };
+    assert_eq!(doc.collect_scopes().unwrap(), vec![PropRef::new(&root, "target")]);
+    assert!(doc.get_entity_by_name("gizmo").is_none());
+    assert!(!doc.entities.contains_key(&gizmo));
+    assert_eq!(doc.get_children(&root).unwrap().len(), 0);
+}
+
+#[test]
+fn test_scope_token_removed_on_next_change() {
+    let mut doc = Document::from_string(r#"<Entity name="root" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    {
+        let mut scope = doc.create_scope();
+        doc.append_scoped_entity(&mut scope, Some(root), "Gizmo", Some("gizmo".to_string())).unwrap();
+    }
+    doc.set_property(&root, "x", Pon::Integer(1)).unwrap();
+    assert!(doc.get_entity_by_name("gizmo").is_none());
+    assert_eq!(doc.get_children(&root).unwrap().len(), 0);
+}
+
+#[test]
+fn test_entity_ref() {
+    let mut doc = Document::from_string(r#"<Scene name="root"><Mesh name="a" x="5" /></Scene>"#).unwrap();
+    let b = {
+        let root = doc.get_entity_by_name("root").unwrap();
+        let mut root = doc.entity_mut(&root).unwrap();
+        let mut b = root.append_child("Mesh", Some("b".to_string())).unwrap();
+        b.set_prop("x", Pon::Integer(7)).unwrap();
+        b.id()
+    };
+    let root = doc.root_entity().unwrap();
+    assert_eq!(root.type_name(), "Scene");
+    assert_eq!(root.children().iter().map(|child| child.name().unwrap()).collect::<Vec<&str>>(), vec!["a", "b"]);
+    let a = root.child_by_name("a").unwrap();
+    assert_eq!(*a.prop("x").unwrap(), Pon::Integer(5));
+    assert!(a.prop("y").is_none());
+    assert_eq!(a.parent().unwrap().name(), Some("root"));
+    assert_eq!(*doc.entity(&b).unwrap().prop("x").unwrap(), Pon::Integer(7));
+    assert!(doc.entity(&1000).is_none());
+}
+
+#[test]
+fn test_random_ids() {
+    let mut doc = Document::new();
+    doc.set_id_generator(Box::new(::ids::RandomIds::new(42)));
+    let root = doc.append_entity(None, "Entity", Some("root".to_string())).unwrap();
+    let child = doc.append_entity(Some(root), "Entity", None).unwrap();
+    assert!(root != child);
+    assert!(root > 1000 && child > 1000);
+    assert_eq!(doc.get_parent(&child).unwrap(), Some(root));
+}
+
+#[test]
+fn test_cascade_limits() {
+    let mut doc = Document::from_string(r#"<Entity name="root" a="1" b="@this.a" c="@this.b" d="@this.c" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.set_cascade_limits(Some(CascadeLimits { max_depth: Some(2), max_size: None }));
+    let cascade = doc.build_cascade(vec![PropRef::new(&root, "a")]);
+    assert_eq!(cascade.len(), 3);
+    let diagnostic = doc.take_cascade_diagnostic().unwrap();
+    assert_eq!(diagnostic.limit, CascadeLimit::Depth(2));
+    assert_eq!(diagnostic.chain, vec![PropRef::new(&root, "a"), PropRef::new(&root, "b"), PropRef::new(&root, "c"), PropRef::new(&root, "d")]);
+    assert!(doc.take_cascade_diagnostic().is_none());
+}
+
+#[test]
+fn test_export_property_table() {
+    let doc = Document::from_string(r#"<Entity name="root"><Entity name="a" x="1" y="2" /><Entity name="b" /><Entity name="c" x="@a.y" /></Entity>"#).unwrap();
+    let table = doc.export_property_table(&["x", "y"]);
+    assert_eq!(table.entity_ids, vec![doc.get_entity_by_name("a").unwrap(), doc.get_entity_by_name("c").unwrap()]);
+    assert_eq!(table.column("x").unwrap(), &vec![Some(Pon::Integer(1)), Some(Pon::Integer(2))]);
+    assert_eq!(table.column("y").unwrap(), &vec![Some(Pon::Integer(2)), None]);
+    assert_eq!(table.row(&doc.get_entity_by_name("c").unwrap()), Some(1));
+}
+
+#[test]
+fn test_duplicate_names() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" /><Entity name="a" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let both = doc.get_entities_by_name("a");
+    assert_eq!(both.len(), 2);
+    assert_eq!(doc.get_entity_by_name("a"), Some(both[1]));
+    doc.set_duplicate_names(DuplicateNames::KeepFirst);
+    doc.append_entity(Some(root), "Entity", Some("a".to_string())).unwrap();
+    assert_eq!(doc.get_entity_by_name("a"), Some(both[1]));
+    doc.set_duplicate_names(DuplicateNames::Suffix);
+    let suffixed = doc.append_entity(Some(root), "Entity", Some("a".to_string())).unwrap();
+    assert_eq!(doc.get_entity_name(&suffixed).unwrap().unwrap(), "a_1");
+    doc.set_duplicate_names(DuplicateNames::Error);
+    assert_eq!(doc.append_entity(Some(root), "Entity", Some("a".to_string())), Err(DocError::NameTaken("a".to_string())));
+    assert_eq!(doc.get_entities_by_name("a").len(), 3);
+    match doc.append_from_string(Some(root), r#"<Entity><Entity name="a" /><Entity name="b" /></Entity>"#) {
+        Err(DocError::LoadError(err)) => assert!(err.position.is_some()),
+        res => panic!("Expected a load error, got {:?}", res)
+    }
+}
+
+#[test]
+fn test_provenance() {
+    let mut doc = Document::from_string(r#"<Entity name="root" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let time = Rc::new(Cell::new(100));
+    let clock_time = time.clone();
+    doc.record_provenance(Box::new(move || clock_time.get()), Some("alice".to_string()));
+    let child = doc.append_entity(Some(root), "Entity", Some("child".to_string())).unwrap();
+    time.set(120);
+    doc.set_property(&child, "x", Pon::Integer(1)).unwrap();
+    let expected = Provenance { created: Some(100), created_by: Some("alice".to_string()), modified: Some(120), modified_by: Some("alice".to_string()) };
+    assert_eq!(doc.get_provenance(&child), Some(&expected));
+    assert_eq!(doc.get_provenance(&root).unwrap().created, None);
+    let reloaded = Document::from_string(&doc.to_string()).unwrap();
+    assert_eq!(reloaded.get_provenance(&reloaded.get_entity_by_name("child").unwrap()), Some(&expected));
+}
+
+#[test]
+fn test_parent_of_root() {
+    let mut doc = Document::from_string(r#"<Entity name="top"><Entity name="child" /></Entity>"#).unwrap();
+    let root = doc.get_root().unwrap();
+    let top = doc.get_entity_by_name("top").unwrap();
+    let child = doc.get_entity_by_name("child").unwrap();
+    assert_eq!(doc.resolve_entity_path(&child, &EntityPath::Parent), Ok(top));
+    assert_eq!(doc.resolve_entity_path(&top, &EntityPath::Parent), Ok(root));
+    assert_eq!(doc.resolve_entity_path(&root, &EntityPath::Parent), Err(DocError::NoParent(root)));
+    assert_eq!(doc.set_property(&root, "x", Pon::from_string("@parent.x").unwrap()), Err(DocError::NoParent(root)));
+}
+
+#[test]
+fn test_keep_unknown_expressions() {
+    let xml = r#"<Entity name="root" x="5" y="future!syntax(1)" />"#;
+    let doc = Document::from_string(xml).unwrap();
+    assert!(!doc.has_property(&doc.get_entity_by_name("root").unwrap(), "y").unwrap());
+    let mut doc = Document::new();
+    doc.set_keep_unknown_expressions(true);
+    doc.append_from_string(None, xml).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    assert_eq!(*doc.get_property(&root, "y").unwrap(), Pon::Unknown("future!syntax(1)".to_string()));
+    assert!(doc.to_string().contains(r#"y="future!syntax(1)""#));
+}
+
+#[test]
+fn test_with_root() {
+    let doc = Document::new();
+    assert_eq!(doc.get_entity_type_name(&doc.get_root().unwrap()).unwrap(), ROOT_TYPE_NAME);
+    assert!(!doc.is_dirty());
+    let mut doc = Document::with_root("Scene");
+    let root = doc.get_root().unwrap();
+    assert_eq!(doc.get_entity_type_name(&root).unwrap(), "Scene");
+    doc.append_entity(Some(root), "Mesh", None).unwrap();
+    doc.append_entity(None, "Light", None).unwrap();
+    // The root isn't saved
+    assert!(!doc.to_string().contains("Scene"));
+    let reloaded = Document::from_string(&doc.to_string()).unwrap();
+    let children = reloaded.get_children(&reloaded.get_root().unwrap()).unwrap();
+    assert_eq!(children.iter().map(|id| reloaded.get_entity_type_name(id).unwrap()).collect::<Vec<&str>>(), vec!["Mesh", "Light"]);
+}
+
+#[test]
+#[cfg(feature = "fs")]
+fn test_entity_source() {
+    let path = ::std::env::temp_dir().join("pyramid_test_entity_source.xml");
+    File::create(&path).unwrap().write_all(b"<Entity name=\"lamp\" />").unwrap();
+    let doc = Document::from_string(&format!("<Entity name=\"root\">\n  <Include file=\"{}\" />\n  <Entity name=\"b\" />\n</Entity>", path.display())).unwrap();
+    let b = doc.get_entity_source(&doc.get_entity_by_name("b").unwrap()).unwrap();
+    assert_eq!(b.file, None);
+    assert_eq!(b.position.map(|(row, _)| row), Some(3));
+    let lamp = doc.get_entity_source(&doc.get_entity_by_name("lamp").unwrap()).unwrap();
+    assert_eq!(lamp.file, Some(path.clone()));
+    assert_eq!(lamp.position.map(|(row, _)| row), Some(1));
+    assert_eq!(lamp.include_stack.len(), 0);
+}
+
+#[test]
+fn test_on_change() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" y="@this.x" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let events = Rc::new(RefCell::new(vec![]));
+    let events_sink = events.clone();
+    doc.on_change(Box::new(move |event| events_sink.borrow_mut().push(event.clone())));
+    let child = doc.append_entity(Some(root), "Entity", None).unwrap();
+    doc.set_property(&root, "x", Pon::Integer(2)).unwrap();
+    doc.remove_entity(&child).unwrap();
+    let events = events.borrow();
+    assert_eq!(events[0], DocEvent::EntityAdded(child));
+    match events[1] {
+        DocEvent::PropertyChanged { ref prop_ref, ref cascade } => {
+            assert_eq!(*prop_ref, PropRef::new(&root, "x"));
+            assert!(cascade.contains(&PropRef::new(&root, "y")));
+        },
+        ref event => panic!("Unexpected {:?}", event)
+    }
+    assert_eq!(events[2], DocEvent::EntityRemoved(child));
+}
+
+#[test]
+fn test_cascade_stages() {
+    let mut doc = Document::from_string(r#"<Entity name="root" force="1" velocity="@this.force" position="@this.velocity" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.set_cascade_barrier(PropRef::new(&root, "velocity"), "physics");
+    let stages = doc.build_cascade_stages(vec![PropRef::new(&root, "force")]);
+    assert_eq!(stages, vec![
+        CascadeStage { barrier: None, properties: vec![PropRef::new(&root, "force"), PropRef::new(&root, "velocity")] },
+        CascadeStage { barrier: Some("physics".to_string()), properties: vec![PropRef::new(&root, "position")] }
+    ]);
+    doc.remove_cascade_barrier(&PropRef::new(&root, "velocity"));
+    assert_eq!(doc.build_cascade_stages(vec![PropRef::new(&root, "force")]).len(), 1);
+}
+
+#[test]
+fn test_subscribe() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let events = doc.subscribe();
+    let child = doc.append_entity(Some(root), "Entity", None).unwrap();
+    doc.set_property(&root, "x", Pon::Integer(2)).unwrap();
+    assert_eq!(events.recv().unwrap(), DocEvent::EntityAdded(child));
+    assert_eq!(events.recv().unwrap(), DocEvent::PropertyChanged {
+        prop_ref: PropRef::new(&root, "x"),
+        cascade: vec![PropRef::new(&root, "x")]
+    });
+    assert!(events.try_recv().is_err());
+    drop(events);
+    doc.remove_entity(&child).unwrap();
+    assert_eq!(doc.subscribers.borrow().len(), 0);
+}
+
+#[test]
+fn test_entity_ordinal() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a"><Entity name="b" /></Entity><Entity name="c" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let c = doc.get_entity_by_name("c").unwrap();
+    // The document's root is 0
+    assert_eq!(doc.entity_ordinal(&root), Some(1));
+    assert_eq!(doc.entity_ordinal(&c), Some(4));
+    assert_eq!(doc.entity_at_ordinal(3), Some(b));
+    assert_eq!(doc.entity_at_ordinal(5), None);
+    let d = doc.append_entity(Some(a), "Entity", None).unwrap();
+    assert_eq!(doc.entity_ordinal(&d), Some(4));
+    assert_eq!(doc.entity_ordinal(&c), Some(5));
+    doc.remove_entity(&a).unwrap();
+    assert_eq!(doc.entity_ordinal(&c), Some(2));
+    assert_eq!(doc.entity_ordinal(&b), None);
+}
+
+#[test]
+fn test_observe() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" y="@this.x" z="2" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let changes = Rc::new(RefCell::new(vec![]));
+    let changes_sink = changes.clone();
+    doc.observe(PropRef::new(&root, "y"), Box::new(move |prop_ref| changes_sink.borrow_mut().push(prop_ref.clone())));
+    doc.set_property(&root, "x", Pon::Integer(2)).unwrap();
+    doc.set_property(&root, "z", Pon::Integer(3)).unwrap();
+    assert_eq!(*changes.borrow(), vec![PropRef::new(&root, "x")]);
+    doc.unobserve(&PropRef::new(&root, "y"));
+    doc.set_property(&root, "x", Pon::Integer(3)).unwrap();
+    assert_eq!(changes.borrow().len(), 1);
+}
+
+#[test]
+fn test_write_xml_progress() {
+    let doc = Document::from_string(r#"<Entity name="root"><Entity name="a" /><Entity name="b" /></Entity>"#).unwrap();
+    let calls = RefCell::new(vec![]);
+    let mut out = vec![];
+    let progress = |written: usize, total: usize| calls.borrow_mut().push((written, total));
+    doc.write_xml_with_progress(&mut out, Some(&progress as &Fn(usize, usize))).unwrap();
+    assert_eq!(*calls.borrow(), vec![(1, 3), (2, 3), (3, 3)]);
+    assert_eq!(String::from_utf8(out).unwrap(), doc.to_string());
+}
+
+#[test]
+fn test_top_level_entities() {
+    let mut doc = Document::from_string(r#"<Entity name="a" /><Entity name="b" />"#).unwrap();
+    let root = doc.get_root().unwrap();
+    doc.append_from_string(None, r#"<Entity name="c" />"#).unwrap();
+    let d = doc.append_entity(None, "Entity", Some("d".to_string())).unwrap();
+    assert_eq!(doc.get_parent(&d).unwrap(), Some(root));
+    let names: Vec<&str> = doc.get_children(&root).unwrap().iter().map(|id| doc.get_entity_name(id).unwrap().unwrap()).collect();
+    assert_eq!(names, vec!["a", "b", "c", "d"]);
+    assert_eq!(Document::from_string(&doc.to_string()).unwrap().to_string(), doc.to_string());
+}
+
+#[test]
+#[cfg(feature = "fs")]
+fn test_save_dirty_includes() {
+    let dir = ::std::env::temp_dir();
+    let a_path = dir.join("pyramid_test_save_dirty_a.xml");
+    let b_path = dir.join("pyramid_test_save_dirty_b.xml");
+    let path = dir.join("pyramid_test_save_dirty.xml");
+    File::create(&a_path).unwrap().write_all(br#"<Entity name="a" x="1" />"#).unwrap();
+    let b_source = r#"<Entity   name="b"   y="2" />"#;
+    File::create(&b_path).unwrap().write_all(b_source.as_bytes()).unwrap();
+    let source = format!("<Entity   name=\"root\">\n  <Include file=\"{}\" />\n  <Include file=\"{}\" />\n</Entity>", a_path.display(), b_path.display());
+    File::create(&path).unwrap().write_all(source.as_bytes()).unwrap();
+    let mut doc = Document::from_file(&path).unwrap();
+    doc.dirty_entities.clear();
+    let a = doc.get_entity_by_name("a").unwrap();
+    doc.set_property(&a, "x", Pon::Integer(5)).unwrap();
+    assert_eq!(doc.save_dirty(&path), Ok(true));
+    let read = |path: &Path| {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    };
+    // Only the file a came from is written
+    assert_eq!(read(&path), source);
+    assert_eq!(read(&b_path), b_source);
+    let reloaded = Document::from_file(&path).unwrap();
+    assert_eq!(*reloaded.get_property(&reloaded.get_entity_by_name("a").unwrap(), "x").unwrap(), Pon::Integer(5));
+    assert_eq!(*reloaded.get_property(&reloaded.get_entity_by_name("b").unwrap(), "y").unwrap(), Pon::Integer(2));
+    // Changes outside the includes rewrite the top level file, with the Include elements kept
+    let mut doc = reloaded;
+    doc.dirty_entities.clear();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.set_property(&root, "z", Pon::Integer(3)).unwrap();
+    assert_eq!(doc.save_dirty(&path), Ok(true));
+    assert_eq!(read(&b_path), b_source);
+    assert_eq!(read(&path).matches("<Include").count(), 2);
+    assert_eq!(Document::from_file(&path).unwrap().to_string(), doc.to_string());
+}
+
+#[test]
+fn test_memory_resolver() {
+    let files = Rc::new(MemoryResolver::new());
+    files.insert(Path::new("level/lamp.xml"), br#"<Entity name="lamp" x="1" />"#.to_vec());
+    let mut doc = Document::new();
+    doc.include_resolver = Some(Box::new(files.clone()));
+    doc.append_from_string(None, r#"<Entity name="root"><Include file="level/lamp.xml" /></Entity>"#).unwrap();
+    let lamp = doc.get_entity_by_name("lamp").unwrap();
+    assert_eq!(doc.get_parent(&lamp).unwrap(), doc.get_entity_by_name("root"));
+    doc.include_resolver = None;
+    doc.append_from_string(None, r#"<Include file="level/lamp.xml" />"#).unwrap();
+    assert_eq!(doc.get_entities_by_type("Entity").count(), 2);
+}
+
+#[test]
+fn test_freeze_subtree() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1"><Entity name="a" y="@parent.x" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    doc.freeze_subtree(&a).unwrap();
+    assert_eq!(doc.set_property(&a, "y", Pon::Integer(3)), Err(DocError::EntityFrozen(a)));
+    doc.set_property(&root, "x", Pon::Integer(2)).unwrap();
+    assert_eq!(doc.get_property(&a, "y").unwrap().concretize().unwrap(), Pon::Integer(1));
+    doc.unfreeze_subtree(&a).unwrap();
+    assert_eq!(doc.get_property(&a, "y").unwrap().concretize().unwrap(), Pon::Integer(2));
+}
+
+#[test]
+fn test_load_error() {
+    match Document::from_string(r#"<Entity name="root"><Entity></Entit></Entity>"#) {
+        Err(DocError::LoadError(LoadError { file: None, position: Some((1, _)), .. })) => {},
+        res => panic!("Expected a load error, got {:?}", res.err())
+    }
+    match Document::from_string(r#"<Entity x="1" x="2" />"#) {
+        Err(DocError::LoadError(err)) => {
+            assert_eq!(err.message, "Duplicate attribute x on Entity");
+            assert_eq!(err.position.map(|(row, _)| row), Some(1));
+        },
+        res => panic!("Expected a load error, got {:?}", res.err())
+    }
+}
+
+#[test]
+fn test_property_floats() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" positions="[1.0, 2, 3.5]" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(&*doc.get_property_floats(&ent, "positions").unwrap(), &[1.0, 2.0, 3.5]);
+    doc.modify_property_floats(&ent, "positions", |floats| for v in floats.iter_mut() { *v *= 2.0 }).unwrap();
+    // Now packed, so read without copying
+    match doc.get_property_floats(&ent, "positions").unwrap() {
+        NumericSlice::Borrowed(floats) => assert_eq!(&*floats, &[2.0, 4.0, 7.0]),
+        NumericSlice::Converted(_) => panic!("Expected the packed floats to be borrowed")
+    }
+    assert!(doc.get_property_integers(&ent, "positions").is_err());
+    // Reading integers as floats leaves them integers, and edits can be rolled back
+    doc.set_property(&ent, "ids", Pon::from_string("[1, 2]").unwrap()).unwrap();
+    assert_eq!(&*doc.get_property_floats(&ent, "ids").unwrap(), &[1.0, 2.0]);
+    doc.begin_transaction().unwrap();
+    doc.modify_property_integers(&ent, "ids", |integers| integers[0] = 5).unwrap();
+    assert_eq!(&*doc.get_property_integers(&ent, "ids").unwrap(), &[5, 2]);
+    doc.rollback().unwrap();
+    assert_eq!(&*doc.get_property_integers(&ent, "ids").unwrap(), &[1, 2]);
+}
+
+#[test]
+fn test_set_property_partitioned() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1"><Mesh name="mesh" transform="@parent.x" /><Sound name="sound" volume="@parent.x" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let mesh = doc.get_entity_by_name("mesh").unwrap();
+    doc.register_interest("renderer", InterestSet::new(Some("Mesh"), vec!["transform", "material_*"]));
+    doc.register_interest("audio", InterestSet::new(None, vec!["volume"]));
+    let partitions = doc.set_property_partitioned(&root, "x", Pon::Integer(2)).unwrap();
+    assert_eq!(partitions["renderer"], vec![PropRef::new(&mesh, "transform")]);
+    assert_eq!(partitions["audio"].len(), 1);
+}
+
+#[test]
+fn test_double_buffered() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="1" y="@this.x" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.set_double_buffered(true).unwrap();
+    doc.set_property(&ent, "x", Pon::Integer(2)).unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Integer(1));
+    doc.flip().unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Integer(2));
+}
+
+#[test]
+fn test_entity_sets() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" /><Entity name="b" /></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    doc.create_set("spawn_points", vec![a, b]).unwrap();
+    doc.remove_from_set("spawn_points", &a).unwrap();
+    assert_eq!(doc.get_set("spawn_points"), Some(vec![b]));
+    let reloaded = Document::from_string(&doc.to_string()).unwrap();
+    let b = reloaded.get_entity_by_name("b").unwrap();
+    assert_eq!(reloaded.get_set("spawn_points"), Some(vec![b]));
+}
+
+#[test]
+fn test_repair() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="player_1" hp="10" /><Entity name="hud" hp="@player1.hp" max="@player_1.max_hp" /></Entity>"#).unwrap();
+    let hud = doc.get_entity_by_name("hud").unwrap();
+    let report = doc.repair(&RepairMode::ReportOnly).unwrap();
+    assert_eq!(report.iter().map(|b| (b.missing.clone(), b.suggestions.clone())).collect::<Vec<(MissingTarget, Vec<String>)>>(), vec![
+        (MissingTarget::Entity("player1".to_string()), vec!["player_1".to_string()]),
+        (MissingTarget::Property("max_hp".to_string()), vec![])
+    ]);
+    doc.repair(&RepairMode::AutoFix(Box::new(|broken: &BrokenReference| broken.suggestions.first().cloned()))).unwrap();
+    assert_eq!(doc.get_property(&hud, "hp").unwrap().concretize().unwrap(), Pon::Integer(10));
+}
+
+#[test]
+fn test_subtree_hashes() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" x="1" /><Entity name="b" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let before = doc.subtree_hashes();
+    assert_eq!(before.iter().map(|x| x.0).collect::<Vec<EntityId>>(), vec![doc.get_root().unwrap(), root, a, b]);
+    doc.set_property(&a, "x", Pon::Integer(2)).unwrap();
+    let after = doc.subtree_hashes();
+    assert!(before[1].1 != after[1].1);
+    assert!(before[2].1 != after[2].1);
+    assert_eq!(before[3].1, after[3].1);
+    assert_eq!(doc.subtree_hash(&b).unwrap(), after[3].1);
+}
+
+#[test]
+fn test_publish_metrics() {
+    struct Sink(RefCell<Vec<(String, f64)>>);
+    impl MetricsSink for Sink {
+        fn counter(&self, name: &str, value: u64) { self.0.borrow_mut().push((name.to_string(), value as f64)); }
+        fn gauge(&self, name: &str, value: f64) { self.0.borrow_mut().push((name.to_string(), value)); }
+    }
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="1" y="@this.x" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.set_property_partitioned(&ent, "x", Pon::Integer(2)).unwrap();
+    let sink = Sink(RefCell::new(vec![]));
+    doc.publish_metrics(&sink, 2.0);
+    let metrics = sink.0.borrow();
+    let get = |name: &str| metrics.iter().find(|m| m.0 == name).map(|m| m.1);
+    assert_eq!(get("pyramid.properties_set"), Some(3.0));
+    assert_eq!(get("pyramid.properties_set_per_second"), Some(1.5));
+    assert_eq!(get("pyramid.average_cascade_length"), Some(2.0));
+}
+
+#[test]
+fn test_hot_report() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="1" y="@this.x" z="@this.y" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.record_access_patterns(true);
+    // Cascades built to notify subscribers aren't recorded
+    let _events = doc.subscribe();
+    doc.set_property_partitioned(&ent, "x", Pon::Integer(2)).unwrap();
+    doc.set_property_partitioned(&ent, "y", Pon::from_string("@this.x").unwrap()).unwrap();
+    let report = doc.hot_report(1).unwrap();
+    assert_eq!(report.most_cascaded, vec![(PropRef::new(&ent, "y"), 2)]);
+    assert_eq!(report.slowest_resolves.len(), 1);
+}
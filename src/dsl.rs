@@ -0,0 +1,139 @@
+
+use std::slice::SliceConcatExt;
+
+use document::*;
+use pon::*;
+use diff::{DocumentPatch, AddedEntity, PatchEntity};
+
+// A one line notation for small documents, mostly for tests and tools. Each `/` goes one level down, `..`
+// goes back up to the parent:
+//
+//   Scene(name=root)/Mesh(name=a, x=1)/../Mesh(y=@a.x)
+//
+// is a Scene with two Mesh children. Attribute values are PON, and may refer to entities defined later.
+pub fn document_from_dsl(source: &str) -> Result<Document, DocError> {
+    let entity = try!(entity_from_dsl(source));
+    let mut doc = Document::new();
+    try!(doc.apply_patch(&DocumentPatch {
+        removed: vec![],
+        added: vec![AddedEntity { parent_id: None, index: 0, entity: entity }],
+        retyped: vec![],
+        properties: vec![]
+    }));
+    Ok(doc)
+}
+
+// The subtree a line of the notation describes
+pub fn entity_from_dsl(source: &str) -> Result<PatchEntity, DocError> {
+    // The path from the top entity down to the current one; entities join their parent when popped
+    let mut stack: Vec<PatchEntity> = vec![];
+    for segment in split_top_level(source, '/') {
+        let segment = segment.trim();
+        if segment == ".." {
+            if stack.len() < 2 {
+                return Err(dsl_error(format!("`..` above the root in {}", source)));
+            }
+            pop_child(&mut stack);
+            continue;
+        }
+        stack.push(try!(parse_segment(source, segment)));
+    }
+    while stack.len() > 1 {
+        pop_child(&mut stack);
+    }
+    match stack.pop() {
+        Some(entity) => Ok(entity),
+        None => Err(dsl_error(format!("Missing type name in {}", source)))
+    }
+}
+
+// The notation for entity, which entity_from_dsl reads back
+pub fn entity_to_dsl(entity: &PatchEntity) -> String {
+    let mut attributes = vec![];
+    if let Some(ref name) = entity.name {
+        attributes.push(format!("name={}", name));
+    }
+    for &(ref key, ref value) in &entity.properties {
+        attributes.push(format!("{}={}", key, value.to_string()));
+    }
+    let mut dsl = if attributes.len() > 0 {
+        format!("{}({})", entity.type_name, attributes.join(", "))
+    } else {
+        entity.type_name.clone()
+    };
+    for child in &entity.children {
+        dsl.push_str(&format!("/{}/..", entity_to_dsl(child)));
+    }
+    dsl
+}
+
+fn pop_child(stack: &mut Vec<PatchEntity>) {
+    let child = stack.pop().unwrap();
+    stack.last_mut().unwrap().children.push(child);
+}
+
+fn parse_segment(source: &str, segment: &str) -> Result<PatchEntity, DocError> {
+    let (type_name, attributes) = match segment.find('(') {
+        Some(i) if segment.ends_with(")") => (segment[..i].trim(), &segment[i + 1..segment.len() - 1]),
+        Some(_) => return Err(dsl_error(format!("Missing `)` in {}", segment))),
+        None => (segment, "")
+    };
+    if type_name.len() == 0 {
+        return Err(dsl_error(format!("Missing type name in {}", source)));
+    }
+    let mut entity = PatchEntity { type_name: type_name.to_string(), name: None, properties: vec![], children: vec![] };
+    for attribute in split_top_level(attributes, ',') {
+        if attribute.trim().len() == 0 {
+            continue;
+        }
+        let (key, value) = match attribute.find('=') {
+            Some(i) => (attribute[..i].trim(), attribute[i + 1..].trim()),
+            None => return Err(dsl_error(format!("Expected key=value, found {}", attribute)))
+        };
+        if key == "name" {
+            entity.name = Some(value.to_string());
+            continue;
+        }
+        let value = try!(Pon::from_string(value).map_err(|err| dsl_error(format!("Bad value for {}: {:?}", key, err))));
+        entity.properties.push((key.to_string(), value));
+    }
+    Ok(entity)
+}
+
+fn dsl_error(message: String) -> DocError {
+    DocError::LoadError(LoadError { file: None, position: None, message: message })
+}
+
+// Splits on separator outside of brackets and quotes
+fn split_top_level(source: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in source.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '(' | '[' | '{' if !quoted => depth += 1,
+            ')' | ']' | '}' if !quoted => depth -= 1,
+            c if c == separator && depth == 0 && !quoted => {
+                parts.push(&source[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    parts.push(&source[start..]);
+    parts
+}
+
+
+#[test]
+fn test_document_from_dsl() {
+    let doc = Document::from_dsl("Scene(name=root)/Mesh(name=a, x=1)/../Mesh(name=b, y=@a.x, v=[1, 2])").unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    assert_eq!(doc.get_entity_type_name(&root).unwrap(), "Scene");
+    assert_eq!(doc.get_children(&root).unwrap().len(), 2);
+    let b = doc.get_entity_by_name("b").unwrap();
+    assert_eq!(doc.get_property(&b, "y").unwrap().concretize().unwrap(), Pon::Integer(1));
+    assert!(Document::from_dsl("Scene/../Scene").is_err());
+}
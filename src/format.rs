@@ -0,0 +1,181 @@
+
+use std::slice::SliceConcatExt;
+
+use xml::reader::EventReader;
+use xml::reader::events::*;
+use xml::common::XmlVersion;
+use xml::attribute::OwnedAttribute;
+
+use document::*;
+use pon::*;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AttributeOrder {
+    // As written in the source
+    Source,
+    Alphabetical,
+    // `name` first, then the rest alphabetically
+    NameFirst
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct FormatStyle {
+    pub indent: String,
+    pub attribute_order: AttributeOrder,
+    // Elements whose start tag would be longer than this get one attribute per line
+    pub max_line_width: usize
+}
+
+impl FormatStyle {
+    pub fn default() -> FormatStyle {
+        FormatStyle {
+            indent: "  ".to_string(),
+            attribute_order: AttributeOrder::NameFirst,
+            max_line_width: 100
+        }
+    }
+}
+
+pub fn escape_attribute(value: &str) -> String {
+    value.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+pub fn escape_text(value: &str) -> String {
+    value.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;")
+}
+
+// Normalizes an attribute value through the PON printer, leaving anything that doesn't parse untouched
+fn format_value(key: &str, value: &str) -> String {
+    if key == "name" {
+        return value.to_string();
+    }
+    match Pon::from_string(value) {
+        Ok(pon) => pon.to_string(),
+        Err(_) => value.to_string()
+    }
+}
+
+fn start_tag(name: &str, attributes: &Vec<OwnedAttribute>, depth: usize, style: &FormatStyle, self_closing: bool) -> String {
+    let mut attrs: Vec<(String, String)> = attributes.iter()
+        .map(|a| (a.name.local_name.to_string(), format_value(&a.name.local_name, &a.value)))
+        .collect();
+    match style.attribute_order {
+        AttributeOrder::Source => {},
+        AttributeOrder::Alphabetical => attrs.sort_by(|a, b| a.0.cmp(&b.0)),
+        AttributeOrder::NameFirst => attrs.sort_by(|a, b| (a.0 != "name", &a.0).cmp(&(b.0 != "name", &b.0)))
+    }
+    let indent: String = (0..depth).map(|_| style.indent.as_str()).collect::<Vec<&str>>().concat();
+    let close = if self_closing { " />" } else { ">" };
+    let parts: Vec<String> = attrs.iter().map(|&(ref k, ref v)| format!("{}=\"{}\"", k, escape_attribute(v))).collect();
+    let single_line = if parts.len() == 0 {
+        format!("{}<{}{}", indent, name, close)
+    } else {
+        format!("{}<{} {}{}", indent, name, parts.join(" "), close)
+    };
+    if single_line.len() <= style.max_line_width || parts.len() <= 1 {
+        return single_line;
+    }
+    let attr_indent = format!("{}{}", indent, style.indent);
+    let lines: Vec<String> = parts.iter().map(|p| format!("{}{}", attr_indent, p)).collect();
+    format!("{}<{}\n{}{}", indent, name, lines.join("\n"), close)
+}
+
+enum Pending {
+    Nothing,
+    Start { name: String, attributes: Vec<OwnedAttribute> }
+}
+
+// Reformats a document's xml and the PON in its attributes. Include elements and comments are kept as they
+// are; the result is checked to load into the same entities and properties as the source.
+pub fn format_xml(source: &str, style: &FormatStyle) -> Result<String, DocError> {
+    let mut out: Vec<String> = vec![];
+    let mut depth = 0;
+    let mut pending = Pending::Nothing;
+    let mut parser = EventReader::from_str(source);
+    for e in parser.events() {
+        if let Pending::Start { name, attributes } = ::std::mem::replace(&mut pending, Pending::Nothing) {
+            if let XmlEvent::EndElement { .. } = e {
+                out.push(start_tag(&name, &attributes, depth, style, true));
+                continue;
+            }
+            out.push(start_tag(&name, &attributes, depth, style, false));
+            depth += 1;
+        }
+        let indent: String = (0..depth).map(|_| style.indent.as_str()).collect::<Vec<&str>>().concat();
+        match e {
+            XmlEvent::StartDocument { version, .. } => {
+                let version = match version {
+                    XmlVersion::Version10 => "1.0",
+                    XmlVersion::Version11 => "1.1"
+                };
+                out.push(format!("<?xml version=\"{}\" encoding=\"UTF-8\"?>", version));
+            },
+            XmlEvent::StartElement { name, attributes, .. } => {
+                pending = Pending::Start { name: name.local_name.to_string(), attributes: attributes };
+            },
+            XmlEvent::EndElement { name } => {
+                depth -= 1;
+                let indent: String = (0..depth).map(|_| style.indent.as_str()).collect::<Vec<&str>>().concat();
+                out.push(format!("{}</{}>", indent, name.local_name));
+            },
+            XmlEvent::Comment(text) => out.push(format!("{}<!--{}-->", indent, text)),
+            XmlEvent::Characters(text) => out.push(format!("{}{}", indent, escape_text(text.trim()))),
+            XmlEvent::CData(text) => out.push(format!("{}<![CDATA[{}]]>", indent, text)),
+            XmlEvent::Error(err) => return Err(DocError::FormatError(format!("{}", err))),
+            _ => {}
+        }
+    }
+    let mut formatted = out.join("\n");
+    formatted.push('\n');
+    let before = try!(Document::from_string(source));
+    let after = try!(Document::from_string(&formatted));
+    if !same_document(&before, &after) {
+        return Err(DocError::FormatError("Formatting would change the document".to_string()));
+    }
+    Ok(formatted)
+}
+
+fn same_entity(a: &Document, a_id: &EntityId, b: &Document, b_id: &EntityId) -> bool {
+    if a.get_entity_type_name(a_id).ok() != b.get_entity_type_name(b_id).ok() ||
+        a.get_entity_name(a_id).ok() != b.get_entity_name(b_id).ok() {
+        return false;
+    }
+    let mut a_keys: Vec<String> = a.get_properties(a_id).unwrap_or(vec![]).into_iter()
+        .filter(|p| a.has_property(a_id, &p.property_key).unwrap_or(false)).map(|p| p.property_key).collect();
+    let mut b_keys: Vec<String> = b.get_properties(b_id).unwrap_or(vec![]).into_iter()
+        .filter(|p| b.has_property(b_id, &p.property_key).unwrap_or(false)).map(|p| p.property_key).collect();
+    a_keys.sort();
+    b_keys.sort();
+    if a_keys != b_keys {
+        return false;
+    }
+    for key in &a_keys {
+        match (a.get_property(a_id, key), b.get_property(b_id, key)) {
+            (Ok(a_value), Ok(b_value)) => if *a_value != *b_value { return false; },
+            _ => return false
+        }
+    }
+    match (a.get_children(a_id), b.get_children(b_id)) {
+        (Ok(a_children), Ok(b_children)) => a_children.len() == b_children.len() &&
+            a_children.iter().zip(b_children.iter()).all(|(a_child, b_child)| same_entity(a, a_child, b, b_child)),
+        _ => false
+    }
+}
+
+// Structural equality: same tree of types, names and property expressions
+pub fn same_document(a: &Document, b: &Document) -> bool {
+    match (a.get_root(), b.get_root()) {
+        (Some(a_root), Some(b_root)) => same_entity(a, &a_root, b, &b_root),
+        (None, None) => true,
+        _ => false
+    }
+}
+
+
+#[test]
+fn test_format_xml() {
+    let source = r#"<Entity    x="5.0" name="tmp"><Entity y="{b:1,a:2}"/></Entity>"#;
+    let formatted = format_xml(source, &FormatStyle::default()).unwrap();
+    assert_eq!(formatted, format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Entity name=\"tmp\" x=\"{}\">\n  <Entity y=\"{{ a: 2, b: 1 }}\" />\n</Entity>\n",
+        Pon::Float(5.0).to_string()));
+}
@@ -0,0 +1,317 @@
+
+use std::collections::HashMap;
+use std::collections::BTreeMap;
+use rustc_serialize::json::Json;
+use cgmath::Matrix4;
+
+use document::*;
+use pon::*;
+
+fn import_err(message: &str) -> DocError {
+    DocError::ImportError(message.to_string())
+}
+
+fn floats(json: Option<&Json>) -> Option<Vec<f32>> {
+    match json.and_then(|x| x.as_array()) {
+        Some(arr) => arr.iter().map(|v| v.as_f64().map(|v| v as f32)).collect(),
+        None => None
+    }
+}
+
+fn xyz(v: &Vec<f32>) -> Pon {
+    Pon::Object(hashmap!(
+        "x" => Pon::Float(v[0]),
+        "y" => Pon::Float(v[1]),
+        "z" => Pon::Float(v[2])
+    ))
+}
+
+// glTF nodes carry either a column-major matrix or translation/rotation/scale, both map onto the
+// transforms pon_to_cgmath knows how to translate
+fn node_transform(node: &Json) -> Option<Pon> {
+    if let Some(matrix) = floats(node.find("matrix")) {
+        return Some(Pon::new_typed_pon("matrix", Pon::FloatArray(matrix)));
+    }
+    let mut parts = vec![];
+    if let Some(t) = floats(node.find("translation")) {
+        parts.push(Pon::new_typed_pon("translate", xyz(&t)));
+    }
+    if let Some(r) = floats(node.find("rotation")) {
+        parts.push(Pon::new_typed_pon("rotate_quaternion", Pon::Object(hashmap!(
+            "x" => Pon::Float(r[0]),
+            "y" => Pon::Float(r[1]),
+            "z" => Pon::Float(r[2]),
+            "w" => Pon::Float(r[3])
+        ))));
+    }
+    if let Some(s) = floats(node.find("scale")) {
+        parts.push(Pon::new_typed_pon("scale", xyz(&s)));
+    }
+    match parts.len() {
+        0 => None,
+        _ => Some(Pon::new_typed_pon("mul", Pon::Array(parts)))
+    }
+}
+
+fn name_of(json: &Json) -> Option<String> {
+    json.find("name").and_then(|x| x.as_string()).map(|x| x.to_string())
+}
+
+fn list<'a>(gltf: &'a Json, key: &str) -> &'a [Json] {
+    match gltf.find(key).and_then(|x| x.as_array()) {
+        Some(arr) => arr,
+        None => &[]
+    }
+}
+
+struct Importer<'a> {
+    document: &'a mut Document,
+    gltf: &'a Json,
+    material_names: HashMap<u64, String>
+}
+
+impl<'a> Importer<'a> {
+    fn import_materials(&mut self, parent_id: EntityId) -> Result<(), DocError> {
+        let materials = list(self.gltf, "materials");
+        if materials.len() == 0 {
+            return Ok(());
+        }
+        let container = try!(self.document.append_entity(Some(parent_id), "Materials", None));
+        for (i, material) in materials.iter().enumerate() {
+            let name = name_of(material).unwrap_or(format!("material_{}", i));
+            let id = try!(self.document.append_entity(Some(container), "Material", Some(name.clone())));
+            if let Some(c) = floats(material.find_path(&["pbrMetallicRoughness", "baseColorFactor"])) {
+                try!(self.document.set_property(&id, "base_color", Pon::new_typed_pon("vec4", Pon::Object(hashmap!(
+                    "x" => Pon::Float(c[0]),
+                    "y" => Pon::Float(c[1]),
+                    "z" => Pon::Float(c[2]),
+                    "w" => Pon::Float(c[3])
+                )))));
+            }
+            self.material_names.insert(i as u64, name);
+        }
+        Ok(())
+    }
+    fn import_mesh(&mut self, parent_id: EntityId, mesh_index: u64) -> Result<(), DocError> {
+        let meshes = list(self.gltf, "meshes");
+        let mesh = match meshes.get(mesh_index as usize) {
+            Some(mesh) => mesh,
+            None => return Err(import_err(&format!("Node references missing mesh {}", mesh_index)))
+        };
+        let primitives = list(mesh, "primitives");
+        for (i, primitive) in primitives.iter().enumerate() {
+            let id = try!(self.document.append_entity(Some(parent_id), "Mesh", None));
+            try!(self.document.set_property(&id, "mesh", Pon::String(name_of(mesh).unwrap_or(format!("mesh_{}", mesh_index)))));
+            try!(self.document.set_property(&id, "primitive", Pon::Integer(i as i64)));
+            if let Some(material) = primitive.find("material").and_then(|x| x.as_u64()) {
+                if let Some(name) = self.material_names.get(&material) {
+                    try!(self.document.set_property(&id, "material", Pon::String(name.clone())));
+                }
+            }
+        }
+        Ok(())
+    }
+    fn import_node(&mut self, parent_id: EntityId, node_index: u64) -> Result<(), DocError> {
+        let nodes = list(self.gltf, "nodes");
+        let node = match nodes.get(node_index as usize) {
+            Some(node) => node,
+            None => return Err(import_err(&format!("Missing node {}", node_index)))
+        };
+        let id = try!(self.document.append_entity(Some(parent_id), "Node", name_of(node)));
+        if let Some(transform) = node_transform(node) {
+            try!(self.document.set_property(&id, "transform", transform));
+        }
+        if let Some(mesh) = node.find("mesh").and_then(|x| x.as_u64()) {
+            try!(self.import_mesh(id, mesh));
+        }
+        if let Some(children) = node.find("children").and_then(|x| x.as_array()) {
+            for child in children {
+                match child.as_u64() {
+                    Some(child) => try!(self.import_node(id, child)),
+                    None => return Err(import_err("Node child index is not an integer"))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Imports the default scene of a glTF 2.0 document (JSON form) as a `Scene` entity under parent_id.
+// Nodes become `Node` entities with a `transform`, meshes become `Mesh` children naming their `material`,
+// and materials are collected under a `Materials` entity.
+pub fn import_gltf(document: &mut Document, parent_id: EntityId, source: &str) -> Result<EntityId, DocError> {
+    let gltf = match Json::from_str(source) {
+        Ok(gltf) => gltf,
+        Err(err) => return Err(DocError::ImportError(format!("{:?}", err)))
+    };
+    let scene_index = gltf.find("scene").and_then(|x| x.as_u64()).unwrap_or(0);
+    let roots: Vec<u64> = match gltf.find("scenes").and_then(|x| x.as_array()).and_then(|x| x.get(scene_index as usize)) {
+        Some(scene) => match scene.find("nodes").and_then(|x| x.as_array()) {
+            Some(nodes) => nodes.iter().filter_map(|x| x.as_u64()).collect(),
+            None => vec![]
+        },
+        None => return Err(import_err("glTF document has no scene"))
+    };
+    let scene_name = gltf.find("scenes").and_then(|x| x.as_array())
+        .and_then(|x| x.get(scene_index as usize)).and_then(|x| name_of(x));
+    let scene_id = try!(document.append_entity(Some(parent_id), "Scene", scene_name));
+    let mut importer = Importer {
+        document: document,
+        gltf: &gltf,
+        material_names: HashMap::new()
+    };
+    try!(importer.import_materials(scene_id));
+    for root in roots {
+        try!(importer.import_node(scene_id, root));
+    }
+    Ok(scene_id)
+}
+
+struct Exporter<'a> {
+    document: &'a Document,
+    nodes: Vec<Json>,
+    meshes: Vec<Json>,
+    materials: Vec<Json>,
+    material_indices: HashMap<String, usize>
+}
+
+fn json_object(fields: Vec<(&str, Json)>) -> Json {
+    let mut obj = BTreeMap::new();
+    for (k, v) in fields {
+        obj.insert(k.to_string(), v);
+    }
+    Json::Object(obj)
+}
+
+impl<'a> Exporter<'a> {
+    fn material_index(&mut self, name: &str) -> Result<usize, DocError> {
+        let document = self.document;
+        if let Some(index) = self.material_indices.get(name) {
+            return Ok(*index);
+        }
+        let mut fields = vec![("name", Json::String(name.to_string()))];
+        if let Some(entity_id) = document.get_entity_by_name(name) {
+            if let Ok(color) = document.get_property(&entity_id, "base_color") {
+                let c: ::cgmath::Vector4<f32> = try!(color.translate(&mut TranslateContext::empty()));
+                let factor = vec![c.x, c.y, c.z, c.w].into_iter().map(|x| Json::F64(x as f64)).collect();
+                fields.push(("pbrMetallicRoughness", json_object(vec![("baseColorFactor", Json::Array(factor))])));
+            }
+        }
+        self.materials.push(json_object(fields));
+        let index = self.materials.len() - 1;
+        self.material_indices.insert(name.to_string(), index);
+        Ok(index)
+    }
+    // Returns the index of the glTF node written for entity_id, or None if it isn't a node
+    fn export_node(&mut self, entity_id: &EntityId) -> Result<Option<usize>, DocError> {
+        let document = self.document;
+        let is_node = try!(document.get_entity_type_name(entity_id)) == "Node" ||
+            try!(document.has_property(entity_id, "transform"));
+        if !is_node {
+            return Ok(None);
+        }
+        let mut fields = vec![];
+        if let Some(name) = try!(document.get_entity_name(entity_id)) {
+            fields.push(("name", Json::String(name.clone())));
+        }
+        if let Ok(transform) = document.get_property(entity_id, "transform") {
+            let m: Matrix4<f32> = try!(transform.translate(&mut TranslateContext::empty()));
+            let columns = vec![m.x, m.y, m.z, m.w];
+            let matrix = columns.iter().flat_map(|c| vec![c.x, c.y, c.z, c.w].into_iter()).map(|x| Json::F64(x as f64)).collect();
+            fields.push(("matrix", Json::Array(matrix)));
+        }
+        let mut primitives = vec![];
+        let mut mesh_name = None;
+        let mut children = vec![];
+        for child in try!(document.get_children(entity_id)).clone() {
+            if try!(document.get_entity_type_name(&child)) == "Mesh" {
+                if let Ok(name) = document.get_property(&child, "mesh") {
+                    mesh_name = Some(try!(name.translate::<String>(&mut TranslateContext::empty())));
+                }
+                let mut primitive = vec![];
+                if let Ok(material) = document.get_property(&child, "material") {
+                    let material: String = try!(material.translate(&mut TranslateContext::empty()));
+                    primitive.push(("material", Json::U64(try!(self.material_index(&material)) as u64)));
+                }
+                primitives.push(json_object(primitive));
+            } else if let Some(index) = try!(self.export_node(&child)) {
+                children.push(Json::U64(index as u64));
+            }
+        }
+        if primitives.len() > 0 {
+            let mut mesh = vec![("primitives", Json::Array(primitives))];
+            if let Some(name) = mesh_name {
+                mesh.push(("name", Json::String(name)));
+            }
+            self.meshes.push(json_object(mesh));
+            fields.push(("mesh", Json::U64((self.meshes.len() - 1) as u64)));
+        }
+        if children.len() > 0 {
+            fields.push(("children", Json::Array(children)));
+        }
+        self.nodes.push(json_object(fields));
+        Ok(Some(self.nodes.len() - 1))
+    }
+}
+
+// Exports the subtree at scene_id as a glTF 2.0 scene graph. Entities of type `Node` (or anything with a
+// `transform`) become nodes, their `Mesh` children become mesh primitives and the materials those name are
+// looked up as entities. Vertex data isn't part of documents, so meshes come out without attributes.
+pub fn export_gltf(document: &Document, scene_id: &EntityId) -> Result<String, DocError> {
+    let mut exporter = Exporter {
+        document: document,
+        nodes: vec![],
+        meshes: vec![],
+        materials: vec![],
+        material_indices: HashMap::new()
+    };
+    let mut roots = vec![];
+    for child in try!(document.get_children(scene_id)) {
+        if let Some(index) = try!(exporter.export_node(child)) {
+            roots.push(Json::U64(index as u64));
+        }
+    }
+    let mut scene = vec![("nodes", Json::Array(roots))];
+    if let Some(name) = try!(document.get_entity_name(scene_id)) {
+        scene.push(("name", Json::String(name.clone())));
+    }
+    let gltf = json_object(vec![
+        ("asset", json_object(vec![("version", Json::String("2.0".to_string()))])),
+        ("scene", Json::U64(0)),
+        ("scenes", Json::Array(vec![json_object(scene)])),
+        ("nodes", Json::Array(exporter.nodes)),
+        ("meshes", Json::Array(exporter.meshes)),
+        ("materials", Json::Array(exporter.materials))
+    ]);
+    Ok(gltf.to_string())
+}
+
+
+#[test]
+fn test_import_gltf() {
+    let mut doc = Document::from_string(r#"<Entity name="root" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    import_gltf(&mut doc, root, r#"{
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [
+            { "name": "ship", "translation": [1.0, 2.0, 3.0], "mesh": 0, "children": [1] },
+            { "name": "turret" }
+        ],
+        "meshes": [{ "name": "hull", "primitives": [{ "material": 0 }] }],
+        "materials": [{ "name": "steel" }]
+    }"#).unwrap();
+    let ship = doc.get_entity_by_name("ship").unwrap();
+    assert_eq!(*doc.get_property(&ship, "transform").unwrap(), Pon::from_string("mul [translate { x: 1.0, y: 2.0, z: 3.0 }]").unwrap());
+    assert!(doc.get_entity_by_name("turret").is_some());
+    assert!(doc.get_entity_by_name("steel").is_some());
+}
+
+#[test]
+fn test_export_gltf() {
+    let doc = Document::from_string(r#"<Scene name="scene"><Node name="ship" transform="translate { x: 1.0, y: 2.0, z: 3.0 }"><Mesh mesh="'hull'" material="'steel'" /></Node></Scene>"#).unwrap();
+    let scene = doc.get_entity_by_name("scene").unwrap();
+    let gltf = Json::from_str(&export_gltf(&doc, &scene).unwrap()).unwrap();
+    assert_eq!(gltf.find_path(&["nodes"]).unwrap().as_array().unwrap().len(), 1);
+    assert_eq!(gltf.find("materials").unwrap()[0].find("name").unwrap().as_string(), Some("steel"));
+}
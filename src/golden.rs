@@ -0,0 +1,135 @@
+
+use std::slice::SliceConcatExt;
+#[cfg(feature = "fs")]
+use std::fs::File;
+#[cfg(feature = "fs")]
+use std::io::{Read, Write};
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use document::*;
+use diff::*;
+use format::escape_attribute;
+use overrides::entity_address;
+
+// Set this environment variable to (re)write golden files instead of comparing against them
+pub const UPDATE_SNAPSHOTS_VAR: &'static str = "PYRAMID_UPDATE_SNAPSHOTS";
+
+// The document as xml with only what makes up its structure: no comments or provenance, attributes sorted
+// by key after the name, two spaces of indentation. Equal documents give equal strings.
+pub fn canonical_xml(doc: &Document) -> String {
+    let mut out = vec!["<?xml version=\"1.0\" encoding=\"UTF-8\"?>".to_string()];
+    // Like write_xml, without the root itself
+    if let Some(root) = doc.get_root() {
+        for child in doc.get_children(&root).unwrap() {
+            write_canonical(doc, child, 0, &mut out);
+        }
+    }
+    let mut xml = out.join("\n");
+    xml.push('\n');
+    xml
+}
+
+fn write_canonical(doc: &Document, entity_id: &EntityId, depth: usize, out: &mut Vec<String>) {
+    let indent: String = (0..depth).map(|_| "  ").collect::<Vec<&str>>().concat();
+    let type_name = doc.get_entity_type_name(entity_id).unwrap();
+    let mut attrs = vec![];
+    if let Ok(Some(name)) = doc.get_entity_name(entity_id) {
+        attrs.push(format!(" name=\"{}\"", escape_attribute(name)));
+    }
+    let mut keys: Vec<String> = doc.get_properties(entity_id).unwrap_or(vec![]).into_iter().map(|p| p.property_key).collect();
+    keys.sort();
+    for key in keys {
+        if let Ok(value) = doc.get_property(entity_id, &key) {
+            attrs.push(format!(" {}=\"{}\"", key, escape_attribute(&value.to_string())));
+        }
+    }
+    let children = doc.get_children(entity_id).map(|c| c.clone()).unwrap_or(vec![]);
+    if children.len() == 0 {
+        out.push(format!("{}<{}{} />", indent, type_name, attrs.concat()));
+        return;
+    }
+    out.push(format!("{}<{}{}>", indent, type_name, attrs.concat()));
+    for child in &children {
+        write_canonical(doc, child, depth + 1, out);
+    }
+    out.push(format!("{}</{}>", indent, type_name));
+}
+
+// What differs between the expected and the actual document, one line per change, with entities addressed
+// as in the expected document (see overrides::entity_address). Empty when they match.
+pub fn snapshot_differences(expected: &Document, actual: &Document) -> Vec<String> {
+    let patch = diff_documents(expected, actual);
+    let mut lines = vec![];
+    for entity_id in &patch.removed {
+        lines.push(format!("missing entity {}", entity_address(expected, entity_id)));
+    }
+    for added in &patch.added {
+        let entity = match added.entity.name {
+            Some(ref name) => format!("{} {}", added.entity.type_name, name),
+            None => added.entity.type_name.to_string()
+        };
+        match added.parent_id {
+            Some(parent_id) => lines.push(format!("unexpected entity {} in {} at {}", entity, entity_address(expected, &parent_id), added.index)),
+            None => lines.push(format!("unexpected root {}", entity))
+        }
+    }
+    for &(ref entity_id, ref type_name) in &patch.retyped {
+        lines.push(format!("{}: expected type {}, found {}", entity_address(expected, entity_id), expected.get_entity_type_name(entity_id).unwrap(), type_name));
+    }
+    for &(ref prop_ref, ref value) in &patch.properties {
+        let address = format!("{}.{}", entity_address(expected, &prop_ref.entity_id), prop_ref.property_key);
+        let expected_value = match expected.has_property(&prop_ref.entity_id, &prop_ref.property_key) {
+            Ok(true) => expected.get_property(&prop_ref.entity_id, &prop_ref.property_key).ok().map(|value| value.to_string()),
+            _ => None
+        };
+        lines.push(match (expected_value, value) {
+            (Some(expected_value), &Some(ref value)) => format!("{}: expected {}, found {}", address, expected_value, value.to_string()),
+            (Some(expected_value), &None) => format!("{}: expected {}, found nothing", address, expected_value),
+            (None, &Some(ref value)) => format!("{}: unexpected {}", address, value.to_string()),
+            (None, &None) => continue
+        });
+    }
+    lines
+}
+
+// Compares the document against the golden file at path, see snapshot_differences. The file is written
+// instead when it doesn't exist yet or UPDATE_SNAPSHOTS_VAR is set.
+#[cfg(feature = "fs")]
+pub fn compare_snapshot(doc: &Document, path: &Path) -> Result<Vec<String>, DocError> {
+    if ::std::env::var(UPDATE_SNAPSHOTS_VAR).is_ok() || !path.exists() {
+        let mut file = try!(File::create(path).map_err(|err| DocError::IoError(err.to_string())));
+        try!(file.write_all(canonical_xml(doc).as_bytes()).map_err(|err| DocError::IoError(err.to_string())));
+        return Ok(vec![]);
+    }
+    let mut source = String::new();
+    let mut file = try!(File::open(path).map_err(|err| DocError::IoError(err.to_string())));
+    try!(file.read_to_string(&mut source).map_err(|err| DocError::IoError(err.to_string())));
+    let expected = try!(Document::from_string(&source));
+    Ok(snapshot_differences(&expected, doc))
+}
+
+// For tests: panics with the differences when the document doesn't match its golden file
+#[cfg(feature = "fs")]
+pub fn assert_snapshot(doc: &Document, path: &Path) {
+    match compare_snapshot(doc, path) {
+        Ok(ref differences) if differences.len() == 0 => {},
+        Ok(differences) => panic!("{} doesn't match the document:\n  {}", path.display(), differences.join("\n  ")),
+        Err(err) => panic!("Can't compare against {}: {:?}", path.display(), err)
+    }
+}
+
+
+#[test]
+fn test_snapshot_differences() {
+    let expected = Document::from_string(r#"<Entity name="root" x="1"><Entity name="a" /><Entity /></Entity>"#).unwrap();
+    let actual = Document::from_string(r#"<Entity y="2" name="root" x="3"><Entity /><Mesh name="b" /></Entity>"#).unwrap();
+    assert_eq!(snapshot_differences(&expected, &actual), vec![
+        "missing entity a".to_string(),
+        "unexpected entity Mesh b in root at 1".to_string(),
+        "root.x: expected 1, found 3".to_string(),
+        "root.y: unexpected 2".to_string()
+    ]);
+    assert_eq!(canonical_xml(&actual), "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Entity name=\"root\" x=\"3\" y=\"2\">\n  <Entity />\n  <Mesh name=\"b\" />\n</Entity>\n");
+    assert_eq!(snapshot_differences(&actual, &Document::from_string(&canonical_xml(&actual)).unwrap()), Vec::<String>::new());
+}
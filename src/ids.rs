@@ -0,0 +1,101 @@
+
+use document::EntityId;
+
+// Where Document::new_id gets entity ids from. Documents that are replicated or merged with each other can
+// use random or node specific ids so entities created on different sides don't collide. Ids already used in
+// the document are skipped, so a generator only has to make collisions unlikely, not impossible.
+pub trait IdGenerator {
+    fn next_id(&mut self) -> EntityId;
+}
+
+// 1, 2, 3...; the default
+pub struct SequentialIds {
+    last: EntityId
+}
+
+impl SequentialIds {
+    pub fn new() -> SequentialIds {
+        SequentialIds { last: 0 }
+    }
+}
+
+impl IdGenerator for SequentialIds {
+    fn next_id(&mut self) -> EntityId {
+        self.last += 1;
+        self.last
+    }
+}
+
+// Pseudo random 64 bit ids (xorshift64*). Seed each replica differently, e.g. from the time it started.
+pub struct RandomIds {
+    state: u64
+}
+
+impl RandomIds {
+    pub fn new(seed: u64) -> RandomIds {
+        // A zero state would only ever produce zeros
+        RandomIds { state: if seed == 0 { 0x9E3779B97F4A7C15 } else { seed } }
+    }
+}
+
+impl IdGenerator for RandomIds {
+    fn next_id(&mut self) -> EntityId {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+}
+
+pub const SNOWFLAKE_NODE_BITS: u64 = 10;
+pub const SNOWFLAKE_SEQUENCE_BITS: u64 = 12;
+
+// Milliseconds from clock, then the node id, then a sequence number for ids created within the same
+// millisecond. Unique across up to 1024 nodes as long as each has its own node id.
+pub struct SnowflakeIds {
+    node_id: u64,
+    clock: Box<Fn() -> u64>,
+    last_time: u64,
+    sequence: u64
+}
+
+impl SnowflakeIds {
+    pub fn new(node_id: u64, clock: Box<Fn() -> u64>) -> SnowflakeIds {
+        SnowflakeIds {
+            node_id: node_id & ((1 << SNOWFLAKE_NODE_BITS) - 1),
+            clock: clock,
+            last_time: 0,
+            sequence: 0
+        }
+    }
+}
+
+impl IdGenerator for SnowflakeIds {
+    fn next_id(&mut self) -> EntityId {
+        let now = (self.clock)();
+        if now > self.last_time {
+            self.last_time = now;
+            self.sequence = 0;
+        } else {
+            // Same millisecond, or the clock went backwards
+            self.sequence += 1;
+            if self.sequence == 1 << SNOWFLAKE_SEQUENCE_BITS {
+                // Out of sequence numbers; borrow from the next millisecond rather than wait for it
+                self.last_time += 1;
+                self.sequence = 0;
+            }
+        }
+        (self.last_time << (SNOWFLAKE_NODE_BITS + SNOWFLAKE_SEQUENCE_BITS)) |
+            (self.node_id << SNOWFLAKE_SEQUENCE_BITS) | self.sequence
+    }
+}
+
+
+#[test]
+fn test_snowflake_ids() {
+    let mut ids = SnowflakeIds::new(3, Box::new(|| 5));
+    let first = ids.next_id();
+    let second = ids.next_id();
+    assert_eq!(first, (5 << 22) | (3 << 12));
+    assert_eq!(second, first + 1);
+}
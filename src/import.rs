@@ -0,0 +1,124 @@
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(feature = "fs")]
+use std::fs::File;
+#[cfg(feature = "fs")]
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use document::*;
+use pon::*;
+
+// What an importer produces: a flat list of edits building a subtree under the point the file is mounted at
+#[derive(PartialEq, Debug, Clone)]
+pub enum SubtreeOp {
+    // parent is the index of an earlier AppendEntity among the ops' appended entities, None is the mount point
+    AppendEntity { parent: Option<usize>, type_name: String, name: Option<String> },
+    // entity is the index of the AppendEntity the property belongs to
+    SetProperty { entity: usize, property_key: String, expression: Pon }
+}
+
+// Turns the bytes of a third party file into entities. Options are the other attributes of the
+// `<Include file="..." />` element, parsed as PON.
+pub trait DocumentImporter {
+    fn import(&self, bytes: &[u8], options: &HashMap<String, Pon>) -> Result<Vec<SubtreeOp>, DocError>;
+}
+
+pub struct ImporterRegistry {
+    importers: HashMap<String, Box<DocumentImporter>>
+}
+
+impl ImporterRegistry {
+    pub fn new() -> ImporterRegistry {
+        ImporterRegistry { importers: HashMap::new() }
+    }
+    // extension is without the dot, e.g. "obj"
+    pub fn register(&mut self, extension: &str, importer: Box<DocumentImporter>) {
+        self.importers.insert(extension.to_lowercase(), importer);
+    }
+    pub fn get(&self, extension: &str) -> Option<&DocumentImporter> {
+        self.importers.get(&extension.to_lowercase()).map(|x| &**x)
+    }
+}
+
+// Where the bytes of an `<Include file="..." />` come from. path is the file attribute joined to the directory
+// of the including file.
+pub trait IncludeResolver {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, DocError>;
+}
+
+// Reads includes from the filesystem; what documents start out with when the fs feature is on
+#[cfg(feature = "fs")]
+pub struct FileResolver;
+
+#[cfg(feature = "fs")]
+impl IncludeResolver for FileResolver {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, DocError> {
+        let mut bytes = vec![];
+        let mut file = try!(File::open(path).map_err(|err| DocError::IoError(format!("{}: {}", path.display(), err))));
+        try!(file.read_to_end(&mut bytes).map_err(|err| DocError::IoError(err.to_string())));
+        Ok(bytes)
+    }
+}
+
+// Includes handed over up front, e.g. fetched by a browser. Shared through an Rc so files can be added
+// after it's given to a document.
+pub struct MemoryResolver {
+    files: RefCell<HashMap<PathBuf, Vec<u8>>>
+}
+
+impl MemoryResolver {
+    pub fn new() -> MemoryResolver {
+        MemoryResolver { files: RefCell::new(HashMap::new()) }
+    }
+    pub fn insert(&self, path: &Path, bytes: Vec<u8>) {
+        self.files.borrow_mut().insert(path.to_path_buf(), bytes);
+    }
+}
+
+impl IncludeResolver for Rc<MemoryResolver> {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, DocError> {
+        match self.files.borrow().get(path) {
+            Some(bytes) => Ok(bytes.clone()),
+            None => Err(DocError::IoError(format!("{}: not found", path.display())))
+        }
+    }
+}
+
+#[cfg(feature = "fs")]
+pub fn default_include_resolver() -> Option<Box<IncludeResolver>> {
+    Some(Box::new(FileResolver))
+}
+
+#[cfg(not(feature = "fs"))]
+pub fn default_include_resolver() -> Option<Box<IncludeResolver>> {
+    None
+}
+
+pub fn apply_subtree_ops(document: &mut Document, mount_id: Option<EntityId>, ops: Vec<SubtreeOp>) -> Result<Vec<EntityId>, DocError> {
+    let mut appended: Vec<EntityId> = vec![];
+    for op in ops {
+        match op {
+            SubtreeOp::AppendEntity { parent, type_name, name } => {
+                let parent_id = match parent {
+                    Some(index) => match appended.get(index) {
+                        Some(id) => Some(*id),
+                        None => return Err(DocError::ImportError(format!("Importer referenced entity {} before appending it", index)))
+                    },
+                    None => mount_id
+                };
+                appended.push(try!(document.append_entity(parent_id, &type_name, name)));
+            },
+            SubtreeOp::SetProperty { entity, property_key, expression } => {
+                let entity_id = match appended.get(entity) {
+                    Some(id) => *id,
+                    None => return Err(DocError::ImportError(format!("Importer referenced entity {} before appending it", entity)))
+                };
+                try!(document.set_property(&entity_id, &property_key, expression));
+            }
+        }
+    }
+    Ok(appended)
+}
@@ -0,0 +1,62 @@
+
+use std::collections::HashMap;
+
+use document::*;
+use pon::*;
+
+// What a consumer wants to hear about: properties whose key matches one of key_patterns, optionally only on
+// entities of one type. A pattern may contain a single `*`, e.g. `transform`, `material_*` or `*`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct InterestSet {
+    pub type_name: Option<String>,
+    pub key_patterns: Vec<String>
+}
+
+impl InterestSet {
+    pub fn new(type_name: Option<&str>, key_patterns: Vec<&str>) -> InterestSet {
+        InterestSet {
+            type_name: type_name.map(|x| x.to_string()),
+            key_patterns: key_patterns.iter().map(|x| x.to_string()).collect()
+        }
+    }
+    pub fn matches(&self, document: &Document, prop_ref: &PropRef) -> bool {
+        if let Some(ref type_name) = self.type_name {
+            match document.get_entity_type_name(&prop_ref.entity_id) {
+                Ok(t) if t == type_name => {},
+                _ => return false
+            }
+        }
+        self.key_patterns.iter().any(|pattern| key_matches(pattern, &prop_ref.property_key))
+    }
+}
+
+fn key_matches(pattern: &str, key: &str) -> bool {
+    match pattern.find('*') {
+        Some(star) => {
+            let (prefix, suffix) = (&pattern[..star], &pattern[star + 1..]);
+            key.len() >= prefix.len() + suffix.len() && key.starts_with(prefix) && key.ends_with(suffix)
+        },
+        None => pattern == key
+    }
+}
+
+// Splits a cascade by interest set name. A property can land in several sets; sets nothing matched get an
+// empty list.
+pub fn partition_cascade(document: &Document, interest_sets: &Vec<(String, InterestSet)>, cascade: &Vec<PropRef>) -> HashMap<String, Vec<PropRef>> {
+    let mut partitions = HashMap::new();
+    for &(ref name, ref interest) in interest_sets {
+        let matching: Vec<PropRef> = cascade.iter().filter(|p| interest.matches(document, p)).cloned().collect();
+        partitions.insert(name.to_string(), matching);
+    }
+    partitions
+}
+
+
+#[test]
+fn test_key_matches() {
+    assert!(key_matches("material_*", "material_color"));
+    assert!(key_matches("*", "x"));
+    assert!(key_matches("transform", "transform"));
+    assert!(!key_matches("material_*", "mesh"));
+    assert!(!key_matches("a*a", "a"));
+}
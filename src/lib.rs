@@ -3,6 +3,14 @@
 
 extern crate xml;
 extern crate cgmath;
+extern crate rustc_serialize;
+extern crate regex;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+#[cfg(feature = "python")]
+extern crate pyo3;
+#[cfg(feature = "scripting")]
+extern crate rhai;
 
 #[macro_use]
 pub mod hashmap_macro;
@@ -12,6 +20,45 @@ mod pon_test;
 #[macro_use]
 pub mod pon;
 pub mod pon_translations;
+pub mod pon_tokenizer;
 pub mod system;
 pub mod interface;
 pub mod pon_to_cgmath;
+pub mod pon_descriptors;
+pub mod bench;
+pub mod shard;
+pub mod wal;
+pub mod access;
+pub mod interest;
+pub mod script;
+#[cfg(feature = "scripting")]
+pub mod script_rhai;
+pub mod capi;
+pub mod gltf;
+pub mod import;
+pub mod schema;
+pub mod complete;
+pub mod format;
+pub mod migrate;
+pub mod repair;
+pub mod cook;
+pub mod metrics;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod diff;
+pub mod culling;
+pub mod workspace;
+pub mod merge;
+pub mod selector;
+pub mod dsl;
+pub mod ids;
+#[macro_use]
+pub mod builder;
+pub mod overrides;
+pub mod prefab;
+pub mod golden;
+pub mod binary;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "python")]
+pub mod python;
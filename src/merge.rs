@@ -0,0 +1,191 @@
+
+use std::collections::HashMap;
+
+use document::*;
+use pon::*;
+
+// The `merge` property of an entity says how Document::merge3 settles conflicting changes to its other
+// properties, by key:
+//
+//   merge="{ health: 'max', tags: 'append', color: 'ours' }"
+//
+// Properties without a strategy are reported as conflicts.
+pub const MERGE_PROPERTY: &'static str = "merge";
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum MergeStrategy {
+    Ours,
+    Theirs,
+    // Numbers only
+    Max,
+    Min,
+    // Arrays only: ours followed by the elements only theirs has
+    Append
+}
+
+impl MergeStrategy {
+    pub fn from_name(name: &str) -> Option<MergeStrategy> {
+        match name {
+            "ours" => Some(MergeStrategy::Ours),
+            "theirs" => Some(MergeStrategy::Theirs),
+            "max" => Some(MergeStrategy::Max),
+            "min" => Some(MergeStrategy::Min),
+            "append" => Some(MergeStrategy::Append),
+            _ => None
+        }
+    }
+}
+
+// A property both sides changed differently, with no strategy that could settle it; ours is kept.
+// None means the property isn't set.
+#[derive(PartialEq, Debug, Clone)]
+pub struct MergeConflict {
+    pub prop_ref: PropRef,
+    pub base: Option<Pon>,
+    pub ours: Option<Pon>,
+    pub theirs: Option<Pon>
+}
+
+// Brings the property changes from base to theirs into ours. Entities are matched by name (roots always
+// match); entities that aren't in all three documents are left alone.
+pub fn merge3(ours: &mut Document, base: &Document, theirs: &Document) -> Result<Vec<MergeConflict>, DocError> {
+    let mut ids: Vec<EntityId> = ours.entities_iter().cloned().collect();
+    ids.sort();
+    let mut writes = vec![];
+    let mut conflicts = vec![];
+    for entity_id in ids {
+        let (base_id, their_id) = match (counterpart(ours, &entity_id, base), counterpart(ours, &entity_id, theirs)) {
+            (Some(base_id), Some(their_id)) => (base_id, their_id),
+            _ => continue
+        };
+        let mut keys = vec![];
+        for &(doc, id) in &[(&*ours, entity_id), (base, base_id), (theirs, their_id)] {
+            for prop_ref in try!(doc.get_properties(&id)) {
+                if !keys.contains(&prop_ref.property_key) {
+                    keys.push(prop_ref.property_key);
+                }
+            }
+        }
+        keys.sort();
+        let strategies = strategies(ours, &entity_id);
+        for key in keys {
+            let our_value = value(ours, &entity_id, &key);
+            let base_value = value(base, &base_id, &key);
+            let their_value = value(theirs, &their_id, &key);
+            if same(&our_value, &their_value) || same(&their_value, &base_value) {
+                continue;
+            }
+            if same(&our_value, &base_value) {
+                writes.push((entity_id, key, their_value));
+                continue;
+            }
+            match strategies.get(&key).and_then(|strategy| resolve(*strategy, &our_value, &their_value)) {
+                Some(merged) => if !same(&merged, &our_value) {
+                    writes.push((entity_id, key, merged));
+                },
+                None => conflicts.push(MergeConflict {
+                    prop_ref: PropRef::new(&entity_id, &key),
+                    base: base_value,
+                    ours: our_value,
+                    theirs: their_value
+                })
+            }
+        }
+    }
+    for (entity_id, key, value) in writes {
+        match value {
+            Some(value) => try!(ours.set_property(&entity_id, &key, value)),
+            None => { try!(ours.unset_property(&entity_id, &key)); }
+        }
+    }
+    Ok(conflicts)
+}
+
+fn counterpart(doc: &Document, entity_id: &EntityId, other: &Document) -> Option<EntityId> {
+    if doc.get_root() == Some(*entity_id) {
+        return other.get_root();
+    }
+    match doc.get_entity_name(entity_id) {
+        Ok(Some(name)) => other.get_entity_by_name(name),
+        _ => None
+    }
+}
+
+fn value(doc: &Document, entity_id: &EntityId, key: &str) -> Option<Pon> {
+    match doc.has_property(entity_id, key) {
+        Ok(true) => doc.get_property(entity_id, key).ok().map(|value| (*value).clone()),
+        _ => None
+    }
+}
+
+// Compares expressions as written, so references are equal whatever they currently resolve to
+fn same(a: &Option<Pon>, b: &Option<Pon>) -> bool {
+    a.as_ref().map(|x| x.to_string()) == b.as_ref().map(|x| x.to_string())
+}
+
+fn strategies(doc: &Document, entity_id: &EntityId) -> HashMap<String, MergeStrategy> {
+    let mut strategies = HashMap::new();
+    if let Some(Pon::Object(annotations)) = value(doc, entity_id, MERGE_PROPERTY) {
+        for (key, strategy) in annotations {
+            if let Pon::String(ref name) = strategy {
+                if let Some(strategy) = MergeStrategy::from_name(name) {
+                    strategies.insert(key, strategy);
+                }
+            }
+        }
+    }
+    strategies
+}
+
+fn number(value: &Pon) -> Option<f64> {
+    match value {
+        &Pon::Integer(v) => Some(v as f64),
+        &Pon::Float(v) => Some(v as f64),
+        _ => None
+    }
+}
+
+// None when the strategy doesn't apply to the values
+fn resolve(strategy: MergeStrategy, ours: &Option<Pon>, theirs: &Option<Pon>) -> Option<Option<Pon>> {
+    match strategy {
+        MergeStrategy::Ours => Some(ours.clone()),
+        MergeStrategy::Theirs => Some(theirs.clone()),
+        MergeStrategy::Max | MergeStrategy::Min => match (ours, theirs) {
+            (&Some(ref a), &Some(ref b)) => match (number(a), number(b)) {
+                (Some(x), Some(y)) => {
+                    let take_ours = if strategy == MergeStrategy::Max { x >= y } else { x <= y };
+                    Some(Some(if take_ours { a.clone() } else { b.clone() }))
+                },
+                _ => None
+            },
+            _ => None
+        },
+        MergeStrategy::Append => match (ours, theirs) {
+            (&Some(Pon::Array(ref a)), &Some(Pon::Array(ref b))) => {
+                let mut merged = a.clone();
+                for item in b {
+                    if !a.iter().any(|x| x.to_string() == item.to_string()) {
+                        merged.push(item.clone());
+                    }
+                }
+                Some(Some(Pon::Array(merged)))
+            },
+            _ => None
+        }
+    }
+}
+
+
+#[test]
+fn test_merge3() {
+    let base = Document::from_string(r#"<Entity name="root" health="10" tags="['a']" score="1" />"#).unwrap();
+    let mut ours = Document::from_string(r#"<Entity name="root" health="12" tags="['a', 'b']" score="2" merge="{ health: 'max', tags: 'append' }" />"#).unwrap();
+    let theirs = Document::from_string(r#"<Entity name="root" health="15" tags="['a', 'c']" score="3" />"#).unwrap();
+    let conflicts = ours.merge3(&base, &theirs).unwrap();
+    let root = ours.get_entity_by_name("root").unwrap();
+    assert_eq!(*ours.get_property(&root, "health").unwrap(), Pon::Integer(15));
+    assert_eq!(*ours.get_property(&root, "tags").unwrap(), Pon::from_string("['a', 'b', 'c']").unwrap());
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].prop_ref, PropRef::new(&root, "score"));
+    assert_eq!(*ours.get_property(&root, "score").unwrap(), Pon::Integer(2));
+}
@@ -0,0 +1,119 @@
+
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use pon::*;
+
+// Where Document::publish_metrics sends its numbers, e.g. an adapter for statsd or Prometheus. Names are
+// prefixed with `pyramid.`.
+pub trait MetricsSink {
+    // Monotonic totals
+    fn counter(&self, name: &str, value: u64);
+    fn gauge(&self, name: &str, value: f64);
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+struct Totals {
+    properties_set: u64,
+    entities_added: u64,
+    cascades: u64,
+    cascaded_properties: u64,
+    // In Document::clock units
+    cascade_time: u64
+}
+
+// Cheap enough to always count; nothing is sent anywhere until publish is called
+pub struct MetricsCounters {
+    totals: Cell<Totals>,
+    last_published: Cell<Totals>
+}
+
+impl MetricsCounters {
+    pub fn new() -> MetricsCounters {
+        let zero = Totals { properties_set: 0, entities_added: 0, cascades: 0, cascaded_properties: 0, cascade_time: 0 };
+        MetricsCounters {
+            totals: Cell::new(zero),
+            last_published: Cell::new(zero)
+        }
+    }
+    fn update<F: FnOnce(&mut Totals)>(&self, func: F) {
+        let mut totals = self.totals.get();
+        func(&mut totals);
+        self.totals.set(totals);
+    }
+    pub fn property_set(&self) {
+        self.update(|t| t.properties_set += 1);
+    }
+    pub fn entity_added(&self) {
+        self.update(|t| t.entities_added += 1);
+    }
+    pub fn cascade(&self, length: usize, time: u64) {
+        self.update(|t| {
+            t.cascades += 1;
+            t.cascaded_properties += length as u64;
+            t.cascade_time += time;
+        });
+    }
+    // Rates and averages cover the time since the previous publish, which was elapsed_seconds ago
+    pub fn publish(&self, sink: &MetricsSink, entities: usize, elapsed_seconds: f64) {
+        let totals = self.totals.get();
+        let last = self.last_published.get();
+        sink.gauge("pyramid.entities", entities as f64);
+        sink.counter("pyramid.properties_set", totals.properties_set);
+        sink.counter("pyramid.entities_added", totals.entities_added);
+        sink.counter("pyramid.cascades", totals.cascades);
+        if elapsed_seconds > 0.0 {
+            sink.gauge("pyramid.properties_set_per_second", (totals.properties_set - last.properties_set) as f64 / elapsed_seconds);
+        }
+        let cascades = totals.cascades - last.cascades;
+        if cascades > 0 {
+            sink.gauge("pyramid.average_cascade_length", (totals.cascaded_properties - last.cascaded_properties) as f64 / cascades as f64);
+            sink.gauge("pyramid.average_cascade_time", (totals.cascade_time - last.cascade_time) as f64 / cascades as f64);
+        }
+        self.last_published.set(totals);
+    }
+}
+
+// Which properties cascades keep reaching and which take longest to resolve, see Document::hot_report
+pub struct AccessPatterns {
+    cascade_counts: HashMap<PropRef, u64>,
+    // Total time (in Document::clock units) and number of resolves
+    resolve_times: HashMap<PropRef, (u64, u64)>
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct HotReport {
+    // Properties appearing in the most cascades, with the number of cascades
+    pub most_cascaded: Vec<(PropRef, u64)>,
+    // Properties with the largest total resolve time, with total time and number of resolves
+    pub slowest_resolves: Vec<(PropRef, u64, u64)>
+}
+
+impl AccessPatterns {
+    pub fn new() -> AccessPatterns {
+        AccessPatterns {
+            cascade_counts: HashMap::new(),
+            resolve_times: HashMap::new()
+        }
+    }
+    pub fn record_cascade(&mut self, cascade: &Vec<PropRef>) {
+        for prop_ref in cascade {
+            *self.cascade_counts.entry(prop_ref.clone()).or_insert(0) += 1;
+        }
+    }
+    pub fn record_resolve(&mut self, prop_ref: &PropRef, time: u64) {
+        let entry = self.resolve_times.entry(prop_ref.clone()).or_insert((0, 0));
+        entry.0 += time;
+        entry.1 += 1;
+    }
+    // The top entries of each list; ties are broken by PropRef so the report is stable
+    pub fn report(&self, top: usize) -> HotReport {
+        let mut most_cascaded: Vec<(PropRef, u64)> = self.cascade_counts.iter().map(|(p, c)| (p.clone(), *c)).collect();
+        most_cascaded.sort_by(|a, b| (b.1, a.0.entity_id, &a.0.property_key).cmp(&(a.1, b.0.entity_id, &b.0.property_key)));
+        most_cascaded.truncate(top);
+        let mut slowest_resolves: Vec<(PropRef, u64, u64)> = self.resolve_times.iter().map(|(p, t)| (p.clone(), t.0, t.1)).collect();
+        slowest_resolves.sort_by(|a, b| (b.1, a.0.entity_id, &a.0.property_key).cmp(&(a.1, b.0.entity_id, &b.0.property_key)));
+        slowest_resolves.truncate(top);
+        HotReport { most_cascaded: most_cascaded, slowest_resolves: slowest_resolves }
+    }
+}
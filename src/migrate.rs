@@ -0,0 +1,174 @@
+
+// Migration scripts, one step per line, `*` matching any entity type:
+//
+//   # comment
+//   rename_type Light PointLight
+//   rename_property Mesh tex texture
+//   wrap_value Mesh position vec3
+//   move_property_to_child Mesh transform Transform
+
+use document::*;
+use pon::*;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Migration {
+    RenameType { from: String, to: String },
+    RenameProperty { type_name: String, from: String, to: String },
+    // key="x" becomes key="wrapper x"
+    WrapValue { type_name: String, property_key: String, wrapper: String },
+    // Moves the property to the first child of child_type, creating the child if there isn't one
+    MovePropertyToChild { type_name: String, property_key: String, child_type: String }
+}
+
+pub fn parse_migrations(source: &str) -> Result<Vec<Migration>, DocError> {
+    let mut migrations = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with("#") { continue; }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let migration = match (words[0], words.len()) {
+            ("rename_type", 3) => Migration::RenameType { from: words[1].to_string(), to: words[2].to_string() },
+            ("rename_property", 4) => Migration::RenameProperty { type_name: words[1].to_string(), from: words[2].to_string(), to: words[3].to_string() },
+            ("wrap_value", 4) => Migration::WrapValue { type_name: words[1].to_string(), property_key: words[2].to_string(), wrapper: words[3].to_string() },
+            ("move_property_to_child", 4) => Migration::MovePropertyToChild { type_name: words[1].to_string(), property_key: words[2].to_string(), child_type: words[3].to_string() },
+            _ => return Err(DocError::MigrationError(format!("line {}: can't parse \"{}\"", i + 1, line)))
+        };
+        migrations.push(migration);
+    }
+    Ok(migrations)
+}
+
+fn entities_of_type(document: &Document, type_name: &str) -> Vec<EntityId> {
+    let mut ids: Vec<EntityId> = document.entities_iter()
+        .filter(|id| type_name == "*" || document.get_entity_type_name(id).map(|t| t == type_name).unwrap_or(false))
+        .map(|id| *id)
+        .collect();
+    ids.sort();
+    ids
+}
+
+// Expressions with references to property `from` of one of entity_ids, rewritten to reference `to` instead
+fn retarget_references(document: &Document, entity_ids: &Vec<EntityId>, from: &str, to: &str) -> Vec<(PropRef, Pon)> {
+    let mut rewrites = vec![];
+    let mut ids: Vec<EntityId> = document.entities_iter().cloned().collect();
+    ids.sort();
+    for id in ids {
+        let mut keys: Vec<String> = document.get_properties(&id).unwrap_or(vec![]).into_iter().map(|p| p.property_key).collect();
+        keys.sort();
+        for key in keys {
+            // The old property itself is about to go
+            if (key == from && entity_ids.contains(&id)) || !document.has_property(&id, &key).unwrap_or(false) {
+                continue;
+            }
+            let mut expression = match document.get_property(&id, &key) {
+                Ok(expression) => (*expression).clone(),
+                Err(_) => continue
+            };
+            let mut changed = false;
+            expression.visit_mut(&mut |node| {
+                let named_prop_ref = match node {
+                    &mut Pon::DependencyReference(ref mut named_prop_ref, _) => named_prop_ref,
+                    &mut Pon::Reference(ref mut named_prop_ref) => named_prop_ref,
+                    _ => return
+                };
+                if named_prop_ref.property_key != from {
+                    return;
+                }
+                match document.resolve_entity_path(&id, &named_prop_ref.entity_path) {
+                    Ok(target) if entity_ids.contains(&target) => {
+                        named_prop_ref.property_key = to.to_string();
+                        changed = true;
+                    },
+                    _ => {}
+                }
+            });
+            if changed {
+                rewrites.push((PropRef::new(&id, &key), expression));
+            }
+        }
+    }
+    rewrites
+}
+
+pub fn apply_migrations(document: &mut Document, migrations: &Vec<Migration>) -> Result<Vec<String>, DocError> {
+    let mut changes = vec![];
+    for migration in migrations {
+        match migration {
+            &Migration::RenameType { ref from, ref to } => {
+                for id in entities_of_type(document, from) {
+                    try!(document.set_entity_type_name(&id, to));
+                    changes.push(format!("entity {}: type {} -> {}", id, from, to));
+                }
+            },
+            &Migration::RenameProperty { ref type_name, ref from, ref to } => {
+                let renamed: Vec<EntityId> = entities_of_type(document, type_name).into_iter()
+                    .filter(|id| document.has_property(id, from).unwrap_or(false))
+                    .collect();
+                // The new keys have to exist before references can be pointed at them
+                for id in &renamed {
+                    let value = (*try!(document.get_property(id, from))).clone();
+                    try!(document.set_property(id, to, value));
+                }
+                for (prop_ref, expression) in retarget_references(document, &renamed, from, to) {
+                    try!(document.set_property(&prop_ref.entity_id, &prop_ref.property_key, expression));
+                    changes.push(format!("entity {}: {} now refers to {}", prop_ref.entity_id, prop_ref.property_key, to));
+                }
+                for id in renamed {
+                    try!(document.unset_property(&id, from));
+                    changes.push(format!("entity {}: property {} -> {}", id, from, to));
+                }
+            },
+            &Migration::WrapValue { ref type_name, ref property_key, ref wrapper } => {
+                for id in entities_of_type(document, type_name) {
+                    if !try!(document.has_property(&id, property_key)) { continue; }
+                    let value = (*try!(document.get_property(&id, property_key))).clone();
+                    try!(document.set_property(&id, property_key, Pon::new_typed_pon(wrapper, value)));
+                    changes.push(format!("entity {}: wrapped {} in {}", id, property_key, wrapper));
+                }
+            },
+            &Migration::MovePropertyToChild { ref type_name, ref property_key, ref child_type } => {
+                for id in entities_of_type(document, type_name) {
+                    if !try!(document.has_property(&id, property_key)) { continue; }
+                    let existing = try!(document.get_children(&id)).iter()
+                        .find(|c| document.get_entity_type_name(c).map(|t| t == child_type).unwrap_or(false))
+                        .map(|c| *c);
+                    let child = match existing {
+                        Some(child) => child,
+                        None => try!(document.append_entity(Some(id), child_type, None))
+                    };
+                    let value = try!(document.unset_property(&id, property_key));
+                    try!(document.set_property(&child, property_key, value));
+                    changes.push(format!("entity {}: moved {} to child {}", id, property_key, child));
+                }
+            }
+        }
+    }
+    Ok(changes)
+}
+
+
+#[test]
+fn test_apply_migrations() {
+    let mut doc = Document::from_string(r#"<Mesh name="tmp" tex="'a.png'" position="{ x: 1.0 }" />"#).unwrap();
+    let migrations = parse_migrations("
+        # upgrade meshes
+        rename_type Mesh StaticMesh
+        rename_property StaticMesh tex texture
+        wrap_value StaticMesh position vec3
+    ").unwrap();
+    apply_migrations(&mut doc, &migrations).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(doc.get_entity_type_name(&ent).unwrap(), "StaticMesh");
+    assert_eq!(*doc.get_property(&ent, "texture").unwrap(), Pon::String("a.png".to_string()));
+    assert_eq!(doc.has_property(&ent, "tex"), Ok(false));
+    assert_eq!(*doc.get_property(&ent, "position").unwrap(), Pon::from_string("vec3 { x: 1.0 }").unwrap());
+}
+
+#[test]
+fn test_rename_property_retargets_references() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Mesh name="mesh" tex="'a.png'" /><Entity name="hud" icon="@mesh.tex" /></Entity>"#).unwrap();
+    apply_migrations(&mut doc, &parse_migrations("rename_property Mesh tex texture").unwrap()).unwrap();
+    let hud = doc.get_entity_by_name("hud").unwrap();
+    assert_eq!(doc.get_property(&hud, "icon").unwrap().to_string(), "@mesh.texture");
+    assert_eq!(doc.get_property(&hud, "icon").unwrap().concretize().unwrap(), Pon::String("a.png".to_string()));
+}
@@ -0,0 +1,165 @@
+
+use std::slice::SliceConcatExt;
+
+use document::*;
+use pon::*;
+use diff::{DocumentPatch, AddedEntity, diff_documents};
+use dsl::{entity_from_dsl, entity_to_dsl};
+
+// Override files are the changes from a base document, one per line, for designers to write, share and stack
+// on top of base content:
+//
+//   # Tougher boss
+//   set boss health = 500
+//   unset boss loot
+//   retype door_1 SlidingDoor
+//   remove crate_3
+//   add arena 0 Spawner(name=spawner_2, rate=0.5)/Marker/..
+//
+// Entities are addressed by name, or by child index from the nearest named ancestor (`arena[2][0]`), or from
+// the root when no ancestor has a name (`/[1]`). Addresses refer to the document before any line is applied.
+// Values are PON, and added subtrees use the notation of dsl.rs; `add -` adds a root to an empty document.
+pub fn export_overrides(base: &Document, document: &Document) -> String {
+    let patch = diff_documents(base, document);
+    let mut lines = vec![];
+    for entity_id in &patch.removed {
+        lines.push(format!("remove {}", entity_address(base, entity_id)));
+    }
+    for added in &patch.added {
+        let parent = match added.parent_id {
+            Some(ref parent_id) => entity_address(base, parent_id),
+            None => "-".to_string()
+        };
+        lines.push(format!("add {} {} {}", parent, added.index, entity_to_dsl(&added.entity)));
+    }
+    for &(ref entity_id, ref type_name) in &patch.retyped {
+        lines.push(format!("retype {} {}", entity_address(base, entity_id), type_name));
+    }
+    for &(ref prop_ref, ref expression) in &patch.properties {
+        let address = entity_address(base, &prop_ref.entity_id);
+        lines.push(match expression {
+            &Some(ref expression) => format!("set {} {} = {}", address, prop_ref.property_key, expression.to_string()),
+            &None => format!("unset {} {}", address, prop_ref.property_key)
+        });
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect::<Vec<String>>().concat()
+}
+
+// Reads an override file into a patch for document
+pub fn parse_overrides(document: &Document, source: &str) -> Result<DocumentPatch, DocError> {
+    let mut patch = DocumentPatch { removed: vec![], added: vec![], retyped: vec![], properties: vec![] };
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with("#") {
+            continue;
+        }
+        let row = i as u64 + 1;
+        let (command, rest) = split_word(line);
+        match command {
+            "set" => {
+                let (address, rest) = split_word(rest);
+                let (key, rest) = split_word(rest);
+                if !rest.starts_with("=") {
+                    return Err(override_error(row, format!("Expected `=` after {}", key)));
+                }
+                let expression = try!(Pon::from_string(rest[1..].trim())
+                    .map_err(|err| override_error(row, format!("Bad value for {}: {:?}", key, err))));
+                let entity_id = try!(resolve_address(document, address, row));
+                patch.properties.push((PropRef::new(&entity_id, key), Some(expression)));
+            },
+            "unset" => {
+                let (address, key) = split_word(rest);
+                let entity_id = try!(resolve_address(document, address, row));
+                patch.properties.push((PropRef::new(&entity_id, key), None));
+            },
+            "retype" => {
+                let (address, type_name) = split_word(rest);
+                patch.retyped.push((try!(resolve_address(document, address, row)), type_name.to_string()));
+            },
+            "remove" => patch.removed.push(try!(resolve_address(document, rest, row))),
+            "add" => {
+                let (address, rest) = split_word(rest);
+                let (index, dsl) = split_word(rest);
+                let parent_id = if address == "-" { None } else { Some(try!(resolve_address(document, address, row))) };
+                let index: usize = try!(index.parse().map_err(|_| override_error(row, format!("Bad child index {}", index))));
+                let entity = try!(entity_from_dsl(dsl).map_err(|err| match err {
+                    DocError::LoadError(err) => override_error(row, err.message),
+                    err => err
+                }));
+                patch.added.push(AddedEntity { parent_id: parent_id, index: index, entity: entity });
+            },
+            _ => return Err(override_error(row, format!("Unknown command {}", command)))
+        }
+    }
+    Ok(patch)
+}
+
+pub fn entity_address(document: &Document, entity_id: &EntityId) -> String {
+    if let Ok(Some(name)) = document.get_entity_name(entity_id) {
+        return name.to_string();
+    }
+    match document.get_parent(entity_id) {
+        Ok(Some(parent_id)) => {
+            let index = document.get_children(&parent_id).unwrap().iter().position(|id| id == entity_id).unwrap();
+            let parent = entity_address(document, &parent_id);
+            if parent == "/" { format!("/[{}]", index) } else { format!("{}[{}]", parent, index) }
+        },
+        _ => "/".to_string()
+    }
+}
+
+fn resolve_address(document: &Document, address: &str, row: u64) -> Result<EntityId, DocError> {
+    let (anchor, indexes) = match address.find('[') {
+        Some(i) => (&address[..i], &address[i..]),
+        None => (address, "")
+    };
+    let mut entity_id = if anchor == "/" {
+        match document.get_root() {
+            Some(root) => root,
+            None => return Err(override_error(row, "The document is empty".to_string()))
+        }
+    } else {
+        match document.get_entity_by_name(anchor) {
+            Some(entity_id) => entity_id,
+            None => return Err(DocError::CantFindEntityByName(anchor.to_string()))
+        }
+    };
+    for index in indexes.split(']').filter(|index| index.len() > 0) {
+        if !index.starts_with("[") {
+            return Err(override_error(row, format!("Bad entity address {}", address)));
+        }
+        let index: usize = try!(index[1..].parse().map_err(|_| override_error(row, format!("Bad entity address {}", address))));
+        entity_id = match try!(document.get_children(&entity_id)).get(index) {
+            Some(child_id) => *child_id,
+            None => return Err(DocError::NoSuchChild(entity_id, index))
+        };
+    }
+    Ok(entity_id)
+}
+
+fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, "")
+    }
+}
+
+fn override_error(row: u64, message: String) -> DocError {
+    DocError::LoadError(LoadError { file: None, position: Some((row, 1)), message: message })
+}
+
+
+#[test]
+fn test_overrides() {
+    let base = Document::from_string(r#"<Entity name="root"><Entity name="boss" health="100" loot="'gold'" /><Entity><Entity x="1" /></Entity></Entity>"#).unwrap();
+    let tweaked = Document::from_string(r#"<Entity name="root"><Entity name="boss" health="500" /><Entity><Entity x="2" /></Entity><Spawner name="spawner" rate="0.5" /></Entity>"#).unwrap();
+    let overrides = tweaked.export_overrides(&base);
+    assert!(overrides.contains("set boss health = 500\n"));
+    assert!(overrides.contains("unset boss loot\n"));
+    assert!(overrides.contains("set root[1][0] x = 2\n"));
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="boss" health="100" loot="'gold'" /><Entity><Entity x="1" /></Entity></Entity>"#).unwrap();
+    doc.apply_overrides(&format!("# Tweaks\n{}", overrides)).unwrap();
+    assert!(doc.diff(&tweaked).is_empty());
+    assert!(doc.apply_overrides("sett boss health = 1").is_err());
+}
@@ -21,13 +21,39 @@ pub enum EntityPath {
     This,
     Parent,
     Named(String),
-    Search(Box<EntityPath>, String)
+    Search(Box<EntityPath>, String),
+    // The first target of this entity's links of a kind, see Document::add_link
+    Link(String)
+}
+impl EntityPath {
+    // Renames every reference to the entity named old_name in this path, returns true if anything changed
+    pub fn rename_entity(&mut self, old_name: &str, new_name: &str) -> bool {
+        match self {
+            &mut EntityPath::Named(ref mut name) => {
+                if name == old_name {
+                    *name = new_name.to_string();
+                    return true;
+                }
+                false
+            },
+            &mut EntityPath::Search(ref mut path, ref mut search) => {
+                let changed = path.rename_entity(old_name, new_name);
+                if search == old_name {
+                    *search = new_name.to_string();
+                    return true;
+                }
+                changed
+            },
+            _ => false
+        }
+    }
 }
 impl ToString for EntityPath {
     fn to_string(&self) -> String {
         match self {
             &EntityPath::This => "this".to_string(),
             &EntityPath::Parent => "parent".to_string(),
+            &EntityPath::Link(ref kind) => format!("link({})", kind),
             &EntityPath::Named(ref name) => name.to_string(),
             &EntityPath::Search(ref path, ref search) => format!("{}:{}", path.to_string(), search),
         }
@@ -77,7 +103,7 @@ pub struct TypedPon {
 }
 impl TypedPon {
     fn stringify(&self, options: &PonStringifyOptions) -> String {
-        format!("{} {}", self.type_name.to_string(), self.data.to_string())
+        format!("{} {}", self.type_name.to_string(), self.data.stringify(options))
     }
 }
 
@@ -103,7 +129,10 @@ pub enum Pon {
     Vector3(cgmath::Vector3<f32>),
     Vector4(cgmath::Vector4<f32>),
     Matrix4(cgmath::Matrix4<f32>),
-    Nil
+    Nil,
+    // An expression that couldn't be parsed (e.g. syntax from a newer version), kept verbatim and written back
+    // unchanged. Never produced by from_string; see Document::set_keep_unknown_expressions.
+    Unknown(String)
 }
 
 
@@ -114,6 +143,24 @@ impl Pon {
     pub fn new_typed_pon(type_name: &str, data: Pon) -> Pon {
         Pon::TypedPon(Box::new(TypedPon { type_name: type_name.to_string(), data: data }))
     }
+    // Calls func on every nested node and then on this node (children before parents)
+    pub fn visit_mut<F: FnMut(&mut Pon)>(&mut self, func: &mut F) {
+        match self {
+            &mut Pon::TypedPon(box TypedPon { ref mut data, .. }) => data.visit_mut(func),
+            &mut Pon::Object(ref mut hm) => {
+                for (_, v) in hm.iter_mut() {
+                    v.visit_mut(func);
+                }
+            },
+            &mut Pon::Array(ref mut arr) => {
+                for v in arr.iter_mut() {
+                    v.visit_mut(func);
+                }
+            },
+            _ => {}
+        }
+        func(self);
+    }
     pub fn get_dependency_references(&self, references: &mut Vec<NamedPropRef>) {
         match self {
             &Pon::TypedPon(box TypedPon { ref data, .. } ) =>
@@ -256,7 +303,41 @@ impl Pon {
         }
     }
 
-    fn stringify(&self, options: &PonStringifyOptions) -> String {
+    // Rounds every float in the expression to decimals digits after the decimal point
+    pub fn round_floats(&mut self, decimals: usize) {
+        let scale = 10f64.powi(decimals as i32);
+        let round = |v: f32| ((v as f64 * scale).round() / scale) as f32;
+        self.visit_mut(&mut |node| {
+            match node {
+                &mut Pon::Float(ref mut v) => *v = round(*v),
+                &mut Pon::FloatArray(ref mut array) => for v in array.iter_mut() { *v = round(*v); },
+                &mut Pon::Vector3(ref mut v) => { v.x = round(v.x); v.y = round(v.y); v.z = round(v.z); },
+                &mut Pon::Vector4(ref mut v) => { v.x = round(v.x); v.y = round(v.y); v.z = round(v.z); v.w = round(v.w); },
+                _ => {}
+            }
+        });
+    }
+    // Equality under a numeric policy: floats are compared at the precision they're serialized with, and
+    // integers may count as the floats with the same value
+    pub fn numeric_eq(&self, other: &Pon, options: &NumericOptions) -> bool {
+        let normalize = |pon: &Pon| {
+            let mut pon = pon.clone();
+            if options.integers_equal_floats {
+                pon.visit_mut(&mut |node| {
+                    let float = match node {
+                        &mut Pon::Integer(v) => Pon::Float(v as f32),
+                        &mut Pon::IntegerArray(ref array) => Pon::FloatArray(array.iter().map(|v| *v as f32).collect()),
+                        _ => return
+                    };
+                    *node = float;
+                });
+            }
+            pon.round_floats(options.float_decimals);
+            pon
+        };
+        normalize(self) == normalize(other)
+    }
+    pub fn stringify(&self, options: &PonStringifyOptions) -> String {
         match self {
             &Pon::TypedPon(box ref typed_pon) => typed_pon.stringify(&options),
             &Pon::DependencyReference(ref named_prop_ref, ref resolved) => {
@@ -286,30 +367,60 @@ impl Pon {
             &Pon::FloatArray(ref array) => array.to_pon().stringify(&options),
             &Pon::IntegerArray(ref array) => array.to_pon().stringify(&options),
             &Pon::Object(ref hm) => {
-                let a: Vec<String> = hm.iter().map(|(k, v)| format!("{}: {}", k.to_string(), v.stringify(&options))).collect();
+                let mut a: Vec<String> = hm.iter().map(|(k, v)| format!("{}: {}", k.to_string(), v.stringify(&options))).collect();
+                a.sort();
                 let mut s = a.join(", ");
                 if s.len() > 120 { s = a.join(",\n"); }
                 format!("{{ {} }}", s)
             },
-            &Pon::Float(ref v) => format!("{:.10}", v),
+            &Pon::Float(ref v) => format!("{:.*}", options.float_decimals, v),
             &Pon::Integer(ref v) => v.to_string(),
             &Pon::String(ref v) => format!("'{}'", v),
             &Pon::Boolean(ref v) => format!("{}", v),
             &Pon::Vector3(ref v) => v.to_pon().stringify(&options),
             &Pon::Vector4(ref v) => v.to_pon().stringify(&options),
             &Pon::Matrix4(ref v) => v.to_pon().stringify(&options),
-            &Pon::Nil => "()".to_string()
+            &Pon::Nil => "()".to_string(),
+            &Pon::Unknown(ref raw) => raw.clone()
         }
     }
 }
 
 pub struct PonStringifyOptions {
-    pub unwrap_dependencies: bool
+    pub unwrap_dependencies: bool,
+    pub float_decimals: usize
 }
 impl PonStringifyOptions {
     pub fn default() -> PonStringifyOptions {
         PonStringifyOptions {
-            unwrap_dependencies: false
+            unwrap_dependencies: false,
+            float_decimals: 10
+        }
+    }
+}
+
+// How a document treats numbers, see `Document::set_numeric_options`. Floats are always stored as f32.
+#[derive(PartialEq, Debug, Clone)]
+pub struct NumericOptions {
+    // Digits after the decimal point when floats are saved
+    pub float_decimals: usize,
+    // Round floats to float_decimals when loading, so a save and reload gives back exactly the same values
+    pub round_on_load: bool,
+    // Whether 1 and 1.0 are the same value when comparing documents
+    pub integers_equal_floats: bool
+}
+impl NumericOptions {
+    pub fn default() -> NumericOptions {
+        NumericOptions {
+            float_decimals: 10,
+            round_on_load: false,
+            integers_equal_floats: false
+        }
+    }
+    pub fn stringify_options(&self) -> PonStringifyOptions {
+        PonStringifyOptions {
+            unwrap_dependencies: false,
+            float_decimals: self.float_decimals
         }
     }
 }
@@ -0,0 +1,217 @@
+use pon::*;
+
+// Descriptors most consumers need, so they all agree on the same object shapes:
+//
+//   sampler { min_filter: 'linear', mag_filter: 'nearest', wrap_s: 'repeat', wrap_t: 'clamp', anisotropy: 1.0 }
+//   viewport { x: 0.0, y: 0.0, width: 1.0, height: 1.0 }
+//   range { min: 0.0, max: 1.0 }
+//
+// Every field is optional and defaults as below. Plain objects are accepted too.
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TextureFilter {
+    Nearest,
+    Linear
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TextureWrap {
+    Repeat,
+    MirroredRepeat,
+    Clamp
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct TextureSampler {
+    pub min_filter: TextureFilter,
+    pub mag_filter: TextureFilter,
+    pub wrap_s: TextureWrap,
+    pub wrap_t: TextureWrap,
+    pub anisotropy: f32
+}
+
+impl TextureSampler {
+    pub fn default() -> TextureSampler {
+        TextureSampler {
+            min_filter: TextureFilter::Linear,
+            mag_filter: TextureFilter::Linear,
+            wrap_s: TextureWrap::Repeat,
+            wrap_t: TextureWrap::Repeat,
+            anisotropy: 1.0
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Range {
+    pub min: f32,
+    pub max: f32
+}
+
+impl Range {
+    pub fn contains(&self, value: f32) -> bool {
+        self.min <= value && value <= self.max
+    }
+}
+
+// The object of a typed pon of the given type, or a plain object
+fn descriptor_data<'a>(pon: &'a Pon, type_name: &str) -> Result<&'a Pon, PonTranslateErr> {
+    match pon {
+        &Pon::TypedPon(box TypedPon { type_name: ref t, ref data }) if t == type_name => Ok(data),
+        &Pon::TypedPon(box TypedPon { type_name: ref t, .. }) => Err(PonTranslateErr::UnrecognizedType(t.to_string())),
+        &Pon::Object(..) => Ok(pon),
+        _ => Err(PonTranslateErr::MismatchType { expected: "TypedPon or Object".to_string(), found: format!("{:?}", pon) })
+    }
+}
+
+fn invalid(value: String) -> PonTranslateErr {
+    PonTranslateErr::InvalidValue { value: value }
+}
+
+impl Translatable<TextureFilter> for Pon {
+    fn inner_translate(&self, context: &mut TranslateContext) -> Result<TextureFilter, PonTranslateErr> {
+        let name: String = try!(self.translate(context));
+        match name.as_str() {
+            "nearest" => Ok(TextureFilter::Nearest),
+            "linear" => Ok(TextureFilter::Linear),
+            _ => Err(invalid(format!("texture filter {}, expected nearest or linear", name)))
+        }
+    }
+}
+
+impl ToPon for TextureFilter {
+    fn to_pon(&self) -> Pon {
+        Pon::String(match self {
+            &TextureFilter::Nearest => "nearest",
+            &TextureFilter::Linear => "linear"
+        }.to_string())
+    }
+}
+
+impl Translatable<TextureWrap> for Pon {
+    fn inner_translate(&self, context: &mut TranslateContext) -> Result<TextureWrap, PonTranslateErr> {
+        let name: String = try!(self.translate(context));
+        match name.as_str() {
+            "repeat" => Ok(TextureWrap::Repeat),
+            "mirrored_repeat" => Ok(TextureWrap::MirroredRepeat),
+            "clamp" => Ok(TextureWrap::Clamp),
+            _ => Err(invalid(format!("texture wrap {}, expected repeat, mirrored_repeat or clamp", name)))
+        }
+    }
+}
+
+impl ToPon for TextureWrap {
+    fn to_pon(&self) -> Pon {
+        Pon::String(match self {
+            &TextureWrap::Repeat => "repeat",
+            &TextureWrap::MirroredRepeat => "mirrored_repeat",
+            &TextureWrap::Clamp => "clamp"
+        }.to_string())
+    }
+}
+
+impl Translatable<TextureSampler> for Pon {
+    fn inner_translate(&self, context: &mut TranslateContext) -> Result<TextureSampler, PonTranslateErr> {
+        let data = try!(descriptor_data(self, "sampler"));
+        let default = TextureSampler::default();
+        let sampler = TextureSampler {
+            min_filter: try!(data.field_as_or("min_filter", default.min_filter, context)),
+            mag_filter: try!(data.field_as_or("mag_filter", default.mag_filter, context)),
+            wrap_s: try!(data.field_as_or("wrap_s", default.wrap_s, context)),
+            wrap_t: try!(data.field_as_or("wrap_t", default.wrap_t, context)),
+            anisotropy: try!(data.field_as_or("anisotropy", default.anisotropy, context))
+        };
+        if sampler.anisotropy < 1.0 {
+            return Err(invalid(format!("anisotropy {}, must be at least 1", sampler.anisotropy)));
+        }
+        Ok(sampler)
+    }
+}
+
+impl ToPon for TextureSampler {
+    fn to_pon(&self) -> Pon {
+        Pon::new_typed_pon("sampler", Pon::Object(hashmap!(
+            "min_filter" => self.min_filter.to_pon(),
+            "mag_filter" => self.mag_filter.to_pon(),
+            "wrap_s" => self.wrap_s.to_pon(),
+            "wrap_t" => self.wrap_t.to_pon(),
+            "anisotropy" => Pon::Float(self.anisotropy)
+        )))
+    }
+}
+
+impl Translatable<Viewport> for Pon {
+    fn inner_translate(&self, context: &mut TranslateContext) -> Result<Viewport, PonTranslateErr> {
+        let data = try!(descriptor_data(self, "viewport"));
+        let viewport = Viewport {
+            x: try!(data.field_as_or("x", 0.0, context)),
+            y: try!(data.field_as_or("y", 0.0, context)),
+            width: try!(data.field_as_or("width", 1.0, context)),
+            height: try!(data.field_as_or("height", 1.0, context))
+        };
+        if viewport.width < 0.0 || viewport.height < 0.0 {
+            return Err(invalid(format!("viewport size {}x{}, can't be negative", viewport.width, viewport.height)));
+        }
+        Ok(viewport)
+    }
+}
+
+impl ToPon for Viewport {
+    fn to_pon(&self) -> Pon {
+        Pon::new_typed_pon("viewport", Pon::Object(hashmap!(
+            "x" => Pon::Float(self.x),
+            "y" => Pon::Float(self.y),
+            "width" => Pon::Float(self.width),
+            "height" => Pon::Float(self.height)
+        )))
+    }
+}
+
+impl Translatable<Range> for Pon {
+    fn inner_translate(&self, context: &mut TranslateContext) -> Result<Range, PonTranslateErr> {
+        let data = try!(descriptor_data(self, "range"));
+        let range = Range {
+            min: try!(data.field_as_or("min", 0.0, context)),
+            max: try!(data.field_as_or("max", 1.0, context))
+        };
+        if range.min > range.max {
+            return Err(invalid(format!("range {}..{}, min is larger than max", range.min, range.max)));
+        }
+        Ok(range)
+    }
+}
+
+impl ToPon for Range {
+    fn to_pon(&self) -> Pon {
+        Pon::new_typed_pon("range", Pon::Object(hashmap!(
+            "min" => Pon::Float(self.min),
+            "max" => Pon::Float(self.max)
+        )))
+    }
+}
+
+#[test]
+fn test_sampler() {
+    let pon = Pon::from_string("sampler { mag_filter: 'nearest', wrap_t: 'clamp' }").unwrap();
+    let sampler: TextureSampler = pon.translate(&mut TranslateContext::empty()).unwrap();
+    assert_eq!(sampler.mag_filter, TextureFilter::Nearest);
+    assert_eq!(sampler.wrap_t, TextureWrap::Clamp);
+    assert_eq!(sampler.min_filter, TextureFilter::Linear);
+    let roundtrip: TextureSampler = sampler.to_pon().translate(&mut TranslateContext::empty()).unwrap();
+    assert_eq!(roundtrip, sampler);
+}
+
+#[test]
+fn test_invalid_range() {
+    let pon = Pon::from_string("range { min: 2.0, max: 1.0 }").unwrap();
+    let range: Result<Range, PonTranslateErr> = pon.translate(&mut TranslateContext::empty());
+    assert!(range.is_err());
+}
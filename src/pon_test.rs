@@ -121,6 +121,12 @@ fn test_path() {
     assert_eq!(v, Ok(Pon::Reference(NamedPropRef::new(EntityPath::Search(Box::new(EntityPath::Named("some".to_string())), "else".to_string()), "test"))));
 }
 
+#[test]
+fn test_link_path() {
+    let v = Pon::from_string("@link(target).position");
+    assert_eq!(v, Ok(Pon::DependencyReference(NamedPropRef::new(EntityPath::Link("target".to_string()), "position"), None)));
+}
+
 #[test]
 fn test_multiline() {
     let v = Pon::from_string("{
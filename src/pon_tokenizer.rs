@@ -0,0 +1,161 @@
+
+// Classified tokens over PON source, for syntax highlighting. Follows pon.rustpeg but never fails; anything it
+// can't make sense of comes out as an Error token so frontends can still render the rest.
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum PonTokenKind {
+    Number,
+    String,
+    Boolean,
+    // @path.property
+    DependencyReference,
+    // path.property
+    Reference,
+    // The name in front of a typed pon, e.g. vec3 in `vec3 { x: 1.0 }`
+    TypeName,
+    // An object key
+    Key,
+    Punctuation,
+    Error
+}
+
+// start and end are byte offsets into the source
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct PonToken {
+    pub kind: PonTokenKind,
+    pub start: usize,
+    pub end: usize
+}
+
+fn is_identifier_start(c: u8) -> bool {
+    (c as char).is_alphabetic() || c == b'_'
+}
+
+fn is_identifier_char(c: u8) -> bool {
+    (c as char).is_alphanumeric() || c == b'_'
+}
+
+struct Tokenizer<'a> {
+    source: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Tokenizer<'a> {
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.source.get(self.pos + offset).map(|x| *x)
+    }
+    fn skip_while<F: Fn(u8) -> bool>(&mut self, f: F) {
+        while let Some(c) = self.peek(0) {
+            if !f(c) { break; }
+            self.pos += 1;
+        }
+    }
+    // identifier ((":" | ".") identifier)*
+    fn path(&mut self) {
+        self.skip_while(is_identifier_char);
+        loop {
+            match (self.peek(0), self.peek(1)) {
+                (Some(b':'), Some(c)) | (Some(b'.'), Some(c)) if is_identifier_start(c) => {
+                    self.pos += 1;
+                    self.skip_while(is_identifier_char);
+                },
+                _ => break
+            }
+        }
+    }
+    fn next_non_whitespace(&self) -> Option<u8> {
+        self.source[self.pos..].iter().find(|c| !(**c as char).is_whitespace()).map(|x| *x)
+    }
+    fn next_kind(&mut self) -> Option<PonTokenKind> {
+        self.skip_while(|c| (c as char).is_whitespace());
+        let c = match self.peek(0) {
+            Some(c) => c,
+            None => return None
+        };
+        let start = self.pos;
+        Some(match c {
+            b'\'' => {
+                self.pos += 1;
+                self.skip_while(|c| c != b'\'');
+                match self.peek(0) {
+                    Some(_) => { self.pos += 1; PonTokenKind::String },
+                    None => PonTokenKind::Error
+                }
+            },
+            b'-' | b'0'...b'9' => {
+                self.pos += 1;
+                self.skip_while(|c| (c as char).is_digit(10));
+                if let (Some(b'.'), Some(c)) = (self.peek(0), self.peek(1)) {
+                    if (c as char).is_digit(10) {
+                        self.pos += 1;
+                        self.skip_while(|c| (c as char).is_digit(10));
+                    }
+                }
+                if self.pos - start == 1 && c == b'-' { PonTokenKind::Error } else { PonTokenKind::Number }
+            },
+            b'@' => {
+                self.pos += 1;
+                self.path();
+                PonTokenKind::DependencyReference
+            },
+            c if is_identifier_start(c) => {
+                self.path();
+                let text = &self.source[start..self.pos];
+                if text.contains(&b'.') {
+                    PonTokenKind::Reference
+                } else if text == b"true" || text == b"false" {
+                    PonTokenKind::Boolean
+                } else if self.next_non_whitespace() == Some(b':') {
+                    PonTokenKind::Key
+                } else {
+                    PonTokenKind::TypeName
+                }
+            },
+            b'{' | b'}' | b'[' | b']' | b'(' | b')' | b',' | b':' | b'.' => {
+                self.pos += 1;
+                PonTokenKind::Punctuation
+            },
+            _ => {
+                self.pos += 1;
+                while self.pos < self.source.len() && (self.source[self.pos] & 0xC0) == 0x80 {
+                    self.pos += 1;
+                }
+                PonTokenKind::Error
+            }
+        })
+    }
+}
+
+pub fn tokenize(source: &str) -> Vec<PonToken> {
+    let mut tokenizer = Tokenizer { source: source.as_bytes(), pos: 0 };
+    let mut tokens = vec![];
+    loop {
+        let start = {
+            tokenizer.skip_while(|c| (c as char).is_whitespace());
+            tokenizer.pos
+        };
+        match tokenizer.next_kind() {
+            Some(kind) => tokens.push(PonToken { kind: kind, start: start, end: tokenizer.pos }),
+            None => break
+        }
+    }
+    tokens
+}
+
+
+#[test]
+fn test_tokenize() {
+    let kinds: Vec<PonTokenKind> = tokenize("vec3 { x: @this.x, y: -1.5, z: other:child.z, s: 'hi' }").iter().map(|t| t.kind).collect();
+    assert_eq!(kinds, vec![
+        PonTokenKind::TypeName, PonTokenKind::Punctuation,
+        PonTokenKind::Key, PonTokenKind::Punctuation, PonTokenKind::DependencyReference, PonTokenKind::Punctuation,
+        PonTokenKind::Key, PonTokenKind::Punctuation, PonTokenKind::Number, PonTokenKind::Punctuation,
+        PonTokenKind::Key, PonTokenKind::Punctuation, PonTokenKind::Reference, PonTokenKind::Punctuation,
+        PonTokenKind::Key, PonTokenKind::Punctuation, PonTokenKind::String,
+        PonTokenKind::Punctuation
+    ]);
+    assert_eq!(tokenize("'hi' 5"), vec![
+        PonToken { kind: PonTokenKind::String, start: 0, end: 4 },
+        PonToken { kind: PonTokenKind::Number, start: 5, end: 6 }
+    ]);
+}
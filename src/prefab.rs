@@ -0,0 +1,124 @@
+
+use std::collections::HashMap;
+
+use document::*;
+use pon::*;
+use diff::{DocumentPatch, AddedEntity, diff_documents, match_children};
+
+// A property an instance overrides that the template also changed; the instance's value is kept. None
+// means the property isn't set.
+#[derive(PartialEq, Debug, Clone)]
+pub struct TemplateConflict {
+    pub prop_ref: PropRef,
+    pub instance: Option<Pon>,
+    pub old: Option<Pon>,
+    pub new: Option<Pon>
+}
+
+// Brings the changes from the old to the new version of a template into each instance, an entity whose
+// subtree was made from the old version. Entities are matched to the template's like diff_documents does,
+// by name or else by type and order. Properties an instance has changed from the old version are kept;
+// where the template changed them too, that's a conflict.
+pub fn propagate_template(document: &mut Document, old: &Document, new: &Document, instances: &[EntityId]) -> Result<Vec<TemplateConflict>, DocError> {
+    let template_patch = diff_documents(old, new);
+    // The template is the old document's top level element
+    let old_root = match old.get_root().and_then(|root| old.get_children(&root).ok().and_then(|children| children.first().cloned())) {
+        Some(root) => root,
+        None => return Ok(vec![])
+    };
+    let mut conflicts = vec![];
+    for instance_id in instances {
+        let mut ids = HashMap::new();
+        map_instance(document, instance_id, old, &old_root, &mut ids);
+        let mut patch = DocumentPatch { removed: vec![], added: vec![], retyped: vec![], properties: vec![] };
+        for entity_id in &template_patch.removed {
+            if let Some(id) = ids.get(entity_id) {
+                patch.removed.push(*id);
+            }
+        }
+        for added in &template_patch.added {
+            if let Some(id) = added.parent_id.and_then(|parent_id| ids.get(&parent_id)) {
+                patch.added.push(AddedEntity { parent_id: Some(*id), index: added.index, entity: added.entity.clone() });
+            }
+        }
+        for &(ref entity_id, ref type_name) in &template_patch.retyped {
+            if let Some(id) = ids.get(entity_id) {
+                // A retyped instance entity is an override like any other
+                if try!(document.get_entity_type_name(id)) == try!(old.get_entity_type_name(entity_id)) {
+                    patch.retyped.push((*id, type_name.clone()));
+                }
+            }
+        }
+        for &(ref prop_ref, ref new_value) in &template_patch.properties {
+            let id = match ids.get(&prop_ref.entity_id) {
+                Some(id) => *id,
+                None => continue
+            };
+            let key = &prop_ref.property_key;
+            let old_value = value(old, &prop_ref.entity_id, key);
+            let instance_value = value(document, &id, key);
+            if same(&instance_value, &old_value) {
+                patch.properties.push((PropRef::new(&id, key), new_value.clone()));
+            } else if !same(&instance_value, new_value) {
+                conflicts.push(TemplateConflict {
+                    prop_ref: PropRef::new(&id, key),
+                    instance: instance_value,
+                    old: old_value,
+                    new: new_value.clone()
+                });
+            }
+        }
+        try!(document.apply_patch(&patch));
+    }
+    Ok(conflicts)
+}
+
+// Template entity ids to the instance entities made from them
+fn map_instance(document: &Document, entity_id: &EntityId, template: &Document, template_id: &EntityId, ids: &mut HashMap<EntityId, EntityId>) {
+    ids.insert(*template_id, *entity_id);
+    let (children, template_children) = match (document.get_children(entity_id), template.get_children(template_id)) {
+        (Ok(children), Ok(template_children)) => (children, template_children),
+        _ => return
+    };
+    let matches = match_children(document, children, template, template_children);
+    for (template_child, child) in template_children.iter().zip(matches) {
+        if let Some(child) = child {
+            map_instance(document, &child, template, template_child, ids);
+        }
+    }
+}
+
+fn value(doc: &Document, entity_id: &EntityId, key: &str) -> Option<Pon> {
+    match doc.has_property(entity_id, key) {
+        Ok(true) => doc.get_property(entity_id, key).ok().map(|value| (*value).clone()),
+        _ => None
+    }
+}
+
+fn same(a: &Option<Pon>, b: &Option<Pon>) -> bool {
+    a.as_ref().map(|x| x.to_string()) == b.as_ref().map(|x| x.to_string())
+}
+
+
+#[test]
+fn test_propagate_template() {
+    let old = Document::from_string(r#"<Turret range="10" damage="5"><Barrel /></Turret>"#).unwrap();
+    let new = Document::from_string(r#"<Turret range="12" damage="6"><Barrel length="2" /><Light /></Turret>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Level name="level">
+        <Turret name="turret_1" range="10" damage="5"><Barrel /></Turret>
+        <Turret name="turret_2" range="10" damage="8"><Barrel /></Turret>
+    </Level>"#).unwrap();
+    let turret_1 = doc.get_entity_by_name("turret_1").unwrap();
+    let turret_2 = doc.get_entity_by_name("turret_2").unwrap();
+    let conflicts = doc.propagate_template(&old, &new, &[turret_1, turret_2]).unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].prop_ref, PropRef::new(&turret_2, "damage"));
+    assert_eq!(*doc.get_property(&turret_1, "damage").unwrap(), Pon::Integer(6));
+    assert_eq!(*doc.get_property(&turret_2, "damage").unwrap(), Pon::Integer(8));
+    assert_eq!(*doc.get_property(&turret_2, "range").unwrap(), Pon::Integer(12));
+    for turret in &[turret_1, turret_2] {
+        let children = doc.get_children(turret).unwrap().clone();
+        assert_eq!(children.len(), 2);
+        assert_eq!(*doc.get_property(&children[0], "length").unwrap(), Pon::Integer(2));
+    }
+}
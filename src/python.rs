@@ -0,0 +1,100 @@
+// Native Python module over the document API, an alternative to the ctypes wrapper in bindings/python that
+// doesn't go through the C ABI. Build with --features python, e.g. through maturin. Documents aren't Send,
+// so a Document object stays on the thread that created it.
+
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use document::*;
+use pon::*;
+
+fn py_err<E: ::std::fmt::Debug>(err: E) -> PyErr {
+    PyValueError::new_err(format!("{:?}", err))
+}
+
+#[pyclass(name = "Document", unsendable)]
+pub struct PyDocument {
+    document: Document
+}
+
+#[pymethods]
+impl PyDocument {
+    #[new]
+    fn new() -> PyDocument {
+        PyDocument { document: Document::new() }
+    }
+    #[staticmethod]
+    fn from_string(xml: &str) -> PyResult<PyDocument> {
+        Document::from_string(xml).map(|document| PyDocument { document: document }).map_err(py_err)
+    }
+    #[cfg(feature = "fs")]
+    #[staticmethod]
+    fn from_file(path: &str) -> PyResult<PyDocument> {
+        Document::from_file(Path::new(path)).map(|document| PyDocument { document: document }).map_err(py_err)
+    }
+    fn to_string(&self) -> String {
+        self.document.to_string()
+    }
+    fn root(&self) -> Option<u64> {
+        self.document.get_root()
+    }
+    fn entity(&self, name: &str) -> Option<u64> {
+        self.document.get_entity_by_name(name)
+    }
+    fn entities(&self) -> Vec<u64> {
+        self.document.entities_iter().cloned().collect()
+    }
+    fn children(&self, entity_id: u64) -> PyResult<Vec<u64>> {
+        self.document.get_children(&entity_id).map(|children| children.clone()).map_err(py_err)
+    }
+    fn type_name(&self, entity_id: u64) -> PyResult<String> {
+        self.document.get_entity_type_name(&entity_id).map(|name| name.to_string()).map_err(py_err)
+    }
+    // See selector.rs
+    fn query(&self, selector: &str) -> PyResult<Vec<u64>> {
+        self.document.query(selector).map_err(py_err)
+    }
+    fn append_entity(&mut self, parent_id: Option<u64>, type_name: &str, name: Option<String>) -> PyResult<u64> {
+        self.document.append_entity(parent_id, type_name, name).map_err(py_err)
+    }
+    fn remove_entity(&mut self, entity_id: u64) -> PyResult<()> {
+        self.document.remove_entity(&entity_id).map(|_| ()).map_err(py_err)
+    }
+    // The resolved value as PON source, like pyramid_get_property
+    fn get(&self, entity_id: u64, key: &str) -> PyResult<String> {
+        let value = try!(self.document.get_property(&entity_id, key).map_err(py_err));
+        value.concretize().map(|value| value.to_string()).map_err(py_err)
+    }
+    // expression is PON source, and can reference other properties like in xml
+    fn set(&mut self, entity_id: u64, key: &str, expression: &str) -> PyResult<()> {
+        let expression = try!(Pon::from_string(expression).map_err(py_err));
+        self.document.set_property(&entity_id, key, expression).map_err(py_err)
+    }
+    fn get_floats(&self, entity_id: u64, key: &str) -> PyResult<Vec<f32>> {
+        self.document.get_property_floats(&entity_id, key).map(|floats| floats.to_vec()).map_err(py_err)
+    }
+    fn set_floats(&mut self, entity_id: u64, key: &str, values: Vec<f32>) -> PyResult<()> {
+        self.document.set_property_floats(&entity_id, key, values).map_err(py_err)
+    }
+    // callback(entity_id, key) is called on every set or unset, like pyramid_subscribe_property_set. Errors it
+    // raises are printed and otherwise ignored.
+    fn subscribe(&mut self, callback: PyObject) {
+        self.document.on_change(Box::new(move |event| {
+            if let &DocEvent::PropertyChanged { ref prop_ref, .. } = event {
+                Python::with_gil(|py| {
+                    if let Err(err) = callback.call1(py, (prop_ref.entity_id, prop_ref.property_key.as_str())) {
+                        err.print(py);
+                    }
+                });
+            }
+        }));
+    }
+}
+
+#[pymodule]
+fn pyramid(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_class::<PyDocument>()
+}
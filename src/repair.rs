@@ -0,0 +1,81 @@
+
+use pon::*;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum MissingTarget {
+    // No entity by this name, e.g. it was renamed or deleted
+    Entity(String),
+    // The entity exists but doesn't have this property set
+    Property(String)
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct BrokenReference {
+    // The property holding the reference
+    pub prop_ref: PropRef,
+    pub reference: NamedPropRef,
+    pub missing: MissingTarget,
+    // Existing entity names or property keys close to the missing one, best first
+    pub suggestions: Vec<String>,
+    pub fixed: bool
+}
+
+pub enum RepairMode {
+    ReportOnly,
+    // Called for every broken reference; returning a name (usually one of the suggestions) replaces the
+    // missing entity name or property key with it
+    AutoFix(Box<Fn(&BrokenReference) -> Option<String>>)
+}
+
+// Levenshtein distance
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev_diagonal = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let substitution = prev_diagonal + if ca == b[j] { 0 } else { 1 };
+            prev_diagonal = row[j + 1];
+            let mut best = substitution;
+            if row[j + 1] + 1 < best { best = row[j + 1] + 1; }
+            if row[j] + 1 < best { best = row[j] + 1; }
+            row[j + 1] = best;
+        }
+    }
+    row[b.len()]
+}
+
+// Up to 3 candidates within a third of the name's length (at least 2 edits), closest first
+pub fn closest_names(name: &str, candidates: Vec<String>) -> Vec<String> {
+    let max_distance = if name.len() / 3 > 2 { name.len() / 3 } else { 2 };
+    let mut scored: Vec<(usize, String)> = candidates.into_iter()
+        .map(|c| (edit_distance(name, &c), c))
+        .filter(|&(d, _)| d <= max_distance)
+        .collect();
+    scored.sort();
+    scored.dedup();
+    scored.into_iter().take(3).map(|(_, c)| c).collect()
+}
+
+// Points the references in expression that are broken in the way `broken` describes at replacement instead
+pub fn rewrite_reference(expression: &mut Pon, broken: &BrokenReference, replacement: &str) {
+    expression.visit_mut(&mut |node| {
+        if let &mut Pon::DependencyReference(ref mut reference, _) = node {
+            match broken.missing {
+                MissingTarget::Entity(ref name) => { reference.entity_path.rename_entity(name, replacement); },
+                MissingTarget::Property(_) => if *reference == broken.reference {
+                    reference.property_key = replacement.to_string();
+                }
+            }
+        }
+    });
+}
+
+
+#[test]
+fn test_closest_names() {
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(closest_names("player_spawn", vec!["player_spawn1".to_string(), "enemy".to_string(), "player_spawns".to_string()]),
+        vec!["player_spawn1".to_string(), "player_spawns".to_string()]);
+}
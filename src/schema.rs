@@ -0,0 +1,503 @@
+
+use std::collections::BTreeMap;
+use std::slice::SliceConcatExt;
+use rustc_serialize::json::Json;
+use regex::Regex;
+use xml::reader::EventReader;
+use xml::reader::events::XmlEvent;
+use xml::common::HasPosition;
+
+use document::*;
+use pon::*;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum PropertyType {
+    Float,
+    Integer,
+    String,
+    Boolean,
+    Array(Box<PropertyType>),
+    Object,
+    // A typed pon, e.g. Typed("vec3") for `vec3 { x: 1.0 }`
+    Typed(String),
+    Any
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PropertySchema {
+    pub property_type: PropertyType,
+    pub required: bool,
+    pub description: Option<String>,
+    // Inclusive bounds for numbers
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    // A regex strings have to match in full
+    pub pattern: Option<String>,
+    // Bounds on the number of items of arrays
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>
+}
+
+impl PropertySchema {
+    pub fn new(property_type: PropertyType) -> PropertySchema {
+        PropertySchema {
+            property_type: property_type,
+            required: false,
+            description: None,
+            min: None,
+            max: None,
+            pattern: None,
+            min_length: None,
+            max_length: None
+        }
+    }
+    // Checks value against the bounds and pattern; values of other kinds than the constraint is for pass
+    pub fn check_constraints(&self, value: &Pon) -> Result<(), String> {
+        let number = match value {
+            &Pon::Float(v) => Some(v as f64),
+            &Pon::Integer(v) => Some(v as f64),
+            _ => None
+        };
+        if let Some(number) = number {
+            if let Some(min) = self.min {
+                if number < min {
+                    return Err(format!("{} is below the minimum {}", number, min));
+                }
+            }
+            if let Some(max) = self.max {
+                if number > max {
+                    return Err(format!("{} is above the maximum {}", number, max));
+                }
+            }
+        }
+        if let (&Pon::String(ref string), &Some(ref pattern)) = (value, &self.pattern) {
+            let regex = try!(Regex::new(&format!("^(?:{})$", pattern)).map_err(|err| format!("Bad pattern {}: {}", pattern, err)));
+            if !regex.is_match(string) {
+                return Err(format!("{:?} doesn't match {}", string, pattern));
+            }
+        }
+        let length = match value {
+            &Pon::Array(ref arr) => Some(arr.len()),
+            &Pon::FloatArray(ref arr) => Some(arr.len()),
+            &Pon::IntegerArray(ref arr) => Some(arr.len()),
+            _ => None
+        };
+        if let Some(length) = length {
+            if let Some(min_length) = self.min_length {
+                if length < min_length {
+                    return Err(format!("{} items, at least {} needed", length, min_length));
+                }
+            }
+            if let Some(max_length) = self.max_length {
+                if length > max_length {
+                    return Err(format!("{} items, at most {} allowed", length, max_length));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct EntitySchema {
+    pub properties: BTreeMap<String, PropertySchema>,
+    // The entity types allowed as children; None allows any
+    pub children: Option<Vec<String>>,
+    // Child type to the minimum and (optional) maximum number of children of that type
+    pub child_counts: BTreeMap<String, (usize, Option<usize>)>
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ValidationError {
+    UnknownEntityType(EntityId, String),
+    UnknownProperty(PropRef),
+    MissingProperty(PropRef),
+    WrongType(PropRef, PropertyType),
+    // Reference to a property that isn't set
+    DanglingReference(PropRef),
+    // A property that (indirectly) depends on itself
+    DependencyCycle(PropRef),
+    // A value outside the bounds or pattern of its property, with a description
+    ConstraintViolated(PropRef, String),
+    // A child of the given type under a parent whose type doesn't allow it
+    DisallowedChild(EntityId, String),
+    // An entity with a number of children of child_type outside the schema's bounds
+    WrongChildCount { entity_id: EntityId, entity_path: String, child_type: String, count: usize }
+}
+
+// A problem with the xml itself, found before any entities are built. Positions are 1-based.
+#[derive(PartialEq, Debug, Clone)]
+pub struct XmlSchemaError {
+    pub row: u64,
+    pub col: u64,
+    pub message: String
+}
+
+// The entity types a document may contain and the properties they take
+#[derive(PartialEq, Debug, Clone)]
+pub struct Schema {
+    pub entity_types: BTreeMap<String, EntitySchema>
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        Schema { entity_types: BTreeMap::new() }
+    }
+    pub fn add_entity_type(&mut self, type_name: &str) -> &mut EntitySchema {
+        self.entity_types.entry(type_name.to_string()).or_insert(EntitySchema { properties: BTreeMap::new(), children: None, child_counts: BTreeMap::new() })
+    }
+    // Restricts the children of type_name to child_types
+    pub fn allow_children(&mut self, type_name: &str, child_types: Vec<&str>) {
+        self.add_entity_type(type_name).children = Some(child_types.iter().map(|x| x.to_string()).collect());
+    }
+    // Checks a value about to be set against the constraints of its property. Values that reference other
+    // properties aren't known yet and pass, as does anything the schema doesn't describe.
+    pub fn check_property(&self, type_name: &str, property_key: &str, value: &Pon) -> Result<(), String> {
+        let key = property_key.split(QUALIFIER_SEPARATOR).next().unwrap();
+        match self.entity_types.get(type_name).and_then(|entity_schema| entity_schema.properties.get(key)) {
+            Some(property) if is_literal(value) => property.check_constraints(value),
+            _ => Ok(())
+        }
+    }
+    // E.g. set_child_count("Node", "Transform", 1, Some(1)) for exactly one Transform per Node
+    pub fn set_child_count(&mut self, type_name: &str, child_type: &str, min: usize, max: Option<usize>) {
+        self.add_entity_type(type_name).child_counts.insert(child_type.to_string(), (min, max));
+    }
+    // Parents of types the schema doesn't know take anything; they're reported as unknown instead
+    pub fn allows_child(&self, parent_type: &str, child_type: &str) -> bool {
+        match self.entity_types.get(parent_type) {
+            Some(&EntitySchema { children: Some(ref children), .. }) => children.iter().any(|c| c == child_type),
+            _ => true
+        }
+    }
+    pub fn add_property(&mut self, type_name: &str, property_key: &str, property: PropertySchema) {
+        self.add_entity_type(type_name).properties.insert(property_key.to_string(), property);
+    }
+    // Problems with the entities and properties of doc; references are checked by Document::integrity_errors
+    pub fn validate(&self, doc: &Document) -> Vec<ValidationError> {
+        let mut errors = vec![];
+        let mut entity_ids: Vec<EntityId> = doc.entities_iter().map(|x| *x).collect();
+        entity_ids.sort();
+        for entity_id in entity_ids {
+            let type_name = doc.get_entity_type_name(&entity_id).unwrap();
+            let entity_schema = match self.entity_types.get(type_name) {
+                Some(entity_schema) => entity_schema,
+                // Schemas describe the saved entities, which the root isn't
+                None if doc.get_root() == Some(entity_id) => continue,
+                None => {
+                    errors.push(ValidationError::UnknownEntityType(entity_id, type_name.to_string()));
+                    continue;
+                }
+            };
+            if let Ok(Some(parent_id)) = doc.get_parent(&entity_id) {
+                if !self.allows_child(doc.get_entity_type_name(&parent_id).unwrap(), type_name) {
+                    errors.push(ValidationError::DisallowedChild(parent_id, type_name.to_string()));
+                }
+            }
+            for (child_type, &(min, max)) in &entity_schema.child_counts {
+                let count = doc.get_children(&entity_id).unwrap().iter()
+                    .filter(|c| doc.get_entity_type_name(c).unwrap() == child_type).count();
+                if count < min || max.map(|max| count > max).unwrap_or(false) {
+                    errors.push(ValidationError::WrongChildCount {
+                        entity_id: entity_id,
+                        entity_path: entity_path(doc, &entity_id),
+                        child_type: child_type.to_string(),
+                        count: count
+                    });
+                }
+            }
+            let mut props = doc.get_properties(&entity_id).unwrap();
+            props.sort_by(|a, b| a.property_key.cmp(&b.property_key));
+            for prop_ref in props {
+                if !doc.has_property(&entity_id, &prop_ref.property_key).unwrap_or(false) {
+                    continue;
+                }
+                match entity_schema.properties.get(&prop_ref.property_key) {
+                    Some(property) => {
+                        // Values that can't be resolved are reported by the integrity check instead
+                        let value = match doc.get_property(&entity_id, &prop_ref.property_key).map(|v| v.concretize()) {
+                            Ok(Ok(value)) => value,
+                            _ => continue
+                        };
+                        if !value_has_type(&value, &property.property_type) {
+                            errors.push(ValidationError::WrongType(prop_ref, property.property_type.clone()));
+                        } else if let Err(message) = property.check_constraints(&value) {
+                            errors.push(ValidationError::ConstraintViolated(prop_ref, message));
+                        }
+                    },
+                    None => errors.push(ValidationError::UnknownProperty(prop_ref))
+                }
+            }
+            for (key, property) in &entity_schema.properties {
+                if property.required && !doc.has_property(&entity_id, key).unwrap_or(false) {
+                    errors.push(ValidationError::MissingProperty(PropRef::new(&entity_id, key)));
+                }
+            }
+        }
+        errors
+    }
+    // Checks the elements and attributes of an xml document against the schema: unknown entity types and
+    // properties, missing required properties and literal values of the wrong type. Values that reference
+    // other properties can only be checked once loaded, see validate. Stops at the first malformed xml.
+    pub fn validate_xml(&self, source: &str) -> Vec<XmlSchemaError> {
+        let mut errors = vec![];
+        let mut parser = EventReader::from_str(source);
+        let mut element_stack: Vec<String> = vec![];
+        loop {
+            let e = parser.next();
+            let (row, col) = (parser.row() + 1, parser.col() + 1);
+            let error = |message: String| XmlSchemaError { row: row, col: col, message: message };
+            match e {
+                // Included entities end up under the Include's parent, like in Document
+                XmlEvent::StartElement { ref name, .. } if name.local_name == "Include" => {
+                    let parent = element_stack.last().cloned().unwrap_or("".to_string());
+                    element_stack.push(parent);
+                },
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    if let Some(parent) = element_stack.last() {
+                        if !self.allows_child(parent, &name.local_name) {
+                            errors.push(error(format!("{} can't be a child of {}", name.local_name, parent)));
+                        }
+                    }
+                    element_stack.push(name.local_name.to_string());
+                    let entity_schema = match self.entity_types.get(&name.local_name) {
+                        Some(entity_schema) => entity_schema,
+                        None => {
+                            errors.push(error(format!("Unknown entity type {}", name.local_name)));
+                            continue;
+                        }
+                    };
+                    let mut keys = vec![];
+                    for attribute in &attributes {
+                        if attribute.name.local_name == "name" { continue; }
+                        // Qualified variants (key--qualifier) share the schema of the plain key
+                        let key = attribute.name.local_name.split(QUALIFIER_SEPARATOR).next().unwrap().to_string();
+                        let property = match entity_schema.properties.get(&key) {
+                            Some(property) => property,
+                            None => {
+                                errors.push(error(format!("Unknown property {} on {}", key, name.local_name)));
+                                continue;
+                            }
+                        };
+                        match Pon::from_string(&attribute.value) {
+                            Ok(value) => if is_literal(&value) && !value_has_type(&value, &property.property_type) {
+                                errors.push(error(format!("Property {} on {} should be {:?}", key, name.local_name, property.property_type)));
+                            },
+                            Err(err) => errors.push(error(format!("Property {} on {} doesn't parse: {:?}", key, name.local_name, err)))
+                        }
+                        keys.push(key);
+                    }
+                    for (key, property) in &entity_schema.properties {
+                        if property.required && !keys.contains(key) {
+                            errors.push(error(format!("Missing required property {} on {}", key, name.local_name)));
+                        }
+                    }
+                },
+                XmlEvent::EndElement { .. } => {
+                    element_stack.pop();
+                },
+                XmlEvent::Error(err) => {
+                    errors.push(XmlSchemaError { row: err.row() + 1, col: err.col() + 1, message: err.msg().to_string() });
+                    break;
+                },
+                XmlEvent::EndDocument => break,
+                _ => {}
+            }
+        }
+        errors
+    }
+    // A JSON Schema (draft 4) document with one definition per entity type, describing the parsed
+    // value of each property, for editors that can't link against this crate
+    pub fn to_json_schema(&self) -> String {
+        let mut definitions = BTreeMap::new();
+        for (type_name, entity) in &self.entity_types {
+            let mut properties = BTreeMap::new();
+            let mut required = vec![];
+            for (key, property) in &entity.properties {
+                let mut def = match property_type_to_json(&property.property_type) {
+                    Json::Object(def) => def,
+                    _ => unreachable!()
+                };
+                if let Some(ref description) = property.description {
+                    def.insert("description".to_string(), Json::String(description.clone()));
+                }
+                properties.insert(key.clone(), Json::Object(def));
+                if property.required {
+                    required.push(Json::String(key.clone()));
+                }
+            }
+            let mut def = BTreeMap::new();
+            def.insert("type".to_string(), Json::String("object".to_string()));
+            def.insert("properties".to_string(), Json::Object(properties));
+            if required.len() > 0 {
+                def.insert("required".to_string(), Json::Array(required));
+            }
+            definitions.insert(type_name.clone(), Json::Object(def));
+        }
+        let mut root = BTreeMap::new();
+        root.insert("$schema".to_string(), Json::String("http://json-schema.org/draft-04/schema#".to_string()));
+        root.insert("definitions".to_string(), Json::Object(definitions));
+        Json::Object(root).pretty().to_string()
+    }
+}
+
+// Like /level/Node[2]/player: entity names where there are any, otherwise the type and index among siblings.
+// The root isn't saved, so it's just the leading /.
+fn entity_path(doc: &Document, entity_id: &EntityId) -> String {
+    let mut segments = vec![];
+    let mut id = *entity_id;
+    while Some(id) != doc.get_root() {
+        let parent = doc.get_parent(&id).unwrap();
+        segments.push(match doc.get_entity_name(&id).unwrap() {
+            Some(name) => name.to_string(),
+            None => match parent {
+                Some(parent) => {
+                    let index = doc.get_children(&parent).unwrap().iter().position(|c| *c == id).unwrap();
+                    format!("{}[{}]", doc.get_entity_type_name(&id).unwrap(), index)
+                },
+                None => doc.get_entity_type_name(&id).unwrap().to_string()
+            }
+        });
+        match parent {
+            Some(parent) => id = parent,
+            None => break
+        }
+    }
+    segments.reverse();
+    format!("/{}", segments.join("/"))
+}
+
+fn is_literal(value: &Pon) -> bool {
+    match value {
+        &Pon::DependencyReference(..) | &Pon::Reference(..) => false,
+        &Pon::TypedPon(ref typed) => is_literal(&typed.data),
+        &Pon::Array(ref arr) => arr.iter().all(is_literal),
+        &Pon::Object(ref hm) => hm.values().all(is_literal),
+        _ => true
+    }
+}
+
+fn value_has_type(value: &Pon, property_type: &PropertyType) -> bool {
+    match (property_type, value) {
+        (&PropertyType::Any, _) => true,
+        (&PropertyType::Float, &Pon::Float(_)) => true,
+        (&PropertyType::Float, &Pon::Integer(_)) => true,
+        (&PropertyType::Integer, &Pon::Integer(_)) => true,
+        (&PropertyType::String, &Pon::String(_)) => true,
+        (&PropertyType::Boolean, &Pon::Boolean(_)) => true,
+        (&PropertyType::Object, &Pon::Object(_)) => true,
+        (&PropertyType::Array(ref item), &Pon::Array(ref arr)) => arr.iter().all(|v| value_has_type(v, item)),
+        (&PropertyType::Array(box PropertyType::Float), &Pon::FloatArray(_)) => true,
+        (&PropertyType::Array(box PropertyType::Integer), &Pon::IntegerArray(_)) => true,
+        (&PropertyType::Array(box PropertyType::Any), &Pon::FloatArray(_)) => true,
+        (&PropertyType::Array(box PropertyType::Any), &Pon::IntegerArray(_)) => true,
+        (&PropertyType::Typed(ref type_name), &Pon::TypedPon(ref typed)) => &typed.type_name == type_name,
+        _ => false
+    }
+}
+
+fn property_type_to_json(property_type: &PropertyType) -> Json {
+    let mut def = BTreeMap::new();
+    match property_type {
+        &PropertyType::Float => { def.insert("type".to_string(), Json::String("number".to_string())); },
+        &PropertyType::Integer => { def.insert("type".to_string(), Json::String("integer".to_string())); },
+        &PropertyType::String => { def.insert("type".to_string(), Json::String("string".to_string())); },
+        &PropertyType::Boolean => { def.insert("type".to_string(), Json::String("boolean".to_string())); },
+        &PropertyType::Array(ref item) => {
+            def.insert("type".to_string(), Json::String("array".to_string()));
+            def.insert("items".to_string(), property_type_to_json(item));
+        },
+        &PropertyType::Object => { def.insert("type".to_string(), Json::String("object".to_string())); },
+        &PropertyType::Typed(ref type_name) => { def.insert("x-pon-type".to_string(), Json::String(type_name.clone())); },
+        &PropertyType::Any => {}
+    }
+    Json::Object(def)
+}
+
+
+#[test]
+fn test_json_schema() {
+    let mut schema = Schema::new();
+    let mut intensity = PropertySchema::new(PropertyType::Float);
+    intensity.required = true;
+    schema.add_property("Light", "intensity", intensity);
+    let json = Json::from_str(&schema.to_json_schema()).unwrap();
+    let light = json.find_path(&["definitions", "Light"]).unwrap();
+    assert_eq!(light.find_path(&["properties", "intensity", "type"]).unwrap().as_string(), Some("number"));
+    assert_eq!(light.find("required").unwrap().as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn test_validate() {
+    let mut schema = Schema::new();
+    let mut intensity = PropertySchema::new(PropertyType::Float);
+    intensity.required = true;
+    schema.add_property("Light", "intensity", intensity);
+    schema.add_property("Light", "label", PropertySchema::new(PropertyType::String));
+    let doc = Document::from_string(r#"<Light name="a" label="5" />"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    assert_eq!(schema.validate(&doc), vec![
+        ValidationError::WrongType(PropRef::new(&a, "label"), PropertyType::String),
+        ValidationError::MissingProperty(PropRef::new(&a, "intensity"))
+    ]);
+}
+
+#[test]
+fn test_validate_xml() {
+    let mut schema = Schema::new();
+    schema.add_property("Light", "intensity", PropertySchema::new(PropertyType::Float));
+    let errors = schema.validate_xml("<Light intensity=\"'bright'\">\n  <Lamp />\n</Light>");
+    assert_eq!(errors.iter().map(|e| (e.row, e.message.clone())).collect::<Vec<(u64, String)>>(), vec![
+        (1, "Property intensity on Light should be Float".to_string()),
+        (2, "Unknown entity type Lamp".to_string())
+    ]);
+}
+
+#[test]
+fn test_child_types() {
+    let mut schema = Schema::new();
+    schema.allow_children("Scene", vec!["Light", "Camera"]);
+    schema.allow_children("Light", vec![]);
+    schema.add_entity_type("Camera");
+    let errors = schema.validate_xml("<Scene>\n  <Light>\n    <Camera />\n  </Light>\n</Scene>");
+    assert_eq!(errors.iter().map(|e| (e.row, e.message.clone())).collect::<Vec<(u64, String)>>(), vec![
+        (3, "Camera can't be a child of Light".to_string())
+    ]);
+    let mut doc = Document::from_string_validated("<Scene><Light name=\"sun\" /></Scene>", &schema).unwrap();
+    let sun = doc.get_entity_by_name("sun").unwrap();
+    assert_eq!(doc.append_entity(Some(sun), "Camera", None).err(),
+        Some(DocError::ValidationFailed(vec![ValidationError::DisallowedChild(sun, "Camera".to_string())])));
+}
+
+#[test]
+fn test_child_counts() {
+    let mut schema = Schema::new();
+    schema.set_child_count("Node", "Transform", 1, Some(1));
+    schema.set_child_count("Node", "Body", 0, Some(1));
+    schema.add_entity_type("Transform");
+    schema.add_entity_type("Body");
+    let doc = Document::from_string(r#"<Node name="level"><Node><Transform /><Body /><Body /></Node></Node>"#).unwrap();
+    let level = doc.get_entity_by_name("level").unwrap();
+    let node = doc.get_children(&level).unwrap()[0];
+    assert_eq!(schema.validate(&doc), vec![
+        ValidationError::WrongChildCount { entity_id: level, entity_path: "/level".to_string(), child_type: "Transform".to_string(), count: 0 },
+        ValidationError::WrongChildCount { entity_id: node, entity_path: "/level/Node[0]".to_string(), child_type: "Body".to_string(), count: 2 }
+    ]);
+}
+
+#[test]
+fn test_constraints() {
+    let mut schema = Schema::new();
+    let mut intensity = PropertySchema::new(PropertyType::Float);
+    intensity.min = Some(0.0);
+    schema.add_property("Light", "intensity", intensity);
+    let mut label = PropertySchema::new(PropertyType::String);
+    label.pattern = Some("[a-z]+".to_string());
+    schema.add_property("Light", "label", label);
+    let doc = Document::from_string(r#"<Light name="a" intensity="-1.0" label="'Sun'" />"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    assert_eq!(schema.validate(&doc), vec![
+        ValidationError::ConstraintViolated(PropRef::new(&a, "intensity"), "-1 is below the minimum 0".to_string()),
+        ValidationError::ConstraintViolated(PropRef::new(&a, "label"), "\"Sun\" doesn't match [a-z]+".to_string())
+    ]);
+}
@@ -0,0 +1,83 @@
+
+use document::*;
+use pon::*;
+use system::*;
+use interface::*;
+use access::*;
+
+// Implemented by the host around whichever script engine it embeds (Lua, rhai, ...). Scripts see the document
+// through ScriptApi, which deals in entity ids, names and PON source strings since that's what crosses a
+// script boundary easily. Changes are checked against the capability the api was made with.
+pub trait ScriptEngine {
+    fn update(&mut self, api: &mut ScriptApi);
+}
+
+pub struct ScriptApi<'a> {
+    document: RestrictedDocument<'a>
+}
+
+impl<'a> ScriptApi<'a> {
+    pub fn new(document: RestrictedDocument<'a>) -> ScriptApi<'a> {
+        ScriptApi { document: document }
+    }
+    pub fn find(&self, name: &str) -> Option<EntityId> {
+        self.document.document().get_entity_by_name(name)
+    }
+    // Returns the resolved value as PON source
+    pub fn get(&self, entity_id: &EntityId, property_key: &str) -> Result<String, DocError> {
+        let value = try!(try!(self.document.get_property(entity_id, property_key)).concretize());
+        Ok(value.to_string())
+    }
+    pub fn set(&mut self, entity_id: &EntityId, property_key: &str, expression: &str) -> Result<(), DocError> {
+        let expression = match Pon::from_string(expression) {
+            Ok(expression) => expression,
+            Err(err) => return Err(DocError::PonTranslateErr(PonTranslateErr::Generic(format!("{:?}", err))))
+        };
+        self.document.set_property(entity_id, property_key, expression)
+    }
+    // All entities of the given type
+    pub fn query(&self, type_name: &str) -> Vec<EntityId> {
+        self.document.document().get_entities_by_type(type_name).collect()
+    }
+    pub fn spawn(&mut self, parent_id: &EntityId, type_name: &str, name: Option<String>) -> Result<EntityId, DocError> {
+        self.document.append_entity(parent_id, type_name, name)
+    }
+}
+
+// Runs a script engine once per update. Changes it makes go through the document like any other, so they are
+// picked up by System's cascade the same frame. The script can only change what token, granted by access,
+// is scoped to.
+pub struct ScriptSubSystem {
+    engine: Box<ScriptEngine>,
+    access: AccessControl,
+    token: CapabilityToken
+}
+
+impl ScriptSubSystem {
+    pub fn new(engine: Box<ScriptEngine>, access: AccessControl, token: CapabilityToken) -> ScriptSubSystem {
+        ScriptSubSystem { engine: engine, access: access, token: token }
+    }
+}
+
+impl ISubSystem for ScriptSubSystem {
+    fn update(&mut self, system: &mut System) {
+        let mut api = ScriptApi::new(self.access.restrict(system.document_mut(), self.token));
+        self.engine.update(&mut api);
+    }
+}
+
+
+#[test]
+fn test_script_api() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Mesh name="ship" x="1.0" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let ship = doc.get_entity_by_name("ship").unwrap();
+    let mut access = AccessControl::new();
+    let token = access.grant(CapabilityScope::Subtree(ship));
+    let mut api = ScriptApi::new(access.restrict(&mut doc, token));
+    assert_eq!(api.find("ship"), Some(ship));
+    api.set(&ship, "y", "@this.x").unwrap();
+    assert_eq!(api.set(&root, "y", "1"), Err(DocError::AccessDenied(PropRef::new(&root, "y"))));
+    assert_eq!(api.get(&ship, "y").unwrap(), Pon::Float(1.0).to_string());
+    assert_eq!(api.query("Mesh"), vec![ship]);
+}
@@ -0,0 +1,89 @@
+// ScriptEngine running a rhai script every update, for hosts that don't bring their own engine. The script
+// sees ScriptApi as functions: find(name), get(id, key), set(id, key, expression), query(type_name) and
+// spawn(parent_id, type_name, name). Ids are rhai integers, find returns () when there's no such entity, and
+// values go in and out as PON source like everywhere else in ScriptApi.
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, AST};
+
+use document::*;
+use script::*;
+
+pub struct RhaiScriptEngine {
+    ast: AST,
+    errors: Vec<String>
+}
+
+impl RhaiScriptEngine {
+    pub fn new(source: &str) -> Result<RhaiScriptEngine, DocError> {
+        match Engine::new().compile(source) {
+            Ok(ast) => Ok(RhaiScriptEngine { ast: ast, errors: vec![] }),
+            Err(err) => Err(DocError::LoadError(LoadError { file: None, position: None, message: err.to_string() }))
+        }
+    }
+    // Errors the script raised since the last call, one per failed update
+    pub fn take_errors(&mut self) -> Vec<String> {
+        ::std::mem::replace(&mut self.errors, vec![])
+    }
+}
+
+fn script_err(err: DocError) -> Box<EvalAltResult> {
+    format!("{:?}", err).into()
+}
+
+// Only dereferenced by the functions registered in update, on an engine that doesn't outlive the call
+#[derive(Clone, Copy)]
+struct ApiHandle(*mut ScriptApi<'static>);
+
+impl ApiHandle {
+    fn api(&self) -> &mut ScriptApi<'static> {
+        unsafe { &mut *self.0 }
+    }
+}
+
+impl ScriptEngine for RhaiScriptEngine {
+    fn update(&mut self, api: &mut ScriptApi) {
+        let h = ApiHandle(api as *mut ScriptApi as *mut ScriptApi<'static>);
+        let mut engine = Engine::new();
+        engine.register_fn("find", move |name: &str| match h.api().find(name) {
+            Some(id) => Dynamic::from(id as i64),
+            None => Dynamic::UNIT
+        });
+        engine.register_fn("get", move |id: i64, key: &str| h.api().get(&(id as EntityId), key).map_err(script_err));
+        engine.register_fn("set", move |id: i64, key: &str, expression: &str| h.api().set(&(id as EntityId), key, expression).map_err(script_err));
+        engine.register_fn("query", move |type_name: &str| h.api().query(type_name).into_iter().map(|id| Dynamic::from(id as i64)).collect::<Array>());
+        engine.register_fn("spawn", move |parent_id: i64, type_name: &str, name: &str| {
+            h.api().spawn(&(parent_id as EntityId), type_name, Some(name.to_string())).map(|id| id as i64).map_err(script_err)
+        });
+        if let Err(err) = engine.run_ast(&self.ast) {
+            self.errors.push(err.to_string());
+        }
+    }
+}
+
+
+#[test]
+fn test_rhai_script_engine() {
+    use access::*;
+    use pon::*;
+    let mut doc = Document::from_string(r#"<Entity name="root"><Mesh name="ship" x="1.0" /></Entity>"#).unwrap();
+    let ship = doc.get_entity_by_name("ship").unwrap();
+    let mut access = AccessControl::new();
+    let token = access.grant(CapabilityScope::Subtree(ship));
+    let mut engine = RhaiScriptEngine::new(r#"
+        let ship = find("ship");
+        set(ship, "y", "@this.x");
+        spawn(ship, "Entity", "exhaust");
+        if find("missing") == () { set(ship, "z", "2") }
+        set(find("root"), "y", "1");
+    "#).unwrap();
+    {
+        let mut api = ScriptApi::new(access.restrict(&mut doc, token));
+        engine.update(&mut api);
+    }
+    assert_eq!(doc.get_property(&ship, "y").unwrap().concretize(), Ok(Pon::Float(1.0)));
+    assert_eq!(*doc.get_property(&ship, "z").unwrap(), Pon::Integer(2));
+    assert!(doc.get_entity_by_name("exhaust").is_some());
+    // Outside the capability
+    assert_eq!(engine.take_errors().len(), 1);
+    assert!(RhaiScriptEngine::new("let = ;").is_err());
+}
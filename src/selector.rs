@@ -0,0 +1,102 @@
+peg_file! selector_peg("selector.rustpeg");
+
+pub use selector::selector_peg::ParseError as SelectorParseError;
+
+use document::*;
+use pon::*;
+
+// CSS like selectors over the entity hierarchy, see Document::query:
+//
+//   Scene > Mesh[visible=true]   Meshes that are direct children of a Scene and have visible set to true
+//   Level #ship [health]         the entity named ship somewhere in a Level, or any entity below it with a health
+//   *[x=1.5]                     any entity with x set to 1.5
+//
+// Values are PON literals; they're compared with the concrete value of the property.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Selector {
+    // The combinator of the first step is ignored
+    pub steps: Vec<(Combinator, Compound)>
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Combinator {
+    Descendant,
+    Child
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Compound {
+    // None matches any type
+    pub type_name: Option<String>,
+    pub name: Option<String>,
+    pub conditions: Vec<Condition>
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Condition {
+    Has(String),
+    Equals(String, Pon)
+}
+
+impl Selector {
+    pub fn from_string(string: &str) -> Result<Selector, SelectorParseError> {
+        selector_peg::selector(string)
+    }
+    pub fn matches(&self, doc: &Document, entity_id: &EntityId) -> bool {
+        self.matches_step(doc, entity_id, self.steps.len() - 1)
+    }
+    fn matches_step(&self, doc: &Document, entity_id: &EntityId, step: usize) -> bool {
+        if !self.steps[step].1.matches(doc, entity_id) {
+            return false;
+        }
+        if step == 0 {
+            return true;
+        }
+        let mut ancestor = doc.get_parent(entity_id).unwrap_or(None);
+        while let Some(id) = ancestor {
+            if self.matches_step(doc, &id, step - 1) {
+                return true;
+            }
+            if self.steps[step].0 == Combinator::Child {
+                return false;
+            }
+            ancestor = doc.get_parent(&id).unwrap_or(None);
+        }
+        false
+    }
+}
+
+impl Compound {
+    fn matches(&self, doc: &Document, entity_id: &EntityId) -> bool {
+        if let Some(ref type_name) = self.type_name {
+            if doc.get_entity_type_name(entity_id).ok() != Some(type_name) {
+                return false;
+            }
+        }
+        if let Some(ref name) = self.name {
+            if doc.get_entity_name(entity_id).unwrap_or(None) != Some(name) {
+                return false;
+            }
+        }
+        self.conditions.iter().all(|condition| match condition {
+            &Condition::Has(ref key) => doc.has_property(entity_id, key).unwrap_or(false),
+            &Condition::Equals(ref key, ref expected) => match doc.get_property(entity_id, key) {
+                Ok(value) => value.concretize().map(|value| &value == expected).unwrap_or(false),
+                Err(_) => false
+            }
+        })
+    }
+}
+
+
+#[test]
+fn test_selector() {
+    let doc = Document::from_string(r#"<Scene name="root"><Mesh name="a" visible="true" /><Group><Mesh name="b" visible="true" /></Group><Mesh name="c" visible="false" /></Scene>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    assert_eq!(doc.query("Scene > Mesh[visible=true]").unwrap(), vec![a]);
+    assert_eq!(doc.query("Scene Mesh[visible=true]").unwrap(), vec![a, b]);
+    assert_eq!(doc.query("Group #b").unwrap(), vec![b]);
+    assert_eq!(doc.query("*[visible]").unwrap().len(), 3);
+    assert!(doc.query("Scene >").is_err());
+}
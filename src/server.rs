@@ -0,0 +1,277 @@
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::slice::SliceConcatExt;
+use std::mem;
+use std::str;
+
+use rustc_serialize::json::Json;
+
+use document::*;
+use pon::*;
+
+// A minimal single threaded HTTP server around a document, for tools that can't link against the crate.
+// Entities are addressed by id or by name; property values go over the wire as PON.
+//
+//   GET  /entities/<entity>                    JSON with type, name, properties and children
+//   GET  /entities/<entity>/properties/<key>   the property's PON
+//   PUT  /entities/<entity>/properties/<key>   body is the new PON
+//   POST /entities/<parent>?type=<type>&name=<name>   appends a child, responds with its id
+//   GET  /changes                              server-sent events, one `<entity id> <key>` per property set
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String
+}
+
+impl Response {
+    fn new(status: u16, body: String) -> Response {
+        Response { status: status, content_type: "text/plain", body: body }
+    }
+    fn error(status: u16, err: DocError) -> Response {
+        Response::new(status, format!("{:?}", err))
+    }
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error"
+    }
+}
+
+fn find_entity(document: &Document, segment: &str) -> Option<EntityId> {
+    if let Ok(id) = segment.parse::<EntityId>() {
+        if document.get_entity_type_name(&id).is_ok() {
+            return Some(id);
+        }
+    }
+    document.get_entity_by_name(segment)
+}
+
+fn entity_json(document: &Document, entity_id: &EntityId) -> Result<Json, DocError> {
+    let mut properties = BTreeMap::new();
+    for prop_ref in try!(document.get_properties(entity_id)) {
+        if try!(document.has_property(entity_id, &prop_ref.property_key)) {
+            let value = try!(document.get_property(entity_id, &prop_ref.property_key)).to_string();
+            properties.insert(prop_ref.property_key, Json::String(value));
+        }
+    }
+    let mut json = BTreeMap::new();
+    json.insert("id".to_string(), Json::U64(*entity_id));
+    json.insert("type".to_string(), Json::String(try!(document.get_entity_type_name(entity_id)).to_string()));
+    json.insert("name".to_string(), match try!(document.get_entity_name(entity_id)) {
+        Some(name) => Json::String(name.to_string()),
+        None => Json::Null
+    });
+    json.insert("properties".to_string(), Json::Object(properties));
+    json.insert("children".to_string(), Json::Array(try!(document.get_children(entity_id)).iter().map(|id| Json::U64(*id)).collect()));
+    Ok(Json::Object(json))
+}
+
+// Decodes %XX escapes, leaving malformed ones as they are
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 3 <= bytes.len() {
+            str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Query values are form encoded, so + is a space there
+fn query_param(query: &str, key: &str) -> Option<String> {
+    query.split('&').filter_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(k), Some(v)) if percent_decode(k) == key => Some(percent_decode(&v.replace("+", " "))),
+            _ => None
+        }
+    }).next()
+}
+
+// Everything but /changes, which needs the connection
+pub fn handle_request(document: &mut Document, method: &str, target: &str, body: &str) -> Response {
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, "")
+    };
+    // Decoded after splitting, so an escaped / stays part of its segment
+    let segments: Vec<String> = path.split('/').filter(|s| s.len() > 0).map(percent_decode).collect();
+    if segments.len() < 2 || segments[0] != "entities" {
+        return Response::new(404, "Not found".to_string());
+    }
+    let entity_id = match find_entity(document, &segments[1]) {
+        Some(id) => id,
+        None => return Response::new(404, format!("No entity {}", segments[1]))
+    };
+    match (method, segments.len()) {
+        ("GET", 2) => match entity_json(document, &entity_id) {
+            Ok(json) => Response { status: 200, content_type: "application/json", body: json.to_string() },
+            Err(err) => Response::error(500, err)
+        },
+        ("POST", 2) => {
+            let type_name = query_param(query, "type").unwrap_or("Entity".to_string());
+            let name = query_param(query, "name");
+            match document.append_entity(Some(entity_id), &type_name, name) {
+                Ok(id) => Response::new(201, id.to_string()),
+                Err(err) => Response::error(400, err)
+            }
+        },
+        ("GET", 4) if segments[2] == "properties" => match document.get_property(&entity_id, &segments[3]) {
+            Ok(value) => Response::new(200, value.to_string()),
+            Err(err) => Response::error(404, err)
+        },
+        ("PUT", 4) if segments[2] == "properties" => {
+            let value = match Pon::from_string(body.trim()) {
+                Ok(value) => value,
+                Err(err) => return Response::new(400, format!("{:?}", err))
+            };
+            match document.set_property(&entity_id, &segments[3], value) {
+                Ok(_) => Response::new(204, "".to_string()),
+                Err(err) => Response::error(400, err)
+            }
+        },
+        _ => Response::new(405, "Method not allowed".to_string())
+    }
+}
+
+pub struct DocumentServer {
+    pub document: Document,
+    changes: Rc<RefCell<Vec<PropRef>>>,
+    listeners: Vec<TcpStream>
+}
+
+impl DocumentServer {
+    pub fn new(mut document: Document) -> DocumentServer {
+        let changes = Rc::new(RefCell::new(vec![]));
+        let changes_cb = changes.clone();
+        // Whoever set the document up keeps getting called
+        let previous = document.on_property_set.take();
+        document.on_property_set = Some(Box::new(move |entity_id, property_key| {
+            if let Some(ref previous) = previous {
+                previous(entity_id, property_key);
+            }
+            changes_cb.borrow_mut().push(PropRef::new(entity_id, property_key));
+        }));
+        DocumentServer {
+            document: document,
+            changes: changes,
+            listeners: vec![]
+        }
+    }
+    // Blocks, handling one request at a time
+    pub fn serve(&mut self, address: &str) -> io::Result<()> {
+        let listener = try!(TcpListener::bind(address));
+        for stream in listener.incoming() {
+            let stream = try!(stream);
+            if let Err(err) = self.handle_connection(stream) {
+                println!("Request failed: {}", err);
+            }
+            self.publish_changes();
+        }
+        Ok(())
+    }
+    fn handle_connection(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        let (method, target, body) = {
+            let mut reader = BufReader::new(&mut stream);
+            let mut request_line = String::new();
+            try!(reader.read_line(&mut request_line));
+            let mut content_length: u64 = 0;
+            loop {
+                let mut header = String::new();
+                try!(reader.read_line(&mut header));
+                let header = header.trim();
+                if header.len() == 0 { break; }
+                let mut parts = header.splitn(2, ':');
+                if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                    if name.trim().to_lowercase() == "content-length" {
+                        content_length = value.trim().parse().unwrap_or(0);
+                    }
+                }
+            }
+            let mut body = vec![];
+            try!(reader.by_ref().take(content_length).read_to_end(&mut body));
+            let mut words = request_line.split_whitespace();
+            let method = words.next().unwrap_or("").to_string();
+            let target = words.next().unwrap_or("").to_string();
+            (method, target, String::from_utf8_lossy(&body).into_owned())
+        };
+        if method == "GET" && target == "/changes" {
+            try!(stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n"));
+            self.listeners.push(stream);
+            return Ok(());
+        }
+        let response = handle_request(&mut self.document, &method, &target, &body);
+        write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response.status, status_text(response.status), response.content_type, response.body.len(), response.body)
+    }
+    // Listeners that can't be written to have gone away and are dropped
+    fn publish_changes(&mut self) {
+        let changes = mem::replace(&mut *self.changes.borrow_mut(), vec![]);
+        if changes.len() == 0 {
+            return;
+        }
+        let events: String = changes.iter().map(|p| format!("data: {} {}\n\n", p.entity_id, p.property_key)).collect::<Vec<String>>().concat();
+        self.listeners.retain(|stream| {
+            let mut stream = stream;
+            stream.write_all(events.as_bytes()).and_then(|_| stream.flush()).is_ok()
+        });
+    }
+}
+
+
+#[test]
+fn test_handle_request() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" />"#).unwrap();
+    let put = handle_request(&mut doc, "PUT", "/entities/root/properties/x", "5");
+    assert_eq!(put.status, 204);
+    assert_eq!(handle_request(&mut doc, "GET", "/entities/root/properties/x", "").body, "5");
+    let post = handle_request(&mut doc, "POST", "/entities/root?type=Light&name=sun", "");
+    assert_eq!(post.status, 201);
+    let json = Json::from_str(&handle_request(&mut doc, "GET", "/entities/sun", "").body).unwrap();
+    assert_eq!(json.find("type").unwrap().as_string(), Some("Light"));
+    // Names are percent-decoded, in the query and in the path
+    assert_eq!(handle_request(&mut doc, "POST", "/entities/root?name=my%20ship", "").status, 201);
+    assert!(doc.get_entity_by_name("my ship").is_some());
+    assert_eq!(handle_request(&mut doc, "PUT", "/entities/my%20ship/properties/x", "2").status, 204);
+    assert_eq!(handle_request(&mut doc, "GET", "/entities/my%20ship/properties/x", "").body, "2");
+}
+
+#[test]
+fn test_server_keeps_property_callback() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="1" />"#).unwrap();
+    let notified = Rc::new(RefCell::new(vec![]));
+    let notified_cb = notified.clone();
+    doc.on_property_set = Some(Box::new(move |_, key| notified_cb.borrow_mut().push(key.to_string())));
+    let mut server = DocumentServer::new(doc);
+    let root = server.document.get_entity_by_name("root").unwrap();
+    server.document.set_property(&root, "x", Pon::Integer(2)).unwrap();
+    assert_eq!(*notified.borrow(), vec!["x".to_string()]);
+    assert_eq!(*server.changes.borrow(), vec![PropRef::new(&root, "x")]);
+}
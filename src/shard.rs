@@ -0,0 +1,268 @@
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use document::*;
+use pon::*;
+
+// A partition of a document's entities into subtree shards. Each of the root's child subtrees lives wholly in
+// one shard, so set_property calls on entities in different shards only interact through cross_shard_edges.
+#[derive(Debug)]
+pub struct DocumentShards {
+    pub shards: Vec<Vec<EntityId>>,
+    // (dependency, dependant) pairs whose entities live in different shards
+    pub cross_shard_edges: Vec<(PropRef, PropRef)>,
+    shard_by_entity: HashMap<EntityId, usize>
+}
+
+impl DocumentShards {
+    pub fn shard_of(&self, entity_id: &EntityId) -> Option<usize> {
+        self.shard_by_entity.get(entity_id).map(|x| *x)
+    }
+}
+
+fn collect_subtree(document: &Document, entity_id: &EntityId, out: &mut Vec<EntityId>) -> Result<(), DocError> {
+    out.push(*entity_id);
+    for child in try!(document.get_children(entity_id)) {
+        try!(collect_subtree(document, child, out));
+    }
+    Ok(())
+}
+
+// Greedily assigns the root's child subtrees to the currently smallest shard. The root itself goes in shard 0.
+pub fn shard_by_subtree(document: &Document, shard_count: usize) -> Result<DocumentShards, DocError> {
+    let shard_count = if shard_count == 0 { 1 } else { shard_count };
+    let mut shards: Vec<Vec<EntityId>> = (0..shard_count).map(|_| vec![]).collect();
+    if let Some(root) = document.get_root() {
+        shards[0].push(root);
+        let mut subtrees = vec![];
+        for child in try!(document.get_children(&root)) {
+            let mut subtree = vec![];
+            try!(collect_subtree(document, child, &mut subtree));
+            subtrees.push(subtree);
+        }
+        subtrees.sort_by(|a, b| b.len().cmp(&a.len()));
+        for subtree in subtrees {
+            let mut smallest = 0;
+            for i in 1..shards.len() {
+                if shards[i].len() < shards[smallest].len() {
+                    smallest = i;
+                }
+            }
+            shards[smallest].extend(subtree);
+        }
+    }
+    let mut shard_by_entity = HashMap::new();
+    for (i, shard) in shards.iter().enumerate() {
+        for entity_id in shard {
+            shard_by_entity.insert(*entity_id, i);
+        }
+    }
+    let mut cross_shard_edges = vec![];
+    for (entity_id, shard) in &shard_by_entity {
+        for prop_ref in try!(document.get_properties(entity_id)) {
+            for dependant in try!(document.get_property_dependants(&prop_ref.entity_id, &prop_ref.property_key)) {
+                if shard_by_entity.get(&dependant.entity_id) != Some(shard) {
+                    cross_shard_edges.push((prop_ref.clone(), dependant.clone()));
+                }
+            }
+        }
+    }
+    Ok(DocumentShards {
+        shards: shards,
+        cross_shard_edges: cross_shard_edges,
+        shard_by_entity: shard_by_entity
+    })
+}
+
+// One shard's property expressions, as PON source since Pon can't cross threads
+struct Shard {
+    values: HashMap<PropRef, String>,
+    // Set through the ShardedDocument since the last write_back, in order
+    changed: Vec<PropRef>,
+    // Properties in this shard depending on something that was set in another shard, since the last
+    // take_invalidated
+    invalidated: Vec<PropRef>
+}
+
+// The document's properties split by shard_by_subtree, each shard behind its own lock, so threads working on
+// unrelated subtrees can set properties at the same time. Dependencies are taken from the document when
+// splitting and kept in one edge table shared by all shards; setting a property marks its dependants in other
+// shards as invalidated. write_back applies the sets to the document.
+//
+// References aren't supported: set_property refuses expressions with any, since they'd need new edges, and
+// get_property returns the expressions the document had when splitting, references included, unresolved.
+pub struct ShardedDocument {
+    shards: Vec<Mutex<Shard>>,
+    shard_by_entity: HashMap<EntityId, usize>,
+    // Dependants by dependency, never changed after splitting so reading needs no lock
+    edges: HashMap<PropRef, Vec<PropRef>>
+}
+
+impl ShardedDocument {
+    pub fn split(document: &Document, shard_count: usize) -> Result<ShardedDocument, DocError> {
+        let partition = try!(shard_by_subtree(document, shard_count));
+        let mut shards = vec![];
+        let mut edges = HashMap::new();
+        for entity_ids in &partition.shards {
+            let mut values = HashMap::new();
+            for entity_id in entity_ids {
+                for prop_ref in try!(document.get_properties(entity_id)) {
+                    let dependants = try!(document.get_property_dependants(&prop_ref.entity_id, &prop_ref.property_key)).clone();
+                    if dependants.len() > 0 {
+                        edges.insert(prop_ref.clone(), dependants);
+                    }
+                    if let Ok(value) = document.get_property(&prop_ref.entity_id, &prop_ref.property_key) {
+                        values.insert(prop_ref.clone(), value.to_string());
+                    }
+                }
+            }
+            shards.push(Mutex::new(Shard { values: values, changed: vec![], invalidated: vec![] }));
+        }
+        Ok(ShardedDocument {
+            shards: shards,
+            shard_by_entity: partition.shard_by_entity,
+            edges: edges
+        })
+    }
+    pub fn shard_of(&self, entity_id: &EntityId) -> Option<usize> {
+        self.shard_by_entity.get(entity_id).map(|x| *x)
+    }
+    fn shard_index(&self, entity_id: &EntityId) -> Result<usize, DocError> {
+        match self.shard_of(entity_id) {
+            Some(shard) => Ok(shard),
+            None => Err(DocError::NoSuchEntity(*entity_id))
+        }
+    }
+    // The expression as set, not its resolved value
+    pub fn get_property(&self, entity_id: &EntityId, property_key: &str) -> Result<Pon, DocError> {
+        let shard = try!(self.shard_index(entity_id));
+        let source = match self.shards[shard].lock().unwrap().values.get(&PropRef::new(entity_id, property_key)) {
+            Some(source) => source.clone(),
+            None => return Err(DocError::NoSuchProperty(property_key.to_string()))
+        };
+        Pon::from_string(&source).map_err(|err| DocError::PonTranslateErr(PonTranslateErr::Generic(format!("{:?}", err))))
+    }
+    // Only locks the entity's shard, and then each other shard its dependants are in, one at a time. The
+    // expression can't reference other properties since that would change the edge table. Returns the cascade.
+    pub fn set_property(&self, entity_id: &EntityId, property_key: &str, expression: Pon) -> Result<Vec<PropRef>, DocError> {
+        let prop_ref = PropRef::new(entity_id, property_key);
+        let mut references = vec![];
+        expression.get_dependency_references(&mut references);
+        if references.len() > 0 {
+            return Err(DocError::ShardedReference(prop_ref));
+        }
+        let shard = try!(self.shard_index(entity_id));
+        {
+            let mut locked = self.shards[shard].lock().unwrap();
+            locked.values.insert(prop_ref.clone(), expression.to_string());
+            if !locked.changed.contains(&prop_ref) {
+                locked.changed.push(prop_ref.clone());
+            }
+        }
+        let mut cascade = vec![prop_ref.clone()];
+        let mut i = 0;
+        while i < cascade.len() {
+            if let Some(dependants) = self.edges.get(&cascade[i]) {
+                for dependant in dependants {
+                    if !cascade.contains(dependant) {
+                        cascade.push(dependant.clone());
+                    }
+                }
+            }
+            i += 1;
+        }
+        for dependant in &cascade[1..] {
+            match self.shard_of(&dependant.entity_id) {
+                Some(other) if other != shard => {
+                    let mut locked = self.shards[other].lock().unwrap();
+                    if !locked.invalidated.contains(dependant) {
+                        locked.invalidated.push(dependant.clone());
+                    }
+                },
+                _ => {}
+            }
+        }
+        Ok(cascade)
+    }
+    // What in the shard was invalidated by sets in other shards since the last call
+    pub fn take_invalidated(&self, shard: usize) -> Vec<PropRef> {
+        ::std::mem::replace(&mut self.shards[shard].lock().unwrap().invalidated, vec![])
+    }
+    // Sets everything set through the shards since the last write_back on document, shard by shard, and
+    // returns what was set. On an error the sets that weren't applied stay changed, so a later write_back
+    // picks them up again.
+    pub fn write_back(&self, document: &mut Document) -> Result<Vec<PropRef>, DocError> {
+        let mut written = vec![];
+        for shard in &self.shards {
+            let (changed, values): (Vec<PropRef>, Vec<String>) = {
+                let locked = shard.lock().unwrap();
+                let values = locked.changed.iter().map(|prop_ref| locked.values[prop_ref].clone()).collect();
+                (locked.changed.clone(), values)
+            };
+            let mut applied = 0;
+            let mut result = Ok(());
+            for (prop_ref, source) in changed.iter().zip(values.iter()) {
+                result = Pon::from_string(source)
+                    .map_err(|err| DocError::PonTranslateErr(PonTranslateErr::Generic(format!("{:?}", err))))
+                    .and_then(|expression| document.set_property(&prop_ref.entity_id, &prop_ref.property_key, expression));
+                if result.is_err() {
+                    break;
+                }
+                applied += 1;
+            }
+            // Other threads may have set more in the meantime; only what was applied is taken off
+            shard.lock().unwrap().changed.retain(|prop_ref| !changed[..applied].contains(prop_ref));
+            written.extend(changed.into_iter().take(applied));
+            try!(result);
+        }
+        Ok(written)
+    }
+}
+
+
+#[test]
+fn test_shard_by_subtree() {
+    let doc = Document::from_string(r#"<Entity name="a" x="1.0"><Entity /></Entity><Entity name="b" y="@a.x" />"#).unwrap();
+    let shards = shard_by_subtree(&doc, 2).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    assert!(shards.shard_of(&a) != shards.shard_of(&b));
+    assert_eq!(shards.cross_shard_edges, vec![(PropRef::new(&a, "x"), PropRef::new(&b, "y"))]);
+}
+
+#[test]
+fn test_sharded_document() {
+    use std::sync::Arc;
+    use std::thread;
+    let mut doc = Document::from_string(r#"<Entity name="a" x="1"><Entity /></Entity><Entity name="b" y="@a.x" z="2" />"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let sharded = Arc::new(ShardedDocument::split(&doc, 2).unwrap());
+    let threads: Vec<_> = vec![(a, "x", 5), (b, "z", 6)].into_iter().map(|(entity_id, key, value)| {
+        let sharded = sharded.clone();
+        thread::spawn(move || sharded.set_property(&entity_id, key, Pon::Integer(value)).unwrap())
+    }).collect();
+    let cascades: Vec<Vec<PropRef>> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    assert_eq!(cascades[0], vec![PropRef::new(&a, "x"), PropRef::new(&b, "y")]);
+    assert_eq!(sharded.take_invalidated(sharded.shard_of(&b).unwrap()), vec![PropRef::new(&b, "y")]);
+    assert_eq!(sharded.set_property(&b, "z", Pon::from_string("@a.x").unwrap()), Err(DocError::ShardedReference(PropRef::new(&b, "z"))));
+    assert_eq!(sharded.write_back(&mut doc).unwrap().len(), 2);
+    assert_eq!(doc.get_property(&b, "y").unwrap().concretize().unwrap(), Pon::Integer(5));
+    assert_eq!(doc.get_property(&b, "z").unwrap().concretize().unwrap(), Pon::Integer(6));
+}
+
+#[test]
+fn test_write_back_error() {
+    let mut doc = Document::from_string(r#"<Entity name="a" x="1" y="2" />"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let sharded = ShardedDocument::split(&doc, 1).unwrap();
+    sharded.set_property(&a, "x", Pon::Integer(5)).unwrap();
+    sharded.set_property(&a, "y", Pon::Integer(6)).unwrap();
+    doc.freeze_subtree(&a).unwrap();
+    assert_eq!(sharded.write_back(&mut doc), Err(DocError::EntityFrozen(a)));
+    doc.unfreeze_subtree(&a).unwrap();
+    assert_eq!(sharded.write_back(&mut doc).unwrap(), vec![PropRef::new(&a, "x"), PropRef::new(&a, "y")]);
+    assert_eq!(*doc.get_property(&a, "y").unwrap(), Pon::Integer(6));
+    assert_eq!(sharded.write_back(&mut doc).unwrap(), vec![]);
+}
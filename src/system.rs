@@ -54,26 +54,16 @@ impl System {
         self.running = false;
     }
     fn build_property_cascades(&mut self) -> Vec<PropRef> {
-        let mut ips = mem::replace(&mut *self.changed_properties.borrow_mut(), HashSet::new());
-        let mut queue: Vec<PropRef> = ips.clone().into_iter().collect();
-        while queue.len() > 0 {
-            let prop_ref = queue.pop().unwrap();
-            let deps = match self.document.get_property_dependants(&prop_ref.entity_id, &prop_ref.property_key) {
-                Ok(deps) => deps,
-                Err(_) => continue
-            };
-            for pr in deps {
-                if ips.insert(pr.clone()) {
-                    queue.push(pr.clone());
-                }
-            }
-        }
-        ips.into_iter().collect()
+        let ips = mem::replace(&mut *self.changed_properties.borrow_mut(), HashSet::new());
+        self.document.build_cascade(ips.into_iter().collect())
     }
     pub fn update(&mut self) {
         for system in self.sub_systems.clone() {
             system.borrow_mut().update(self);
         }
+        // Coalesced notifications (see Document::set_notification_window) come in once per update; with a
+        // window of 0 this is the only place they're flushed
+        self.document.flush_notifications();
         while {
             let ae = mem::replace(&mut *self.added_entities.borrow_mut(), vec![]);
             for e in ae {
@@ -81,6 +71,7 @@ impl System {
             }
             let ips = self.build_property_cascades();
             self.on_property_value_change(&ips);
+            self.document.flush_notifications();
             self.changed_properties.borrow().len() > 0 || self.added_entities.borrow().len() > 0
         } {};
     }
@@ -0,0 +1,238 @@
+
+#[cfg(feature = "fs")]
+use std::fs::File;
+#[cfg(feature = "fs")]
+use std::fs::OpenOptions;
+#[cfg(feature = "fs")]
+use std::io::BufRead;
+#[cfg(feature = "fs")]
+use std::io::BufReader;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+
+use document::*;
+use pon::*;
+
+// Appends every mutation of a document to a log next to its snapshot, so `Document::recover` can rebuild the
+// document after a crash. Entity ids in the log are the ids the entities get when the snapshot is reloaded,
+// which is why we keep a mapping from live ids. Entries are numbered from 0 since the last snapshot, which
+// lets `Document::replay_to` rebuild any intermediate state.
+pub struct WriteAheadLog {
+    snapshot_path: PathBuf,
+    file: File,
+    entries: usize,
+    log_ids: HashMap<EntityId, EntityId>,
+    next_log_id: EntityId
+}
+
+// Logs can only be created with the fs feature; without it the type is only there for Document's field
+#[cfg(not(feature = "fs"))]
+type File = ::std::io::Sink;
+
+#[cfg(feature = "fs")]
+fn sync(file: &File) -> ::std::io::Result<()> {
+    file.sync_data()
+}
+
+#[cfg(not(feature = "fs"))]
+fn sync(_file: &File) -> ::std::io::Result<()> {
+    Ok(())
+}
+
+pub fn log_path(snapshot_path: &Path) -> PathBuf {
+    snapshot_path.with_extension("wal")
+}
+
+fn io_err<T: ToString>(err: T) -> DocError {
+    DocError::IoError(err.to_string())
+}
+
+fn escape(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\t", "\\t").replace("\n", "\\n")
+}
+
+#[cfg(feature = "fs")]
+fn unescape(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some(c) => out.push(c),
+                None => {}
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(feature = "fs")]
+fn parse_id(value: &str) -> Result<EntityId, DocError> {
+    value.parse().map_err(|_| DocError::IoError(format!("Bad entity id in write-ahead log: {}", value)))
+}
+
+impl WriteAheadLog {
+    // snapshot_order is the entities in the order they are written to (and therefore re-read from) the snapshot
+    #[cfg(feature = "fs")]
+    pub fn create(snapshot_path: &Path, snapshot_order: &Vec<EntityId>) -> Result<WriteAheadLog, DocError> {
+        let file = try!(File::create(log_path(snapshot_path)).map_err(io_err));
+        let mut log = WriteAheadLog {
+            snapshot_path: snapshot_path.to_path_buf(),
+            file: file,
+            entries: 0,
+            log_ids: HashMap::new(),
+            next_log_id: 1
+        };
+        log.map_snapshot(snapshot_order);
+        Ok(log)
+    }
+    pub fn snapshot_path(&self) -> &Path {
+        &self.snapshot_path
+    }
+    // Sequence number the next entry will get
+    pub fn next_seq(&self) -> usize {
+        self.entries
+    }
+    // Called after a new snapshot has been written; the log restarts empty
+    #[cfg(feature = "fs")]
+    pub fn reset(&mut self, snapshot_order: &Vec<EntityId>) -> Result<(), DocError> {
+        self.file = try!(File::create(log_path(&self.snapshot_path)).map_err(io_err));
+        self.entries = 0;
+        self.map_snapshot(snapshot_order);
+        Ok(())
+    }
+    #[cfg(feature = "fs")]
+    fn map_snapshot(&mut self, snapshot_order: &Vec<EntityId>) {
+        self.log_ids.clear();
+        self.next_log_id = 1;
+        for id in snapshot_order {
+            self.log_ids.insert(*id, self.next_log_id);
+            self.next_log_id += 1;
+        }
+    }
+    fn log_id(&self, entity_id: &EntityId) -> Result<EntityId, DocError> {
+        match self.log_ids.get(entity_id) {
+            Some(id) => Ok(*id),
+            None => Err(DocError::NoSuchEntity(*entity_id))
+        }
+    }
+    fn write_line(&mut self, fields: Vec<String>) -> Result<(), DocError> {
+        let fields: Vec<String> = fields.iter().map(|x| escape(x)).collect();
+        let line = format!("{}\n", fields.join("\t"));
+        try!(self.file.write_all(line.as_bytes()).map_err(io_err));
+        self.entries += 1;
+        sync(&self.file).map_err(io_err)
+    }
+    pub fn log_append_entity(&mut self, entity_id: &EntityId, parent_id: Option<EntityId>, type_name: &str, name: &Option<String>) -> Result<(), DocError> {
+        let parent = match parent_id {
+            Some(parent_id) => try!(self.log_id(&parent_id)).to_string(),
+            None => "-".to_string()
+        };
+        let log_id = self.next_log_id;
+        self.next_log_id += 1;
+        self.log_ids.insert(*entity_id, log_id);
+        let name = match name {
+            &Some(ref name) => format!("={}", name),
+            &None => "-".to_string()
+        };
+        self.write_line(vec!["append".to_string(), parent, type_name.to_string(), name])
+    }
+    pub fn log_set_property(&mut self, entity_id: &EntityId, property_key: &str, expression: &str) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(entity_id));
+        self.write_line(vec!["set".to_string(), log_id.to_string(), property_key.to_string(), expression.to_string()])
+    }
+    pub fn log_unset_property(&mut self, entity_id: &EntityId, property_key: &str) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(entity_id));
+        self.write_line(vec!["unset".to_string(), log_id.to_string(), property_key.to_string()])
+    }
+    pub fn log_set_entity_type(&mut self, entity_id: &EntityId, type_name: &str) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(entity_id));
+        self.write_line(vec!["retype".to_string(), log_id.to_string(), type_name.to_string()])
+    }
+    pub fn log_remove_property(&mut self, entity_id: &EntityId, property_key: &str) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(entity_id));
+        self.write_line(vec!["remove_property".to_string(), log_id.to_string(), property_key.to_string()])
+    }
+    pub fn log_move_child(&mut self, parent_id: &EntityId, from: usize, to: usize) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(parent_id));
+        self.write_line(vec!["move".to_string(), log_id.to_string(), from.to_string(), to.to_string()])
+    }
+    pub fn log_rename_entity(&mut self, entity_id: &EntityId, new_name: &str) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(entity_id));
+        self.write_line(vec!["rename".to_string(), log_id.to_string(), new_name.to_string()])
+    }
+    pub fn log_trash_entity(&mut self, entity_id: &EntityId) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(entity_id));
+        self.write_line(vec!["trash".to_string(), log_id.to_string()])
+    }
+    pub fn log_restore_entity(&mut self, entity_id: &EntityId) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(entity_id));
+        self.write_line(vec!["restore".to_string(), log_id.to_string()])
+    }
+    pub fn log_remove_entity(&mut self, entity_id: &EntityId) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(entity_id));
+        self.write_line(vec!["remove".to_string(), log_id.to_string()])
+    }
+    pub fn log_reparent_entity(&mut self, entity_id: &EntityId, new_parent_id: &EntityId, index: usize) -> Result<(), DocError> {
+        let log_id = try!(self.log_id(entity_id));
+        let parent_log_id = try!(self.log_id(new_parent_id));
+        self.write_line(vec!["reparent".to_string(), log_id.to_string(), parent_log_id.to_string(), index.to_string()])
+    }
+    // Applies the log (if there is one) for snapshot_path to a document freshly loaded from that snapshot
+    #[cfg(feature = "fs")]
+    pub fn replay(document: &mut Document, snapshot_path: &Path) -> Result<(), DocError> {
+        WriteAheadLog::replay_until(document, snapshot_path, None)
+    }
+    // Like replay, but stops before entry number end_seq
+    #[cfg(feature = "fs")]
+    pub fn replay_until(document: &mut Document, snapshot_path: &Path, end_seq: Option<usize>) -> Result<(), DocError> {
+        let file = match OpenOptions::new().read(true).open(log_path(snapshot_path)) {
+            Ok(file) => file,
+            Err(_) => return Ok(())
+        };
+        let mut seq = 0;
+        for line in BufReader::new(file).lines() {
+            let line = try!(line.map_err(io_err));
+            if line.len() == 0 { continue; }
+            if let Some(end_seq) = end_seq {
+                if seq >= end_seq { break; }
+            }
+            seq += 1;
+            let fields: Vec<String> = line.split('\t').map(|x| unescape(x)).collect();
+            match (fields[0].as_str(), fields.len()) {
+                ("append", 4) => {
+                    let parent = if fields[1] == "-" { None } else { Some(try!(parse_id(&fields[1]))) };
+                    let name = if fields[3] == "-" { None } else { Some(fields[3][1..].to_string()) };
+                    try!(document.append_entity(parent, &fields[2], name));
+                },
+                ("set", 4) => {
+                    let entity_id = try!(parse_id(&fields[1]));
+                    let expression = try!(Pon::from_string(&fields[3]).map_err(|err| DocError::IoError(format!("{:?}", err))));
+                    try!(document.set_property(&entity_id, &fields[2], expression));
+                },
+                ("unset", 3) => { try!(document.unset_property(&try!(parse_id(&fields[1])), &fields[2])); },
+                ("retype", 3) => try!(document.set_entity_type_name(&try!(parse_id(&fields[1])), &fields[2])),
+                ("remove_property", 3) => { try!(document.remove_property(&try!(parse_id(&fields[1])), &fields[2])); },
+                ("move", 4) => {
+                    let parse_index = |value: &str| value.parse().map_err(|_| DocError::IoError(format!("Bad index in write-ahead log: {}", value)));
+                    try!(document.move_child(&try!(parse_id(&fields[1])), try!(parse_index(&fields[2])), try!(parse_index(&fields[3]))));
+                },
+                ("rename", 3) => { try!(document.rename_entity(&try!(parse_id(&fields[1])), &fields[2])); },
+                ("trash", 2) => try!(document.trash_entity(&try!(parse_id(&fields[1])))),
+                ("restore", 2) => try!(document.restore_entity(&try!(parse_id(&fields[1])))),
+                ("reparent", 4) => {
+                    let index = try!(fields[3].parse().map_err(|_| DocError::IoError(format!("Bad index in write-ahead log: {}", fields[3]))));
+                    try!(document.reparent_entity(&try!(parse_id(&fields[1])), &try!(parse_id(&fields[2])), index));
+                },
+                ("remove", 2) => { try!(document.remove_entity(&try!(parse_id(&fields[1])))); },
+                _ => return Err(DocError::IoError(format!("Bad write-ahead log entry: {}", line)))
+            }
+        }
+        Ok(())
+    }
+}
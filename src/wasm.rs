@@ -0,0 +1,93 @@
+// wasm-bindgen wrappers over the document API, for viewers and editors running in a browser. Build for
+// wasm32 with --no-default-features --features wasm: there's no filesystem there, so includes are read from
+// the files handed to add_file. Entity ids are f64 on the JavaScript side, exact up to 2^53.
+
+use std::path::Path;
+use std::rc::Rc;
+
+use wasm_bindgen::prelude::*;
+
+use document::*;
+use import::MemoryResolver;
+use pon::*;
+
+fn js_err(err: DocError) -> JsValue {
+    JsValue::from_str(&format!("{:?}", err))
+}
+
+fn parse(expression: &str) -> Result<Pon, JsValue> {
+    Pon::from_string(expression).map_err(|err| JsValue::from_str(&format!("{:?}", err)))
+}
+
+#[wasm_bindgen]
+pub struct WasmDocument {
+    document: Document,
+    files: Rc<MemoryResolver>
+}
+
+#[wasm_bindgen]
+impl WasmDocument {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmDocument {
+        WasmDocument::wrap(Document::new())
+    }
+    fn wrap(mut document: Document) -> WasmDocument {
+        let files = Rc::new(MemoryResolver::new());
+        document.include_resolver = Some(Box::new(files.clone()));
+        WasmDocument { document: document, files: files }
+    }
+    // Makes path available to Include elements loaded afterwards
+    pub fn add_file(&mut self, path: &str, contents: &str) {
+        self.files.insert(Path::new(path), contents.as_bytes().to_vec());
+    }
+    // Loads the xml below parent_id, or at the top level
+    pub fn append_from_string(&mut self, parent_id: Option<f64>, xml: &str) -> Result<(), JsValue> {
+        self.document.append_from_string(parent_id.map(|id| id as EntityId), xml).map_err(js_err)
+    }
+    pub fn to_xml(&self) -> String {
+        self.document.to_string()
+    }
+    pub fn root(&self) -> Option<f64> {
+        self.document.get_root().map(|id| id as f64)
+    }
+    pub fn entity_by_name(&self, name: &str) -> Option<f64> {
+        self.document.get_entity_by_name(name).map(|id| id as f64)
+    }
+    pub fn type_name(&self, entity_id: f64) -> Result<String, JsValue> {
+        self.document.get_entity_type_name(&(entity_id as EntityId)).map(|name| name.to_string()).map_err(js_err)
+    }
+    pub fn children(&self, entity_id: f64) -> Result<Vec<f64>, JsValue> {
+        self.document.get_children(&(entity_id as EntityId)).map(|children| children.iter().map(|id| *id as f64).collect()).map_err(js_err)
+    }
+    // See selector.rs
+    pub fn query(&self, selector: &str) -> Result<Vec<f64>, JsValue> {
+        self.document.query(selector).map(|ids| ids.into_iter().map(|id| id as f64).collect()).map_err(js_err)
+    }
+    pub fn append_entity(&mut self, parent_id: Option<f64>, type_name: &str, name: Option<String>) -> Result<f64, JsValue> {
+        self.document.append_entity(parent_id.map(|id| id as EntityId), type_name, name).map(|id| id as f64).map_err(js_err)
+    }
+    pub fn remove_entity(&mut self, entity_id: f64) -> Result<(), JsValue> {
+        self.document.remove_entity(&(entity_id as EntityId)).map(|_| ()).map_err(js_err)
+    }
+    // The expression as set, as PON source
+    pub fn get_expression(&self, entity_id: f64, key: &str) -> Result<String, JsValue> {
+        self.document.get_property(&(entity_id as EntityId), key).map(|value| value.to_string()).map_err(js_err)
+    }
+    // The resolved value, as PON source
+    pub fn get_property(&self, entity_id: f64, key: &str) -> Result<String, JsValue> {
+        let value = try!(self.document.get_property(&(entity_id as EntityId), key).map_err(js_err));
+        value.concretize().map(|value| value.to_string()).map_err(|err| JsValue::from_str(&format!("{:?}", err)))
+    }
+    // expression is PON source, and can reference other properties like in xml
+    pub fn set_property(&mut self, entity_id: f64, key: &str, expression: &str) -> Result<(), JsValue> {
+        let expression = try!(parse(expression));
+        self.document.set_property(&(entity_id as EntityId), key, expression).map_err(js_err)
+    }
+}
+
+#[wasm_bindgen]
+pub fn document_from_string(xml: &str) -> Result<WasmDocument, JsValue> {
+    let mut document = WasmDocument::new();
+    try!(document.append_from_string(None, xml));
+    Ok(document)
+}
@@ -0,0 +1,220 @@
+
+use std::path::{Path, PathBuf};
+
+use document::*;
+use pon::*;
+
+// A set of documents that reference each other by entity name (e.g. a level and the files it includes),
+// with refactorings that keep all of them consistent. Each refactoring checks every document before
+// changing any, so a conflict anywhere leaves the whole workspace untouched.
+pub struct Workspace {
+    pub documents: Vec<(PathBuf, Document)>
+}
+
+// What a refactoring changed, by document path
+#[derive(PartialEq, Debug, Clone)]
+pub struct RefactorReport {
+    // Entities renamed or retyped, and entities whose property was renamed
+    pub entities: Vec<(PathBuf, EntityId)>,
+    // Properties whose expression was rewritten to follow the change
+    pub references: Vec<(PathBuf, PropRef)>
+}
+
+impl RefactorReport {
+    fn new() -> RefactorReport {
+        RefactorReport { entities: vec![], references: vec![] }
+    }
+}
+
+impl Workspace {
+    pub fn new() -> Workspace {
+        Workspace { documents: vec![] }
+    }
+    pub fn add(&mut self, path: &Path, document: Document) {
+        self.documents.push((path.to_path_buf(), document));
+    }
+    pub fn get(&self, path: &Path) -> Option<&Document> {
+        self.documents.iter().find(|&&(ref p, _)| p == path).map(|&(_, ref document)| document)
+    }
+    // Files included by several of the documents are only parsed once
+    #[cfg(feature = "fs")]
+    pub fn load(paths: &[&Path]) -> Result<Workspace, DocError> {
+        let mut workspace = Workspace::new();
+        let include_cache = ::std::rc::Rc::new(IncludeCache::new());
+        for path in paths {
+            let mut document = Document::new();
+            document.include_cache = Some(include_cache.clone());
+            try!(document.append_from_file(None, path));
+            workspace.add(path, document);
+        }
+        Ok(workspace)
+    }
+    // Writes the documents that changed; returns the paths written
+    #[cfg(feature = "fs")]
+    pub fn save_dirty(&mut self) -> Result<Vec<PathBuf>, DocError> {
+        let mut saved = vec![];
+        for &mut (ref path, ref mut document) in self.documents.iter_mut() {
+            if try!(document.save_dirty(path)) {
+                saved.push(path.clone());
+            }
+        }
+        Ok(saved)
+    }
+
+    // Renames the entity named old_name, wherever it lives, and every reference to it in every document
+    pub fn rename_entity(&mut self, old_name: &str, new_name: &str) -> Result<RefactorReport, DocError> {
+        if self.documents.iter().all(|&(_, ref document)| document.get_entity_by_name(old_name).is_none()) {
+            return Err(DocError::CantFindEntityByName(old_name.to_string()));
+        }
+        if self.documents.iter().any(|&(_, ref document)| document.get_entity_by_name(new_name).is_some()) {
+            return Err(DocError::NameTaken(new_name.to_string()));
+        }
+        let mut report = RefactorReport::new();
+        for &mut (ref path, ref mut document) in self.documents.iter_mut() {
+            for prop_ref in try!(document.replace_references(old_name, new_name, true)) {
+                report.references.push((path.clone(), prop_ref));
+            }
+            match document.get_entity_by_name(old_name) {
+                Some(entity_id) => {
+                    try!(document.rename_entity(&entity_id, new_name));
+                    report.entities.push((path.clone(), entity_id));
+                },
+                None => { try!(document.replace_references(old_name, new_name, false)); }
+            }
+        }
+        Ok(report)
+    }
+
+    // Renames property old_key to new_key on entities of type_name (all entities if None), along with the
+    // references to it. References to entities in other documents are matched by the entity's name.
+    pub fn rename_property(&mut self, type_name: Option<&str>, old_key: &str, new_key: &str) -> Result<RefactorReport, DocError> {
+        let mut moves = vec![];
+        for (i, &(_, ref document)) in self.documents.iter().enumerate() {
+            let mut entity_ids: Vec<EntityId> = document.entities_iter().cloned().collect();
+            entity_ids.sort();
+            for entity_id in entity_ids {
+                if !has_type(document, &entity_id, type_name) || !try!(document.has_property(&entity_id, old_key)) {
+                    continue;
+                }
+                if try!(document.has_property(&entity_id, new_key)) {
+                    return Err(DocError::NameTaken(new_key.to_string()));
+                }
+                moves.push((i, entity_id));
+            }
+        }
+        let mut rewrites = vec![];
+        for (i, &(_, ref document)) in self.documents.iter().enumerate() {
+            let mut entity_ids: Vec<EntityId> = document.entities_iter().cloned().collect();
+            entity_ids.sort();
+            for entity_id in entity_ids {
+                let mut props = try!(document.get_properties(&entity_id));
+                props.sort_by(|a, b| a.property_key.cmp(&b.property_key));
+                for prop_ref in props {
+                    if !try!(document.has_property(&entity_id, &prop_ref.property_key)) {
+                        continue;
+                    }
+                    let mut expression = (*try!(document.get_property(&entity_id, &prop_ref.property_key))).clone();
+                    let mut changed = false;
+                    expression.visit_mut(&mut |node| {
+                        let named_prop_ref = match node {
+                            &mut Pon::DependencyReference(ref mut named_prop_ref, _) => named_prop_ref,
+                            &mut Pon::Reference(ref mut named_prop_ref) => named_prop_ref,
+                            _ => return
+                        };
+                        if named_prop_ref.property_key == old_key && self.targets_type(i, &entity_id, &named_prop_ref.entity_path, type_name) {
+                            named_prop_ref.property_key = new_key.to_string();
+                            changed = true;
+                        }
+                    });
+                    if changed {
+                        rewrites.push((i, prop_ref, expression));
+                    }
+                }
+            }
+        }
+        let mut report = RefactorReport::new();
+        for &(i, entity_id) in &moves {
+            let document = &mut self.documents[i].1;
+            let expression = (*try!(document.get_property(&entity_id, old_key))).clone();
+            try!(document.set_property(&entity_id, new_key, expression));
+        }
+        for (i, prop_ref, expression) in rewrites {
+            try!(self.documents[i].1.set_property(&prop_ref.entity_id, &prop_ref.property_key, expression));
+            report.references.push((self.documents[i].0.clone(), prop_ref));
+        }
+        for (i, entity_id) in moves {
+            try!(self.documents[i].1.remove_property(&entity_id, old_key));
+            report.entities.push((self.documents[i].0.clone(), entity_id));
+        }
+        Ok(report)
+    }
+
+    // Changes the type of every entity of type old_type
+    pub fn rename_type(&mut self, old_type: &str, new_type: &str) -> Result<RefactorReport, DocError> {
+        let mut report = RefactorReport::new();
+        for &mut (ref path, ref mut document) in self.documents.iter_mut() {
+            let mut entity_ids: Vec<EntityId> = document.entities_iter().cloned().collect();
+            entity_ids.sort();
+            for entity_id in entity_ids {
+                let matches = try!(document.get_entity_type_name(&entity_id)) == old_type;
+                if matches {
+                    try!(document.set_entity_type_name(&entity_id, new_type));
+                    report.entities.push((path.clone(), entity_id));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // Whether path, seen from entity_id in document number i, leads to an entity of type_name. Names that
+    // aren't in the document are looked up in the others.
+    fn targets_type(&self, i: usize, entity_id: &EntityId, path: &EntityPath, type_name: Option<&str>) -> bool {
+        let document = &self.documents[i].1;
+        match document.resolve_entity_path(entity_id, path) {
+            Ok(target) => has_type(document, &target, type_name),
+            Err(DocError::CantFindEntityByName(name)) => self.documents.iter().any(|&(_, ref other)| {
+                match other.get_entity_by_name(&name) {
+                    Some(target) => has_type(other, &target, type_name),
+                    None => false
+                }
+            }),
+            Err(_) => false
+        }
+    }
+}
+
+fn has_type(document: &Document, entity_id: &EntityId, type_name: Option<&str>) -> bool {
+    match type_name {
+        Some(type_name) => document.get_entity_type_name(entity_id).map(|t| t == type_name).unwrap_or(false),
+        None => true
+    }
+}
+
+
+#[test]
+fn test_rename_entity() {
+    let mut workspace = Workspace::new();
+    workspace.add(Path::new("player.xml"), Document::from_string(r#"<Entity name="player" health="10" />"#).unwrap());
+    workspace.add(Path::new("hud.xml"), Document::from_string(r#"<Entity name="hud" value="player.health" />"#).unwrap());
+    let report = workspace.rename_entity("player", "hero").unwrap();
+    assert_eq!(report.entities.len(), 1);
+    assert_eq!(report.references.len(), 1);
+    let hud = workspace.get(Path::new("hud.xml")).unwrap();
+    assert_eq!(hud.get_property(&hud.get_entity_by_name("hud").unwrap(), "value").unwrap().to_string(), "hero.health");
+    assert_eq!(workspace.rename_entity("hud", "hero"), Err(DocError::NameTaken("hero".to_string())));
+}
+
+#[test]
+fn test_rename_property() {
+    let mut workspace = Workspace::new();
+    workspace.add(Path::new("player.xml"), Document::from_string(r#"<Player name="player" health="10" />"#).unwrap());
+    workspace.add(Path::new("hud.xml"), Document::from_string(r#"<Entity name="hud" value="player.health" health="1" />"#).unwrap());
+    let report = workspace.rename_property(Some("Player"), "health", "hp").unwrap();
+    assert_eq!(report.entities.len(), 1);
+    let player = workspace.get(Path::new("player.xml")).unwrap();
+    assert_eq!(player.get_property(&player.get_entity_by_name("player").unwrap(), "hp").unwrap().to_string(), "10");
+    let hud = workspace.get(Path::new("hud.xml")).unwrap();
+    let hud_id = hud.get_entity_by_name("hud").unwrap();
+    assert_eq!(hud.get_property(&hud_id, "value").unwrap().to_string(), "player.hp");
+    assert!(hud.has_property(&hud_id, "health").unwrap());
+}